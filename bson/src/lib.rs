@@ -31,6 +31,17 @@ extern crate misc;
 use misc::endian::*;
 use misc::bufndx;
 
+pub mod binary_subtype {
+    pub const GENERIC: u8 = 0;
+    pub const FUNCTION: u8 = 1;
+    // the old binary subtype has an extra length prefix inside the data
+    pub const BINARY_OLD: u8 = 2;
+    pub const UUID_OLD: u8 = 3;
+    pub const UUID: u8 = 4;
+    pub const MD5: u8 = 5;
+    pub const USER_DEFINED: u8 = 128;
+}
+
 #[derive(Debug)]
 pub enum Error {
     // TODO remove Misc
@@ -82,9 +93,13 @@ impl From<std::str::Utf8Error> for Error {
 pub type Result<T> = std::result::Result<T, Error>;
 
 // TODO this function doesn't seem to go here
-pub fn split_name(s: &str) -> (&str, &str) {
-    // TODO
-    (&s[0 .. 2], &s[2 .. 4])
+// splits a full collection name at the first dot.  the collection
+// part can have dots of its own.
+pub fn split_name(s: &str) -> Result<(&str, &str)> {
+    match s.find('.') {
+        Some(dot) if dot > 0 && dot + 1 < s.len() => Ok((&s[0 .. dot], &s[dot + 1 ..])),
+        _ => Err(Error::Misc(format!("invalid namespace: {}", s))),
+    }
 }
 
 // TODO is it sufficient to derive PartialEq?
@@ -212,7 +227,7 @@ impl Document {
                                 v.entry(subpath)
                             },
                             _ => {
-                                Err(Error::Misc(String::from("trying to dive into non-object")))
+                                Err(Error::Misc(format!("cannot set {} inside {}, which is a {}", subpath, name, v.get_type_name())))
                             },
                         }
                     },
@@ -326,6 +341,94 @@ impl Document {
         Ok(())
     }
 
+    // like mongo's $inc.  when the path does not exist, it gets set
+    // to delta.
+    pub fn inc_path(&mut self, path: &str, delta: &Value) -> Result<()> {
+        if !delta.is_numeric() {
+            return Err(Error::Misc(format!("argument to $inc must be numeric")));
+        }
+        match try!(self.entry(path)) {
+            Entry::Found(e) => {
+                let sum = try!(e.get().add_numeric(delta));
+                let _ = e.replace(sum);
+            },
+            Entry::Absent(e) => try!(e.insert(delta.clone())),
+        }
+        Ok(())
+    }
+
+    // like mongo's $unset.  a path which does not exist is not an error,
+    // but the result says whether anything was removed.
+    pub fn unset_path(&mut self, path: &str) -> Result<bool> {
+        match path.find('.') {
+            None => Ok(self.remove(path).is_some()),
+            Some(dot) => {
+                match self.get_mut(&path[0 .. dot]) {
+                    Some(v) => v.unset_path(&path[dot + 1 ..]),
+                    None => Ok(false),
+                }
+            },
+        }
+    }
+
+    // like mongo's $push.  when the path does not exist, it gets set
+    // to an array containing just v.
+    pub fn push_path(&mut self, path: &str, v: Value) -> Result<()> {
+        match try!(self.entry(path)) {
+            Entry::Found(mut e) => {
+                match e.get_mut() {
+                    &mut Value::BArray(ref mut ba) => ba.items.push(v),
+                    _ => return Err(Error::Misc(format!("$push requires an array: {}", path))),
+                }
+            },
+            Entry::Absent(e) => try!(e.insert(Value::BArray(Array { items: vec![v] }))),
+        }
+        Ok(())
+    }
+
+    // like mongo's $pull with a value.  removes every element which
+    // matches_eq v, and returns how many there were.  a path which does
+    // not exist is not an error.
+    pub fn pull_path(&mut self, path: &str, v: &Value) -> Result<usize> {
+        match try!(self.entry(path)) {
+            Entry::Found(mut e) => {
+                match e.get_mut() {
+                    &mut Value::BArray(ref mut ba) => {
+                        let before = ba.items.len();
+                        ba.items.retain(|item| !item.matches_eq(v));
+                        Ok(before - ba.items.len())
+                    },
+                    _ => Err(Error::Misc(format!("$pull requires an array: {}", path))),
+                }
+            },
+            Entry::Absent(_) => Ok(0),
+        }
+    }
+
+    // like mongo's $addToSet.  v is pushed only if the array does not
+    // already contain it.  returns whether it was added.
+    pub fn add_to_set_path(&mut self, path: &str, v: Value) -> Result<bool> {
+        match try!(self.entry(path)) {
+            Entry::Found(mut e) => {
+                match e.get_mut() {
+                    &mut Value::BArray(ref mut ba) => {
+                        if ba.items.iter().any(|item| *item == v) {
+                            Ok(false)
+                        } else {
+                            ba.items.push(v);
+                            Ok(true)
+                        }
+                    },
+                    _ => Err(Error::Misc(format!("$addToSet requires an array: {}", path))),
+                }
+            },
+            Entry::Absent(e) => {
+                try!(e.insert(Value::BArray(Array { items: vec![v] })));
+                Ok(true)
+            },
+        }
+    }
+
     pub fn set_objectid(&mut self, k: &str, v: [u8; 12]) {
         self.set(k, Value::BObjectID(v));
     }
@@ -370,25 +473,26 @@ impl Document {
         self.set(k, Value::BDateTime(v));
     }
 
-    pub fn to_bson(&self, w: &mut Vec<u8>) {
+    pub fn to_bson(&self, w: &mut Vec<u8>) -> Result<()> {
         let start = w.len();
         // placeholder for length
         w.push_all(&i32_to_bytes_le(0));
         for t in self.pairs.iter() {
             let (ref ksub, ref vsub) = *t;
             w.push(vsub.getTypeNumber_u8());
-            vec_push_c_string(w, &ksub);;
-            vsub.to_bson(w);
+            try!(vec_push_checked_c_string(w, &ksub));
+            try!(vsub.to_bson(w));
         }
         w.push(0u8);
-        let len = w.len() - start;
-        misc::bytes::copy_into(&i32_to_bytes_le(len as i32), &mut w[start .. start + 4]);
+        let len = try!(checked_len(w.len() - start));
+        misc::bytes::copy_into(&i32_to_bytes_le(len), &mut w[start .. start + 4]);
+        Ok(())
     }
 
-    pub fn to_bson_array(&self) -> Vec<u8> {
+    pub fn to_bson_array(&self) -> Result<Vec<u8>> {
         let mut v = Vec::new();
-        self.to_bson(&mut v);
-        v
+        try!(self.to_bson(&mut v));
+        Ok(v)
     }
 
     pub fn find_all_strings<'a>(&'a self, dest: &mut Vec<&'a str>) {
@@ -397,6 +501,14 @@ impl Document {
         }
     }
 
+    // calls f for every value in the document, at any depth, with its
+    // dotted path.  a document or array gets visited before its contents.
+    pub fn walk<F: FnMut(&str, &Value)>(&self, f: &mut F) {
+        for &(ref k, ref v) in &self.pairs {
+            v.walk_at(k, f);
+        }
+    }
+
     pub fn find_path(&self, path: &str) -> Value {
         let dot = path.find('.');
         let name = match dot { 
@@ -416,10 +528,47 @@ impl Document {
         }
     }
 
+    // like find_path(), but instead of gathering the matches inside an
+    // array into one array value, returns every value the path reaches,
+    // one per element.  empty when nothing matches.
+    pub fn find_path_all(&self, path: &str) -> Vec<Value> {
+        let mut dest = Vec::new();
+        self.find_path_all_into(path, &mut dest);
+        dest
+    }
+
+    fn find_path_all_into(&self, path: &str, dest: &mut Vec<Value>) {
+        let dot = path.find('.');
+        let name = match dot { 
+            None => path,
+            Some(ndx) => &path[0 .. ndx]
+        };
+        match slice_find(&self.pairs, name) {
+            Some(ndx) => {
+                let v = &self.pairs[ndx].1;
+                match dot {
+                    None => dest.push(v.clone()),
+                    Some(dot) => v.find_path_all_into(&path[dot+1..], dest)
+                }
+            },
+            None => ()
+        }
+    }
+
     pub fn from_bson(w: &[u8]) -> Result<Document> {
+        let (d, len) = try!(Self::from_bson_prefix(w));
+        if len != w.len() {
+            return Err(Error::CorruptFile("trailing bytes after document"));
+        }
+        Ok(d)
+    }
+
+    // like from_bson(), but anything after the document is allowed.
+    // returns the number of bytes consumed.
+    pub fn from_bson_prefix(w: &[u8]) -> Result<(Document, usize)> {
         let mut cur = 0;
         let d = try!(slurp_document(w, &mut cur));
-        Ok(d)
+        Ok((d, cur))
     }
 
     pub fn is_dbref(&self) -> bool {
@@ -503,7 +652,7 @@ impl Array {
                                     v.entry(subpath)
                                 },
                                 _ => {
-                                    Err(Error::Misc(String::from("trying to dive into non-object")))
+                                    Err(Error::Misc(format!("cannot set {} inside array element {}, which is a {}", subpath, i, v.get_type_name())))
                                 },
                             }
                         },
@@ -530,7 +679,7 @@ impl Array {
         }
     }
 
-    fn to_bson(&self, w: &mut Vec<u8>) {
+    fn to_bson(&self, w: &mut Vec<u8>) -> Result<()> {
         let start = w.len();
         // placeholder for length
         w.push_all(&i32_to_bytes_le(0));
@@ -538,11 +687,12 @@ impl Array {
             w.push(vsub.getTypeNumber_u8());
             let s = format!("{}", i);
             vec_push_c_string(w, &s);
-            vsub.to_bson(w);
+            try!(vsub.to_bson(w));
         }
         w.push(0u8);
-        let len = w.len() - start;
-        misc::bytes::copy_into(&i32_to_bytes_le(len as i32), &mut w[start .. start + 4]);
+        let len = try!(checked_len(w.len() - start));
+        misc::bytes::copy_into(&i32_to_bytes_le(len), &mut w[start .. start + 4]);
+        Ok(())
     }
 
     fn find_all_strings<'a>(&'a self, dest: &mut Vec<&'a str>) {
@@ -551,6 +701,18 @@ impl Array {
         }
     }
 
+    // append nulls until the array has len items
+    fn pad_to(&mut self, len: usize) -> Result<()> {
+        if len > 1500001 {
+            // TODO same limit as setValueAtIndex
+            return Err(Error::Misc(format!("array index too big: {}", len)));
+        }
+        while self.items.len() < len {
+            self.items.push(Value::BNull);
+        }
+        Ok(())
+    }
+
     fn tryGetValueAtIndex(&self, ndx: usize) -> Option<&Value> {
         if ndx<0 {
             return None
@@ -608,12 +770,33 @@ pub enum Value {
 // So we provide implementations below for Value that
 // are sufficient for our purposes.
 
+// two values are equal when they have the same type and would encode
+// the same, so a double is compared by its bits, and a document by its
+// pairs in order.  unlike encoding, this cannot fail.
+
 impl PartialEq for Value {
     fn eq(&self, other: &Value) -> bool {
-        // TODO slow
-        let a = self.to_bson_array();
-        let b = other.to_bson_array();
-        a == b
+        match (self, other) {
+            (&Value::BDouble(a), &Value::BDouble(b)) => f64_to_bytes_le(a) == f64_to_bytes_le(b),
+            (&Value::BString(ref a), &Value::BString(ref b)) => a == b,
+            (&Value::BInt64(a), &Value::BInt64(b)) => a == b,
+            (&Value::BInt32(a), &Value::BInt32(b)) => a == b,
+            (&Value::BUndefined, &Value::BUndefined) => true,
+            (&Value::BObjectID(ref a), &Value::BObjectID(ref b)) => a == b,
+            (&Value::BNull, &Value::BNull) => true,
+            (&Value::BRegex(ref a_expr, ref a_opt), &Value::BRegex(ref b_expr, ref b_opt)) => a_expr == b_expr && a_opt == b_opt,
+            (&Value::BJSCode(ref a), &Value::BJSCode(ref b)) => a == b,
+            (&Value::BJSCodeWithScope(ref a), &Value::BJSCodeWithScope(ref b)) => a == b,
+            (&Value::BBinary(a_subtype, ref a), &Value::BBinary(b_subtype, ref b)) => a_subtype == b_subtype && a == b,
+            (&Value::BMinKey, &Value::BMinKey) => true,
+            (&Value::BMaxKey, &Value::BMaxKey) => true,
+            (&Value::BDateTime(a), &Value::BDateTime(b)) => a == b,
+            (&Value::BTimeStamp(a), &Value::BTimeStamp(b)) => a == b,
+            (&Value::BBoolean(a), &Value::BBoolean(b)) => a == b,
+            (&Value::BArray(ref a), &Value::BArray(ref b)) => a.items == b.items,
+            (&Value::BDocument(ref a), &Value::BDocument(ref b)) => a.pairs == b.pairs,
+            _ => false,
+        }
     }
 }
 
@@ -622,9 +805,41 @@ impl Eq for Value {
 
 impl std::hash::Hash for Value {
     fn hash<H>(&self, state: &mut H) where H: std::hash::Hasher {
-        // TODO slow
-        let a = self.to_bson_array();
-        state.write(&a);
+        use std::hash::Hash;
+
+        state.write_u8(self.getTypeNumber_u8());
+        match self {
+            &Value::BDouble(f) => state.write(&f64_to_bytes_le(f)),
+            &Value::BString(ref s) => s.hash(state),
+            &Value::BInt64(n) => n.hash(state),
+            &Value::BInt32(n) => n.hash(state),
+            &Value::BUndefined => (),
+            &Value::BObjectID(ref a) => state.write(a),
+            &Value::BNull => (),
+            &Value::BRegex(ref expr, ref opt) => {
+                expr.hash(state);
+                opt.hash(state);
+            },
+            &Value::BJSCode(ref s) => s.hash(state),
+            &Value::BJSCodeWithScope(ref s) => s.hash(state),
+            &Value::BBinary(subtype, ref ba) => {
+                state.write_u8(subtype);
+                ba.hash(state);
+            },
+            &Value::BMinKey => (),
+            &Value::BMaxKey => (),
+            &Value::BDateTime(n) => n.hash(state),
+            &Value::BTimeStamp(n) => n.hash(state),
+            &Value::BBoolean(b) => b.hash(state),
+            &Value::BArray(ref ba) => ba.items.hash(state),
+            &Value::BDocument(ref bd) => {
+                for &(ref k, ref v) in &bd.pairs {
+                    k.hash(state);
+                    v.hash(state);
+                }
+                state.write_usize(bd.pairs.len());
+            },
+        }
     }
 }
 
@@ -633,11 +848,33 @@ fn vec_push_c_string(v: &mut Vec<u8>, s: &str) {
     v.push(0);
 }
 
-fn vec_push_bson_string(v: &mut Vec<u8>, s: &str) {
+// a cstring in bson ends at the first 0, so one inside s would
+// truncate it when the document is parsed again.
+fn vec_push_checked_c_string(v: &mut Vec<u8>, s: &str) -> Result<()> {
+    if s.as_bytes().contains(&0) {
+        return Err(Error::Misc(format!("cstring cannot contain a 0 byte: {:?}", s)));
+    }
+    vec_push_c_string(v, s);
+    Ok(())
+}
+
+// bson lengths are signed 32-bit.  a length which does not fit is an
+// error, rather than a cast that wraps around to a negative number.
+pub fn checked_len(n: usize) -> Result<i32> {
+    if n > (std::i32::MAX as usize) {
+        Err(Error::Misc(format!("too long for bson: {} bytes", n)))
+    } else {
+        Ok(n as i32)
+    }
+}
+
+fn vec_push_bson_string(v: &mut Vec<u8>, s: &str) -> Result<()> {
     // TODO i32 vs u32.  silly.
-    v.push_all(&i32_to_bytes_le( (s.len() + 1) as i32 ));
+    let len = try!(checked_len(s.len() + 1));
+    v.push_all(&i32_to_bytes_le(len));
     v.push_all(s.as_bytes());
     v.push(0);
+    Ok(())
 }
 
 // TODO this should be a library func, right?
@@ -651,9 +888,25 @@ fn slice_find(pairs: &[(String, Value)], s: &str) -> Option<usize> {
     None
 }
 
+// every length in bson is a signed little-endian i32.  a negative one
+// can only be corruption, and read as unsigned it would be huge.
+fn read_bson_len(ba: &[u8], i: &mut usize) -> Result<usize> {
+    if *i + 4 > ba.len() {
+        return Err(Error::CorruptFile("length extends past the end of the buffer"));
+    }
+    let len = bufndx::slurp_i32_le(ba, i);
+    if len < 0 {
+        return Err(Error::CorruptFile("negative length"));
+    }
+    Ok(len as usize)
+}
+
 fn slurp_bson_string(ba: &[u8], i: &mut usize) -> Result<String> {
-    // TODO the spec says the len here is a signed number, but that's silly
-    let len = bufndx::slurp_u32_le(ba, i) as usize;
+    let len = try!(read_bson_len(ba, i));
+    // the len includes the terminating null
+    if len == 0 || *i + len > ba.len() {
+        return Err(Error::CorruptFile("bad string length"));
+    }
 
     let s = try!(std::str::from_utf8(&ba[*i .. *i + len - 1]));
     *i = *i + len;
@@ -667,7 +920,7 @@ fn slurp_bson_value(ba: &[u8], i: &mut usize, valtype: u8) -> Result<Value> {
             2 => Value::BString(try!(slurp_bson_string(ba, i))),
             3 => Value::BDocument(try!(slurp_document(ba, i))),
             4 => Value::BArray(try!(slurp_array(ba, i))),
-            5 => slurp_binary(ba, i),
+            5 => try!(slurp_binary(ba, i)),
             6 => Value::BUndefined,
             7 => slurp_objectid(ba, i),
             8 => slurp_boolean(ba, i),
@@ -699,8 +952,7 @@ fn slurp_js(ba: &[u8], i: &mut usize) -> Result<Value> {
 }
 
 fn slurp_js_with_scope(ba: &[u8], i: &mut usize) -> Result<Value> {
-    // TODO the spec says the len here is a signed number, but that's silly
-    let len = bufndx::slurp_u32_le(ba, i);
+    let len = try!(read_bson_len(ba, i));
 
     let a = try!(slurp_bson_string(ba, i));
     let scope = try!(slurp_document(ba, i));
@@ -713,16 +965,32 @@ fn slurp_regex(ba: &[u8], i: &mut usize) -> Result<Value> {
     Ok(Value::BRegex(expr, options))
 }
 
-fn slurp_binary(ba: &[u8], i: &mut usize) -> Value {
-    // TODO the spec says the len here is a signed number, but that's silly
-    let len = bufndx::slurp_u32_le(ba, i) as usize;
+fn slurp_binary(ba: &[u8], i: &mut usize) -> Result<Value> {
+    let len = try!(read_bson_len(ba, i));
 
+    if *i >= ba.len() {
+        return Err(Error::CorruptFile("binary without a subtype"));
+    }
     let subtype = ba[*i];
     *i = *i + 1;
+    let len =
+        if subtype == binary_subtype::BINARY_OLD {
+            // the outer len includes the inner one
+            let inner = try!(read_bson_len(ba, i));
+            if inner + 4 != len {
+                return Err(Error::CorruptFile("old binary lengths disagree"));
+            }
+            inner
+        } else {
+            len
+        };
+    if *i + len > ba.len() {
+        return Err(Error::CorruptFile("binary extends past the end of the buffer"));
+    }
     let mut b = Vec::with_capacity(len);
     b.push_all(&ba[*i .. *i + len]);
     *i = *i + len;
-    Value::BBinary(subtype, b)
+    Ok(Value::BBinary(subtype, b))
 }
 
 fn slurp_objectid(ba: &[u8], i: &mut usize) -> Value {
@@ -739,26 +1007,167 @@ fn slurp_boolean(ba: &[u8], i: &mut usize) -> Value {
 }
 
 fn slurp_document_pairs(ba: &[u8], i: &mut usize) -> Result<Vec<(String, Value)>> {
-    // TODO the spec says the len here is a signed number, but that's silly
-    let len = misc::bufndx::slurp_u32_le(ba, i) as usize;
+    let start = *i;
+    let len = try!(read_bson_len(ba, i));
+    // even an empty document has the length itself and the 0
+    if len < 5 {
+        return Err(Error::CorruptFile("bad document length"));
+    }
 
     let mut pairs = Vec::new();
-    while ba[*i] != 0 {
-        let valtype = ba[*i];
-        *i = *i + 1;
-        let k = try!(bufndx::slurp_cstring(ba, i));
-        let v = try!(slurp_bson_value(ba, i, valtype));
-        pairs.push((k,v));
-    }
-    assert!(ba[*i] == 0);
+    loop {
+        match bufndx::peek_u8(ba, *i) {
+            None => return Err(Error::CorruptFile("document not terminated")),
+            Some(0) => break,
+            Some(valtype) => {
+                *i = *i + 1;
+                let k = try!(bufndx::slurp_cstring(ba, i));
+                let v = try!(slurp_bson_value(ba, i, valtype));
+                pairs.push((k,v));
+            },
+        }
+    }
+    // the 0 which ended the pairs must be the last byte the length covers
+    if *i != start + len - 1 {
+        return Err(Error::CorruptFile("document length does not match where it ends"));
+    }
     *i = *i + 1;
-    // TODO verify len
     Ok(pairs)
 }
 
+// BSON allows a key to appear more than once, but everything else in
+// Document (get, set, remove) assumes it doesn't.  so when parsing, the
+// last value for a key wins, and it stays in the position where the
+// key first appeared.
 pub fn slurp_document(ba: &[u8], i: &mut usize) -> Result<Document> {
     let pairs = try!(slurp_document_pairs(ba, i));
-    Ok(Document {pairs: pairs})
+    // repeats are rare, so the keys are only copied when there is one
+    if !has_duplicate_keys(&pairs) {
+        return Ok(Document {pairs: pairs});
+    }
+    let mut unique: Vec<(String, Value)> = Vec::with_capacity(pairs.len());
+    let mut positions: std::collections::HashMap<String, usize> = std::collections::HashMap::with_capacity(pairs.len());
+    for (k, v) in pairs {
+        let found = positions.get(&k).map(|ndx| *ndx);
+        match found {
+            Some(ndx) => unique[ndx].1 = v,
+            None => {
+                positions.insert(k.clone(), unique.len());
+                unique.push((k, v));
+            },
+        }
+    }
+    Ok(Document {pairs: unique})
+}
+
+fn has_duplicate_keys(pairs: &[(String, Value)]) -> bool {
+    let mut seen = std::collections::HashSet::with_capacity(pairs.len());
+    pairs.iter().any(|&(ref k, _)| !seen.insert(k.as_str()))
+}
+
+pub struct DocIter<'a> {
+    ba: &'a [u8],
+    cur: usize,
+}
+
+impl<'a> Iterator for DocIter<'a> {
+    type Item = Result<Document>;
+    fn next(&mut self) -> Option<Result<Document>> {
+        if self.cur >= self.ba.len() {
+            None
+        } else {
+            let r = slurp_document(self.ba, &mut self.cur);
+            if r.is_err() {
+                // no way to find the next document after a bad one
+                self.cur = self.ba.len();
+            }
+            Some(r)
+        }
+    }
+}
+
+// back-to-back documents, as in a dump file or the tail of
+// an insert message.
+pub fn documents_from_bytes(ba: &[u8]) -> DocIter {
+    DocIter {
+        ba: ba,
+        cur: 0,
+    }
+}
+
+// moves past a value without building it.  the lengths get checked
+// against the buffer, since this doesn't look at the bytes otherwise.
+fn skip_bson_value(ba: &[u8], i: &mut usize, valtype: u8) -> Result<()> {
+    fn need(ba: &[u8], i: usize, n: usize) -> Result<()> {
+        if i + n > ba.len() {
+            Err(Error::CorruptFile("value extends past the end of the buffer"))
+        } else {
+            Ok(())
+        }
+    }
+    fn len_at(ba: &[u8], i: usize) -> Result<usize> {
+        let mut j = i;
+        read_bson_len(ba, &mut j)
+    }
+    fn skip_cstring(ba: &[u8], i: usize) -> Result<usize> {
+        match ba[i ..].iter().position(|&b| b == 0) {
+            Some(n) => Ok(n + 1),
+            None => Err(Error::CorruptFile("cstring without a terminator")),
+        }
+    }
+    let n =
+        match valtype {
+            1 | 9 | 17 | 18 => 8,
+            2 | 13 | 14 => 4 + try!(len_at(ba, *i)),
+            // these lengths include themselves
+            3 | 4 | 15 => try!(len_at(ba, *i)),
+            5 => 4 + 1 + try!(len_at(ba, *i)),
+            6 | 10 | 127 | 255 => 0,
+            7 => 12,
+            8 => 1,
+            11 => {
+                let a = try!(skip_cstring(ba, *i));
+                a + try!(skip_cstring(ba, *i + a))
+            },
+            12 => 4 + try!(len_at(ba, *i)) + 12,
+            16 => 4,
+            _ => return Err(Error::CorruptFile("invalid BSON value type")),
+        };
+    try!(need(ba, *i, n));
+    *i = *i + n;
+    Ok(())
+}
+
+// finds one top-level field of an encoded document without decoding
+// the rest of it.  the result is the whole element:  the type byte,
+// the key, and the value.
+pub fn field_bytes<'a>(ba: &'a [u8], key: &str) -> Result<Option<&'a [u8]>> {
+    if ba.len() < 5 {
+        return Err(Error::CorruptFile("document too short"));
+    }
+    let mut i = 4;
+    loop {
+        if i >= ba.len() {
+            return Err(Error::CorruptFile("document without a terminator"));
+        }
+        let valtype = ba[i];
+        if valtype == 0 {
+            return Ok(None);
+        }
+        let start = i;
+        i = i + 1;
+        let nul =
+            match ba[i ..].iter().position(|&b| b == 0) {
+                Some(n) => i + n,
+                None => return Err(Error::CorruptFile("cstring without a terminator")),
+            };
+        let found = &ba[i .. nul] == key.as_bytes();
+        i = nul + 1;
+        try!(skip_bson_value(ba, &mut i, valtype));
+        if found {
+            return Ok(Some(&ba[start .. i]));
+        }
+    }
 }
 
 fn slurp_array(ba: &[u8], i: &mut usize) -> Result<Array> {
@@ -843,7 +1252,9 @@ impl<'v,'p> EntryAbsent<'v,'p> {
                 bd.pairs.push((String::from(k), v));
             },
             EntryAbsent::ArrayParent(ba, i) => {
-                panic!("TODO EntryAbsent::ArrayParent insert: len={}, i={}", ba.len(), i);
+                // like mongo, fill any gap with nulls
+                try!(ba.pad_to(i));
+                ba.items.push(v);
             },
             EntryAbsent::DocumentAncestor(bd, path) => {
                 let dot = path.find('.').expect("should not be here if no dot");
@@ -861,7 +1272,17 @@ impl<'v,'p> EntryAbsent<'v,'p> {
                 }
             },
             EntryAbsent::ArrayAncestor(ba, path) => {
-                panic!("TODO EntryAbsent::ArrayAncestor insert: len={}, path={}", ba.len(), path);
+                let dot = path.find('.').expect("should not be here if no dot");
+                let i = try!(path[0 .. dot].parse::<usize>().map_err(|_| Error::Misc(format!("array index required: {}", path))));
+                let subpath = &path[dot + 1 ..];
+                try!(ba.pad_to(i));
+                let sub =
+                    match subpath.split('.').next().expect("split always has one").parse::<usize>() {
+                        Ok(_) => Value::BArray(Array::new_empty()),
+                        Err(_) => Value::BDocument(Document::new_empty()),
+                    };
+                ba.items.push(sub);
+                try!(ba.items[i].set_path(subpath, v));
             },
         }
         Ok(())
@@ -878,8 +1299,39 @@ impl Value {
         match self {
             &mut Value::BDocument(ref mut bd) => bd.set_path(path, v),
             &mut Value::BArray(ref mut ba) => ba.set_path(path, v),
-            // TODO the following line should probably be Err
-            _ => unreachable!(),
+            _ => Err(Error::Misc(format!("cannot set {} inside a {}", path, self.get_type_name()))),
+        }
+    }
+
+    // an array element does not get removed.  it becomes null, so the
+    // other elements keep their indexes.
+    pub fn unset_path(&mut self, path: &str) -> Result<bool> {
+        match self {
+            &mut Value::BDocument(ref mut bd) => bd.unset_path(path),
+            &mut Value::BArray(ref mut ba) => {
+                let (name, subpath) =
+                    match path.find('.') {
+                        None => (path, None),
+                        Some(dot) => (&path[0 .. dot], Some(&path[dot + 1 ..])),
+                    };
+                match name.parse::<usize>() {
+                    Ok(i) => {
+                        if i >= ba.items.len() {
+                            Ok(false)
+                        } else {
+                            match subpath {
+                                None => {
+                                    ba.unsetValueAtIndex(i);
+                                    Ok(true)
+                                },
+                                Some(subpath) => ba.items[i].unset_path(subpath),
+                            }
+                        }
+                    },
+                    Err(_) => Ok(false),
+                }
+            },
+            _ => Ok(false),
         }
     }
 
@@ -913,6 +1365,56 @@ impl Value {
         }
     }
 
+    // shorthand constructors, so building a value does not need the
+    // variant spelled out every time.
+
+    pub fn int(v: i32) -> Value {
+        Value::BInt32(v)
+    }
+
+    pub fn long(v: i64) -> Value {
+        Value::BInt64(v)
+    }
+
+    pub fn double(v: f64) -> Value {
+        Value::BDouble(v)
+    }
+
+    pub fn string(v: &str) -> Value {
+        Value::BString(String::from(v))
+    }
+
+    pub fn boolean(v: bool) -> Value {
+        Value::BBoolean(v)
+    }
+
+    pub fn null() -> Value {
+        Value::BNull
+    }
+
+    pub fn document(pairs: Vec<(String, Value)>) -> Value {
+        Value::BDocument(Document { pairs: pairs })
+    }
+
+    pub fn array(items: Vec<Value>) -> Value {
+        Value::BArray(Array { items: items })
+    }
+
+    // the number of fields in a document or elements in an array.
+    // None for anything else.
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            &Value::BDocument(ref bd) => Some(bd.pairs.len()),
+            &Value::BArray(ref ba) => Some(ba.items.len()),
+            _ => None,
+        }
+    }
+
+    // true only for a document or array with nothing in it
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
     pub fn is_array(&self) -> bool {
         match self {
             &Value::BArray(_) => true,
@@ -1028,6 +1530,23 @@ impl Value {
         }
     }
 
+    // the mongo internal timestamp keeps seconds in the high 32 bits
+    // and an increment in the low 32.
+    pub fn timestamp(secs: u32, inc: u32) -> Value {
+        Value::BTimeStamp((((secs as u64) << 32) | (inc as u64)) as i64)
+    }
+
+    // (seconds, increment)
+    pub fn timestamp_parts(&self) -> Result<(u32, u32)> {
+        match self {
+            &Value::BTimeStamp(n) => {
+                let n = n as u64;
+                Ok(((n >> 32) as u32, (n & 0xffffffff) as u32))
+            },
+            _ => Err(Error::Misc(format!("timestamp required, but found {:?}", self))),
+        }
+    }
+
     fn getDate(&self) -> Result<i64> {
         match self {
             &Value::BDateTime(ref s) => Ok(*s),
@@ -1035,6 +1554,44 @@ impl Value {
         }
     }
 
+    // milliseconds since the Unix epoch
+    pub fn datetime_millis(&self) -> Result<i64> {
+        self.getDate()
+    }
+
+    // like 2015-10-28T02:40:00.000Z, always UTC.  a value before the
+    // epoch works too, as long as the year has four digits.
+    pub fn datetime_iso8601(&self) -> Result<String> {
+        let ms = try!(self.getDate());
+        // floor, not truncate, so that pre-epoch times count back
+        // from the start of their day instead of forward.  fix up the
+        // remainder rather than biasing ms, which could overflow.
+        const MS_PER_DAY: i64 = 86400000;
+        let mut days = ms / MS_PER_DAY;
+        let mut ms_of_day = ms % MS_PER_DAY;
+        if ms_of_day < 0 {
+            days -= 1;
+            ms_of_day += MS_PER_DAY;
+        }
+
+        // days since 1970-01-01 to a civil date, from Howard Hinnant's
+        // days_from_civil paper.
+        let z = days + 719468;
+        let era = if z >= 0 { z / 146097 } else { (z - 146096) / 146097 };
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        let secs = ms_of_day / 1000;
+        Ok(format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                   year, month, day,
+                   secs / 3600, (secs / 60) % 60, secs % 60, ms_of_day % 1000))
+    }
+
     pub fn as_i32(&self) -> Result<i32> {
         match self {
             &Value::BInt32(ref s) => Ok(*s),
@@ -1042,6 +1599,32 @@ impl Value {
         }
     }
 
+    // the result type follows mongo:  a double if either side is a
+    // double, otherwise int64 if either side is int64 or if an int32
+    // sum would overflow.
+    pub fn add_numeric(&self, delta: &Value) -> Result<Value> {
+        match (self, delta) {
+            (&Value::BInt32(a), &Value::BInt32(b)) => {
+                match a.checked_add(b) {
+                    Some(n) => Ok(Value::BInt32(n)),
+                    None => Ok(Value::BInt64((a as i64) + (b as i64))),
+                }
+            },
+            (&Value::BDouble(_), _) | (_, &Value::BDouble(_)) => {
+                Ok(Value::BDouble(try!(self.numeric_to_f64()) + try!(delta.numeric_to_f64())))
+            },
+            (&Value::BInt32(_), &Value::BInt64(_))
+            | (&Value::BInt64(_), &Value::BInt32(_))
+            | (&Value::BInt64(_), &Value::BInt64(_)) => {
+                match try!(self.numeric_to_i64()).checked_add(try!(delta.numeric_to_i64())) {
+                    Some(n) => Ok(Value::BInt64(n)),
+                    None => Err(Error::Misc(format!("integer overflow: {:?} + {:?}", self, delta))),
+                }
+            },
+            _ => Err(Error::Misc(format!("numeric required, but found {:?} and {:?}", self, delta))),
+        }
+    }
+
     pub fn numeric_to_i32(&self) -> Result<i32> {
         match self {
             &Value::BInt32(ref s) => Ok((*s) as i32),
@@ -1069,6 +1652,38 @@ impl Value {
         }
     }
 
+    // numbers which compare equal should also be equal as keys, so
+    // int32, int64 and integral doubles all become int64.  doubles with
+    // a fraction, or outside the range of an int64, stay doubles.  -0.0
+    // becomes 0.  documents and arrays are canonicalized recursively.
+    pub fn canonicalize(&self) -> Value {
+        match self {
+            &Value::BInt32(n) => Value::BInt64(n as i64),
+            &Value::BDouble(f) => {
+                if f.fract() == 0.0 && f >= (i64::min_value() as f64) && f < (i64::max_value() as f64) {
+                    Value::BInt64(f as i64)
+                } else {
+                    Value::BDouble(f)
+                }
+            },
+            &Value::BDocument(ref bd) => {
+                let pairs = bd.pairs.iter().map(|&(ref k, ref v)| (k.clone(), v.canonicalize())).collect();
+                Value::BDocument(Document { pairs: pairs })
+            },
+            &Value::BArray(ref ba) => {
+                let items = ba.items.iter().map(|v| v.canonicalize()).collect();
+                Value::BArray(Array { items: items })
+            },
+            _ => self.clone(),
+        }
+    }
+
+    // equality the way a query sees it, where numbers of different
+    // types are equal if their values are.
+    pub fn matches_eq(&self, other: &Value) -> bool {
+        self.canonicalize() == other.canonicalize()
+    }
+
     fn getAsExprBool(&self) -> bool {
         match self {
             &Value::BBoolean(false) => false,
@@ -1172,6 +1787,84 @@ impl Value {
         }
     }
 
+    pub fn find_path_all(&self, path: &str) -> Vec<Value> {
+        let mut dest = Vec::new();
+        self.find_path_all_into(path, &mut dest);
+        dest
+    }
+
+    // same walk as find_path(), except that an index past the end of an
+    // array is just a miss.
+    fn find_path_all_into(&self, path: &str, dest: &mut Vec<Value>) {
+        let dot = path.find('.');
+        let name = match dot { 
+            None => path,
+            Some(ndx) => &path[0 .. ndx]
+        };
+        match self {
+            &Value::BDocument(ref bd) => bd.find_path_all_into(path, dest),
+            &Value::BArray(ref ba) => {
+                match name.parse::<usize>() {
+                    Err(_) => {
+                        for subv in &ba.items {
+                            match subv {
+                                &Value::BDocument(_) => subv.find_path_all_into(path, dest),
+                                _ => (),
+                            }
+                        }
+                    },
+                    Ok(ndx) => {
+                        match ba.items.get(ndx) {
+                            Some(v) => {
+                                match dot {
+                                    None => dest.push(v.clone()),
+                                    Some(dot) => v.find_path_all_into(&path[dot+1..], dest)
+                                }
+                            },
+                            None => (),
+                        }
+                    },
+                }
+            },
+            _ => ()
+        }
+    }
+
+    // like Document::walk.  the value itself is not visited, since it
+    // has no path, only the things inside it.
+    pub fn walk<F: FnMut(&str, &Value)>(&self, f: &mut F) {
+        match self {
+            &Value::BDocument(ref bd) => {
+                for &(ref k, ref v) in &bd.pairs {
+                    v.walk_at(k, f);
+                }
+            },
+            &Value::BArray(ref ba) => {
+                for (i, v) in ba.items.iter().enumerate() {
+                    v.walk_at(&format!("{}", i), f);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn walk_at<F: FnMut(&str, &Value)>(&self, path: &str, f: &mut F) {
+        f(path, self);
+        match self {
+            &Value::BDocument(ref bd) => {
+                for &(ref k, ref v) in &bd.pairs {
+                    v.walk_at(&format!("{}.{}", path, k), f);
+                }
+            },
+            &Value::BArray(ref ba) => {
+                for (i, v) in ba.items.iter().enumerate() {
+                    v.walk_at(&format!("{}.{}", path, i), f);
+                }
+            },
+            _ => (),
+        }
+    }
+
     pub fn getTypeNumber_u8(&self) -> u8 {
         match self {
             &Value::BDouble(_) => 1,
@@ -1348,10 +2041,10 @@ impl Value {
         }
     }
 
-    pub fn to_bson_array(&self) -> Vec<u8> {
+    pub fn to_bson_array(&self) -> Result<Vec<u8>> {
         let mut v = Vec::new();
-        self.to_bson(&mut v);
-        v
+        try!(self.to_bson(&mut v));
+        Ok(v)
     }
 
     pub fn encode_for_index_into(&self, w: &mut Vec<u8>) {
@@ -1432,6 +2125,83 @@ impl Value {
         r
     }
 
+    // an encoding where comparing the bytes gives the same answer as
+    // comparing the values.  each value starts with its type order
+    // (shifted so that MinKey is 0), and every part is self-delimiting,
+    // so the encoding of a value is never a prefix of another one.
+    // strings escape 0x00 as 00 FF and end with 00 00.  documents and
+    // arrays put a 1 before each item and a 0 at the end, so a shorter
+    // one sorts first.
+    //
+    // numbers of all types share one encoding, so 1 and 1.0 give the
+    // same bytes.
+    pub fn to_index_key_bytes(&self) -> Box<[u8]> {
+        let mut w = Vec::new();
+        self.push_index_key_bytes(&mut w);
+        w.into_boxed_slice()
+    }
+
+    fn push_index_key_bytes(&self, w: &mut Vec<u8>) {
+        fn push_escaped(w: &mut Vec<u8>, s: &[u8]) {
+            for b in s {
+                w.push(*b);
+                if *b == 0 {
+                    w.push(0xff);
+                }
+            }
+            w.push(0);
+            w.push(0);
+        }
+
+        fn push_i64(w: &mut Vec<u8>, n: i64) {
+            // flip the sign bit so negative numbers sort first
+            w.push_all(&u64_to_bytes_be((n as u64) ^ 0x8000_0000_0000_0000));
+        }
+
+        w.push((self.get_type_order() + 1) as u8);
+        match self {
+            &Value::BBoolean(b) => if b { w.push(1u8) } else { w.push(0u8) },
+            &Value::BNull => (),
+            &Value::BMinKey => (),
+            &Value::BMaxKey => (),
+            &Value::BUndefined => (),
+            &Value::BObjectID(ref a) => w.push_all(a),
+            &Value::BString(ref s) => push_escaped(w, s.as_bytes()),
+            &Value::BDouble(f) => misc::Sqlite4Num::from_f64(f).encode_for_index(w),
+            &Value::BInt64(n) => misc::Sqlite4Num::from_i64(n).encode_for_index(w),
+            &Value::BInt32(n) => misc::Sqlite4Num::from_i64(n as i64).encode_for_index(w),
+            &Value::BDocument(ref bd) => {
+                for t in &bd.pairs {
+                    w.push(1);
+                    push_escaped(w, t.0.as_bytes());
+                    t.1.push_index_key_bytes(w);
+                }
+                w.push(0);
+            },
+            &Value::BArray(ref ba) => {
+                for v in &ba.items {
+                    w.push(1);
+                    v.push_index_key_bytes(w);
+                }
+                w.push(0);
+            },
+            &Value::BRegex(ref expr, ref opt) => {
+                push_escaped(w, expr.as_bytes());
+                push_escaped(w, opt.as_bytes());
+            },
+            &Value::BJSCode(ref s) => push_escaped(w, s.as_bytes()),
+            &Value::BJSCodeWithScope(ref s) => push_escaped(w, s.as_bytes()),
+            &Value::BDateTime(n) => push_i64(w, n),
+            &Value::BTimeStamp(n) => push_i64(w, n),
+            &Value::BBinary(subtype, ref ba) => {
+                // like mongo, length first, then subtype
+                w.push_all(&u32_to_bytes_be(ba.len() as u32));
+                w.push(subtype);
+                w.push_all(&ba);
+            },
+        }
+    }
+
     pub fn replace_undefined(&mut self) {
         match self {
             &mut Value::BArray(ref mut ba) => {
@@ -1461,38 +2231,45 @@ impl Value {
         }
     }
 
-    pub fn to_bson(&self, w: &mut Vec<u8>) {
+    pub fn to_bson(&self, w: &mut Vec<u8>) -> Result<()> {
         match self {
             &Value::BDouble(f) => w.push_all(&f64_to_bytes_le(f)),
             &Value::BInt32(n) => w.push_all(&i32_to_bytes_le(n)),
             &Value::BDateTime(n) => w.push_all(&i64_to_bytes_le(n)),
             &Value::BTimeStamp(n) => w.push_all(&i64_to_bytes_le(n)),
             &Value::BInt64(n) => w.push_all(&i64_to_bytes_le(n)),
-            &Value::BString(ref s) => vec_push_bson_string(w, &s),
+            &Value::BString(ref s) => try!(vec_push_bson_string(w, &s)),
             &Value::BObjectID(ref a) => w.push_all(a),
             &Value::BBoolean(b) => if b { w.push(1u8) } else { w.push(0u8) },
             &Value::BNull => (),
             &Value::BMinKey => (),
             &Value::BMaxKey => (),
             &Value::BRegex(ref expr, ref opt) => {
-                vec_push_c_string(w, &expr); 
-                vec_push_c_string(w, &opt);
+                try!(vec_push_checked_c_string(w, &expr));
+                try!(vec_push_checked_c_string(w, &opt));
             },
             &Value::BUndefined => (),
-            &Value::BJSCode(ref s) => vec_push_bson_string(w, &s),
+            &Value::BJSCode(ref s) => try!(vec_push_bson_string(w, &s)),
             &Value::BJSCodeWithScope(ref s) => panic!("TODO write BJSCodeWithScope"),
             &Value::BBinary(subtype, ref ba) => {
-                w.push_all(&i32_to_bytes_le(ba.len() as i32));
-                w.push(subtype);
+                if subtype == binary_subtype::BINARY_OLD {
+                    w.push_all(&i32_to_bytes_le(try!(checked_len(ba.len() + 4))));
+                    w.push(subtype);
+                    w.push_all(&i32_to_bytes_le(try!(checked_len(ba.len()))));
+                } else {
+                    w.push_all(&i32_to_bytes_le(try!(checked_len(ba.len()))));
+                    w.push(subtype);
+                }
                 w.push_all(&ba);
             },
             &Value::BArray(ref ba) => {
-                ba.to_bson(w);
+                try!(ba.to_bson(w));
             },
             &Value::BDocument(ref bd) => {
-                bd.to_bson(w);
+                try!(bd.to_bson(w));
             },
         }
+        Ok(())
     }
 
 }
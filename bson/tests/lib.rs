@@ -1,5 +1,6 @@
 
 extern crate bson;
+extern crate misc;
 
 use bson::BsonValue;
 
@@ -51,4 +52,725 @@ fn bson_simple() {
     assert!(f().is_ok());
 }
 
+#[test]
+fn binary_subtype_round_trip() {
+    fn f() -> bson::Result<()> {
+        for &subtype in &[bson::binary_subtype::USER_DEFINED, bson::binary_subtype::BINARY_OLD] {
+            let mut doc = bson::Document::new_empty();
+            doc.pairs.push((String::from("b"), bson::Value::BBinary(subtype, vec![1, 2, 3])));
+            let mut buf = Vec::new();
+            try!(doc.to_bson(&mut buf));
+            let doc = try!(bson::Document::from_bson(&buf));
+            match try!(doc.must_get("b")) {
+                &bson::Value::BBinary(st, ref ba) => {
+                    assert_eq!(st, subtype);
+                    assert_eq!(ba, &vec![1, 2, 3]);
+                },
+                _ => panic!(),
+            }
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn from_bson_trailing_bytes() {
+    let mut doc = bson::Document::new_empty();
+    doc.set_i32("a", 1);
+    let mut buf = Vec::new();
+    doc.to_bson(&mut buf).unwrap();
+    let len = buf.len();
+    buf.push(0);
+    assert!(bson::Document::from_bson(&buf).is_err());
+    let (d, used) = bson::Document::from_bson_prefix(&buf).unwrap();
+    assert_eq!(used, len);
+    assert_eq!(d.pairs.len(), 1);
+    assert!(bson::Document::from_bson(&buf[0 .. len]).is_ok());
+}
+
+#[test]
+fn documents_from_bytes() {
+    fn f() -> bson::Result<()> {
+        let mut buf = Vec::new();
+        for i in 0 .. 3 {
+            let mut doc = bson::Document::new_empty();
+            doc.set_i32("i", i);
+            try!(doc.to_bson(&mut buf));
+        }
+        let docs = try!(bson::documents_from_bytes(&buf).collect::<bson::Result<Vec<_>>>());
+        assert_eq!(docs.len(), 3);
+        for i in 0 .. 3 {
+            assert_eq!(try!(try!(docs[i].must_get("i")).as_i32()), i as i32);
+        }
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn walk() {
+    let mut inner = bson::Document::new_empty();
+    inner.set_i32("c", 1);
+    let mut doc = bson::Document::new_empty();
+    doc.set_str("a", "x");
+    doc.set_document("b", inner);
+    doc.set_array("d", bson::Array { items: vec![bson::Value::BInt32(5), bson::Value::BNull] });
+    let mut paths = vec![];
+    doc.walk(&mut |path: &str, v: &bson::Value| paths.push((String::from(path), v.get_type_name())));
+    let expected = vec![
+        ("a", "string"),
+        ("b", "document"),
+        ("b.c", "i32"),
+        ("d", "array"),
+        ("d.0", "i32"),
+        ("d.1", "null"),
+        ];
+    let expected = expected.into_iter().map(|(p, t)| (String::from(p), t)).collect::<Vec<_>>();
+    assert_eq!(paths, expected);
+}
+
+#[test]
+fn set_path() {
+    fn f() -> bson::Result<()> {
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("x", 1);
+
+        // a deep path which does not exist yet
+        try!(doc.set_path("a.b.c", bson::Value::BInt32(5)));
+        assert_eq!(try!(doc.find_path("a.b.c").as_i32()), 5);
+
+        // overwrite an existing value
+        try!(doc.set_path("a.b.c", bson::Value::BString(String::from("five"))));
+        assert_eq!(try!(doc.find_path("a.b.c").as_str()), "five");
+        try!(doc.set_path("x", bson::Value::BInt32(2)));
+        assert_eq!(try!(doc.find_path("x").as_i32()), 2);
+        assert_eq!(doc.pairs.len(), 2);
+
+        // array indexes, past the end
+        doc.set_array("arr", bson::Array { items: vec![bson::Value::BInt32(0)] });
+        try!(doc.set_path("arr.3", bson::Value::BInt32(3)));
+        try!(doc.set_path("arr.5.y", bson::Value::BInt32(6)));
+        let arr = try!(doc.must_get_array("arr"));
+        assert_eq!(arr.len(), 6);
+        assert_eq!(try!(arr.items[3].as_i32()), 3);
+        assert!(match arr.items[1] { bson::Value::BNull => true, _ => false });
+        assert_eq!(try!(doc.find_path("arr.5.y").as_i32()), 6);
+
+        // cannot dive into a scalar
+        assert!(doc.set_path("x.y", bson::Value::BInt32(1)).is_err());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn value_len() {
+    let empty = bson::Value::BDocument(bson::Document::new_empty());
+    assert_eq!(empty.len(), Some(0));
+    assert!(empty.is_empty());
+
+    let arr = bson::Value::BArray(bson::Array { items: vec![bson::Value::BInt32(1), bson::Value::BNull, bson::Value::BString(String::from("x"))] });
+    assert_eq!(arr.len(), Some(3));
+    assert!(!arr.is_empty());
+
+    let scalar = bson::Value::BInt32(7);
+    assert_eq!(scalar.len(), None);
+    assert!(!scalar.is_empty());
+
+    // an empty string is a scalar, not an empty container
+    assert_eq!(bson::Value::BString(String::new()).len(), None);
+}
+
+#[test]
+fn find_path_all() {
+    fn f() -> bson::Result<()> {
+        let mut c1 = bson::Document::new_empty();
+        c1.set_i32("c", 1);
+        let mut c2 = bson::Document::new_empty();
+        c2.set_i32("c", 2);
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("a", 1);
+        doc.set_array("b", bson::Array { items: vec![bson::Value::BDocument(c1), bson::Value::BInt32(3), bson::Value::BDocument(c2)] });
+
+        let a = doc.find_path_all("b.c");
+        assert_eq!(a.len(), 2);
+        assert_eq!(try!(a[0].as_i32()), 1);
+        assert_eq!(try!(a[1].as_i32()), 2);
+
+        let a = doc.find_path_all("b.2.c");
+        assert_eq!(a.len(), 1);
+        assert_eq!(try!(a[0].as_i32()), 2);
+
+        assert_eq!(doc.find_path_all("a").len(), 1);
+        assert!(doc.find_path_all("b.d").is_empty());
+        assert!(doc.find_path_all("b.7.c").is_empty());
+        assert!(doc.find_path_all("nope").is_empty());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn unset_path() {
+    fn f() -> bson::Result<()> {
+        let mut inner = bson::Document::new_empty();
+        inner.set_i32("c", 1);
+        inner.set_i32("d", 2);
+        let mut doc = bson::Document::new_empty();
+        doc.set_document("b", inner);
+        doc.set_array("arr", bson::Array { items: vec![bson::Value::BInt32(0), bson::Value::BInt32(1), bson::Value::BInt32(2)] });
+
+        assert!(try!(doc.unset_path("b.c")));
+        assert!(doc.find_path("b.c").is_undefined());
+        assert_eq!(try!(doc.find_path("b.d").as_i32()), 2);
+        assert!(!try!(doc.unset_path("b.c")));
+        assert!(!try!(doc.unset_path("nope.c")));
+
+        assert!(try!(doc.unset_path("arr.1")));
+        let arr = try!(doc.must_get_array("arr"));
+        assert_eq!(arr.len(), 3);
+        assert!(match arr.items[1] { bson::Value::BNull => true, _ => false });
+        assert_eq!(try!(arr.items[2].as_i32()), 2);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn inc_path() {
+    fn f() -> bson::Result<()> {
+        let mut inner = bson::Document::new_empty();
+        inner.set_i32("n", 5);
+        inner.set_f64("d", 1.5);
+        let mut doc = bson::Document::new_empty();
+        doc.set_document("a", inner);
+
+        try!(doc.inc_path("a.n", &bson::Value::BInt32(3)));
+        assert!(match doc.find_path("a.n") { bson::Value::BInt32(8) => true, _ => false });
+
+        try!(doc.inc_path("a.n", &bson::Value::BInt64(1)));
+        assert!(match doc.find_path("a.n") { bson::Value::BInt64(9) => true, _ => false });
+
+        try!(doc.inc_path("a.d", &bson::Value::BInt32(2)));
+        assert!(match doc.find_path("a.d") { bson::Value::BDouble(f) => f == 3.5, _ => false });
+
+        try!(doc.inc_path("a.missing", &bson::Value::BInt32(7)));
+        assert!(match doc.find_path("a.missing") { bson::Value::BInt32(7) => true, _ => false });
+
+        doc.set_str("s", "text");
+        assert!(doc.inc_path("s", &bson::Value::BInt32(1)).is_err());
+        assert!(doc.inc_path("a.n", &bson::Value::BString(String::from("x"))).is_err());
+
+        let mut big = bson::Document::new_empty();
+        big.set_i32("n", std::i32::MAX);
+        try!(big.inc_path("n", &bson::Value::BInt32(1)));
+        assert!(match big.find_path("n") { bson::Value::BInt64(n) => n == (std::i32::MAX as i64) + 1, _ => false });
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn field_bytes() {
+    fn f() -> bson::Result<()> {
+        let mut inner = bson::Document::new_empty();
+        inner.set_i32("x", 1);
+        let mut doc = bson::Document::new_empty();
+        doc.set_f64("d", 2.5);
+        doc.set_str("s", "hello");
+        doc.set_document("sub", inner.clone());
+        doc.set_array("a", bson::Array { items: vec![bson::Value::BInt32(1), bson::Value::BNull] });
+        doc.set_i64("n", 42);
+        let ba = try!(doc.to_bson_array());
+
+        assert!(try!(bson::field_bytes(&ba, "nope")).is_none());
+        assert!(try!(bson::field_bytes(&ba, "su")).is_none());
+
+        // wrap the element in a document of its own, to parse it again
+        let reparse = |elem: &[u8]| -> bson::Result<bson::Document> {
+            let len = (elem.len() + 5) as u32;
+            let mut w = vec![len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8];
+            w.extend(elem.iter().cloned());
+            w.push(0);
+            bson::Document::from_bson(&w)
+        };
+        let elem = try!(try!(bson::field_bytes(&ba, "sub")).ok_or(bson::Error::Misc(String::from("missing"))));
+        assert_eq!(elem[0], 3);
+        let d = try!(reparse(elem));
+        assert_eq!(d.get("sub"), Some(&bson::Value::BDocument(inner)));
+        let elem = try!(try!(bson::field_bytes(&ba, "n")).ok_or(bson::Error::Misc(String::from("missing"))));
+        let d = try!(reparse(elem));
+        assert!(match d.get("n") { Some(&bson::Value::BInt64(42)) => true, _ => false });
+        assert_eq!(d.pairs.len(), 1);
+
+        assert!(bson::field_bytes(&ba[0 .. ba.len() - 8], "n").is_err());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn duplicate_keys() {
+    fn f() -> bson::Result<()> {
+        let doc = bson::Document {
+            pairs: vec![
+                (String::from("a"), bson::Value::BInt32(1)),
+                (String::from("b"), bson::Value::BInt32(2)),
+                (String::from("a"), bson::Value::BInt32(3)),
+            ],
+        };
+        let ba = try!(doc.to_bson_array());
+        let mut doc = try!(bson::Document::from_bson(&ba));
+        assert_eq!(doc.pairs.len(), 2);
+        assert_eq!(doc.pairs[0].0, "a");
+        assert_eq!(doc.get("a"), Some(&bson::Value::BInt32(3)));
+        assert_eq!(doc.get("b"), Some(&bson::Value::BInt32(2)));
+        assert!(doc.remove("a").is_some());
+        assert!(doc.get("a").is_none());
+
+        // and in a bigger document, with more than one repeat
+        let mut pairs = Vec::new();
+        for i in 0 .. 100 {
+            pairs.push((format!("k{}", i), bson::Value::BInt32(i)));
+        }
+        pairs.push((String::from("k5"), bson::Value::BInt32(-5)));
+        pairs.push((String::from("k50"), bson::Value::BInt32(-50)));
+        let ba = try!(bson::Document { pairs: pairs }.to_bson_array());
+        let doc = try!(bson::Document::from_bson(&ba));
+        assert_eq!(doc.pairs.len(), 100);
+        assert_eq!(doc.pairs[5], (String::from("k5"), bson::Value::BInt32(-5)));
+        assert_eq!(doc.pairs[50], (String::from("k50"), bson::Value::BInt32(-50)));
+        assert_eq!(doc.pairs[99].0, "k99");
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn canonicalize() {
+    fn f() -> bson::Result<()> {
+        let mut a = bson::Document::new_empty();
+        a.set_i32("a", 1);
+        a.set_array("b", bson::Array { items: vec![bson::Value::BInt64(7), bson::Value::BDouble(-0.0)] });
+        let mut b = bson::Document::new_empty();
+        b.set_f64("a", 1.0);
+        b.set_array("b", bson::Array { items: vec![bson::Value::BDouble(7.0), bson::Value::BInt32(0)] });
+        let a = bson::Value::BDocument(a);
+        let b = bson::Value::BDocument(b);
+        assert!(try!(a.to_bson_array()) != try!(b.to_bson_array()));
+        assert_eq!(try!(a.canonicalize().to_bson_array()), try!(b.canonicalize().to_bson_array()));
+
+        // a fraction is kept
+        let c = bson::Value::BDouble(1.5).canonicalize();
+        assert!(match c { bson::Value::BDouble(f) => f == 1.5, _ => false });
+        assert!(try!(bson::Value::BInt32(1).canonicalize().to_bson_array()) != try!(c.to_bson_array()));
+
+        // non-numeric values are unchanged
+        let s = bson::Value::BString(String::from("1"));
+        assert_eq!(s.canonicalize(), s);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn index_key_bytes_order() {
+    fn doc(pairs: Vec<(&str, bson::Value)>) -> bson::Value {
+        bson::Value::BDocument(bson::Document { pairs: pairs.into_iter().map(|(k, v)| (String::from(k), v)).collect() })
+    }
+    fn arr(items: Vec<bson::Value>) -> bson::Value {
+        bson::Value::BArray(bson::Array { items: items })
+    }
+    fn s(v: &str) -> bson::Value {
+        bson::Value::BString(String::from(v))
+    }
+
+    // each value must sort before the one after it
+    let sorted = vec![
+        bson::Value::BMinKey,
+        bson::Value::BNull,
+        bson::Value::BDouble(std::f64::NAN),
+        bson::Value::BDouble(std::f64::NEG_INFINITY),
+        bson::Value::BInt64(-1000000),
+        bson::Value::BInt32(-2),
+        bson::Value::BDouble(-1.5),
+        bson::Value::BInt32(0),
+        bson::Value::BDouble(0.25),
+        bson::Value::BInt32(1),
+        bson::Value::BDouble(1.5),
+        bson::Value::BInt64(100),
+        bson::Value::BInt32(101),
+        bson::Value::BDouble(std::f64::INFINITY),
+        s(""),
+        s("a"),
+        s("a\u{0}"),
+        s("a\u{0}b"),
+        s("ab"),
+        s("b"),
+        doc(vec![]),
+        doc(vec![("a", bson::Value::BInt32(1))]),
+        doc(vec![("a", bson::Value::BInt32(1)), ("b", bson::Value::BInt32(0))]),
+        doc(vec![("a", bson::Value::BInt32(2))]),
+        doc(vec![("a", s("x"))]),
+        doc(vec![("b", bson::Value::BMinKey)]),
+        arr(vec![]),
+        arr(vec![bson::Value::BNull]),
+        arr(vec![bson::Value::BInt32(1)]),
+        arr(vec![bson::Value::BInt32(1), bson::Value::BInt32(1)]),
+        arr(vec![bson::Value::BInt32(2)]),
+        bson::Value::BBinary(5, vec![9, 9]),
+        bson::Value::BBinary(0, vec![1, 2, 3]),
+        bson::Value::BBinary(0, vec![1, 2, 4]),
+        bson::Value::BObjectID([0; 12]),
+        bson::Value::BObjectID([1; 12]),
+        bson::Value::BBoolean(false),
+        bson::Value::BBoolean(true),
+        bson::Value::BDateTime(-5),
+        bson::Value::BDateTime(0),
+        bson::Value::BDateTime(7),
+        bson::Value::BTimeStamp(1),
+        bson::Value::BMaxKey,
+    ];
+    for i in 1 .. sorted.len() {
+        let a = sorted[i - 1].to_index_key_bytes();
+        let b = sorted[i].to_index_key_bytes();
+        println!("{:?} < {:?}", sorted[i - 1], sorted[i]);
+        assert!(a < b);
+    }
+
+    // equal numbers encode the same, whatever the type
+    assert_eq!(bson::Value::BInt32(3).to_index_key_bytes(), bson::Value::BDouble(3.0).to_index_key_bytes());
+    assert_eq!(bson::Value::BInt64(3).to_index_key_bytes(), bson::Value::BInt32(3).to_index_key_bytes());
+}
+
+#[test]
+fn array_push_pull() {
+    fn f() -> bson::Result<()> {
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("n", 1);
+
+        // a missing path gets a new array
+        try!(doc.push_path("a", bson::Value::BInt32(1)));
+        try!(doc.push_path("a", bson::Value::BInt32(2)));
+        try!(doc.push_path("a", bson::Value::BInt32(1)));
+        try!(doc.push_path("sub.b", bson::Value::BString(String::from("x"))));
+        assert_eq!(doc.find_path("a"), bson::Value::BArray(bson::Array { items: vec![bson::Value::BInt32(1), bson::Value::BInt32(2), bson::Value::BInt32(1)] }));
+        assert_eq!(doc.find_path("sub.b"), bson::Value::BArray(bson::Array { items: vec![bson::Value::BString(String::from("x"))] }));
+        assert!(doc.push_path("n", bson::Value::BInt32(1)).is_err());
+
+        // pull matches numbers of any type
+        assert_eq!(try!(doc.pull_path("a", &bson::Value::BDouble(1.0))), 2);
+        assert_eq!(doc.find_path("a"), bson::Value::BArray(bson::Array { items: vec![bson::Value::BInt32(2)] }));
+        assert_eq!(try!(doc.pull_path("a", &bson::Value::BInt32(5))), 0);
+        assert_eq!(try!(doc.pull_path("nope", &bson::Value::BInt32(5))), 0);
+        assert!(doc.pull_path("n", &bson::Value::BInt32(1)).is_err());
+
+        // addToSet does not add a value which is already there
+        assert!(!try!(doc.add_to_set_path("a", bson::Value::BInt32(2))));
+        assert!(try!(doc.add_to_set_path("a", bson::Value::BInt32(3))));
+        assert!(!try!(doc.add_to_set_path("a", bson::Value::BInt32(3))));
+        assert!(try!(doc.add_to_set_path("c", bson::Value::BNull)));
+        assert_eq!(doc.find_path("a"), bson::Value::BArray(bson::Array { items: vec![bson::Value::BInt32(2), bson::Value::BInt32(3)] }));
+        assert_eq!(doc.find_path("c"), bson::Value::BArray(bson::Array { items: vec![bson::Value::BNull] }));
+        assert!(doc.add_to_set_path("n", bson::Value::BInt32(1)).is_err());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn length_overflow() {
+    fn f() -> bson::Result<()> {
+        assert_eq!(try!(bson::checked_len(0)), 0);
+        assert_eq!(try!(bson::checked_len(std::i32::MAX as usize)), std::i32::MAX);
+        // one more would wrap around to a negative length
+        assert!(bson::checked_len((std::i32::MAX as usize) + 1).is_err());
+        assert!(bson::checked_len(std::usize::MAX).is_err());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn key_with_nul() {
+    fn f() -> bson::Result<()> {
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("a\u{0}b", 1);
+        assert!(doc.to_bson_array().is_err());
+
+        // also when nested
+        let mut outer = bson::Document::new_empty();
+        outer.set_document("ok", doc);
+        assert!(outer.to_bson_array().is_err());
+
+        // which does not stop it from being compared or hashed
+        let a = bson::Value::BDocument(outer.clone());
+        let b = bson::Value::BDocument(outer);
+        assert!(a == b);
+        let mut set = std::collections::HashSet::new();
+        set.insert(a);
+        assert!(set.contains(&b));
+        // the type counts, even where the encoding would be the same
+        assert!(bson::Value::BNull != bson::Value::BMinKey);
+        assert!(bson::Value::BInt64(1) != bson::Value::BDateTime(1));
+
+        let mut doc = bson::Document::new_empty();
+        doc.set("r", bson::Value::BRegex(String::from("x\u{0}"), String::new()));
+        assert!(doc.to_bson_array().is_err());
+
+        // a 0 inside a string value is fine, since it has a length
+        let mut doc = bson::Document::new_empty();
+        doc.set_str("s", "a\u{0}b");
+        let ba = try!(doc.to_bson_array());
+        let doc = try!(bson::Document::from_bson(&ba));
+        assert_eq!(try!(doc.must_get_str("s")), "a\u{0}b");
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn peek_u8() {
+    let ba = [1u8, 2, 3];
+    assert_eq!(Some(1), misc::bufndx::peek_u8(&ba, 0));
+    assert_eq!(Some(3), misc::bufndx::peek_u8(&ba, 2));
+    assert_eq!(None, misc::bufndx::peek_u8(&ba, 3));
+    assert_eq!(None, misc::bufndx::peek_u8(&ba, 100));
+    assert_eq!(None, misc::bufndx::peek_u8(&[], 0));
+}
+
+#[test]
+fn unterminated_document() {
+    fn f() -> bson::Result<()> {
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("a", 1);
+        let ba = try!(doc.to_bson_array());
+        // drop the terminating 0 so the pairs loop runs off the end
+        let r = bson::Document::from_bson(&ba[0 .. ba.len() - 1]);
+        assert!(r.is_err());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn timestamp_parts() {
+    fn f() -> bson::Result<()> {
+        let v = bson::Value::timestamp(1446000000, 7);
+        assert_eq!(try!(v.timestamp_parts()), (1446000000, 7));
+
+        // the high bit of the seconds lands in the sign bit of the i64
+        let v = bson::Value::timestamp(0xffffffff, 0xffffffff);
+        assert_eq!(try!(v.timestamp_parts()), (0xffffffff, 0xffffffff));
+        assert_eq!(v, bson::Value::BTimeStamp(-1));
+
+        let v = bson::Value::timestamp(1, 2);
+        assert_eq!(v, bson::Value::BTimeStamp((1 << 32) | 2));
+
+        assert!(bson::Value::BInt64(5).timestamp_parts().is_err());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn datetime_iso8601() {
+    fn f() -> bson::Result<()> {
+        let v = bson::Value::BDateTime(0);
+        assert_eq!(try!(v.datetime_millis()), 0);
+        assert_eq!(try!(v.datetime_iso8601()), "1970-01-01T00:00:00.000Z");
+
+        let v = bson::Value::BDateTime(1446000000123);
+        assert_eq!(try!(v.datetime_millis()), 1446000000123);
+        assert_eq!(try!(v.datetime_iso8601()), "2015-10-28T02:40:00.123Z");
+
+        let v = bson::Value::BDateTime(951782400000);
+        assert_eq!(try!(v.datetime_iso8601()), "2000-02-29T00:00:00.000Z");
 
+        // before the epoch
+        let v = bson::Value::BDateTime(-1);
+        assert_eq!(try!(v.datetime_iso8601()), "1969-12-31T23:59:59.999Z");
+        let v = bson::Value::BDateTime(-365 * 86400000);
+        assert_eq!(try!(v.datetime_iso8601()), "1969-01-01T00:00:00.000Z");
+
+        // the extremes must not overflow
+        let v = bson::Value::BDateTime(std::i64::MIN);
+        assert_eq!(try!(v.datetime_iso8601()), "-292275055-05-16T16:47:04.192Z");
+        let v = bson::Value::BDateTime(std::i64::MAX);
+        assert_eq!(try!(v.datetime_iso8601()), "292278994-08-17T07:12:55.807Z");
+
+        assert!(bson::Value::BInt64(0).datetime_iso8601().is_err());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn set_path_through_array_element() {
+    fn f() -> bson::Result<()> {
+        fn elem(name: &str, n: i32) -> bson::Value {
+            let mut d = bson::Document::new_empty();
+            d.set_str("name", name);
+            d.set_i32("n", n);
+            bson::Value::BDocument(d)
+        }
+        let mut doc = bson::Document::new_empty();
+        doc.set_array("arr", bson::Array { items: vec![elem("a", 1), elem("b", 2), bson::Value::BInt32(3)] });
+
+        try!(doc.set_path("arr.0.name", bson::Value::BString(String::from("z"))));
+        try!(doc.inc_path("arr.1.n", &bson::Value::BInt32(10)));
+        let arr = try!(doc.must_get_array("arr"));
+        assert_eq!(arr.items[0], elem("z", 1));
+        assert_eq!(arr.items[1], elem("b", 12));
+        assert_eq!(arr.items[2], bson::Value::BInt32(3));
+
+        // the third element is not a document
+        assert!(doc.set_path("arr.2.name", bson::Value::BInt32(1)).is_err());
+        assert!(doc.inc_path("arr.2.n", &bson::Value::BInt32(1)).is_err());
+        assert_eq!(try!(doc.must_get_array("arr")).items[2], bson::Value::BInt32(3));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn scalar_constructors() {
+    fn f() -> bson::Result<()> {
+        let v = bson::Value::document(vec![
+            (String::from("i"), bson::Value::int(1)),
+            (String::from("l"), bson::Value::long(2)),
+            (String::from("d"), bson::Value::double(3.5)),
+            (String::from("s"), bson::Value::string("four")),
+            (String::from("b"), bson::Value::boolean(true)),
+            (String::from("n"), bson::Value::null()),
+            (String::from("a"), bson::Value::array(vec![bson::Value::int(5), bson::Value::string("six")])),
+            ]);
+        let ba = try!(v.to_bson_array());
+        let doc = try!(bson::Document::from_bson(&ba));
+
+        let mut expected = bson::Document::new_empty();
+        expected.set_i32("i", 1);
+        expected.set_i64("l", 2);
+        expected.set_f64("d", 3.5);
+        expected.set_str("s", "four");
+        expected.set_bool("b", true);
+        expected.set("n", bson::Value::BNull);
+        expected.set_array("a", bson::Array { items: vec![bson::Value::BInt32(5), bson::Value::BString(String::from("six"))] });
+        assert_eq!(doc, expected);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn negative_lengths() {
+    fn corrupt(r: bson::Result<bson::Document>) {
+        match r {
+            Err(bson::Error::CorruptFile(_)) => (),
+            r => panic!("expected CorruptFile: {:?}", r),
+        }
+    }
+
+    // the document's own length
+    corrupt(bson::Document::from_bson(&[0xff, 0xff, 0xff, 0xff, 0]));
+
+    let mut doc = bson::Document::new_empty();
+    doc.set_str("s", "hello");
+    let good = doc.to_bson_array().unwrap();
+    assert!(bson::Document::from_bson(&good).is_ok());
+
+    // the string's length comes after the document length, the type
+    // byte, and the key "s" with its null
+    let mut ba = good.clone();
+    for j in 7 .. 11 {
+        ba[j] = 0xff;
+    }
+    corrupt(bson::Document::from_bson(&ba));
+    assert!(bson::field_bytes(&ba, "s").is_err());
+
+    // a binary value
+    let mut doc = bson::Document::new_empty();
+    doc.set("b", bson::Value::BBinary(0, vec![1, 2, 3]));
+    let mut ba = doc.to_bson_array().unwrap();
+    for j in 7 .. 11 {
+        ba[j] = 0xff;
+    }
+    corrupt(bson::Document::from_bson(&ba));
+}
+
+#[test]
+fn document_length_mismatch() {
+    fn corrupt(r: bson::Result<bson::Document>) {
+        match r {
+            Err(bson::Error::CorruptFile(_)) => (),
+            r => panic!("expected CorruptFile: {:?}", r),
+        }
+    }
+
+    let mut inner = bson::Document::new_empty();
+    inner.set_i32("a", 1);
+    let mut doc = bson::Document::new_empty();
+    doc.set_document("d", inner);
+    let good = doc.to_bson_array().unwrap();
+    assert!(bson::Document::from_bson(&good).is_ok());
+
+    // a length one too long, with a byte for it to cover
+    let mut ba = good.clone();
+    ba[0] = ba[0] + 1;
+    ba.push(0);
+    corrupt(bson::Document::from_bson(&ba));
+
+    // and one too short
+    let mut ba = good.clone();
+    ba[0] = ba[0] - 1;
+    corrupt(bson::Document::from_bson(&ba));
+
+    // the inner document's length comes after the outer length, the
+    // type byte, and the key "d" with its null
+    let mut ba = good.clone();
+    ba[7] = ba[7] + 1;
+    corrupt(bson::Document::from_bson(&ba));
+
+    // too small to hold even an empty document
+    corrupt(bson::Document::from_bson(&[4, 0, 0, 0, 0]));
+}
+
+#[test]
+fn split_name() {
+    assert_eq!(bson::split_name("test.foo").unwrap(), ("test", "foo"));
+    assert_eq!(bson::split_name("test.system.indexes").unwrap(), ("test", "system.indexes"));
+    assert!(bson::split_name("t").is_err());
+    assert!(bson::split_name("test.").is_err());
+    assert!(bson::split_name(".foo").is_err());
+}
@@ -125,6 +125,14 @@ pub struct CollectionInfo {
     pub options: bson::Document,
 }
 
+#[derive(Debug)]
+pub struct CollectionStats {
+    pub count: u64,
+    // total size of the documents, as bson
+    pub size: u64,
+    pub nindexes: usize,
+}
+
 // TODO remove derive Clone later
 #[derive(Clone,Debug)]
 pub struct IndexInfo {
@@ -218,6 +226,195 @@ pub fn cmp_row(d: &Row, lit: &Row) -> Ordering {
     matcher::cmp(&d.doc, &lit.doc)
 }
 
+// a sort spec is a document like {a:1, "b.c":-1}.  the result is the
+// list of paths, each with true if descending.
+fn parse_sort_spec(orderby: bson::Value) -> Result<Vec<(String, bool)>> {
+    let orderby = try!(orderby.into_document());
+    let mut keys = Vec::new();
+    for (k, v) in orderby.pairs {
+        let n = try!(v.numeric_to_f64().map_err(|_| Error::Misc(format!("bad sort direction for {}", k))));
+        if n > 0.0 {
+            keys.push((k, false));
+        } else if n < 0.0 {
+            keys.push((k, true));
+        } else {
+            return Err(Error::Misc(format!("bad sort direction for {}", k)));
+        }
+    }
+    Ok(keys)
+}
+
+// TODO mongo sorts an array by its smallest (or, descending, its
+// largest) element.  here the array gets compared as a whole.
+fn cmp_row_by_sort_spec(keys: &Vec<(String, bool)>, a: &Row, b: &Row) -> Ordering {
+    fn get(r: &Row, path: &str) -> bson::Value {
+        match r.doc.find_path(path) {
+            // a missing field sorts like null
+            bson::Value::BUndefined => bson::Value::BNull,
+            v => v,
+        }
+    }
+    for &(ref path, desc) in keys {
+        let c = matcher::cmp(&get(a, path), &get(b, path));
+        let c = if desc { c.reverse() } else { c };
+        if c != Ordering::Equal {
+            return c;
+        }
+    }
+    Ordering::Equal
+}
+
+// part of an array, from {$slice: n} or {$slice: [skip, limit]}.
+// a negative n or skip counts from the end.
+enum ProjectionSlice {
+    First(i64),
+    Range(i64, i64),
+}
+
+impl ProjectionSlice {
+    fn parse(v: &bson::Value) -> Result<ProjectionSlice> {
+        match v {
+            &bson::Value::BArray(ref ba) => {
+                if ba.items.len() != 2 {
+                    return Err(Error::Misc(String::from("$slice array must be [skip, limit]")));
+                }
+                let skip = try!(ba.items[0].numeric_to_i64());
+                let limit = try!(ba.items[1].numeric_to_i64());
+                if limit <= 0 {
+                    return Err(Error::Misc(String::from("$slice limit must be positive")));
+                }
+                Ok(ProjectionSlice::Range(skip, limit))
+            },
+            v if v.is_numeric() => Ok(ProjectionSlice::First(try!(v.numeric_to_i64()))),
+            _ => Err(Error::Misc(format!("unsupported $slice: {:?}", v))),
+        }
+    }
+
+    fn apply(&self, mut items: Vec<bson::Value>) -> Vec<bson::Value> {
+        let len = items.len() as i64;
+        let (start, end) =
+            match *self {
+                ProjectionSlice::First(n) if n >= 0 => (0, std::cmp::min(n, len)),
+                ProjectionSlice::First(n) => (std::cmp::max(len + n, 0), len),
+                ProjectionSlice::Range(skip, limit) => {
+                    let start = 
+                        if skip < 0 {
+                            std::cmp::max(len + skip, 0)
+                        } else {
+                            std::cmp::min(skip, len)
+                        };
+                    (start, std::cmp::min(start.saturating_add(limit), len))
+                },
+            };
+        items.truncate(end as usize);
+        items.drain(0 .. start as usize);
+        items
+    }
+}
+
+// the projection argument of a find.  either every path listed gets
+// included, or every path listed gets excluded.  _id is included
+// unless it says otherwise, and it is the only field which can be
+// excluded from an inclusion.  a $slice is neither.  its array is
+// kept, but cut down.
+struct FindProjection {
+    paths: Vec<String>,
+    include: bool,
+    id: bool,
+    slices: Vec<(String, ProjectionSlice)>,
+}
+
+impl FindProjection {
+    fn parse(proj: bson::Document) -> Result<FindProjection> {
+        let mut paths = Vec::new();
+        let mut include = None;
+        let mut id = true;
+        let mut slices = Vec::new();
+        for (k, v) in proj.pairs {
+            if let bson::Value::BDocument(ref d) = v {
+                if d.pairs.len() == 1 && d.pairs[0].0 == "$slice" {
+                    slices.push((k.clone(), try!(ProjectionSlice::parse(&d.pairs[0].1))));
+                    continue;
+                }
+            }
+            let b =
+                match v {
+                    bson::Value::BBoolean(b) => b,
+                    ref v if v.is_numeric() => try!(v.numeric_to_f64()) != 0.0,
+                    _ => return Err(Error::Misc(format!("unsupported projection for {}: {:?}", k, v))),
+                };
+            if k == "_id" {
+                id = b;
+            } else {
+                match include {
+                    Some(prev) if prev != b => {
+                        return Err(Error::Misc(String::from("projection cannot mix inclusion and exclusion")));
+                    },
+                    _ => include = Some(b),
+                }
+                paths.push(k);
+            }
+        }
+        let p = FindProjection {
+            paths: paths,
+            // {_id:0} by itself is an exclusion
+            include: include.unwrap_or(false),
+            id: id,
+            slices: slices,
+        };
+        Ok(p)
+    }
+
+    fn apply(&self, mut row: Row) -> Result<Row> {
+        let mut d = try!(row.doc.into_document());
+        if self.include {
+            let mut out = bson::Document::new_empty();
+            if self.id {
+                match d.remove("_id") {
+                    Some(v) => {
+                        out.set("_id", v);
+                    },
+                    None => (),
+                }
+            }
+            for path in self.paths.iter().chain(self.slices.iter().map(|&(ref path, _)| path)) {
+                let v = d.find_path(path);
+                if !v.is_undefined() {
+                    try!(out.set_path(path, v));
+                }
+            }
+            d = out;
+        } else {
+            for path in self.paths.iter() {
+                try!(d.unset_path(path));
+            }
+            if !self.id {
+                d.remove("_id");
+            }
+        }
+        for &(ref path, ref slice) in self.slices.iter() {
+            match d.find_path(path) {
+                bson::Value::BArray(ba) => {
+                    let items = slice.apply(ba.items);
+                    try!(d.set_path(path, bson::Value::BArray(bson::Array { items: items })));
+                },
+                _ => (),
+            }
+        }
+        row.doc = bson::Value::BDocument(d);
+        Ok(row)
+    }
+}
+
+// sorting has to see every row first, so the whole result set gets
+// held in memory.
+fn sort_rows(seq: Box<Iterator<Item=Result<Row>>>, orderby: bson::Value) -> Result<Box<Iterator<Item=Result<Row>>>> {
+    let keys = try!(parse_sort_spec(orderby));
+    let mut a = try!(seq.collect::<Result<Vec<_>>>());
+    a.sort_by(|x, y| cmp_row_by_sort_spec(&keys, x, y));
+    Ok(box a.into_iter().map(|d| Ok(d)))
+}
+
 #[derive(Debug)]
 enum UpdateOp {
     Min(String, bson::Value),
@@ -236,7 +433,7 @@ enum UpdateOp {
     Rename(String, String),
     AddToSet(String, Vec<bson::Value>),
     PullAll(String, Vec<bson::Value>),
-    // TODO push
+    Push(String, Vec<bson::Value>),
     PullQuery(String, matcher::QueryDoc),
     PullPredicates(String, Vec<matcher::Pred>),
     Pop(String, i32),
@@ -275,6 +472,11 @@ pub trait StorageWriter : StorageBase {
     fn create_indexes(&self, Vec<IndexInfo>) -> Result<Vec<bool>>;
     fn drop_index(&self, db: &str, coll: &str, name: &str) -> Result<bool>;
 
+    // replace the stored options.  false if there was no such
+    // collection or index.
+    fn set_collection_options(&self, db: &str, coll: &str, options: bson::Document) -> Result<bool>;
+    fn set_index_options(&self, db: &str, coll: &str, name: &str, options: bson::Document) -> Result<bool>;
+
     fn drop_database(&self, db: &str) -> Result<bool>;
 
     fn get_collection_writer(&self, db: &str, coll: &str) -> Result<Box<StorageCollectionWriter + 'static>>;
@@ -407,6 +609,21 @@ pub fn get_normalized_spec(info: &IndexInfo) -> Result<(Vec<(String,IndexType)>,
 pub trait StorageConnection {
     fn begin_write(&self) -> Result<Box<StorageWriter + 'static>>;
     fn begin_read(&self) -> Result<Box<StorageReader + 'static>>;
+
+    // gets a database ready to have collections created in it, if it
+    // is not already.  must not be called inside a tx, so callers do
+    // this before any write which might create a collection.
+    fn prepare_database(&self, db: &str) -> Result<()>;
+
+    // reclaims unused space in the underlying storage.  returns the
+    // number of bytes freed.  must not be called inside a tx.
+    fn compact(&self) -> Result<u64>;
+
+    // makes sure every committed write is in the data files, not
+    // just a log.  returns the number of files.  must not be called
+    // inside a tx.
+    fn checkpoint(&self) -> Result<usize>;
+
     // TODO note that only one tx can exist at a time per connection.
 
     // but it would be possible to have multiple iterators at the same time.
@@ -456,6 +673,7 @@ enum AggOp {
     Out(String),
     Unwind(String),
     Match(matcher::QueryDoc),
+    Count(String),
     Project(Vec<(String,AggProj)>),
     Group(bson::Value, Vec<(String, GroupAccum)>),
     GeoNear(bson::Value),
@@ -577,20 +795,13 @@ impl Connection {
                         return Err(Error::Misc(format!("argument to $inc must be numeric")));
                     }
                     match try!(doc.entry(&path)) {
-                        bson::Entry::Found(mut e) => {
-                            if try!(v.numeric_to_i64()) != 0 {
-                                match e.get_mut() {
-                                    &mut bson::Value::BInt32(ref mut n) => {
-                                        *n = *n + try!(v.numeric_to_i32())
-                                    },
-                                    &mut bson::Value::BInt64(ref mut n) => {
-                                        *n = *n + try!(v.numeric_to_i64())
-                                    },
-                                    &mut bson::Value::BDouble(ref mut n) => {
-                                        *n = *n + try!(v.numeric_to_f64())
-                                    },
-                                    _ => return Err(Error::Misc(format!("can't $inc to this type"))),
+                        bson::Entry::Found(e) => {
+                            if try!(v.numeric_to_f64()) != 0.0 {
+                                if !e.get().is_numeric() {
+                                    return Err(Error::Misc(format!("can't $inc to this type")));
                                 }
+                                let sum = try!(e.get().add_numeric(v));
+                                let _ = e.replace(sum);
                                 count = count + 1;
                             }
                         },
@@ -638,7 +849,10 @@ impl Connection {
                     count = count + 1;
                 },
                 &UpdateOp::PullValue(ref path, ref v) => {
-                    panic!("TODO UpdateOp::PullValue");
+                    let path = Self::fix_positional(path, pos);
+                    if try!(doc.pull_path(&path, v)) > 0 {
+                        count = count + 1;
+                    }
                 },
                 &UpdateOp::SetOnInsert(ref path, ref v) => {
                     panic!("TODO UpdateOp::SetOnInsert");
@@ -724,10 +938,31 @@ impl Connection {
                     panic!("TODO UpdateOp::Rename");
                 },
                 &UpdateOp::AddToSet(ref path, ref v) => {
-                    panic!("TODO UpdateOp::AddToSet");
+                    let path = Self::fix_positional(path, pos);
+                    let mut added = false;
+                    for item in v {
+                        added = try!(doc.add_to_set_path(&path, item.clone())) || added;
+                    }
+                    if added {
+                        count = count + 1;
+                    }
                 },
                 &UpdateOp::PullAll(ref path, ref v) => {
-                    panic!("TODO UpdateOp::PullAll");
+                    let path = Self::fix_positional(path, pos);
+                    let mut removed = 0;
+                    for item in v {
+                        removed = removed + try!(doc.pull_path(&path, item));
+                    }
+                    if removed > 0 {
+                        count = count + 1;
+                    }
+                },
+                &UpdateOp::Push(ref path, ref v) => {
+                    let path = Self::fix_positional(path, pos);
+                    for item in v {
+                        try!(doc.push_path(&path, item.clone()));
+                    }
+                    count = count + 1;
                 },
                 &UpdateOp::PullQuery(ref path, ref qd) => {
                     panic!("TODO UpdateOp::PullQuery");
@@ -780,12 +1015,54 @@ impl Connection {
                         result.push(UpdateOp::Unset(path));
                     }
                 },
+                "$push" => {
+                    for (path, v) in try!(v.into_document()).pairs {
+                        result.push(UpdateOp::Push(path, try!(Self::parse_each(v))));
+                    }
+                },
+                "$addToSet" => {
+                    for (path, v) in try!(v.into_document()).pairs {
+                        result.push(UpdateOp::AddToSet(path, try!(Self::parse_each(v))));
+                    }
+                },
+                "$pull" => {
+                    for (path, v) in try!(v.into_document()).pairs {
+                        match v {
+                            bson::Value::BDocument(_) => return Err(Error::Misc(format!("TODO $pull with a query: {}", path))),
+                            _ => result.push(UpdateOp::PullValue(path, v)),
+                        }
+                    }
+                },
+                "$pullAll" => {
+                    for (path, v) in try!(v.into_document()).pairs {
+                        result.push(UpdateOp::PullAll(path, try!(v.into_array()).items));
+                    }
+                },
                 _ => return Err(Error::Misc(format!("unknown update op: {}", k))),
             }
         }
         Ok(result)
     }
 
+    // the argument to $push or $addToSet is either one value, or
+    // {$each: [...]} for several.
+    fn parse_each(v: bson::Value) -> Result<Vec<bson::Value>> {
+        match v {
+            bson::Value::BDocument(mut bd) => {
+                if bd.get("$each").is_some() {
+                    if bd.pairs.len() > 1 {
+                        return Err(Error::Misc(String::from("TODO $each with other modifiers")));
+                    }
+                    let a = try!(bd.must_remove_array("$each"));
+                    Ok(a.items)
+                } else {
+                    Ok(vec![bson::Value::BDocument(bd)])
+                }
+            },
+            _ => Ok(vec![v]),
+        }
+    }
+
     fn get_one_match(db: &str, coll: &str, w: &StorageWriter, m: &matcher::QueryDoc) -> Result<Option<Row>> {
         let indexes = try!(w.list_indexes()).into_iter().filter(
             |ndx| ndx.db == db && ndx.coll == coll
@@ -811,6 +1088,26 @@ impl Connection {
         Ok(d)
     }
 
+    fn get_matching_ids(db: &str, coll: &str, w: &StorageWriter, m: &matcher::QueryDoc, just_one: bool) -> Result<Vec<bson::Value>> {
+        let indexes = try!(w.list_indexes()).into_iter().filter(
+            |ndx| ndx.db == db && ndx.coll == coll
+            ).collect::<Vec<_>>();
+        let plan = try!(Self::choose_index(&indexes, &m, None));
+        let seq = try!(w.get_collection_reader(db, coll, plan));
+        let mut ids = Vec::new();
+        for r in seq {
+            let row = try!(r);
+            if matcher::match_query(&m, &row.doc) {
+                let id = try!(try!(row.doc.as_document()).must_get("_id")).clone();
+                ids.push(id);
+                if just_one {
+                    break;
+                }
+            }
+        }
+        Ok(ids)
+    }
+
     fn build_upsert_with_update_operators(m: &matcher::QueryDoc, ops: &Vec<UpdateOp>) -> Result<bson::Document> {
         let a = matcher::get_eqs(m);
         let mut doc = bson::Document::new_empty();
@@ -864,18 +1161,23 @@ impl Connection {
         Ok(())
     }
 
-    // TODO this func needs to return the 4-tuple
-    // (count_matches, count_modified, Option<TODO>, Option<TODO>)
-    pub fn update(&self, db: &str, coll: &str, updates: &mut Vec<bson::Document>) -> Result<Vec<Result<()>>> {
+    // for writes which may create a collection in db
+    fn begin_write_in(&self, db: &str) -> Result<Box<StorageWriter + 'static>> {
+        try!(self.conn.prepare_database(db));
+        self.conn.begin_write()
+    }
+
+    // each result is (count_matches, count_modified, upserted _id).
+    pub fn update(&self, db: &str, coll: &str, updates: &mut Vec<bson::Document>) -> Result<Vec<Result<(usize, usize, Option<bson::Value>)>>> {
         //println!("in update: {:?}", updates);
         // TODO need separate conn?
         let mut results = Vec::new();
         {
-            let writer = try!(self.conn.begin_write());
+            let writer = try!(self.begin_write_in(db));
             {
                 let mut collwriter = try!(writer.get_collection_writer(db, coll));
                 // TODO why does this closure need to be mut?
-                let mut one_update_or_upsert = |upd: &mut bson::Document| -> Result<()> {
+                let mut one_update_or_upsert = |upd: &mut bson::Document| -> Result<(usize, usize, Option<bson::Value>)> {
                     //println!("in closure: {:?}", upd);
                     let q = try!(upd.must_remove_document("q"));
                     let mut u = try!(upd.must_remove_document("u"));
@@ -921,15 +1223,12 @@ impl Connection {
                                 try!(Self::validate_for_storage(&mut doc));
                                 // TODO handle error in following line
                                 collwriter.insert(&doc);
-                                // TODO return something
-                                Ok(())
+                                Ok((0, 0, doc.get("_id").map(|id| id.clone())))
                             } else {
-                                Ok(())
-                                //Ok((count_matches, count_modified, None, None))
+                                Ok((0, 0, None))
                             }
                         } else {
-                            Ok(())
-                            //Ok((count_matches, count_modified, None, None))
+                            Ok((count_matches, count_modified, None))
                         }
                     } else {
                         // TODO what happens if the update document has no update operators
@@ -947,8 +1246,7 @@ impl Connection {
                                 try!(Self::validate_for_storage(&mut u));
                                 // TODO handle error in following line
                                 collwriter.update(&u);
-                                // TODO return something
-                                Ok(())
+                                Ok((1, 1, None))
                             },
                             None => {
                                 if upsert {
@@ -956,12 +1254,9 @@ impl Connection {
                                     try!(Self::validate_for_storage(&mut u));
                                     // TODO handle error in following line
                                     collwriter.insert(&u);
-                                    // TODO return something
-                                    Ok(())
+                                    Ok((0, 0, u.get("_id").map(|id| id.clone())))
                                 } else {
-                                    // TODO (0,0,None,None)
-                                    //panic!("TODO nothing updated");
-                                    Ok(())
+                                    Ok((0, 0, None))
                                 }
                             },
                         }
@@ -985,7 +1280,7 @@ impl Connection {
         }
         let mut results = Vec::new();
         {
-            let writer = try!(self.conn.begin_write());
+            let writer = try!(self.begin_write_in(db));
             {
                 let mut collwriter = try!(writer.get_collection_writer(db, coll));
                 for mut doc in docs {
@@ -1011,6 +1306,36 @@ impl Connection {
         Ok(v)
     }
 
+    // TODO the storage layer doesn't keep counts, so this reads
+    // every document in the collection.
+    pub fn collection_stats(&self, db: &str, coll: &str) -> Result<CollectionStats> {
+        let reader = try!(self.conn.begin_read());
+        let nindexes = try!(reader.list_indexes()).into_iter().filter(
+            |ndx| ndx.db == db && ndx.coll == coll
+            ).count();
+        let mut count = 0;
+        let mut size = 0;
+        for r in try!(reader.get_collection_reader(db, coll, None)) {
+            let r = try!(r);
+            count = count + 1;
+            size = size + try!(try!(r.doc.as_document()).to_bson_array()).len() as u64;
+        }
+        let stats = CollectionStats {
+            count: count,
+            size: size,
+            nindexes: nindexes,
+        };
+        Ok(stats)
+    }
+
+    pub fn compact(&self) -> Result<u64> {
+        self.conn.compact()
+    }
+
+    pub fn checkpoint(&self) -> Result<usize> {
+        self.conn.checkpoint()
+    }
+
     fn try_find_index_by_name_or_spec<'a>(indexes: &'a Vec<IndexInfo>, desc: &bson::Value) -> Option<&'a IndexInfo> {
         let mut a =
             match desc {
@@ -1042,10 +1367,17 @@ impl Connection {
                     |ndx| ndx.name != "_id_"
                 ).collect::<Vec<_>>()
             } else {
-                // TODO we're supposed to disallow delete of _id_, right?
+                if !index.is_string() && !index.is_document() {
+                    return Err(Error::Misc(String::from("index must be a name or a key pattern")));
+                }
                 // TODO if let
                 match Self::try_find_index_by_name_or_spec(&indexes, index) {
-                    Some(ndx) => vec![ndx],
+                    Some(ndx) => {
+                        if ndx.name == "_id_" {
+                            return Err(Error::Misc(String::from("cannot drop _id index")));
+                        }
+                        vec![ndx]
+                    },
                     None => vec![],
                 }
             };
@@ -1059,13 +1391,100 @@ impl Connection {
         Ok((count_before, count_deleted))
     }
 
+    // applies the modifications of a collMod command, everything in
+    // the command except the collection name.  only validator and a
+    // change to an index's expireAfterSeconds are supported.
+    pub fn coll_mod(&self, db: &str, coll: &str, mods: bson::Document) -> Result<()> {
+        let writer = try!(self.conn.begin_write());
+        let mut options = 
+            match try!(writer.list_collections()).into_iter().find(|c| c.db == db && c.coll == coll) {
+                Some(c) => c.options,
+                None => return Err(Error::Misc(String::from("ns does not exist"))),
+            };
+        let mut options_changed = false;
+        for (k, v) in mods.pairs {
+            match k.as_str() {
+                "validator" => {
+                    let v = try!(v.into_document());
+                    options.set_document("validator", v);
+                    options_changed = true;
+                },
+                "index" => {
+                    let mut v = try!(v.into_document());
+                    let which = 
+                        match (v.remove("name"), v.remove("keyPattern")) {
+                            (Some(name @ bson::Value::BString(_)), None) => name,
+                            (None, Some(spec @ bson::Value::BDocument(_))) => spec,
+                            _ => return Err(Error::Misc(String::from("collMod index must have either name or keyPattern"))),
+                        };
+                    let ttl = 
+                        match v.remove("expireAfterSeconds") {
+                            Some(ttl) => ttl,
+                            None => return Err(Error::Misc(String::from("collMod index has no modification"))),
+                        };
+                    if let Some(&(ref k, _)) = v.pairs.iter().next() {
+                        return Err(Error::Misc(format!("collMod index option not supported: {}", k)));
+                    }
+                    let indexes = try!(writer.list_indexes()).into_iter().filter(
+                        |ndx| ndx.db == db && ndx.coll == coll
+                        ).collect::<Vec<_>>();
+                    let mut ndx = 
+                        match Self::try_find_index_by_name_or_spec(&indexes, &which) {
+                            Some(ndx) => ndx.clone(),
+                            None => return Err(Error::Misc(String::from("index not found"))),
+                        };
+                    if ndx.options.get("expireAfterSeconds").is_none() {
+                        return Err(Error::Misc(String::from("no expireAfterSeconds field to update")));
+                    }
+                    ndx.options.set("expireAfterSeconds", ttl);
+                    if !try!(writer.set_index_options(&ndx.db, &ndx.coll, &ndx.name, ndx.options)) {
+                        return Err(Error::Misc(String::from("index not found")));
+                    }
+                },
+                _ => return Err(Error::Misc(format!("collMod option not supported: {}", k))),
+            }
+        }
+        if options_changed {
+            if !try!(writer.set_collection_options(db, coll, options)) {
+                return Err(Error::Misc(String::from("ns does not exist")));
+            }
+        }
+        try!(writer.commit());
+        Ok(())
+    }
+
     pub fn create_indexes(&self, indexes: Vec<IndexInfo>) -> Result<Vec<bool>> {
+        for info in &indexes {
+            try!(self.conn.prepare_database(&info.db));
+        }
         let writer = try!(self.conn.begin_write());
         let results = try!(writer.create_indexes(indexes));
         try!(writer.commit());
         Ok(results)
     }
 
+    pub fn rename_collection(&self, old_name: &str, new_name: &str, drop_target: bool) -> Result<()> {
+        let old = try!(bson::split_name(old_name));
+        let new = try!(bson::split_name(new_name));
+        let writer = try!(self.begin_write_in(new.0));
+        {
+            let collections = try!(writer.list_collections());
+            let exists = |name: (&str, &str)| {
+                let (db, coll) = name;
+                collections.iter().any(|c| c.db == db && c.coll == coll)
+            };
+            if !exists(old) {
+                return Err(Error::Misc(String::from("source namespace does not exist")));
+            }
+            if exists(new) && !drop_target {
+                return Err(Error::Misc(String::from("target namespace exists")));
+            }
+        }
+        let _created = try!(writer.rename_collection(old_name, new_name, drop_target));
+        try!(writer.commit());
+        Ok(())
+    }
+
     pub fn drop_collection(&self, db: &str, coll: &str) -> Result<bool> {
         let deleted = {
             let writer = try!(self.conn.begin_write());
@@ -1086,33 +1505,30 @@ impl Connection {
         Ok(deleted)
     }
 
+    // each item is {q, limit}.  limit 1 deletes only the first match,
+    // and 0, or no limit at all, deletes every match.
     pub fn delete(&self, db: &str, coll: &str, items: &Vec<bson::Value>) -> Result<usize> {
         let mut count = 0;
         {
-            let writer = try!(self.conn.begin_write());
+            let writer = try!(self.begin_write_in(db));
             {
                 let mut collwriter = try!(writer.get_collection_writer(db, coll));
                 for del in items {
-                    // TODO
-                    /*
-                    let q = bson.getValueForKey upd "q"
-                    let limit = bson.tryGetValueForKey upd "limit"
-                    let m = Matcher.parseQuery q
-                    // TODO is this safe?  or do we need two-conn isolation like update?
-                    let indexes = w.getIndexes()
-                    let plan = chooseIndex indexes m None
-                    let {docs=s;funk=funk} = w.getSelect plan
-                    try
-                        s |> seqMatch m |> 
-                            Seq.iter (fun {doc=doc} -> 
-                                // TODO is it possible to delete from an autoIndexId=false collection?
-                                let id = bson.getValueForKey doc "_id"
-                                if basicDelete w id then
-                                    count := !count + 1
-                                )
-                    finally
-                        funk()
-                    */
+                    let del = try!(del.as_document());
+                    let q = try!(try!(del.must_get("q")).as_document()).clone();
+                    let limit = match del.get("limit") {
+                        Some(v) => try!(v.numeric_to_i32()),
+                        None => 0,
+                    };
+                    let m = try!(matcher::parse_query(q));
+                    // gather the ids before deleting anything, so the
+                    // reader is not walking the rows being removed
+                    let ids = try!(Self::get_matching_ids(db, coll, &*writer, &m, limit == 1));
+                    for id in ids {
+                        if try!(collwriter.delete(&id)) {
+                            count = count + 1;
+                        }
+                    }
                 }
             }
             try!(writer.commit());
@@ -1121,7 +1537,7 @@ impl Connection {
     }
 
     pub fn create_collection(&self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
-        let writer = try!(self.conn.begin_write());
+        let writer = try!(self.begin_write_in(db));
         let result = try!(writer.create_collection(db, coll, options));
         try!(writer.commit());
         Ok(result)
@@ -2079,6 +2495,13 @@ impl Connection {
                         "$unwind" => {
                             Ok(AggOp::Unwind(try!(v.into_string())))
                         },
+                        "$count" => {
+                            let name = try!(v.into_string());
+                            if name.is_empty() || name.starts_with("$") || name.contains('.') {
+                                return Err(Error::Misc(format!("invalid $count field name: {}", name)))
+                            }
+                            Ok(AggOp::Count(name))
+                        },
                         "$match" => {
                             let v = try!(v.into_document());
                             let m = try!(matcher::parse_query(v));
@@ -2225,48 +2648,60 @@ impl Connection {
                     );
                 },
                 AggOp::Sort(k) => {
-                    let mut a = try!(seq.collect::<Result<Vec<_>>>());
-                    a.sort_by(cmp_row);
-                    seq = box a.into_iter().map(|d| Ok(d));
+                    seq = try!(sort_rows(seq, k));
                 },
                 AggOp::Project(expressions) => {
                     seq = box Self::agg_project(seq, expressions);
                 },
+                AggOp::Count(name) => {
+                    let mut n = 0;
+                    for r in seq {
+                        try!(r);
+                        n = n + 1;
+                    }
+                    // like mongo, nothing in means nothing out, not
+                    // a count of zero
+                    if n == 0 {
+                        seq = box std::iter::empty::<Result<Row>>();
+                    } else {
+                        let mut d = bson::Document::new_empty();
+                        d.set_i32(&name, n);
+                        let row = Row {
+                            doc: bson::Value::BDocument(d),
+                        };
+                        seq = box std::iter::once(Ok(row));
+                    }
+                },
+                AggOp::Out(_) => {
+                    return Err(Error::Misc(String::from("agg pipeline TODO: $out")))
+                },
+                AggOp::Unwind(_) => {
+                    return Err(Error::Misc(String::from("agg pipeline TODO: $unwind")))
+                },
                 _ => {
-                    //return Err(Error::Misc(format!("agg pipeline TODO: {:?}", ops)))
+                    // $group, $redact and $geoNear already failed in parse_agg
+                    return Err(Error::Misc(String::from("agg pipeline stage not supported")))
                 },
             }
         }
         Ok((None, seq))
     }
 
-    pub fn find(&self,
-                db: &str,
-                coll: &str,
-                query: bson::Document,
-                orderby: Option<bson::Value>,
-                projection: Option<bson::Document>,
-                min: Option<bson::Value>,
-                max: Option<bson::Value>,
-                hint: Option<bson::Value>,
-                explain: Option<bson::Value>
-                ) 
-        -> Result<Box<Iterator<Item=Result<Row>> + 'static>>
+    fn choose_plan(indexes: &Vec<IndexInfo>,
+                   m: &matcher::QueryDoc,
+                   min: Option<bson::Value>,
+                   max: Option<bson::Value>,
+                   hint: Option<bson::Value>
+                   )
+        -> Result<Option<QueryPlan>>
     {
-        let reader = try!(self.conn.begin_read());
-        // TODO make the following filter DRY
-        let indexes = try!(reader.list_indexes()).into_iter().filter(
-            |ndx| ndx.db == db && ndx.coll == coll
-            ).collect::<Vec<_>>();
-        // TODO maybe we should get normalized index specs for all the indexes now.
-        let m = try!(matcher::parse_query(query));
         let (natural, hint) = 
             match hint {
                 Some(ref v) => {
                     if v.is_string() && try!(v.as_str()) == "$natural" {
                         (true, None)
                     } else {
-                        if let Some(ndx) = Self::try_find_index_by_name_or_spec(&indexes, v) {
+                        if let Some(ndx) = Self::try_find_index_by_name_or_spec(indexes, v) {
                             (false, Some(ndx))
                         } else {
                             return Err(Error::Misc(String::from("bad hint")));
@@ -2285,7 +2720,7 @@ impl Connection {
                     if natural {
                         None
                     } else {
-                        try!(Self::choose_index(&indexes, &m, hint))
+                        try!(Self::choose_index(indexes, m, hint))
                     }
                 },
                 (min, max) => {
@@ -2302,7 +2737,7 @@ impl Connection {
                             (Some(min), None) => {
                                 let min = try!(Self::parse_index_min_max(min));
                                 let (keys, minvals): (Vec<_>, Vec<_>) = min.into_iter().unzip();
-                                match try!(Self::find_index_for_min_max(&indexes, &keys)) {
+                                match try!(Self::find_index_for_min_max(indexes, &keys)) {
                                     Some(ndx) => {
                                         let bounds = QueryBounds::GTE(minvals);
                                         (ndx, bounds)
@@ -2328,6 +2763,51 @@ impl Connection {
                     Some(plan)
                 }
             };
+        Ok(plan)
+    }
+
+    // figures out how find() would run the query, without running it.
+    // returns the index that would be used, if any.
+    pub fn explain_find(&self,
+                db: &str,
+                coll: &str,
+                query: bson::Document,
+                min: Option<bson::Value>,
+                max: Option<bson::Value>,
+                hint: Option<bson::Value>
+                ) 
+        -> Result<Option<IndexInfo>>
+    {
+        let reader = try!(self.conn.begin_read());
+        let indexes = try!(reader.list_indexes()).into_iter().filter(
+            |ndx| ndx.db == db && ndx.coll == coll
+            ).collect::<Vec<_>>();
+        let m = try!(matcher::parse_query(query));
+        let plan = try!(Self::choose_plan(&indexes, &m, min, max, hint));
+        Ok(plan.map(|p| p.ndx))
+    }
+
+    pub fn find(&self,
+                db: &str,
+                coll: &str,
+                query: bson::Document,
+                orderby: Option<bson::Value>,
+                projection: Option<bson::Document>,
+                min: Option<bson::Value>,
+                max: Option<bson::Value>,
+                hint: Option<bson::Value>,
+                explain: Option<bson::Value>
+                ) 
+        -> Result<Box<Iterator<Item=Result<Row>> + 'static>>
+    {
+        let reader = try!(self.conn.begin_read());
+        // TODO make the following filter DRY
+        let indexes = try!(reader.list_indexes()).into_iter().filter(
+            |ndx| ndx.db == db && ndx.coll == coll
+            ).collect::<Vec<_>>();
+        // TODO maybe we should get normalized index specs for all the indexes now.
+        let m = try!(matcher::parse_query(query));
+        let plan = try!(Self::choose_plan(&indexes, &m, min, max, hint));
 
         let mut seq: Box<Iterator<Item=Result<Row>>> = try!(reader.into_collection_reader(db, coll, plan));
         seq = box seq
@@ -2343,14 +2823,19 @@ impl Connection {
         );
         match orderby {
             Some(orderby) => {
-                let mut a = try!(seq.collect::<Result<Vec<_>>>());
-                a.sort_by(cmp_row);
-                seq = box a.into_iter().map(|d| Ok(d))
+                seq = try!(sort_rows(seq, orderby));
+            },
+            None => {
+            },
+        }
+        match projection {
+            Some(projection) => {
+                let projection = try!(FindProjection::parse(projection));
+                seq = box seq.map(move |r| r.and_then(|row| projection.apply(row)));
             },
             None => {
             },
         }
-        // TODO projection
         Ok(seq)
     }
 }
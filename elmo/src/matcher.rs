@@ -6,6 +6,7 @@ use super::Result;
 
 extern crate misc;
 extern crate bson;
+extern crate regex;
 
 #[derive(Debug)]
 pub enum QueryDoc {
@@ -42,8 +43,7 @@ pub enum Pred {
     LT(bson::Value),
     GTE(bson::Value),
     LTE(bson::Value),
-    // TODO regex should be in compiled form, not a string
-    REGEX(String),
+    REGEX(regex::Regex),
     Near(bson::Value),
     NearSphere(bson::Value),
     GeoWithin(bson::Value),
@@ -381,7 +381,7 @@ fn match_predicate<F: Fn(usize)>(pred: &Pred, d: &bson::Value, cb_array_pos: &F)
             match d {
                 &bson::Value::BArray(ref ba) => {
                     let found = 
-                        ba.items.iter().position(|vsub| preds.iter().any(|p| !match_predicate(p, vsub, cb_array_pos)));
+                        ba.items.iter().position(|vsub| preds.iter().all(|p| match_predicate(p, vsub, cb_array_pos)));
                     match found {
                         Some(n) => {
                             cb_array_pos(n);
@@ -396,7 +396,7 @@ fn match_predicate<F: Fn(usize)>(pred: &Pred, d: &bson::Value, cb_array_pos: &F)
         &Pred::AllElemMatchObjects(ref docs) => {
             // for each elemMatch doc in the $all array, run it against
             // the candidate array.  if any elemMatch doc fails, false.
-            docs.iter().any(|doc| !do_elem_match_objects(doc, d, cb_array_pos))
+            docs.iter().all(|doc| do_elem_match_objects(doc, d, cb_array_pos))
         },
         &Pred::All(ref lits) => {
             // TODO does this ever happen, now that it is handled earlier?
@@ -425,12 +425,9 @@ fn match_predicate<F: Fn(usize)>(pred: &Pred, d: &bson::Value, cb_array_pos: &F)
         &Pred::GT(ref lit) => cmp_gt(d, lit),
         &Pred::LTE(ref lit) => cmp_lte(d, lit),
         &Pred::GTE(ref lit) => cmp_gte(d, lit),
-        &Pred::REGEX(_) => {
+        &Pred::REGEX(ref re) => {
             match d {
-                &bson::Value::BString(ref s) => {
-                    // TODO use regex to match s
-                    panic!("TODO regex");
-                },
+                &bson::Value::BString(ref s) => re.is_match(s),
                 _ => false,
             }
         },
@@ -481,7 +478,7 @@ fn match_pair_exists(pred: &Pred, path: &str, start: &bson::Value) -> bool {
                             } else {
                                 ba.items.iter().any(|vsub| {
                                     match vsub {
-                                        &bson::Value::BDocument(_) => match_pair_exists(pred, subpath, v),
+                                        &bson::Value::BDocument(_) => match_pair_exists(pred, subpath, vsub),
                                         _ => false,
                                     }
                                 })
@@ -545,7 +542,7 @@ fn match_pair_other<F: Fn(usize)>(pred: &Pred, path: &str, start: &bson::Value,
                             } else {
                                 let f = |vsub| {
                                     match vsub {
-                                        &bson::Value::BDocument(_) => match_pair_other(pred, subpath, v, false, cb_array_pos),
+                                        &bson::Value::BDocument(_) => match_pair_other(pred, subpath, vsub, false, cb_array_pos),
                                         _ => false,
                                     }
                                 };
@@ -754,6 +751,30 @@ fn is_query_doc(v: &bson::Value) -> bool {
     }
 }
 
+// mongo's options are i (case-insensitive), m (multiline), s (dot
+// matches newline) and x (extended).  the regex crate has the same
+// flags, so they become a (?flags) group in front of the pattern.
+fn compile_regex(expr: &str, options: &str) -> Result<regex::Regex> {
+    let mut flags = String::new();
+    for c in options.chars() {
+        match c {
+            'i' | 'm' | 's' | 'x' => {
+                if !flags.contains(c) {
+                    flags.push(c);
+                }
+            },
+            _ => return Err(super::Error::Misc(format!("invalid regex option: {}", c))),
+        }
+    }
+    let pattern = 
+        if flags.is_empty() {
+            String::from(expr)
+        } else {
+            format!("(?{}){}", flags, expr)
+        };
+    regex::Regex::new(&pattern).map_err(|e| super::Error::Misc(format!("invalid regex: {}: {}", expr, e)))
+}
+
 fn parse_pred(k: &str, v: bson::Value) -> Result<Pred> {
     fn not_regex(v: bson::Value) -> Result<bson::Value> {
         match v {
@@ -769,7 +790,8 @@ fn parse_pred(k: &str, v: bson::Value) -> Result<Pred> {
         "$lt" => Ok(Pred::LT(try!(not_regex(v)))),
         "$gte" => Ok(Pred::GTE(try!(not_regex(v)))),
         "$lte" => Ok(Pred::LTE(try!(not_regex(v)))),
-        "$regex" => panic!("TODO parse_pred regex"),
+        // parse_pred_list handles $regex, since it needs $options too
+        "$regex" => unreachable!(),
         "$exists" => Ok(Pred::Exists(try!(v.as_bool()))),
         // TODO as_i32 below: should probably allow conversion
         "$type" => Ok(Pred::Type(try!(v.as_i32()))),
@@ -866,8 +888,8 @@ fn parse_pred(k: &str, v: bson::Value) -> Result<Pred> {
                         Ok(Pred::Not(preds))
                     }
                 },
-                bson::Value::BRegex(_,_) => {
-                    panic!("TODO regex");
+                bson::Value::BRegex(ref expr, ref options) => {
+                    Ok(Pred::Not(vec![Pred::REGEX(try!(compile_regex(expr, options)))]))
                 },
                 _ => {
                     Err(super::Error::Misc(format!("invalid $not: {:?}", v)))
@@ -895,9 +917,10 @@ fn parse_pred(k: &str, v: bson::Value) -> Result<Pred> {
                 let d = QueryDoc::QueryDoc(d);
                 Ok(Pred::ElemMatchObjects(d))
             } else {
+                // all the predicates must match the same element
                 let bd = try!(v.into_document());
                 let preds = try!(parse_pred_list(bd.pairs));
-                Ok(Pred::Not(preds))
+                Ok(Pred::ElemMatchPreds(preds))
             }
         },
         "$near" => panic!("TODO parse_pred $near"),
@@ -910,19 +933,28 @@ fn parse_pred(k: &str, v: bson::Value) -> Result<Pred> {
 
 fn parse_pred_list(pairs: Vec<(String,bson::Value)>) -> Result<Vec<Pred>> {
     let (regex, other): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|&(ref k,_)| k == "$regex" || k == "$options");
-    let preds = try!(other.into_iter().map(|(k,v)| parse_pred(&k,v)).collect::<Result<Vec<_>>>());
+    let mut preds = try!(other.into_iter().map(|(k,v)| parse_pred(&k,v)).collect::<Result<Vec<_>>>());
     let expr = regex.iter().find(|&&(ref k, _)| k == "$regex");
     let options = regex.iter().find(|&&(ref k, _)| k == "$options");
     match (expr, options) {
-        (Some(expr), None) => {
-            panic!("TODO regex");
-        },
-        (Some(expr), Some(options)) => {
-            panic!("TODO regex");
+        (Some(&(_, ref expr)), options) => {
+            // $regex can be a string or a regex.  $options, if present,
+            // replaces the options of a regex.
+            let (expr, embedded) = 
+                match expr {
+                    &bson::Value::BString(ref s) => (s.as_str(), ""),
+                    &bson::Value::BRegex(ref s, ref o) => (s.as_str(), o.as_str()),
+                    _ => return Err(super::Error::Misc(format!("$regex has to be a string: {:?}", expr))),
+                };
+            let options = 
+                match options {
+                    Some(&(_, ref o)) => try!(o.as_str()),
+                    None => embedded,
+                };
+            preds.push(Pred::REGEX(try!(compile_regex(expr, options))));
         },
         (None, Some(_)) => {
-            // TODO error
-            panic!("TODO regex");
+            return Err(super::Error::Misc(String::from("$options needs a $regex")));
         },
         (None, None) => {
             // nothing to do here
@@ -949,7 +981,7 @@ fn parse_compare(k: &str, v: &bson::Value) -> Result<QueryItem> {
                 }
             },
             &bson::Value::BRegex(ref expr, ref options) => {
-                QueryItem::Compare(String::from(k), vec![Pred::REGEX(String::from("TODO"))])
+                QueryItem::Compare(String::from(k), vec![Pred::REGEX(try!(compile_regex(expr, options)))])
             },
             _ => {
                 // TODO clone
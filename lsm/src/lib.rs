@@ -66,6 +66,87 @@ pub enum Blob {
     Tombstone,
 }
 
+impl Blob {
+    // returns a copy of (up to) the first n bytes without using them
+    // up.  for a Stream, the bytes read get put back in front of the
+    // rest of it, so a later read still sees the whole value.
+    pub fn peek_bytes(&mut self, n: usize) -> Result<Box<[u8]>> {
+        match *self {
+            Blob::Array(ref a) => {
+                let n = std::cmp::min(n, a.len());
+                Ok(a[0 .. n].to_vec().into_boxed_slice())
+            },
+            Blob::Tombstone => Ok(Vec::new().into_boxed_slice()),
+            Blob::Stream(_) => {
+                let mut strm =
+                    match std::mem::replace(self, Blob::Tombstone) {
+                        Blob::Stream(strm) => strm,
+                        _ => unreachable!(),
+                    };
+                // not read_fully(), which would lose whatever it got
+                // before an error.  the stream goes back either way.
+                let mut buf = vec![0; n];
+                let mut got = 0;
+                let mut err = None;
+                while got < n {
+                    match strm.read(&mut buf[got ..]) {
+                        Ok(0) => break,
+                        Ok(k) => got = got + k,
+                        Err(e) => {
+                            err = Some(e);
+                            break;
+                        },
+                    }
+                }
+                buf.truncate(got);
+                let peeked = buf.clone().into_boxed_slice();
+                *self = Blob::Stream(box io::Cursor::new(buf).chain(strm));
+                match err {
+                    Some(e) => Err(Error::Io(e)),
+                    None => Ok(peeked),
+                }
+            },
+        }
+    }
+
+    // the bytes of an Array, without copying them.  None for a Stream,
+    // which would have to be read first, and for a Tombstone.
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        match *self {
+            Blob::Array(ref a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Blob {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match *self {
+            Blob::Stream(_) => write!(f, "<stream>"),
+            Blob::Array(ref a) => {
+                if a.len() > 16 {
+                    write!(f, "Array, len={}, {:?}...", a.len(), &a[0 .. 16])
+                } else {
+                    write!(f, "Array, len={}, {:?}", a.len(), a)
+                }
+            },
+            Blob::Tombstone => write!(f, "<tombstone>"),
+        }
+    }
+}
+
+// a Stream can only be read once, so comparing one would consume it.
+// a Stream is never equal to anything, not even itself.
+impl PartialEq for Blob {
+    fn eq(&self, other: &Blob) -> bool {
+        match (self, other) {
+            (&Blob::Array(ref a), &Blob::Array(ref b)) => a == b,
+            (&Blob::Tombstone, &Blob::Tombstone) => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug)]
 enum Error {
     // TODO remove Misc
@@ -145,6 +226,12 @@ pub struct kvp {
     Value : Blob,
 }
 
+impl kvp {
+    pub fn new(k: Box<[u8]>, v: Blob) -> kvp {
+        kvp { Key: k, Value: v }
+    }
+}
+
 struct PendingSegment {
     blockList: Vec<PageBlock>,
     segnum: SegmentNum,
@@ -233,6 +320,18 @@ impl<'a> KeyRef<'a> {
         KeyRef::Array(k)
     }
 
+    // the key as one borrowed slice, without copying.  a prefixed
+    // key is in two pieces, so it gets None.  a cursor never hands one
+    // out, since it puts the keys of a prefixed leaf back together
+    // when it reads the leaf.
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        match *self {
+            KeyRef::Overflowed(ref a) => Some(a),
+            KeyRef::Array(a) => Some(a),
+            KeyRef::Prefixed(_,_) => None,
+        }
+    }
+
     pub fn into_boxed_slice(self) -> Box<[u8]> {
         match self {
             KeyRef::Overflowed(a) => {
@@ -425,9 +524,15 @@ pub type SegmentNum = u64;
 
 trait IPages {
     fn PageSize(&self) -> usize;
+    fn PrefixCompression(&self) -> bool;
+    fn InlineValueMaxBytes(&self) -> Option<usize>;
     fn Begin(&self) -> Result<PendingSegment>;
     fn GetBlock(&self, token: &mut PendingSegment) -> Result<PageBlock>;
+    fn GetBlobBlock(&self, token: &mut PendingSegment) -> Result<PageBlock>;
     fn End(&self, token: PendingSegment, page: PageNum) -> Result<SegmentNum>;
+    // for a segment which will not be finished.  its blocks go back
+    // on the free list.
+    fn Abandon(&self, token: PendingSegment) -> Result<()>;
 }
 
 #[derive(PartialEq,Copy,Clone)]
@@ -541,6 +646,40 @@ pub struct DbSettings {
     pub AutoMergeMinimumPages : PageNum,
     pub DefaultPageSize : usize,
     pub PagesPerBlock : PageNum,
+    // a merge may discard tombstones only if they were committed
+    // at least this many commits ago.  0 means as soon as possible.
+    pub TombstoneRetention : u64,
+    // leaf pages store the prefix shared by their keys only once.
+    // this helps with sorted string keys but just costs CPU time
+    // for random or binary ones.
+    pub PrefixCompression : bool,
+    // the size of each block allocated while writing a value or key
+    // which overflows into a chain of pages.  None means PagesPerBlock.
+    pub BlobBlockPages : Option<PageNum>,
+    // a value longer than this goes into overflow pages even if it
+    // would fit in the leaf.  None means keep any value inline which
+    // fits.
+    pub InlineValueMaxBytes : Option<usize>,
+}
+
+impl DbSettings {
+    // a page must hold at least a few keys, and a block needs room
+    // for more than the page which points at the next block.
+    pub fn validate(&self) -> Result<()> {
+        if self.DefaultPageSize < 256 {
+            return Err(Error::Misc("DefaultPageSize must be at least 256"));
+        }
+        if self.DefaultPageSize > 65536 {
+            return Err(Error::Misc("DefaultPageSize must be at most 65536"));
+        }
+        if self.PagesPerBlock < 2 {
+            return Err(Error::Misc("PagesPerBlock must be at least 2"));
+        }
+        if self.BlobBlockPages == Some(0) {
+            return Err(Error::Misc("BlobBlockPages must be at least 1"));
+        }
+        Ok(())
+    }
 }
 
 pub const DEFAULT_SETTINGS : DbSettings = 
@@ -550,12 +689,45 @@ pub const DEFAULT_SETTINGS : DbSettings =
         AutoMergeMinimumPages : 4,
         DefaultPageSize : 4096,
         PagesPerBlock : 256,
+        TombstoneRetention : 0,
+        PrefixCompression : true,
+        BlobBlockPages : None,
+        InlineValueMaxBytes : None,
     };
 
+// what a merge did.  keys_kept counts what went into the new
+// segment, tombstones included.  older values of a key which were
+// replaced by a newer one are not counted anywhere.  the byte counts
+// are whole pages.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct CompactionStats {
+    pub segments_in: usize,
+    pub segments_out: usize,
+    pub keys_kept: usize,
+    pub tombstones_dropped: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+// a segment in the current state, as segments_since reports it.
+// commit is the changeCounter of the commit which made it current.
+// age is 0 for a segment written directly, and for a merged one, one
+// more than the highest age among the segments it replaced.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct CommittedSegment {
+    pub segnum: SegmentNum,
+    pub commit: u64,
+    pub age: u32,
+}
+
 #[derive(Clone)]
 struct SegmentInfo {
     root : PageNum,
     age : u32,
+    // the changeCounter of the commit which made this segment part of
+    // currentState.  for a merged segment, the newest of the segments
+    // it replaced.
+    commit : u64,
     // TODO does this grow?  shouldn't it be a boxed array?
     // yes, but then derive clone complains.
     // ideally we could just stop cloning this struct.
@@ -583,6 +755,76 @@ pub mod utils {
 
 }
 
+// the smallest key which is greater than every key that starts with
+// the given prefix.  useful as the exclusive upper bound of a prefix
+// scan.  trailing 0xFF bytes get dropped, and then the last byte is
+// incremented.  if nothing is left (the prefix is empty or all 0xFF),
+// there is no such key.
+pub fn prefix_successor(prefix: &[u8]) -> Option<Box<[u8]>> {
+    match prefix.iter().rposition(|&b| b != 0xff) {
+        Some(i) => {
+            let mut k = Vec::with_capacity(i + 1);
+            k.push_all(&prefix[0 .. i + 1]);
+            k[i] = k[i] + 1;
+            Some(k.into_boxed_slice())
+        },
+        None => None,
+    }
+}
+
+// combines several parts into one key, such that the keys sort the
+// same way the lists of parts would, comparing part by part.  a length
+// prefix would not do that (a short part would always sort before a
+// long one), so instead every part ends with 0x00 0x00, and any 0x00
+// within a part is written as 0x00 0xFF.
+pub fn encode_index_key(parts: &[&[u8]]) -> Box<[u8]> {
+    let len = parts.iter().fold(0, |acc, p| acc + p.len() + 2);
+    let mut k = Vec::with_capacity(len);
+    for p in parts {
+        for &b in p.iter() {
+            k.push(b);
+            if b == 0 {
+                k.push(0xff);
+            }
+        }
+        k.push(0);
+        k.push(0);
+    }
+    k.into_boxed_slice()
+}
+
+pub fn decode_index_key(k: &[u8]) -> Result<Vec<Box<[u8]>>> {
+    let mut parts = Vec::new();
+    let mut cur = Vec::new();
+    let mut i = 0;
+    while i < k.len() {
+        if k[i] == 0 {
+            if i + 1 >= k.len() {
+                return Err(Error::Misc("index key ends in the middle of an escape"));
+            }
+            match k[i + 1] {
+                0 => {
+                    parts.push(std::mem::replace(&mut cur, Vec::new()).into_boxed_slice());
+                },
+                0xff => {
+                    cur.push(0);
+                },
+                _ => {
+                    return Err(Error::Misc("invalid escape in index key"));
+                },
+            }
+            i = i + 2;
+        } else {
+            cur.push(k[i]);
+            i = i + 1;
+        }
+    }
+    if cur.len() > 0 {
+        return Err(Error::Misc("index key does not end with a terminator"));
+    }
+    Ok(parts)
+}
+
 mod bcmp {
     use std::cmp::Ordering;
     use std::cmp::min;
@@ -981,6 +1223,31 @@ impl<'a> MultiCursor<'a> {
         }
     }
 
+    // whether any segment older than the one providing the current
+    // value has a tombstone for the current key.  the subcursors are
+    // not necessarily positioned on this key (after a seek, the older
+    // ones are not touched at all), so this looks with fresh cursors
+    // and leaves the position alone.
+    fn was_resurrected(&self) -> Result<bool> {
+        match self.cur {
+            None => Err(Error::CursorNotValid),
+            Some(icur) => {
+                let k = try!(self.subcursors[icur].KeyRef()).into_boxed_slice();
+                let kref = KeyRef::for_slice(&k);
+                // subcursors are newest first
+                for older in self.subcursors[icur + 1 ..].iter() {
+                    let mut csr = try!(older.inner.cloneSegmentCursor(older));
+                    if try!(csr.SeekRef(&kref, SeekOp::SEEK_EQ)).is_valid_and_equal() {
+                        if try!(csr.ValueLength()).is_none() {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(false)
+            },
+        }
+    }
+
     fn Create(subs: Vec<SegmentCursor>) -> MultiCursor {
         let s = subs.into_boxed_slice();
         let mut sorted = Vec::with_capacity(s.len());
@@ -1296,6 +1563,139 @@ impl<'a> LivingCursor<'a> {
     fn Create(ch : MultiCursor) -> LivingCursor {
         LivingCursor { chain : ch }
     }
+
+    // SeekRef without having to build a KeyRef first
+    pub fn SeekBytes(&mut self, k: &[u8], sop: SeekOp) -> Result<SeekResult> {
+        self.SeekRef(&KeyRef::for_slice(k), sop)
+    }
+
+    pub fn SeekStr(&mut self, k: &str, sop: SeekOp) -> Result<SeekResult> {
+        self.SeekBytes(k.as_bytes(), sop)
+    }
+
+    // Key and Value are KeyRef and ValueRef for callers who would
+    // rather not check IsValid first.  when the cursor is not on a
+    // live key, as after a SEEK_EQ which found nothing, they return
+    // Ok(None) instead of an error, and never anything left over from
+    // an earlier position.
+    pub fn Key(&self) -> Result<Option<Box<[u8]>>> {
+        if self.IsValid() {
+            let k = try!(self.chain.KeyRef());
+            Ok(Some(k.into_boxed_slice()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn Value(&'a self) -> Result<Option<ValueRef<'a>>> {
+        if self.IsValid() {
+            let v = try!(self.chain.ValueRef());
+            Ok(Some(v))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // positions the cursor on the nth live key, counting from 0, or
+    // leaves it invalid if there are not that many.  parent pages do
+    // not keep counts of the keys beneath them, so this is just First
+    // and n calls to Next.
+    pub fn SeekOrdinal(&mut self, n: usize) -> Result<()> {
+        try!(self.First());
+        for _ in 0 .. n {
+            if !self.IsValid() {
+                break;
+            }
+            try!(self.Next());
+        }
+        Ok(())
+    }
+
+    // true if the current key was deleted at some point and then
+    // written again, which is to say an older segment has a tombstone
+    // for it, hidden by the value here.
+    pub fn was_resurrected(&self) -> Result<bool> {
+        self.chain.was_resurrected()
+    }
+
+    // for values which are a big-endian u64.  avoids reading the
+    // value into a Box just to parse it.  None means tombstone.
+    pub fn ValueAsU64(&'a self) -> Result<Option<u64>> {
+        match try!(self.chain.ValueLength()) {
+            None => Ok(None),
+            Some(8) => {
+                match try!(self.chain.ValueRef()) {
+                    ValueRef::Array(a) => {
+                        Ok(Some(endian::u64_from_bytes_be(misc::bytes::extract_8(a))))
+                    },
+                    ValueRef::Overflowed(_, mut strm) => {
+                        let mut a = [0; 8];
+                        let got = try!(misc::io::read_fully(&mut *strm, &mut a));
+                        if got != 8 {
+                            return Err(Error::CorruptFile("overflowed value too short"));
+                        }
+                        Ok(Some(endian::u64_from_bytes_be(a)))
+                    },
+                    ValueRef::Tombstone => Ok(None),
+                }
+            },
+            Some(_) => Err(Error::Misc("value is not 8 bytes")),
+        }
+    }
+}
+
+// walks a MultiCursor and yields the keys whose value length passes
+// keep.  the length is None for a tombstone.  the length is in the
+// leaf, so overflowed values never get read.
+pub struct FilteredKeyIter<'a> {
+    chain : MultiCursor<'a>,
+    started : bool,
+    keep : fn(&Option<usize>) -> bool,
+}
+
+// only the keys which are tombstones, which is everything
+// LivingCursor skips.
+pub type TombstoneIter<'a> = FilteredKeyIter<'a>;
+
+// the live keys, the same ones a LivingCursor visits, without their
+// values.
+pub type KeyIter<'a> = FilteredKeyIter<'a>;
+
+impl<'a> FilteredKeyIter<'a> {
+    fn Create(ch : MultiCursor, keep : fn(&Option<usize>) -> bool) -> FilteredKeyIter {
+        FilteredKeyIter { chain : ch, started : false, keep : keep }
+    }
+}
+
+impl<'a> Iterator for FilteredKeyIter<'a> {
+    type Item = Result<Box<[u8]>>;
+
+    fn next(&mut self) -> Option<Result<Box<[u8]>>> {
+        let r = 
+            if self.started {
+                self.chain.Next()
+            } else {
+                self.started = true;
+                self.chain.First()
+            };
+        if let Err(e) = r {
+            return Some(Err(e));
+        }
+        while self.chain.IsValid() {
+            match self.chain.ValueLength() {
+                Err(e) => return Some(Err(e)),
+                Ok(len) => {
+                    if (self.keep)(&len) {
+                        return Some(self.chain.KeyRef().map(|k| k.into_boxed_slice()));
+                    }
+                    if let Err(e) = self.chain.Next() {
+                        return Some(Err(e));
+                    }
+                },
+            }
+        }
+        None
+    }
 }
 
 impl<'a> ICursor<'a> for LivingCursor<'a> {
@@ -1319,8 +1719,14 @@ impl<'a> ICursor<'a> for LivingCursor<'a> {
         self.chain.ValueRef()
     }
 
+    // a LivingCursor never rests on a tombstone, so None here means
+    // the cursor is not valid.
     fn ValueLength(&self) -> Result<Option<usize>> {
-        self.chain.ValueLength()
+        if self.chain.IsValid() {
+            self.chain.ValueLength()
+        } else {
+            Ok(None)
+        }
     }
 
     fn IsValid(&self) -> bool {
@@ -1469,192 +1875,186 @@ struct LeafState {
     blk : PageBlock,
 }
 
-fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite, 
-                                                            pageManager: &IPages, 
-                                                            source: I,
-                                                           ) -> Result<(SegmentNum,PageNum)> where I:Iterator<Item=Result<kvp>>, SeekWrite : Seek+Write {
-
-    fn writeOverflow<SeekWrite>(startingBlock: PageBlock, 
-                                ba: &mut Read, 
-                                pageManager: &IPages, 
-                                fs: &mut SeekWrite
-                               ) -> Result<(usize,PageBlock)> where SeekWrite : Seek+Write {
+fn writeOverflow<SeekWrite>(startingBlock: PageBlock, 
+                            ba: &mut Read, 
+                            pageManager: &IPages, 
+                            fs: &mut SeekWrite
+                           ) -> Result<(usize,PageBlock)> where SeekWrite : Seek+Write {
+
+    fn buildFirstPage(ba: &mut Read, pbFirstOverflow : &mut PageBuilder, pgsz: usize) -> Result<(usize,bool)> {
+        pbFirstOverflow.Reset();
+        pbFirstOverflow.PutByte(PageType::OVERFLOW_NODE.to_u8());
+        pbFirstOverflow.PutByte(0u8); // starts 0, may be changed later
+        let room = pgsz - (2 + SIZE_32);
+        // something will be put in lastInt32 later
+        let put = try!(pbFirstOverflow.PutStream2(ba, room));
+        Ok((put, put<room))
+    };
 
-        fn buildFirstPage(ba: &mut Read, pbFirstOverflow : &mut PageBuilder, pgsz: usize) -> Result<(usize,bool)> {
-            pbFirstOverflow.Reset();
-            pbFirstOverflow.PutByte(PageType::OVERFLOW_NODE.to_u8());
-            pbFirstOverflow.PutByte(0u8); // starts 0, may be changed later
-            let room = pgsz - (2 + SIZE_32);
-            // something will be put in lastInt32 later
-            let put = try!(pbFirstOverflow.PutStream2(ba, room));
-            Ok((put, put<room))
-        };
+    fn buildRegularPage(ba: &mut Read, pbOverflow : &mut PageBuilder, pgsz: usize) -> Result<(usize,bool)> {
+        pbOverflow.Reset();
+        let room = pgsz;
+        let put = try!(pbOverflow.PutStream2(ba, room));
+        Ok((put, put<room))
+    };
 
-        fn buildRegularPage(ba: &mut Read, pbOverflow : &mut PageBuilder, pgsz: usize) -> Result<(usize,bool)> {
-            pbOverflow.Reset();
-            let room = pgsz;
-            let put = try!(pbOverflow.PutStream2(ba, room));
-            Ok((put, put<room))
-        };
+    fn buildBoundaryPage(ba: &mut Read, pbOverflow : &mut PageBuilder, pgsz: usize) -> Result<(usize,bool)> {
+        pbOverflow.Reset();
+        let room = pgsz - SIZE_32;
+        // something will be put in lastInt32 before the page is written
+        let put = try!(pbOverflow.PutStream2(ba, room));
+        Ok((put, put<room))
+    }
 
-        fn buildBoundaryPage(ba: &mut Read, pbOverflow : &mut PageBuilder, pgsz: usize) -> Result<(usize,bool)> {
-            pbOverflow.Reset();
-            let room = pgsz - SIZE_32;
-            // something will be put in lastInt32 before the page is written
-            let put = try!(pbOverflow.PutStream2(ba, room));
-            Ok((put, put<room))
-        }
-
-        fn writeRegularPages<SeekWrite>(max: PageNum, 
-                                        sofar: usize, 
-                                        pb: &mut PageBuilder, 
-                                        fs: &mut SeekWrite, 
-                                        ba: &mut Read, 
-                                        pgsz: usize
-                                       ) -> Result<(PageNum,usize,bool)> where SeekWrite : Seek+Write {
-            let mut i = 0;
-            let mut sofar = sofar;
-            loop {
-                if i < max {
-                    let (put, finished) = try!(buildRegularPage(ba, pb, pgsz));
-                    if put==0 {
-                        return Ok((i, sofar, true));
+    fn writeRegularPages<SeekWrite>(max: PageNum, 
+                                    sofar: usize, 
+                                    pb: &mut PageBuilder, 
+                                    fs: &mut SeekWrite, 
+                                    ba: &mut Read, 
+                                    pgsz: usize
+                                   ) -> Result<(PageNum,usize,bool)> where SeekWrite : Seek+Write {
+        let mut i = 0;
+        let mut sofar = sofar;
+        loop {
+            if i < max {
+                let (put, finished) = try!(buildRegularPage(ba, pb, pgsz));
+                if put==0 {
+                    return Ok((i, sofar, true));
+                } else {
+                    sofar = sofar + put;
+                    try!(pb.Write(fs));
+                    if finished {
+                        return Ok((i+1, sofar, true));
                     } else {
-                        sofar = sofar + put;
-                        try!(pb.Write(fs));
-                        if finished {
-                            return Ok((i+1, sofar, true));
-                        } else {
-                            i = i + 1;
-                        }
+                        i = i + 1;
                     }
-                } else {
-                    return Ok((i, sofar, false));
                 }
+            } else {
+                return Ok((i, sofar, false));
             }
         }
+    }
 
-        // TODO misnamed
-        fn writeOneBlock<SeekWrite>(param_sofar: usize, 
-                                    param_firstBlk: PageBlock,
-                                    fs: &mut SeekWrite, 
-                                    ba: &mut Read, 
-                                    pgsz: usize,
-                                    pbOverflow: &mut PageBuilder,
-                                    pbFirstOverflow: &mut PageBuilder,
-                                    pageManager: &IPages,
-                                    token: &mut PendingSegment
-                                   ) -> Result<(usize,PageBlock)> where SeekWrite : Seek+Write {
-            // each trip through this loop will write out one
-            // block, starting with the overflow first page,
-            // followed by zero-or-more "regular" overflow pages,
-            // which have no header.  we'll stop at the block boundary,
-            // either because we land there or because the whole overflow
-            // won't fit and we have to continue into the next block.
-            // the boundary page will be like a regular overflow page,
-            // headerless, but it is four bytes smaller.
-            let mut loop_sofar = param_sofar;
-            let mut loop_firstBlk = param_firstBlk;
-            loop {
-                let sofar = loop_sofar;
-                let firstBlk = loop_firstBlk;
-                let (putFirst,finished) = try!(buildFirstPage (ba, pbFirstOverflow, pgsz));
-                if putFirst==0 { 
-                    return Ok((sofar, firstBlk));
+    // TODO misnamed
+    fn writeOneBlock<SeekWrite>(param_sofar: usize, 
+                                param_firstBlk: PageBlock,
+                                fs: &mut SeekWrite, 
+                                ba: &mut Read, 
+                                pgsz: usize,
+                                pbOverflow: &mut PageBuilder,
+                                pbFirstOverflow: &mut PageBuilder,
+                                pageManager: &IPages,
+                                token: &mut PendingSegment
+                               ) -> Result<(usize,PageBlock)> where SeekWrite : Seek+Write {
+        // each trip through this loop will write out one
+        // block, starting with the overflow first page,
+        // followed by zero-or-more "regular" overflow pages,
+        // which have no header.  we'll stop at the block boundary,
+        // either because we land there or because the whole overflow
+        // won't fit and we have to continue into the next block.
+        // the boundary page will be like a regular overflow page,
+        // headerless, but it is four bytes smaller.
+        let mut loop_sofar = param_sofar;
+        let mut loop_firstBlk = param_firstBlk;
+        loop {
+            let sofar = loop_sofar;
+            let firstBlk = loop_firstBlk;
+            let (putFirst,finished) = try!(buildFirstPage (ba, pbFirstOverflow, pgsz));
+            if putFirst==0 { 
+                return Ok((sofar, firstBlk));
+            } else {
+                // note that we haven't written the first page yet.  we may have to fix
+                // a couple of things before it gets written out.
+                let sofar = sofar + putFirst;
+                if firstBlk.firstPage == firstBlk.lastPage {
+                    // the first page landed on a boundary.
+                    // we can just set the flag and write it now.
+                    pbFirstOverflow.SetPageFlag(PageFlag::FLAG_BOUNDARY_NODE);
+                    let blk = try!(pageManager.GetBlobBlock(&mut *token));
+                    pbFirstOverflow.SetLastInt32(blk.firstPage);
+                    try!(pbFirstOverflow.Write(fs));
+                    try!(utils::SeekPage(fs, pgsz, blk.firstPage));
+                    if !finished {
+                        loop_sofar = sofar;
+                        loop_firstBlk = blk;
+                    } else {
+                        return Ok((sofar, blk));
+                    }
                 } else {
-                    // note that we haven't written the first page yet.  we may have to fix
-                    // a couple of things before it gets written out.
-                    let sofar = sofar + putFirst;
-                    if firstBlk.firstPage == firstBlk.lastPage {
-                        // the first page landed on a boundary.
-                        // we can just set the flag and write it now.
-                        pbFirstOverflow.SetPageFlag(PageFlag::FLAG_BOUNDARY_NODE);
-                        let blk = try!(pageManager.GetBlock(&mut *token));
-                        pbFirstOverflow.SetLastInt32(blk.firstPage);
+                    let firstRegularPageNumber = firstBlk.firstPage + 1;
+                    if finished {
+                        // the first page is also the last one
+                        pbFirstOverflow.SetLastInt32(0); 
+                        // offset to last used page in this block, which is this one
                         try!(pbFirstOverflow.Write(fs));
-                        try!(utils::SeekPage(fs, pgsz, blk.firstPage));
-                        if !finished {
-                            loop_sofar = sofar;
-                            loop_firstBlk = blk;
-                        } else {
-                            return Ok((sofar, blk));
-                        }
+                        return Ok((sofar, PageBlock::new(firstRegularPageNumber,firstBlk.lastPage)));
                     } else {
-                        let firstRegularPageNumber = firstBlk.firstPage + 1;
+                        // we need to write more pages,
+                        // until the end of the block,
+                        // or the end of the stream, 
+                        // whichever comes first
+
+                        try!(utils::SeekPage(fs, pgsz, firstRegularPageNumber));
+
+                        // availableBeforeBoundary is the number of pages until the boundary,
+                        // NOT counting the boundary page, and the first page in the block
+                        // has already been accounted for, so we're just talking about data pages.
+                        let availableBeforeBoundary = 
+                            if firstBlk.lastPage > 0 
+                                { (firstBlk.lastPage - firstRegularPageNumber) }
+                            else 
+                                { PageNum::max_value() }
+                            ;
+
+                        let (numRegularPages, sofar, finished) = 
+                            try!(writeRegularPages(availableBeforeBoundary, sofar, pbOverflow, fs, ba, pgsz));
+
                         if finished {
-                            // the first page is also the last one
-                            pbFirstOverflow.SetLastInt32(0); 
-                            // offset to last used page in this block, which is this one
+                            // go back and fix the first page
+                            pbFirstOverflow.SetLastInt32(numRegularPages);
+                            try!(utils::SeekPage(fs, pgsz, firstBlk.firstPage));
                             try!(pbFirstOverflow.Write(fs));
-                            return Ok((sofar, PageBlock::new(firstRegularPageNumber,firstBlk.lastPage)));
+                            // now reset to the next page in the block
+                            let blk = PageBlock::new(firstRegularPageNumber + numRegularPages, firstBlk.lastPage);
+                            try!(utils::SeekPage(fs, pgsz, blk.firstPage));
+                            return Ok((sofar,blk));
                         } else {
-                            // we need to write more pages,
-                            // until the end of the block,
-                            // or the end of the stream, 
-                            // whichever comes first
-
-                            try!(utils::SeekPage(fs, pgsz, firstRegularPageNumber));
-
-                            // availableBeforeBoundary is the number of pages until the boundary,
-                            // NOT counting the boundary page, and the first page in the block
-                            // has already been accounted for, so we're just talking about data pages.
-                            let availableBeforeBoundary = 
-                                if firstBlk.lastPage > 0 
-                                    { (firstBlk.lastPage - firstRegularPageNumber) }
-                                else 
-                                    { PageNum::max_value() }
-                                ;
-
-                            let (numRegularPages, sofar, finished) = 
-                                try!(writeRegularPages(availableBeforeBoundary, sofar, pbOverflow, fs, ba, pgsz));
-
-                            if finished {
+                            // we need to write out a regular page except with a
+                            // boundary pointer in it.  and we need to set
+                            // FLAG_ENDS_ON_BOUNDARY on the first
+                            // overflow page in this block.
+
+                            let (putBoundary,finished) = try!(buildBoundaryPage (ba, pbOverflow, pgsz));
+                            if putBoundary==0 {
                                 // go back and fix the first page
                                 pbFirstOverflow.SetLastInt32(numRegularPages);
                                 try!(utils::SeekPage(fs, pgsz, firstBlk.firstPage));
                                 try!(pbFirstOverflow.Write(fs));
+
                                 // now reset to the next page in the block
                                 let blk = PageBlock::new(firstRegularPageNumber + numRegularPages, firstBlk.lastPage);
-                                try!(utils::SeekPage(fs, pgsz, blk.firstPage));
+                                try!(utils::SeekPage(fs, pgsz, firstBlk.lastPage));
                                 return Ok((sofar,blk));
                             } else {
-                                // we need to write out a regular page except with a
-                                // boundary pointer in it.  and we need to set
-                                // FLAG_ENDS_ON_BOUNDARY on the first
-                                // overflow page in this block.
-
-                                let (putBoundary,finished) = try!(buildBoundaryPage (ba, pbOverflow, pgsz));
-                                if putBoundary==0 {
-                                    // go back and fix the first page
-                                    pbFirstOverflow.SetLastInt32(numRegularPages);
-                                    try!(utils::SeekPage(fs, pgsz, firstBlk.firstPage));
-                                    try!(pbFirstOverflow.Write(fs));
-
-                                    // now reset to the next page in the block
-                                    let blk = PageBlock::new(firstRegularPageNumber + numRegularPages, firstBlk.lastPage);
-                                    try!(utils::SeekPage(fs, pgsz, firstBlk.lastPage));
-                                    return Ok((sofar,blk));
+                                // write the boundary page
+                                let sofar = sofar + putBoundary;
+                                let blk = try!(pageManager.GetBlobBlock(&mut *token));
+                                pbOverflow.SetLastInt32(blk.firstPage);
+                                try!(pbOverflow.Write(fs));
+
+                                // go back and fix the first page
+                                pbFirstOverflow.SetPageFlag(PageFlag::FLAG_ENDS_ON_BOUNDARY);
+                                pbFirstOverflow.SetLastInt32(numRegularPages + 1);
+                                try!(utils::SeekPage(fs, pgsz, firstBlk.firstPage));
+                                try!(pbFirstOverflow.Write(fs));
+
+                                // now reset to the first page in the next block
+                                try!(utils::SeekPage(fs, pgsz, blk.firstPage));
+                                if finished {
+                                    loop_sofar = sofar;
+                                    loop_firstBlk = blk;
                                 } else {
-                                    // write the boundary page
-                                    let sofar = sofar + putBoundary;
-                                    let blk = try!(pageManager.GetBlock(&mut *token));
-                                    pbOverflow.SetLastInt32(blk.firstPage);
-                                    try!(pbOverflow.Write(fs));
-
-                                    // go back and fix the first page
-                                    pbFirstOverflow.SetPageFlag(PageFlag::FLAG_ENDS_ON_BOUNDARY);
-                                    pbFirstOverflow.SetLastInt32(numRegularPages + 1);
-                                    try!(utils::SeekPage(fs, pgsz, firstBlk.firstPage));
-                                    try!(pbFirstOverflow.Write(fs));
-
-                                    // now reset to the first page in the next block
-                                    try!(utils::SeekPage(fs, pgsz, blk.firstPage));
-                                    if finished {
-                                        loop_sofar = sofar;
-                                        loop_firstBlk = blk;
-                                    } else {
-                                        return Ok((sofar,blk));
-                                    }
+                                    return Ok((sofar,blk));
                                 }
                             }
                         }
@@ -1662,591 +2062,723 @@ fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite,
                 }
             }
         }
-
-        let pgsz = pageManager.PageSize();
-        let mut token = try!(pageManager.Begin());
-        let mut pbFirstOverflow = PageBuilder::new(pgsz);
-        let mut pbOverflow = PageBuilder::new(pgsz);
-
-        writeOneBlock(0, startingBlock, fs, ba, pgsz, &mut pbOverflow, &mut pbFirstOverflow, pageManager, &mut token)
     }
 
-    fn writeLeaves<I,SeekWrite>(leavesBlk:PageBlock,
-                                pageManager: &IPages,
-                                source: I,
-                                vbuf: &mut [u8],
-                                fs: &mut SeekWrite, 
-                                pb: &mut PageBuilder,
-                                token: &mut PendingSegment,
-                                ) -> Result<(PageBlock,Vec<pgitem>,PageNum)> where I: Iterator<Item=Result<kvp>> , SeekWrite : Seek+Write {
-        // 2 for the page type and flags
-        // 4 for the prev page
-        // 2 for the stored count
-        // 4 for lastInt32 (which isn't in pb.Available)
-        const LEAF_PAGE_OVERHEAD: usize = 2 + 4 + 2 + 4;
-
-        fn buildLeaf(st: &mut LeafState, pb: &mut PageBuilder) -> Box<[u8]> {
-            pb.Reset();
-            pb.PutByte(PageType::LEAF_NODE.to_u8());
-            pb.PutByte(0u8); // flags
-            pb.PutInt32 (st.prevLeaf); // prev page num.
-            // TODO prefixLen is one byte.  should it be two?
-            pb.PutByte(st.prefixLen as u8);
-            if st.prefixLen > 0 {
-                pb.PutArray(&st.keys_in_this_leaf[0].key[0 .. st.prefixLen]);
-            }
-            let count_keys_in_this_leaf = st.keys_in_this_leaf.len();
-            // TODO should we support more than 64k keys in a leaf?
-            // either way, overflow-check this cast.
-            pb.PutInt16 (count_keys_in_this_leaf as u16);
-
-            fn f(pb: &mut PageBuilder, prefixLen: usize, lp: &LeafPair) {
-                match lp.kLoc {
-                    KeyLocation::Inline => {
-                        pb.PutByte(0u8); // flags
-                        pb.PutVarint(lp.key.len() as u64);
-                        pb.PutArray(&lp.key[prefixLen .. lp.key.len()]);
-                    },
-                    KeyLocation::Overflowed(kpage) => {
-                        pb.PutByte(ValueFlag::FLAG_OVERFLOW);
-                        pb.PutVarint(lp.key.len() as u64);
-                        pb.PutInt32(kpage);
-                    },
-                }
-                match lp.vLoc {
-                    ValueLocation::Tombstone => {
-                        pb.PutByte(ValueFlag::FLAG_TOMBSTONE);
-                    },
-                    ValueLocation::Buffer (ref vbuf) => {
-                        pb.PutByte(0u8);
-                        pb.PutVarint(vbuf.len() as u64);
-                        pb.PutArray(&vbuf);
-                    },
-                    ValueLocation::Overflowed (vlen,vpage) => {
-                        pb.PutByte(ValueFlag::FLAG_OVERFLOW);
-                        pb.PutVarint(vlen as u64);
-                        pb.PutInt32(vpage);
-                    },
-                }
-            }
-
-            // deal with all the keys except the last one
-            for lp in st.keys_in_this_leaf.drain(0 .. count_keys_in_this_leaf-1) {
-                f(pb, st.prefixLen, &lp);
-            }
-            assert!(st.keys_in_this_leaf.len() == 1);
+    let pgsz = pageManager.PageSize();
+    let mut token = try!(pageManager.Begin());
+    let mut pbFirstOverflow = PageBuilder::new(pgsz);
+    let mut pbOverflow = PageBuilder::new(pgsz);
 
-            let lp = st.keys_in_this_leaf.remove(0); 
-            assert!(st.keys_in_this_leaf.is_empty());
+    writeOneBlock(0, startingBlock, fs, ba, pgsz, &mut pbOverflow, &mut pbFirstOverflow, pageManager, &mut token)
+}
 
-            f(pb, st.prefixLen, &lp);
-            lp.key
+// 2 for the page type and flags
+// 4 for the prev page
+// 2 for the stored count
+// 4 for lastInt32 (which isn't in pb.Available)
+const LEAF_PAGE_OVERHEAD: usize = 2 + 4 + 2 + 4;
+
+fn buildLeaf(st: &mut LeafState, pb: &mut PageBuilder) -> Box<[u8]> {
+    pb.Reset();
+    pb.PutByte(PageType::LEAF_NODE.to_u8());
+    pb.PutByte(0u8); // flags
+    pb.PutInt32 (st.prevLeaf); // prev page num.
+    // TODO prefixLen is one byte.  should it be two?
+    pb.PutByte(st.prefixLen as u8);
+    if st.prefixLen > 0 {
+        pb.PutArray(&st.keys_in_this_leaf[0].key[0 .. st.prefixLen]);
+    }
+    let count_keys_in_this_leaf = st.keys_in_this_leaf.len();
+    // TODO should we support more than 64k keys in a leaf?
+    // either way, overflow-check this cast.
+    pb.PutInt16 (count_keys_in_this_leaf as u16);
+
+    fn f(pb: &mut PageBuilder, prefixLen: usize, lp: &LeafPair) {
+        match lp.kLoc {
+            KeyLocation::Inline => {
+                pb.PutByte(0u8); // flags
+                pb.PutVarint(lp.key.len() as u64);
+                pb.PutArray(&lp.key[prefixLen .. lp.key.len()]);
+            },
+            KeyLocation::Overflowed(kpage) => {
+                pb.PutByte(ValueFlag::FLAG_OVERFLOW);
+                pb.PutVarint(lp.key.len() as u64);
+                pb.PutInt32(kpage);
+            },
         }
-
-        fn writeLeaf<SeekWrite>(st: &mut LeafState, 
-                                isRootPage: bool, 
-                                pb: &mut PageBuilder, 
-                                fs: &mut SeekWrite, 
-                                pgsz: usize,
-                                pageManager: &IPages,
-                                token: &mut PendingSegment,
-                               ) -> Result<()> where SeekWrite : Seek+Write { 
-            let last_key = buildLeaf(st, pb);
-            assert!(st.keys_in_this_leaf.is_empty());
-            let thisPageNumber = st.blk.firstPage;
-            let firstLeaf = if st.leaves.is_empty() { thisPageNumber } else { st.firstLeaf };
-            let nextBlk = 
-                if isRootPage {
-                    PageBlock::new(thisPageNumber + 1, st.blk.lastPage)
-                } else if thisPageNumber == st.blk.lastPage {
-                    pb.SetPageFlag(PageFlag::FLAG_BOUNDARY_NODE);
-                    let newBlk = try!(pageManager.GetBlock(&mut *token));
-                    pb.SetLastInt32(newBlk.firstPage);
-                    newBlk
-                } else {
-                    PageBlock::new(thisPageNumber + 1, st.blk.lastPage)
-                };
-            try!(pb.Write(fs));
-            if nextBlk.firstPage != (thisPageNumber+1) {
-                try!(utils::SeekPage(fs, pgsz, nextBlk.firstPage));
-            }
-            let pg = pgitem {page:thisPageNumber, key:last_key};
-            st.leaves.push(pg);
-            st.sofarLeaf = 0;
-            st.prevLeaf = thisPageNumber;
-            st.prefixLen = 0;
-            st.firstLeaf = firstLeaf;
-            st.blk = nextBlk;
-            Ok(())
+        match lp.vLoc {
+            ValueLocation::Tombstone => {
+                pb.PutByte(ValueFlag::FLAG_TOMBSTONE);
+            },
+            ValueLocation::Buffer (ref vbuf) => {
+                pb.PutByte(0u8);
+                pb.PutVarint(vbuf.len() as u64);
+                pb.PutArray(&vbuf);
+            },
+            ValueLocation::Overflowed (vlen,vpage) => {
+                pb.PutByte(ValueFlag::FLAG_OVERFLOW);
+                pb.PutVarint(vlen as u64);
+                pb.PutInt32(vpage);
+            },
         }
+    }
 
-        // TODO can the overflow page number become a varint?
-        const NEEDED_FOR_OVERFLOW_PAGE_NUMBER: usize = 4;
+    // deal with all the keys except the last one
+    for lp in st.keys_in_this_leaf.drain(0 .. count_keys_in_this_leaf-1) {
+        f(pb, st.prefixLen, &lp);
+    }
+    assert!(st.keys_in_this_leaf.len() == 1);
 
-        // the max limit of an inline key is when that key is the only
-        // one in the leaf, and its value is overflowed.
+    let lp = st.keys_in_this_leaf.remove(0); 
+    assert!(st.keys_in_this_leaf.is_empty());
 
-        let pgsz = pageManager.PageSize();
-        let maxKeyInline = 
-            pgsz 
-            - LEAF_PAGE_OVERHEAD 
-            - 1 // prefixLen
-            - 1 // key flags
-            - varint::space_needed_for(pgsz as u64) // approx worst case inline key len
-            - 1 // value flags
-            - 9 // worst case varint value len
-            - NEEDED_FOR_OVERFLOW_PAGE_NUMBER; // overflowed value page
-
-        fn kLocNeed(k: &[u8], kloc: &KeyLocation, prefixLen: usize) -> usize {
-            let klen = k.len();
-            match *kloc {
-                KeyLocation::Inline => {
-                    1 + varint::space_needed_for(klen as u64) + klen - prefixLen
-                },
-                KeyLocation::Overflowed(_) => {
-                    1 + varint::space_needed_for(klen as u64) + NEEDED_FOR_OVERFLOW_PAGE_NUMBER
-                },
-            }
-        }
+    f(pb, st.prefixLen, &lp);
+    lp.key
+}
 
-        fn vLocNeed (vloc: &ValueLocation) -> usize {
-            match *vloc {
-                ValueLocation::Tombstone => {
-                    1
-                },
-                ValueLocation::Buffer(ref vbuf) => {
-                    let vlen = vbuf.len();
-                    1 + varint::space_needed_for(vlen as u64) + vlen
-                },
-                ValueLocation::Overflowed(vlen,_) => {
-                    1 + varint::space_needed_for(vlen as u64) + NEEDED_FOR_OVERFLOW_PAGE_NUMBER
-                },
-            }
-        }
+fn writeLeaf<SeekWrite>(st: &mut LeafState, 
+                        isRootPage: bool, 
+                        pb: &mut PageBuilder, 
+                        fs: &mut SeekWrite, 
+                        pgsz: usize,
+                        pageManager: &IPages,
+                        token: &mut PendingSegment,
+                       ) -> Result<()> where SeekWrite : Seek+Write { 
+    let last_key = buildLeaf(st, pb);
+    assert!(st.keys_in_this_leaf.is_empty());
+    let thisPageNumber = st.blk.firstPage;
+    let firstLeaf = if st.leaves.is_empty() { thisPageNumber } else { st.firstLeaf };
+    let nextBlk = 
+        if isRootPage {
+            PageBlock::new(thisPageNumber + 1, st.blk.lastPage)
+        } else if thisPageNumber == st.blk.lastPage {
+            pb.SetPageFlag(PageFlag::FLAG_BOUNDARY_NODE);
+            let newBlk = try!(pageManager.GetBlock(&mut *token));
+            pb.SetLastInt32(newBlk.firstPage);
+            newBlk
+        } else {
+            PageBlock::new(thisPageNumber + 1, st.blk.lastPage)
+        };
+    try!(pb.Write(fs));
+    if nextBlk.firstPage != (thisPageNumber+1) {
+        try!(utils::SeekPage(fs, pgsz, nextBlk.firstPage));
+    }
+    let pg = pgitem {page:thisPageNumber, key:last_key};
+    st.leaves.push(pg);
+    st.sofarLeaf = 0;
+    st.prevLeaf = thisPageNumber;
+    st.prefixLen = 0;
+    st.firstLeaf = firstLeaf;
+    st.blk = nextBlk;
+    Ok(())
+}
 
-        fn leafPairSize(prefixLen: usize, lp: &LeafPair) -> usize {
-            kLocNeed(&lp.key, &lp.kLoc, prefixLen)
-            +
-            vLocNeed(&lp.vLoc)
-        }
+// TODO can the overflow page number become a varint?
+const NEEDED_FOR_OVERFLOW_PAGE_NUMBER: usize = 4;
 
-        fn defaultPrefixLen(k: &[u8]) -> usize {
-            // TODO max prefix.  relative to page size?  currently must fit in one byte.
-            if k.len() > 255 { 255 } else { k.len() }
-        }
+fn kLocNeed(k: &[u8], kloc: &KeyLocation, prefixLen: usize) -> usize {
+    let klen = k.len();
+    match *kloc {
+        KeyLocation::Inline => {
+            1 + varint::space_needed_for(klen as u64) + klen - prefixLen
+        },
+        KeyLocation::Overflowed(_) => {
+            1 + varint::space_needed_for(klen as u64) + NEEDED_FOR_OVERFLOW_PAGE_NUMBER
+        },
+    }
+}
 
-        // this is the body of writeLeaves
-        let mut st = LeafState {
-            sofarLeaf: 0,
-            firstLeaf: 0,
-            prevLeaf: 0,
-            keys_in_this_leaf:Vec::new(),
-            prefixLen: 0,
-            leaves:Vec::new(),
-            blk:leavesBlk,
-            };
+fn vLocNeed (vloc: &ValueLocation) -> usize {
+    match *vloc {
+        ValueLocation::Tombstone => {
+            1
+        },
+        ValueLocation::Buffer(ref vbuf) => {
+            let vlen = vbuf.len();
+            1 + varint::space_needed_for(vlen as u64) + vlen
+        },
+        ValueLocation::Overflowed(vlen,_) => {
+            1 + varint::space_needed_for(vlen as u64) + NEEDED_FOR_OVERFLOW_PAGE_NUMBER
+        },
+    }
+}
 
-        for result_pair in source {
-            let mut pair = try!(result_pair);
-            let k = pair.Key;
+fn leafPairSize(prefixLen: usize, lp: &LeafPair) -> usize {
+    kLocNeed(&lp.key, &lp.kLoc, prefixLen)
+    +
+    vLocNeed(&lp.vLoc)
+}
 
-            // TODO is it possible for this to conclude that the key must be overflowed
-            // when it would actually fit because of prefixing?
+// adds one pair to the leaves being written, writing out the current
+// leaf first if the pair does not fit in it.
+fn addLeafPair<SeekWrite>(st: &mut LeafState,
+                          mut pair: kvp,
+                          pageManager: &IPages,
+                          vbuf: &mut [u8],
+                          fs: &mut SeekWrite,
+                          pb: &mut PageBuilder,
+                          token: &mut PendingSegment,
+                         ) -> Result<()> where SeekWrite : Seek+Write {
+    // the max limit of an inline key is when that key is the only
+    // one in the leaf, and its value is overflowed.
 
-            let (blkAfterKey,kloc) = 
-                if k.len() <= maxKeyInline {
-                    (st.blk, KeyLocation::Inline)
-                } else {
-                    let vPage = st.blk.firstPage;
-                    let (_,newBlk) = try!(writeOverflow(st.blk, &mut &*k, pageManager, fs));
-                    (newBlk, KeyLocation::Overflowed(vPage))
-                };
+    let pgsz = pageManager.PageSize();
+    let maxKeyInline = 
+        pgsz 
+        - LEAF_PAGE_OVERHEAD 
+        - 1 // prefixLen
+        - 1 // key flags
+        - varint::space_needed_for(pgsz as u64) // approx worst case inline key len
+        - 1 // value flags
+        - 9 // worst case varint value len
+        - NEEDED_FOR_OVERFLOW_PAGE_NUMBER; // overflowed value page
+
+    // every leaf page records its own prefixLen, so a page
+    // written with 0 here reads back the same way as any other.
+    let prefixCompression = pageManager.PrefixCompression();
+    let defaultPrefixLen = |k: &[u8]| -> usize {
+        // TODO max prefix.  relative to page size?  currently must fit in one byte.
+        if !prefixCompression { 0 } else if k.len() > 255 { 255 } else { k.len() }
+    };
 
-            // the max limit of an inline value is when the key is inline
-            // on a new page.
-
-            // TODO this is a usize, so it might cause integer underflow.
-            let availableOnNewPageAfterKey = 
-                pgsz 
-                - LEAF_PAGE_OVERHEAD 
-                - 1 // prefixLen
-                - 1 // key flags
-                - varint::space_needed_for(k.len() as u64)
-                - k.len() 
-                - 1 // value flags
-                ;
-
-            // availableOnNewPageAfterKey needs to accomodate the value and its length as a varint.
-            // it might already be <=0 because of the key length
-
-            let maxValueInline = 
-                if availableOnNewPageAfterKey > 0 {
-                    let neededForVarintLen = varint::space_needed_for(availableOnNewPageAfterKey as u64);
-                    let avail2 = availableOnNewPageAfterKey - neededForVarintLen;
-                    if avail2 > 0 { avail2 } else { 0 }
-                } else {
-                    0
-                };
+    let k = pair.Key;
 
-            let (blkAfterValue, vloc) = 
-                match pair.Value {
-                    Blob::Tombstone => {
-                        (blkAfterKey, ValueLocation::Tombstone)
-                    },
-                    _ => match kloc {
-                         KeyLocation::Inline => {
-                            if maxValueInline == 0 {
-                                match pair.Value {
-                                    Blob::Tombstone => {
-                                        (blkAfterKey, ValueLocation::Tombstone)
-                                    },
-                                    Blob::Stream(ref mut strm) => {
-                                        let valuePage = blkAfterKey.firstPage;
-                                        let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut *strm, pageManager, fs));
-                                        (newBlk, ValueLocation::Overflowed(len,valuePage))
-                                    },
-                                    Blob::Array(a) => {
-                                        if a.is_empty() {
-                                            // TODO maybe we need ValueLocation::Empty
-                                            (blkAfterKey, ValueLocation::Buffer(a))
-                                        } else {
-                                            let valuePage = blkAfterKey.firstPage;
-                                            let strm = a; // TODO need a Read for this
-                                            let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut &*strm, pageManager, fs));
-                                            (newBlk, ValueLocation::Overflowed(len,valuePage))
-                                        }
-                                    },
-                                }
-                            } else {
-                                match pair.Value {
-                                    Blob::Tombstone => {
-                                        (blkAfterKey, ValueLocation::Tombstone)
-                                    },
-                                    Blob::Stream(ref mut strm) => {
-                                        // not sure reusing vbuf is worth it.  maybe we should just
-                                        // alloc here.  ownership will get passed into the
-                                        // ValueLocation when it fits.
-                                        let vread = try!(misc::io::read_fully(&mut *strm, &mut vbuf[0 .. maxValueInline+1]));
-                                        let vbuf = &vbuf[0 .. vread];
-                                        if vread < maxValueInline {
-                                            // TODO this alloc+copy is unfortunate
-                                            let mut va = Vec::with_capacity(vbuf.len());
-                                            for i in 0 .. vbuf.len() {
-                                                va.push(vbuf[i]);
-                                            }
-                                            (blkAfterKey, ValueLocation::Buffer(va.into_boxed_slice()))
-                                        } else {
-                                            let valuePage = blkAfterKey.firstPage;
-                                            let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut (vbuf.chain(strm)), pageManager, fs));
-                                            (newBlk, ValueLocation::Overflowed (len,valuePage))
-                                        }
-                                    },
-                                    Blob::Array(a) => {
-                                        if a.len() < maxValueInline {
-                                            (blkAfterKey, ValueLocation::Buffer(a))
-                                        } else {
-                                            let valuePage = blkAfterKey.firstPage;
-                                            let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut &*a, pageManager, fs));
-                                            (newBlk, ValueLocation::Overflowed(len,valuePage))
-                                        }
-                                    },
-                                }
-                            }
-                         },
+    // zero-length keys are not allowed.  a cursor can still
+    // seek to one, as in SEEK_GE to find the first key.
+    if k.len() == 0 {
+        return Err(Error::Misc("zero-length key"));
+    }
 
-                         KeyLocation::Overflowed(_) => {
-                            match pair.Value {
-                                Blob::Tombstone => {
-                                    (blkAfterKey, ValueLocation::Tombstone)
-                                },
-                                Blob::Stream(ref mut strm) => {
+    // the pairs are written straight into pages, so nothing
+    // else would catch a key that arrives out of order.  the
+    // previous key is either still waiting in this leaf or was
+    // the last key of the leaf just written.
+    {
+        let prev = match st.keys_in_this_leaf.last() {
+            Some(lp) => Some(&lp.key),
+            None => st.leaves.last().map(|pg| &pg.key),
+        };
+        if let Some(prev) = prev {
+            if Ordering::Less != bcmp::Compare(prev, &k) {
+                return Err(Error::Misc("keys must be in ascending order"));
+            }
+        }
+    }
+
+    // TODO is it possible for this to conclude that the key must be overflowed
+    // when it would actually fit because of prefixing?
+
+    let (blkAfterKey,kloc) = 
+        if k.len() <= maxKeyInline {
+            (st.blk, KeyLocation::Inline)
+        } else {
+            let vPage = st.blk.firstPage;
+            let (_,newBlk) = try!(writeOverflow(st.blk, &mut &*k, pageManager, fs));
+            (newBlk, KeyLocation::Overflowed(vPage))
+        };
+
+    // the max limit of an inline value is when the key is inline
+    // on a new page.
+
+    // TODO this is a usize, so it might cause integer underflow.
+    let availableOnNewPageAfterKey = 
+        pgsz 
+        - LEAF_PAGE_OVERHEAD 
+        - 1 // prefixLen
+        - 1 // key flags
+        - varint::space_needed_for(k.len() as u64)
+        - k.len() 
+        - 1 // value flags
+        ;
+
+    // availableOnNewPageAfterKey needs to accomodate the value and its length as a varint.
+    // it might already be <=0 because of the key length
+
+    let maxValueInline = 
+        if availableOnNewPageAfterKey > 0 {
+            let neededForVarintLen = varint::space_needed_for(availableOnNewPageAfterKey as u64);
+            let avail2 = availableOnNewPageAfterKey - neededForVarintLen;
+            if avail2 > 0 { avail2 } else { 0 }
+        } else {
+            0
+        };
+
+    // a value is inline when its length is < maxValueInline, so
+    // the setting, which is inclusive, needs the + 1.
+    let maxValueInline =
+        match pageManager.InlineValueMaxBytes() {
+            Some(n) => std::cmp::min(maxValueInline, n.saturating_add(1)),
+            None => maxValueInline,
+        };
+
+    let (blkAfterValue, vloc) = 
+        match pair.Value {
+            Blob::Tombstone => {
+                (blkAfterKey, ValueLocation::Tombstone)
+            },
+            _ => match kloc {
+                 KeyLocation::Inline => {
+                    if maxValueInline == 0 {
+                        match pair.Value {
+                            Blob::Tombstone => {
+                                (blkAfterKey, ValueLocation::Tombstone)
+                            },
+                            Blob::Stream(ref mut strm) => {
+                                let valuePage = blkAfterKey.firstPage;
+                                let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut *strm, pageManager, fs));
+                                (newBlk, ValueLocation::Overflowed(len,valuePage))
+                            },
+                            Blob::Array(a) => {
+                                if a.is_empty() {
+                                    // TODO maybe we need ValueLocation::Empty
+                                    (blkAfterKey, ValueLocation::Buffer(a))
+                                } else {
                                     let valuePage = blkAfterKey.firstPage;
-                                    let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut *strm, pageManager, fs));
+                                    let strm = a; // TODO need a Read for this
+                                    let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut &*strm, pageManager, fs));
                                     (newBlk, ValueLocation::Overflowed(len,valuePage))
-                                },
-                                Blob::Array(a) => {
-                                    if a.is_empty() {
-                                        // TODO maybe we need ValueLocation::Empty
-                                        (blkAfterKey, ValueLocation::Buffer(a))
-                                    } else {
-                                        let valuePage = blkAfterKey.firstPage;
-                                        let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut &*a, pageManager, fs));
-                                        (newBlk, ValueLocation::Overflowed(len,valuePage))
+                                }
+                            },
+                        }
+                    } else {
+                        match pair.Value {
+                            Blob::Tombstone => {
+                                (blkAfterKey, ValueLocation::Tombstone)
+                            },
+                            Blob::Stream(ref mut strm) => {
+                                // not sure reusing vbuf is worth it.  maybe we should just
+                                // alloc here.  ownership will get passed into the
+                                // ValueLocation when it fits.
+                                let vread = try!(misc::io::read_fully(&mut *strm, &mut vbuf[0 .. maxValueInline+1]));
+                                let vbuf = &vbuf[0 .. vread];
+                                if vread < maxValueInline {
+                                    // TODO this alloc+copy is unfortunate
+                                    let mut va = Vec::with_capacity(vbuf.len());
+                                    for i in 0 .. vbuf.len() {
+                                        va.push(vbuf[i]);
                                     }
+                                    (blkAfterKey, ValueLocation::Buffer(va.into_boxed_slice()))
+                                } else {
+                                    let valuePage = blkAfterKey.firstPage;
+                                    let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut (vbuf.chain(strm)), pageManager, fs));
+                                    (newBlk, ValueLocation::Overflowed (len,valuePage))
+                                }
+                            },
+                            Blob::Array(a) => {
+                                if a.len() < maxValueInline {
+                                    (blkAfterKey, ValueLocation::Buffer(a))
+                                } else {
+                                    let valuePage = blkAfterKey.firstPage;
+                                    let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut &*a, pageManager, fs));
+                                    (newBlk, ValueLocation::Overflowed(len,valuePage))
                                 }
+                            },
+                        }
+                    }
+                 },
+
+                 KeyLocation::Overflowed(_) => {
+                    match pair.Value {
+                        Blob::Tombstone => {
+                            (blkAfterKey, ValueLocation::Tombstone)
+                        },
+                        Blob::Stream(ref mut strm) => {
+                            let valuePage = blkAfterKey.firstPage;
+                            let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut *strm, pageManager, fs));
+                            (newBlk, ValueLocation::Overflowed(len,valuePage))
+                        },
+                        Blob::Array(a) => {
+                            if a.is_empty() {
+                                // TODO maybe we need ValueLocation::Empty
+                                (blkAfterKey, ValueLocation::Buffer(a))
+                            } else {
+                                let valuePage = blkAfterKey.firstPage;
+                                let (len,newBlk) = try!(writeOverflow(blkAfterKey, &mut &*a, pageManager, fs));
+                                (newBlk, ValueLocation::Overflowed(len,valuePage))
                             }
-                         }
+                        }
                     }
-            };
+                 }
+            }
+    };
 
-            // whether/not the key/value are to be overflowed is now already decided.
-            // now all we have to do is decide if this key/value are going into this leaf
-            // or not.  note that it is possible to overflow these and then have them not
-            // fit into the current leaf and end up landing in the next leaf.
+    // whether/not the key/value are to be overflowed is now already decided.
+    // now all we have to do is decide if this key/value are going into this leaf
+    // or not.  note that it is possible to overflow these and then have them not
+    // fit into the current leaf and end up landing in the next leaf.
 
-            st.blk = blkAfterValue;
+    st.blk = blkAfterValue;
 
-            // TODO ignore prefixLen for overflowed keys?
-            let newPrefixLen = 
-                if st.keys_in_this_leaf.is_empty() {
-                    defaultPrefixLen(&k)
-                } else {
-                    bcmp::PrefixMatch(&*st.keys_in_this_leaf[0].key, &k, st.prefixLen)
-                };
-            let sofar = 
-                if newPrefixLen < st.prefixLen {
-                    // the prefixLen would change with the addition of this key,
-                    // so we need to recalc sofar
-                    let sum = st.keys_in_this_leaf.iter().map(|lp| leafPairSize(newPrefixLen, lp)).sum();;
-                    sum
-                } else {
-                    st.sofarLeaf
-                };
-            let fit = {
-                let needed = kLocNeed(&k, &kloc, newPrefixLen) + vLocNeed(&vloc);
-                let used = sofar + LEAF_PAGE_OVERHEAD + 1 + newPrefixLen;
-                if pgsz > used {
-                    let available = pgsz - used;
-                    (available >= needed)
-                } else {
-                    false
-                }
-            };
-            let writeThisPage = (! st.keys_in_this_leaf.is_empty()) && (! fit);
+    // TODO ignore prefixLen for overflowed keys?
+    let newPrefixLen = 
+        if st.keys_in_this_leaf.is_empty() {
+            defaultPrefixLen(&k)
+        } else {
+            bcmp::PrefixMatch(&*st.keys_in_this_leaf[0].key, &k, st.prefixLen)
+        };
+    let sofar = 
+        if newPrefixLen < st.prefixLen {
+            // the prefixLen would change with the addition of this key,
+            // so we need to recalc sofar
+            let sum = st.keys_in_this_leaf.iter().map(|lp| leafPairSize(newPrefixLen, lp)).sum();;
+            sum
+        } else {
+            st.sofarLeaf
+        };
+    let fit = {
+        let needed = kLocNeed(&k, &kloc, newPrefixLen) + vLocNeed(&vloc);
+        let used = sofar + LEAF_PAGE_OVERHEAD + 1 + newPrefixLen;
+        if pgsz > used {
+            let available = pgsz - used;
+            (available >= needed)
+        } else {
+            false
+        }
+    };
+    let writeThisPage = (! st.keys_in_this_leaf.is_empty()) && (! fit);
 
-            if writeThisPage {
-                try!(writeLeaf(&mut st, false, pb, fs, pgsz, pageManager, &mut *token));
-            }
+    if writeThisPage {
+        try!(writeLeaf(st, false, pb, fs, pgsz, pageManager, &mut *token));
+    }
 
-            // TODO ignore prefixLen for overflowed keys?
-            let newPrefixLen = 
-                if st.keys_in_this_leaf.is_empty() {
-                    defaultPrefixLen(&k)
-                } else {
-                    bcmp::PrefixMatch(&*st.keys_in_this_leaf[0].key, &k, st.prefixLen)
-                };
-            let sofar = 
-                if newPrefixLen < st.prefixLen {
-                    // the prefixLen will change with the addition of this key,
-                    // so we need to recalc sofar
-                    let sum = st.keys_in_this_leaf.iter().map(|lp| leafPairSize(newPrefixLen, lp)).sum();;
-                    sum
-                } else {
-                    st.sofarLeaf
+    // TODO ignore prefixLen for overflowed keys?
+    let newPrefixLen = 
+        if st.keys_in_this_leaf.is_empty() {
+            defaultPrefixLen(&k)
+        } else {
+            bcmp::PrefixMatch(&*st.keys_in_this_leaf[0].key, &k, st.prefixLen)
+        };
+    let sofar = 
+        if newPrefixLen < st.prefixLen {
+            // the prefixLen will change with the addition of this key,
+            // so we need to recalc sofar
+            let sum = st.keys_in_this_leaf.iter().map(|lp| leafPairSize(newPrefixLen, lp)).sum();;
+            sum
+        } else {
+            st.sofarLeaf
+        };
+    // note that the LeafPair struct gets ownership of the key provided
+    // from above.
+    let lp = LeafPair {
+                key:k,
+                kLoc:kloc,
+                vLoc:vloc,
                 };
-            // note that the LeafPair struct gets ownership of the key provided
-            // from above.
-            let lp = LeafPair {
-                        key:k,
-                        kLoc:kloc,
-                        vLoc:vloc,
-                        };
 
-            st.sofarLeaf=sofar + leafPairSize(newPrefixLen, &lp);
-            st.keys_in_this_leaf.push(lp);
-            st.prefixLen=newPrefixLen;
-        }
+    st.sofarLeaf=sofar + leafPairSize(newPrefixLen, &lp);
+    st.keys_in_this_leaf.push(lp);
+    st.prefixLen=newPrefixLen;
+    Ok(())
+}
 
-        if !st.keys_in_this_leaf.is_empty() {
-            let isRootNode = st.leaves.is_empty();
-            try!(writeLeaf(&mut st, isRootNode, pb, fs, pgsz, pageManager, &mut *token));
-        }
-        Ok((st.blk,st.leaves,st.firstLeaf))
-    }
-
-    fn writeParentNodes<SeekWrite>(startingBlk: PageBlock, 
-                                   children: &mut Vec<pgitem>,
-                                   pgsz: usize,
-                                   fs: &mut SeekWrite,
-                                   pageManager: &IPages,
-                                   token: &mut PendingSegment,
-                                   lastLeaf: PageNum,
-                                   firstLeaf: PageNum,
-                                   pb: &mut PageBuilder,
-                                  ) -> Result<(PageBlock, Vec<pgitem>)> where SeekWrite : Seek+Write {
-        // 2 for the page type and flags
-        // 2 for the stored count
-        // 5 for the extra ptr we will add at the end, a varint, 5 is worst case (page num < 4294967295L)
-        // 4 for lastInt32
-        const PARENT_PAGE_OVERHEAD: usize = 2 + 2 + 5 + 4;
-
-        fn calcAvailable(currentSize: usize, couldBeRoot: bool, pgsz: usize) -> usize {
-            let basicSize = pgsz - currentSize;
-            let allowanceForRootNode = if couldBeRoot { SIZE_32 } else { 0 }; // first/last Leaf, lastInt32 already
-            // TODO can this cause integer overflow?
-            basicSize - allowanceForRootNode
-        }
-
-        fn buildParentPage(items: &mut Vec<pgitem>,
-                           lastPtr: PageNum, 
-                           overflows: &HashMap<usize,PageNum>,
-                           pb : &mut PageBuilder,
-                          ) {
-            pb.Reset();
-            pb.PutByte(PageType::PARENT_NODE.to_u8());
-            pb.PutByte(0u8);
-            pb.PutInt16(items.len() as u16);
-            // store all the ptrs, n+1 of them
-            for x in items.iter() {
-                pb.PutVarint(x.page as u64);
-            }
-            pb.PutVarint(lastPtr as u64);
-            // store all the keys, n of them
-            for (i,x) in items.drain(..).enumerate() {
-                match overflows.get(&i) {
-                    Some(pg) => {
-                        pb.PutByte(ValueFlag::FLAG_OVERFLOW);
-                        pb.PutVarint(x.key.len() as u64);
-                        pb.PutInt32(*pg as PageNum);
-                    },
-                    None => {
-                        pb.PutByte(0u8);
-                        pb.PutVarint(x.key.len() as u64);
-                        pb.PutArray(&x.key);
-                    },
-                }
+fn writeParentNodes<SeekWrite>(startingBlk: PageBlock, 
+                               children: &mut Vec<pgitem>,
+                               pgsz: usize,
+                               fs: &mut SeekWrite,
+                               pageManager: &IPages,
+                               token: &mut PendingSegment,
+                               lastLeaf: PageNum,
+                               firstLeaf: PageNum,
+                               pb: &mut PageBuilder,
+                              ) -> Result<(PageBlock, Vec<pgitem>)> where SeekWrite : Seek+Write {
+    // 2 for the page type and flags
+    // 2 for the stored count
+    // 5 for the extra ptr we will add at the end, a varint, 5 is worst case (page num < 4294967295L)
+    // 4 for lastInt32
+    const PARENT_PAGE_OVERHEAD: usize = 2 + 2 + 5 + 4;
+
+    fn calcAvailable(currentSize: usize, couldBeRoot: bool, pgsz: usize) -> usize {
+        let basicSize = pgsz - currentSize;
+        let allowanceForRootNode = if couldBeRoot { SIZE_32 } else { 0 }; // first/last Leaf, lastInt32 already
+        // TODO can this cause integer overflow?
+        basicSize - allowanceForRootNode
+    }
+
+    fn buildParentPage(items: &mut Vec<pgitem>,
+                       lastPtr: PageNum, 
+                       overflows: &HashMap<usize,PageNum>,
+                       pb : &mut PageBuilder,
+                      ) {
+        pb.Reset();
+        pb.PutByte(PageType::PARENT_NODE.to_u8());
+        pb.PutByte(0u8);
+        pb.PutInt16(items.len() as u16);
+        // store all the ptrs, n+1 of them
+        for x in items.iter() {
+            pb.PutVarint(x.page as u64);
+        }
+        pb.PutVarint(lastPtr as u64);
+        // store all the keys, n of them
+        for (i,x) in items.drain(..).enumerate() {
+            match overflows.get(&i) {
+                Some(pg) => {
+                    pb.PutByte(ValueFlag::FLAG_OVERFLOW);
+                    pb.PutVarint(x.key.len() as u64);
+                    pb.PutInt32(*pg as PageNum);
+                },
+                None => {
+                    pb.PutByte(0u8);
+                    pb.PutVarint(x.key.len() as u64);
+                    pb.PutArray(&x.key);
+                },
             }
         }
+    }
 
-        fn writeParentPage<SeekWrite>(st: &mut ParentState, 
-                                      items: &mut Vec<pgitem>,
-                                      overflows: &HashMap<usize,PageNum>,
-                                      pgnum: PageNum,
-                                      key: Box<[u8]>,
-                                      isRootNode: bool, 
-                                      pb: &mut PageBuilder, 
-                                      lastLeaf: PageNum,
-                                      fs: &mut SeekWrite,
-                                      pageManager: &IPages,
-                                      pgsz: usize,
-                                      token: &mut PendingSegment,
-                                      firstLeaf: PageNum,
-                                     ) -> Result<()> where SeekWrite : Seek+Write {
-            // assert st.sofar > 0
-            let thisPageNumber = st.blk.firstPage;
-            buildParentPage(items, pgnum, &overflows, pb);
-            let nextBlk =
-                if isRootNode {
-                    pb.SetPageFlag(PageFlag::FLAG_ROOT_NODE);
-                    pb.SetSecondToLastInt32(firstLeaf);
-                    pb.SetLastInt32(lastLeaf);
-                    PageBlock::new(thisPageNumber+1,st.blk.lastPage)
+    fn writeParentPage<SeekWrite>(st: &mut ParentState, 
+                                  items: &mut Vec<pgitem>,
+                                  overflows: &HashMap<usize,PageNum>,
+                                  pgnum: PageNum,
+                                  key: Box<[u8]>,
+                                  isRootNode: bool, 
+                                  pb: &mut PageBuilder, 
+                                  lastLeaf: PageNum,
+                                  fs: &mut SeekWrite,
+                                  pageManager: &IPages,
+                                  pgsz: usize,
+                                  token: &mut PendingSegment,
+                                  firstLeaf: PageNum,
+                                 ) -> Result<()> where SeekWrite : Seek+Write {
+        // assert st.sofar > 0
+        let thisPageNumber = st.blk.firstPage;
+        buildParentPage(items, pgnum, &overflows, pb);
+        let nextBlk =
+            if isRootNode {
+                pb.SetPageFlag(PageFlag::FLAG_ROOT_NODE);
+                pb.SetSecondToLastInt32(firstLeaf);
+                pb.SetLastInt32(lastLeaf);
+                PageBlock::new(thisPageNumber+1,st.blk.lastPage)
+            } else {
+                if st.blk.firstPage == st.blk.lastPage {
+                    pb.SetPageFlag(PageFlag::FLAG_BOUNDARY_NODE);
+                    let newBlk = try!(pageManager.GetBlock(&mut *token));
+                    pb.SetLastInt32(newBlk.firstPage);
+                    newBlk
                 } else {
-                    if st.blk.firstPage == st.blk.lastPage {
-                        pb.SetPageFlag(PageFlag::FLAG_BOUNDARY_NODE);
-                        let newBlk = try!(pageManager.GetBlock(&mut *token));
-                        pb.SetLastInt32(newBlk.firstPage);
-                        newBlk
-                    } else {
-                        PageBlock::new(thisPageNumber+1,st.blk.lastPage)
-                    }
-                };
-            try!(pb.Write(fs));
-            if nextBlk.firstPage != (thisPageNumber+1) {
-                try!(utils::SeekPage(fs, pgsz, nextBlk.firstPage));
-            }
-            st.sofar = 0;
-            st.blk = nextBlk;
-            let pg = pgitem {page:thisPageNumber, key:key};
-            st.nextGeneration.push(pg);
-            Ok(())
+                    PageBlock::new(thisPageNumber+1,st.blk.lastPage)
+                }
+            };
+        try!(pb.Write(fs));
+        if nextBlk.firstPage != (thisPageNumber+1) {
+            try!(utils::SeekPage(fs, pgsz, nextBlk.firstPage));
         }
+        st.sofar = 0;
+        st.blk = nextBlk;
+        let pg = pgitem {page:thisPageNumber, key:key};
+        st.nextGeneration.push(pg);
+        Ok(())
+    }
 
-        // this is the body of writeParentNodes
-        let mut st = ParentState {nextGeneration:Vec::new(),sofar: 0,blk:startingBlk,};
-        let mut items = Vec::new();
-        let mut overflows = HashMap::new();
-        let count_children = children.len();
-        // deal with all the children except the last one
-        for pair in children.drain(0 .. count_children-1) {
-            let pgnum = pair.page;
-
-            let neededEitherWay = 1 + varint::space_needed_for(pair.key.len() as u64) + varint::space_needed_for(pgnum as u64);
-            let neededForInline = neededEitherWay + pair.key.len();
-            let neededForOverflow = neededEitherWay + SIZE_32;
-            let couldBeRoot = st.nextGeneration.is_empty();
-
-            let available = calcAvailable(st.sofar, couldBeRoot, pgsz);
-            let fitsInline = available >= neededForInline;
-            let wouldFitInlineOnNextPage = (pgsz - PARENT_PAGE_OVERHEAD) >= neededForInline;
-            let fitsOverflow = available >= neededForOverflow;
-            let writeThisPage = (! fitsInline) && (wouldFitInlineOnNextPage || (! fitsOverflow));
-
-            if writeThisPage {
-                // assert sofar > 0
-                // we need to make a copy of this key because writeParentPage needs to own one,
-                // but we still need to put this pair in the items (below).
-                let mut copy_key = vec![0; pair.key.len()].into_boxed_slice(); 
-                copy_key.clone_from_slice(&pair.key);
-                try!(writeParentPage(&mut st, &mut items, &overflows, pair.page, copy_key, false, pb, lastLeaf, fs, pageManager, pgsz, &mut *token, firstLeaf));
-                assert!(items.is_empty());
-            }
+    // this is the body of writeParentNodes
+    let mut st = ParentState {nextGeneration:Vec::new(),sofar: 0,blk:startingBlk,};
+    let mut items = Vec::new();
+    let mut overflows = HashMap::new();
+    let count_children = children.len();
+    // deal with all the children except the last one
+    for pair in children.drain(0 .. count_children-1) {
+        let pgnum = pair.page;
 
-            if st.sofar == 0 {
-                st.sofar = PARENT_PAGE_OVERHEAD;
-                assert!(items.is_empty());
-            }
+        let neededEitherWay = 1 + varint::space_needed_for(pair.key.len() as u64) + varint::space_needed_for(pgnum as u64);
+        let neededForInline = neededEitherWay + pair.key.len();
+        let neededForOverflow = neededEitherWay + SIZE_32;
+        let couldBeRoot = st.nextGeneration.is_empty();
 
-            if calcAvailable(st.sofar, st.nextGeneration.is_empty(), pgsz) >= neededForInline {
-                st.sofar = st.sofar + neededForInline;
-            } else {
-                let keyOverflowFirstPage = st.blk.firstPage;
-                let (_,newBlk) = try!(writeOverflow(st.blk, &mut &*pair.key, pageManager, fs));
-                st.sofar = st.sofar + neededForOverflow;
-                st.blk = newBlk;
-                // items.len() is the index that this pair is about to get, just below
-                overflows.insert(items.len(),keyOverflowFirstPage);
-            }
-            items.push(pair);
+        let available = calcAvailable(st.sofar, couldBeRoot, pgsz);
+        let fitsInline = available >= neededForInline;
+        let wouldFitInlineOnNextPage = (pgsz - PARENT_PAGE_OVERHEAD) >= neededForInline;
+        let fitsOverflow = available >= neededForOverflow;
+        let writeThisPage = (! fitsInline) && (wouldFitInlineOnNextPage || (! fitsOverflow));
+
+        if writeThisPage {
+            // assert sofar > 0
+            // we need to make a copy of this key because writeParentPage needs to own one,
+            // but we still need to put this pair in the items (below).
+            let mut copy_key = vec![0; pair.key.len()].into_boxed_slice(); 
+            copy_key.clone_from_slice(&pair.key);
+            try!(writeParentPage(&mut st, &mut items, &overflows, pair.page, copy_key, false, pb, lastLeaf, fs, pageManager, pgsz, &mut *token, firstLeaf));
+            assert!(items.is_empty());
         }
-        assert!(children.len() == 1);
-        let isRootNode = st.nextGeneration.is_empty();
-        let pgitem {page: pgnum, key: key} = children.remove(0);
-        assert!(children.is_empty());
 
-        try!(writeParentPage(&mut st, &mut items, &overflows, pgnum, key, isRootNode, pb, lastLeaf, fs, pageManager, pgsz, &mut *token, firstLeaf));
-        Ok((st.blk,st.nextGeneration))
-    }
+        if st.sofar == 0 {
+            st.sofar = PARENT_PAGE_OVERHEAD;
+            assert!(items.is_empty());
+        }
 
-    // this is the body of Create
-    let pgsz = pageManager.PageSize();
-    let mut pb = PageBuilder::new(pgsz);
-    let mut token = try!(pageManager.Begin());
-    let startingBlk = try!(pageManager.GetBlock(&mut token));
-    try!(utils::SeekPage(fs, pgsz, startingBlk.firstPage));
+        if calcAvailable(st.sofar, st.nextGeneration.is_empty(), pgsz) >= neededForInline {
+            st.sofar = st.sofar + neededForInline;
+        } else {
+            let keyOverflowFirstPage = st.blk.firstPage;
+            let (_,newBlk) = try!(writeOverflow(st.blk, &mut &*pair.key, pageManager, fs));
+            st.sofar = st.sofar + neededForOverflow;
+            st.blk = newBlk;
+            // items.len() is the index that this pair is about to get, just below
+            overflows.insert(items.len(),keyOverflowFirstPage);
+        }
+        items.push(pair);
+    }
+    assert!(children.len() == 1);
+    let isRootNode = st.nextGeneration.is_empty();
+    let pgitem {page: pgnum, key: key} = children.remove(0);
+    assert!(children.is_empty());
+
+    try!(writeParentPage(&mut st, &mut items, &overflows, pgnum, key, isRootNode, pb, lastLeaf, fs, pageManager, pgsz, &mut *token, firstLeaf));
+    Ok((st.blk,st.nextGeneration))
+}
 
+// writes a segment from pairs given one at a time, in ascending key
+// order.  each leaf is written as soon as it is full, so memory use
+// does not grow with the number of pairs.  the parent nodes are
+// written by finish(), from the list of leaves.
+//
+// a sorted source may repeat a key, as when it is a log of changes.
+// each run of equal keys collapses to its last pair, so the newest
+// value wins.
+struct SegmentBuilder {
+    st: LeafState,
+    pb: PageBuilder,
     // TODO this is a buffer just for the purpose of being reused
     // in cases where the blob is provided as a stream, and we need
     // read a bit of it to figure out if it might fit inline rather
     // than overflow.
-    let mut vbuf = vec![0;pgsz].into_boxed_slice(); 
-    let (blkAfterLeaves, leaves, firstLeaf) = try!(writeLeaves(startingBlk, pageManager, source, &mut vbuf, fs, &mut pb, &mut token));
-
-    // all the leaves are written.
-    // now write the parent pages.
-    // maybe more than one level of them.
-    // keep writing until we have written a level which has only one node,
-    // which is the root node.
+    vbuf: Box<[u8]>,
+    token: PendingSegment,
+    // the last pair given, held back until a different key shows up
+    pending: Option<kvp>,
+}
 
-    let lastLeaf = leaves[leaves.len()-1].page;
+impl SegmentBuilder {
+    fn new<SeekWrite>(fs: &mut SeekWrite, pageManager: &IPages) -> Result<SegmentBuilder> where SeekWrite : Seek+Write {
+        let pgsz = pageManager.PageSize();
+        let mut token = try!(pageManager.Begin());
+        let startingBlk = try!(pageManager.GetBlock(&mut token));
+        if let Err(e) = utils::SeekPage(fs, pgsz, startingBlk.firstPage) {
+            try!(pageManager.Abandon(token));
+            return Err(e);
+        }
+        let st = LeafState {
+            sofarLeaf: 0,
+            firstLeaf: 0,
+            prevLeaf: 0,
+            keys_in_this_leaf:Vec::new(),
+            prefixLen: 0,
+            leaves:Vec::new(),
+            blk:startingBlk,
+            };
+        let b = SegmentBuilder {
+            st: st,
+            pb: PageBuilder::new(pgsz),
+            vbuf: vec![0;pgsz].into_boxed_slice(),
+            token: token,
+            pending: None,
+        };
+        Ok(b)
+    }
 
-    let rootPage = {
-        let mut blk = blkAfterLeaves;
-        let mut children = leaves;
-        loop {
-            let (newBlk, newChildren) = try!(writeParentNodes(blk, &mut children, pgsz, fs, pageManager, &mut token, lastLeaf, firstLeaf, &mut pb));
-            assert!(children.is_empty());
-            blk = newBlk;
-            children = newChildren;
-            if children.len()==1 {
-                break;
+    fn add<SeekWrite>(&mut self, fs: &mut SeekWrite, pageManager: &IPages, pair: kvp) -> Result<()> where SeekWrite : Seek+Write {
+        if let Some(prev) = self.pending.take() {
+            match bcmp::Compare(&prev.Key, &pair.Key) {
+                Ordering::Less => {
+                    try!(addLeafPair(&mut self.st, prev, pageManager, &mut self.vbuf, fs, &mut self.pb, &mut self.token));
+                },
+                Ordering::Equal => {
+                    // the same key again, so prev is dropped
+                },
+                Ordering::Greater => {
+                    return Err(Error::Misc("keys must be in ascending order"));
+                },
             }
         }
-        children[0].page
-    };
+        self.pending = Some(pair);
+        Ok(())
+    }
+
+    // gives every block taken so far back to the page manager.  for
+    // when the segment cannot be finished.
+    fn abandon(self, pageManager: &IPages) -> Result<()> {
+        pageManager.Abandon(self.token)
+    }
+
+    fn finish<SeekWrite>(self, fs: &mut SeekWrite, pageManager: &IPages) -> Result<(SegmentNum,PageNum)> where SeekWrite : Seek+Write {
+        let SegmentBuilder { mut st, mut pb, mut token, mut vbuf, pending } = self;
+        let r =
+            match pending {
+                Some(pair) => addLeafPair(&mut st, pair, pageManager, &mut vbuf, fs, &mut pb, &mut token),
+                None => Ok(()),
+            };
+        let r =
+            match r {
+                Ok(()) => Self::writeRest(&mut st, &mut pb, &mut token, fs, pageManager),
+                Err(e) => Err(e),
+            };
+        match r {
+            Ok(rootPage) => {
+                let g = try!(pageManager.End(token, rootPage));
+                Ok((g,rootPage))
+            },
+            Err(e) => {
+                try!(pageManager.Abandon(token));
+                Err(e)
+            },
+        }
+    }
+
+    // the last leaf, and then the parent pages.  returns the root page.
+    fn writeRest<SeekWrite>(st: &mut LeafState, pb: &mut PageBuilder, token: &mut PendingSegment, fs: &mut SeekWrite, pageManager: &IPages) -> Result<PageNum> where SeekWrite : Seek+Write {
+        let pgsz = pageManager.PageSize();
+        if !st.keys_in_this_leaf.is_empty() {
+            let isRootNode = st.leaves.is_empty();
+            try!(writeLeaf(st, isRootNode, pb, fs, pgsz, pageManager, &mut *token));
+        }
+        if st.leaves.is_empty() {
+            return Err(Error::Misc("a segment needs at least one key"));
+        }
+        let blkAfterLeaves = st.blk;
+        let leaves = std::mem::replace(&mut st.leaves, Vec::new());
+        let firstLeaf = st.firstLeaf;
+
+        // all the leaves are written.
+        // now write the parent pages.
+        // maybe more than one level of them.
+        // keep writing until we have written a level which has only one node,
+        // which is the root node.
 
-    let g = try!(pageManager.End(token, rootPage));
-    Ok((g,rootPage))
+        let lastLeaf = leaves[leaves.len()-1].page;
+
+        let rootPage = {
+            let mut blk = blkAfterLeaves;
+            let mut children = leaves;
+            loop {
+                let (newBlk, newChildren) = try!(writeParentNodes(blk, &mut children, pgsz, fs, pageManager, &mut *token, lastLeaf, firstLeaf, pb));
+                assert!(children.is_empty());
+                blk = newBlk;
+                children = newChildren;
+                if children.len()==1 {
+                    break;
+                }
+            }
+            children[0].page
+        };
+        Ok(rootPage)
+    }
+}
+
+fn CreateFromSortedSequenceOfKeyValuePairs<I,SeekWrite>(fs: &mut SeekWrite, 
+                                                            pageManager: &IPages, 
+                                                            source: I,
+                                                           ) -> Result<(SegmentNum,PageNum)> where I:Iterator<Item=Result<kvp>>, SeekWrite : Seek+Write {
+    let mut b = try!(SegmentBuilder::new(fs, pageManager));
+    for result_pair in source {
+        let r =
+            match result_pair {
+                Ok(pair) => b.add(fs, pageManager, pair),
+                Err(e) => Err(e),
+            };
+        if let Err(e) = r {
+            try!(b.abandon(pageManager));
+            return Err(e);
+        }
+    }
+    b.finish(fs, pageManager)
 }
 
 struct myOverflowReadStream {
@@ -2495,6 +3027,11 @@ struct SegmentCursor<'a> {
     previousLeaf: PageNum,
     currentKey: Option<usize>,
     prefix: Option<Box<[u8]>>,
+    // when the leaf has a prefix, its inline keys are copied here
+    // whole, so that KeyRef can borrow each one as a single slice.
+    // prefixedKeyPos has where each key starts.
+    prefixedKeys: Vec<u8>,
+    prefixedKeyPos: Vec<usize>,
     firstLeaf: PageNum,
     lastLeaf: PageNum,
 }
@@ -2536,6 +3073,8 @@ impl<'a> SegmentCursor<'a> {
             previousLeaf: 0,
             currentKey: None,
             prefix: None,
+            prefixedKeys: Vec::new(),
+            prefixedKeyPos: Vec::new(),
             firstLeaf: 0, // temporary
             lastLeaf: 0, // temporary
         };
@@ -2566,6 +3105,8 @@ impl<'a> SegmentCursor<'a> {
         self.previousLeaf = 0;
         self.currentKey = None;
         self.prefix = None;
+        self.prefixedKeys.clear();
+        self.prefixedKeyPos.clear();
     }
 
     fn setCurrentPage(&mut self, pgnum: PageNum) -> Result<bool> {
@@ -2587,8 +3128,20 @@ impl<'a> SegmentCursor<'a> {
             // TODO is this the right place for this check?    
             let pos = (self.currentPage - 1) as u64 * self.pr.PageSize() as u64;
             if pos + self.pr.PageSize() as u64 <= self.len {
-                try!(utils::SeekPage(&mut self.fs, self.pr.PageSize(), self.currentPage));
-                try!(self.pr.Read(&mut self.fs));
+                match self.inner.cache {
+                    Some((ref cache, id)) => {
+                        let key = (id, self.segnum, self.currentPage);
+                        if !try!(cache.get(&key, &mut self.pr.buf)) {
+                            try!(utils::SeekPage(&mut self.fs, self.pr.PageSize(), self.currentPage));
+                            try!(self.pr.Read(&mut self.fs));
+                            try!(cache.put(key, &self.pr.buf));
+                        }
+                    },
+                    None => {
+                        try!(utils::SeekPage(&mut self.fs, self.pr.PageSize(), self.currentPage));
+                        try!(self.pr.Read(&mut self.fs));
+                    },
+                }
                 Ok(true)
             } else {
                 Err(Error::InvalidPageNumber)
@@ -2685,6 +3238,16 @@ impl<'a> SegmentCursor<'a> {
         }
         for i in 0 .. countLeafKeys {
             self.leafKeys[i] = cur;
+            if let Some(ref a) = self.prefix {
+                let mut kcur = cur;
+                let kflag = self.pr.GetByte(&mut kcur);
+                let klen = self.pr.GetVarint(&mut kcur) as usize;
+                self.prefixedKeyPos.push(self.prefixedKeys.len());
+                if 0 == (kflag & ValueFlag::FLAG_OVERFLOW) {
+                    self.prefixedKeys.push_all(a);
+                    self.prefixedKeys.push_all(self.pr.get_slice(kcur, klen - a.len()));
+                }
+            }
             self.skipKey(&mut cur);
             self.skipValue(&mut cur);
         }
@@ -2697,8 +3260,9 @@ impl<'a> SegmentCursor<'a> {
         let klen = self.pr.GetVarint(&mut cur) as usize;
         if 0 == (kflag & ValueFlag::FLAG_OVERFLOW) {
             match self.prefix {
-                Some(ref a) => {
-                    Ok(KeyRef::Prefixed(&a, self.pr.get_slice(cur, klen - a.len())))
+                Some(_) => {
+                    let pos = self.prefixedKeyPos[n];
+                    Ok(KeyRef::Array(&self.prefixedKeys[pos .. pos + klen]))
                 },
                 None => {
                     Ok(KeyRef::Array(self.pr.get_slice(cur, klen)))
@@ -3170,6 +3734,13 @@ struct HeaderData {
 
 const HEADER_SIZE_IN_BYTES: usize = 4096;
 
+// the header starts with the page size as an int32.  no page size comes
+// anywhere near 2^24, so the top byte of that int32 is the format
+// version, which is 0 in files written before there was one.
+// version 0: a segment info is root, age, blocks
+// version 1: a segment info is root, age, commit, blocks
+const HEADER_FORMAT_VERSION: u32 = 1;
+
 impl PendingSegment {
     fn new(num: SegmentNum) -> PendingSegment {
         // TODO maybe set capacity of the blocklist vec to something low
@@ -3222,7 +3793,7 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
     }
 
     fn parse<R>(pr: &PageBuffer, cur: &mut usize, fs: &mut R) -> Result<(HeaderData, usize)> where R : Read+Seek {
-        fn readSegmentList(pr: &PageBuffer, cur: &mut usize) -> Result<(Vec<SegmentNum>,HashMap<SegmentNum,SegmentInfo>)> {
+        fn readSegmentList(pr: &PageBuffer, cur: &mut usize, version: u32) -> Result<(Vec<SegmentNum>,HashMap<SegmentNum,SegmentInfo>)> {
             fn readBlockList(prBlocks: &PageBuffer, cur: &mut usize) -> Vec<PageBlock> {
                 let count = prBlocks.GetVarint(cur) as usize;
                 let mut a = Vec::with_capacity(count);
@@ -3245,11 +3816,14 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
                 a.push(g);
                 let root = pr.GetVarint(cur) as PageNum;
                 let age = pr.GetVarint(cur) as u32;
+                // before commits were recorded, everything counts as
+                // committed at the very start
+                let commit = if version >= 1 { pr.GetVarint(cur) } else { 0 };
                 let blocks = readBlockList(pr, cur);
                 if !block_list_contains_page(&blocks, root) {
                     return Err(Error::RootPageNotInSegmentBlockList);
                 }
-                let info = SegmentInfo {root:root,age:age,blocks:blocks};
+                let info = SegmentInfo {root:root,age:age,commit:commit,blocks:blocks};
                 m.insert(g,info);
             }
             Ok((a,m))
@@ -3257,7 +3831,12 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
 
         // --------
 
-        let pgsz = pr.GetInt32(cur) as usize;
+        let versionAndPageSize = pr.GetInt32(cur);
+        let version = versionAndPageSize >> 24;
+        if version > HEADER_FORMAT_VERSION {
+            return Err(Error::CorruptFile("header format version is newer than this code"));
+        }
+        let pgsz = (versionAndPageSize & 0x00ffffff) as usize;
         let changeCounter = pr.GetVarint(cur);
         let mergeCounter = pr.GetVarint(cur);
         let lenSegmentList = pr.GetVarint(cur) as usize;
@@ -3279,10 +3858,10 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
                 try!(utils::SeekPage(fs, pgsz, firstPageChunk2));
                 try!(pr2.ReadPart(fs, lenChunk1, lenChunk2));
                 let mut cur2 = 0;
-                let (state, segments) = try!(readSegmentList(&pr2, &mut cur2));
+                let (state, segments) = try!(readSegmentList(&pr2, &mut cur2, version));
                 (state, segments, Some (PageBlock::new(firstPageChunk2, lastPageChunk2)))
             } else {
-                let (state,segments) = try!(readSegmentList(pr, cur));
+                let (state,segments) = try!(readSegmentList(pr, cur, version));
                 (state, segments, None)
             };
 
@@ -3337,6 +3916,10 @@ fn readHeader<R>(fs: &mut R) -> Result<(HeaderData,usize,PageNum,SegmentNum)> wh
 
 }
 
+fn count_pages_in_blocks(blocks: &[PageBlock]) -> PageNum {
+    blocks.iter().fold(0, |acc, b| acc + b.count_pages())
+}
+
 fn consolidateBlockList(blocks: &mut Vec<PageBlock>) {
     blocks.sort_by(|a,b| a.firstPage.cmp(&b.firstPage));
     loop {
@@ -3430,6 +4013,7 @@ struct InnerPart {
     path: String,
     pgsz: usize,
     settings: DbSettings,
+    read_only: bool,
 
     nextSeg: Mutex<NextSeg>,
     space: Mutex<Space>,
@@ -3438,6 +4022,9 @@ struct InnerPart {
     segmentsInWaiting: Mutex<SafeSegmentsInWaiting>,
     mergeStuff: Mutex<SafeMergeStuff>,
     cursors: Mutex<SafeCursors>,
+    // the page cache of the Env which opened this db, and the id of
+    // this db in it.  None for a db opened by itself.
+    cache: Option<(std::sync::Arc<PageCache>, u64)>,
 }
 
 pub struct WriteLock<'a> {
@@ -3454,7 +4041,231 @@ impl<'a> WriteLock<'a> {
     }
 }
 
+// collects many writes so that they become a single segment, rather
+// than one segment per write.  a later put or delete of the same key
+// replaces the earlier one.
+pub struct WriteBatch {
+    pairs: HashMap<Box<[u8]>,Blob>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch {
+            pairs: HashMap::new(),
+        }
+    }
+
+    pub fn put(&mut self, k: Box<[u8]>, v: Blob) {
+        self.pairs.insert(k, v);
+    }
+
+    pub fn delete(&mut self, k: Box<[u8]>) {
+        self.pairs.insert(k, Blob::Tombstone);
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+}
+
+// how many pages an Env keeps in its PageCache
+const ENV_CACHE_PAGES: usize = 4096;
+
+// the pages read by the segment cursors of every db in an Env.  a page
+// is keyed by its db and segment as well as its number.  the pages of
+// a segment never change while it is in use, and segment numbers are
+// never reused, so nothing here ever goes stale.  a page which is freed
+// and reused by another segment is just a different key.  when the
+// cache is full, the page which has been in it longest goes first.
+struct PageCache {
+    capacity: usize,
+    pages: Mutex<CachedPages>,
+}
+
+struct CachedPages {
+    map: HashMap<(u64, SegmentNum, PageNum), Box<[u8]>>,
+    order: std::collections::VecDeque<(u64, SegmentNum, PageNum)>,
+}
+
+impl PageCache {
+    fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity: capacity,
+            pages: Mutex::new(CachedPages {
+                map: HashMap::new(),
+                order: std::collections::VecDeque::new(),
+            }),
+        }
+    }
+
+    // copies the page into buf, if it is here
+    fn get(&self, key: &(u64, SegmentNum, PageNum), buf: &mut [u8]) -> Result<bool> {
+        let pages = try!(self.pages.lock());
+        match pages.map.get(key) {
+            Some(page) => {
+                misc::bytes::copy_into(page, buf);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn put(&self, key: (u64, SegmentNum, PageNum), buf: &[u8]) -> Result<()> {
+        let mut pages = try!(self.pages.lock());
+        if pages.map.contains_key(&key) {
+            return Ok(());
+        }
+        while pages.map.len() >= self.capacity {
+            match pages.order.pop_front() {
+                Some(old) => {
+                    pages.map.remove(&old);
+                },
+                None => break,
+            }
+        }
+        let mut page = Vec::with_capacity(buf.len());
+        page.push_all(buf);
+        pages.map.insert(key, page.into_boxed_slice());
+        pages.order.push_back(key);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize> {
+        let pages = try!(self.pages.lock());
+        Ok(pages.map.len())
+    }
+}
+
+// a directory of named databases, one file each.  a db stays open
+// for as long as the Env does, so opening the same name again gives
+// back the same handle.  a handle does not borrow the Env, so more
+// names can be opened while others are in use.
+//
+// the dbs share one PageCache, so the pages that are read most stay
+// in memory no matter which db they belong to.
+pub struct Env<'a> {
+    dir: String,
+    settings: DbSettings,
+    cache: std::sync::Arc<PageCache>,
+    next_id: u64,
+    dbs: HashMap<String, std::sync::Arc<db<'a>>>,
+}
+
+impl<'a> Env<'a> {
+    // creates the directory if it does not exist
+    pub fn new(dir: String, settings: DbSettings) -> Result<Env<'a>> {
+        try!(settings.validate());
+        try!(std::fs::create_dir_all(&dir));
+        let res = Env {
+            dir: dir,
+            settings: settings,
+            cache: std::sync::Arc::new(PageCache::new(ENV_CACHE_PAGES)),
+            next_id: 1,
+            dbs: HashMap::new(),
+        };
+        Ok(res)
+    }
+
+    // each db gets its own copy of the Env's settings
+    fn copy_settings(&self) -> DbSettings {
+        DbSettings {
+            AutoMergeEnabled : self.settings.AutoMergeEnabled,
+            AutoMergeMinimumPages : self.settings.AutoMergeMinimumPages,
+            DefaultPageSize : self.settings.DefaultPageSize,
+            PagesPerBlock : self.settings.PagesPerBlock,
+            TombstoneRetention : self.settings.TombstoneRetention,
+            PrefixCompression : self.settings.PrefixCompression,
+            BlobBlockPages : self.settings.BlobBlockPages,
+            InlineValueMaxBytes : self.settings.InlineValueMaxBytes,
+        }
+    }
+
+    // the name is used as the file name, so it cannot be empty or
+    // reach outside the directory.
+    pub fn open(&mut self, name: &str) -> Result<std::sync::Arc<db<'a>>> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\\') {
+            return Err(Error::Misc("invalid db name"));
+        }
+        if let Some(d) = self.dbs.get(name) {
+            return Ok(d.clone());
+        }
+        let path = format!("{}/{}", self.dir, name);
+        let settings = self.copy_settings();
+        let id = self.next_id;
+        let d = std::sync::Arc::new(try!(db::open(path, settings, false, Some((self.cache.clone(), id)))));
+        self.next_id = self.next_id + 1;
+        self.dbs.insert(String::from(name), d.clone());
+        Ok(d)
+    }
+
+    // None if the name has not been opened
+    pub fn get(&self, name: &str) -> Option<std::sync::Arc<db<'a>>> {
+        self.dbs.get(name).map(|d| d.clone())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut a = self.dbs.keys().map(|k| k.clone()).collect::<Vec<_>>();
+        a.sort();
+        a
+    }
+
+    // how many pages, from all of the dbs, are in the shared cache
+    pub fn cached_pages(&self) -> Result<usize> {
+        self.cache.len()
+    }
+}
+
 // TODO rename this
+// writes a segment from pairs pushed in by the caller, each leaf going
+// to disk as it fills, so a bulk load of any size runs in bounded
+// memory.  the keys must be given in ascending order.  a key which
+// repeats is written once, with the last value given for it.
+// like WriteSegment, finish() returns a segment which still has to be
+// committed.
+//
+// after an error, or if it is dropped without finish(), the writer
+// gives back the pages it used, and any further add() fails.
+pub struct SegmentWriter<'a> {
+    inner: &'a InnerPart,
+    fs: File,
+    builder: Option<SegmentBuilder>,
+}
+
+impl<'a> SegmentWriter<'a> {
+    pub fn add(&mut self, k: Box<[u8]>, v: Blob) -> Result<()> {
+        let r =
+            match self.builder {
+                Some(ref mut b) => b.add(&mut self.fs, self.inner, kvp {Key: k, Value: v}),
+                None => return Err(Error::Misc("segment writer already failed")),
+            };
+        if let Err(e) = r {
+            if let Some(b) = self.builder.take() {
+                try!(b.abandon(self.inner));
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<SegmentNum> {
+        match self.builder.take() {
+            Some(b) => {
+                let (g, _) = try!(b.finish(&mut self.fs, self.inner));
+                Ok(g)
+            },
+            None => Err(Error::Misc("segment writer already failed")),
+        }
+    }
+}
+
+impl<'a> Drop for SegmentWriter<'a> {
+    fn drop(&mut self) {
+        if let Some(b) = self.builder.take() {
+            let _ = b.abandon(self.inner);
+        }
+    }
+}
+
 pub struct db<'a> {
 
     inner: InnerPart,
@@ -3463,11 +4274,40 @@ pub struct db<'a> {
 
 impl<'a> db<'a> {
     pub fn new(path: String, settings : DbSettings) -> Result<db<'a>> {
+        Self::open(path, settings, false, None)
+    }
 
-        let mut f = try!(OpenOptions::new()
-                .read(true)
-                .create(true)
-                .open(&path));
+    // the file must already exist.  cursors work, but anything
+    // that would write to the file returns an error.
+    pub fn open_read_only(path: String, settings : DbSettings) -> Result<db<'a>> {
+        Self::open(path, settings, true, None)
+    }
+
+    // for a file which has been damaged.  opens read-only, then walks
+    // every segment in the current state and leaves out any which
+    // cannot be read all the way through.  returns the skipped
+    // segments, each with the error that ruled it out.  cursors see
+    // whatever survived.
+    pub fn open_salvage(path: String, settings : DbSettings) -> Result<(db<'a>, Vec<(SegmentNum, Error)>)> {
+        let res = try!(Self::open(path, settings, true, None));
+        let skipped = try!(res.inner.drop_unreadable_segments());
+        Ok((res, skipped))
+    }
+
+    fn open(path: String, settings : DbSettings, read_only: bool, cache: Option<(std::sync::Arc<PageCache>, u64)>) -> Result<db<'a>> {
+        try!(settings.validate());
+
+        let mut f =
+            if read_only {
+                try!(OpenOptions::new()
+                    .read(true)
+                    .open(&path))
+            } else {
+                try!(OpenOptions::new()
+                    .read(true)
+                    .create(true)
+                    .open(&path))
+            };
 
         let (header,pgsz,firstAvailablePage,nextAvailableSegmentNum) = try!(readHeader(&mut f));
 
@@ -3509,12 +4349,14 @@ impl<'a> db<'a> {
             path: path,
             pgsz: pgsz,
             settings: settings, 
+            read_only: read_only,
             header: Mutex::new(header),
             nextSeg: Mutex::new(nextSeg),
             space: Mutex::new(space),
             segmentsInWaiting: Mutex::new(segmentsInWaiting),
             mergeStuff: Mutex::new(mergeStuff),
             cursors: Mutex::new(cursors),
+            cache: cache,
         };
 
         // WriteLock contains a reference to another part of
@@ -3530,15 +4372,45 @@ impl<'a> db<'a> {
         Ok(res)
     }
 
-    // TODO func to ask for the write lock without blocking?
-
     pub fn GetWriteLock(&'a self) -> Result<std::sync::MutexGuard<WriteLock<'a>>> {
+        if self.inner.read_only {
+            return Err(Error::Misc("database is read-only"));
+        }
         let mut lck = try!(self.write_lock.lock());
         // set the inner reference
         lck.inner = Some(&self.inner);
         Ok(lck)
     }
 
+    // like GetWriteLock, but gives up after roughly dur and returns
+    // None, so a caller can refuse a write instead of hanging.  Mutex
+    // has no timed lock, so this polls.
+    pub fn GetWriteLockTimeout(&'a self, dur: std::time::Duration) -> Result<Option<std::sync::MutexGuard<WriteLock<'a>>>> {
+        if self.inner.read_only {
+            return Err(Error::Misc("database is read-only"));
+        }
+        let step = std::cmp::min(dur, std::time::Duration::from_millis(1));
+        let mut waited = std::time::Duration::new(0, 0);
+        loop {
+            match self.write_lock.try_lock() {
+                Ok(mut lck) => {
+                    lck.inner = Some(&self.inner);
+                    return Ok(Some(lck));
+                },
+                Err(std::sync::TryLockError::WouldBlock) => {
+                    if waited >= dur {
+                        return Ok(None);
+                    }
+                    std::thread::sleep(step);
+                    waited = waited + step;
+                },
+                Err(std::sync::TryLockError::Poisoned(_)) => {
+                    return Err(Error::Poisoned);
+                },
+            }
+        }
+    }
+
     // the following methods are passthrus, exposing inner
     // stuff publicly.
 
@@ -3546,6 +4418,21 @@ impl<'a> db<'a> {
         self.inner.OpenCursor()
     }
 
+    // the keys whose newest entry is a tombstone, in order.  this is
+    // the part of the data that a LivingCursor hides, for something
+    // like a replica that needs to apply the deletes.
+    pub fn iter_tombstones(&self) -> Result<TombstoneIter> {
+        self.inner.iter_tombstones()
+    }
+
+    // the live keys, in order, without reading any values.  cheaper
+    // than a LivingCursor when values are big.  filter it for a subset.
+    pub fn scan_keys(&self) -> Result<KeyIter> {
+        self.inner.scan_keys()
+    }
+
+    // the keys must be ascending.  a key which repeats is written
+    // once, with the last value given for it.
     pub fn WriteSegmentFromSortedSequence<I>(&self, source: I) -> Result<SegmentNum> where I:Iterator<Item=Result<kvp>> {
         self.inner.WriteSegmentFromSortedSequence(source)
     }
@@ -3558,9 +4445,115 @@ impl<'a> db<'a> {
         self.inner.WriteSegment2(pairs)
     }
 
+    // for writing a segment one pair at a time, when the pairs do not
+    // come from an iterator.  see SegmentWriter.
+    pub fn segment_writer(&self) -> Result<SegmentWriter> {
+        self.inner.segment_writer()
+    }
+
     pub fn merge(&self, level: u32, min: usize, max: Option<usize>) -> Result<Option<SegmentNum>> {
         self.inner.merge(level, min, max)
     }
+
+    // merges all segments into one and commits the result.  returns
+    // None if there was nothing to merge.
+    pub fn compact_all(&'a self) -> Result<Option<(SegmentNum, CompactionStats)>> {
+        match try!(self.inner.compact_all()) {
+            Some((g, stats)) => {
+                let lck = try!(self.GetWriteLock());
+                try!(lck.commitMerge(g));
+                Ok(Some((g, stats)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn segment_range(&self, g: SegmentNum) -> Result<(Box<[u8]>, Box<[u8]>)> {
+        self.inner.segment_range(g)
+    }
+
+    // reads the whole database once, in order, to get it into the OS
+    // cache.  worth doing right after opening, before queries arrive.
+    pub fn warm(&self) -> Result<()> {
+        self.inner.warm()
+    }
+
+    // merges each run of adjacent segments with overlapping key ranges
+    // and commits the results.  segments which overlap nothing are left
+    // alone.  returns the new segments, if any.
+    pub fn merge_overlapping(&'a self) -> Result<Vec<SegmentNum>> {
+        let merged = try!(self.inner.merge_overlapping());
+        if !merged.is_empty() {
+            let lck = try!(self.GetWriteLock());
+            for g in merged.iter() {
+                try!(lck.commitMerge(*g));
+            }
+        }
+        Ok(merged)
+    }
+
+    // writes the whole batch as one segment and commits it.  an empty
+    // batch writes nothing.
+    pub fn write_batch(&'a self, batch: WriteBatch) -> Result<Option<SegmentNum>> {
+        if batch.pairs.is_empty() {
+            return Ok(None);
+        }
+        let g = try!(self.inner.WriteSegment2(batch.pairs));
+        let lck = try!(self.GetWriteLock());
+        try!(lck.commitSegments(vec![g]));
+        Ok(Some(g))
+    }
+
+    // how many segments in the current state have an entry for k,
+    // counting tombstones and values hidden by newer segments.  more
+    // than a few suggests compaction is overdue.
+    pub fn key_segment_depth(&self, k: &[u8]) -> Result<usize> {
+        self.inner.key_segment_depth(k)
+    }
+
+    // flushes everything written so far to the disk.  commits do not
+    // do this themselves, so a crash can lose anything since the last
+    // sync.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    // the number of segments in the current state
+    pub fn segment_count(&self) -> Result<usize> {
+        let st = try!(self.inner.header.lock());
+        Ok(st.header.currentState.len())
+    }
+
+    // every commitSegments bumps the changeCounter, and each segment
+    // records the commit that made it current, so a replica can
+    // remember this number and later ask for what came after it.
+    pub fn latest_seq(&self) -> Result<u64> {
+        let st = try!(self.inner.header.lock());
+        Ok(st.header.changeCounter)
+    }
+
+    // the segments in the current state committed after seq, newest
+    // first.  a merged segment carries the commit of the newest
+    // segment it replaced, so merging does not make old data look new.
+    pub fn segments_since(&self, seq: u64) -> Result<Vec<CommittedSegment>> {
+        let st = try!(self.inner.header.lock());
+        let mut a = Vec::new();
+        for g in st.header.currentState.iter() {
+            match st.header.segments.get(g) {
+                Some(info) => {
+                    if info.commit > seq {
+                        a.push(CommittedSegment {
+                            segnum: *g,
+                            commit: info.commit,
+                            age: info.age,
+                        });
+                    }
+                },
+                None => return Err(Error::Misc("segment num in currentState but not in segments")),
+            }
+        }
+        Ok(a)
+    }
 }
 
 // TODO this could be generic
@@ -3587,6 +4580,12 @@ impl InnerPart {
         let mut cursors = self.cursors.lock().unwrap(); // gotta succeed
         let seg = cursors.cursors.remove(&csrnum).expect("gotta be there");
         assert_eq!(seg, segnum);
+        // the cursors map is effectively a reference count for each
+        // segment.  a zombie can only be freed when its last cursor
+        // goes away.
+        if cursors.cursors.values().any(|g| *g == segnum) {
+            return;
+        }
         match cursors.zombies.remove(&segnum) {
             Some(info) => {
                 // TODO maybe allow this lock to fail with try_lock.  the
@@ -3636,6 +4635,9 @@ impl InnerPart {
     }
 
     fn OpenForWriting(&self) -> io::Result<File> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "database is read-only"));
+        }
         OpenOptions::new()
                 .read(true)
                 .write(true)
@@ -3648,6 +4650,44 @@ impl InnerPart {
                 .open(&self.path)
     }
 
+    // holding the header lock keeps a commit from writing a new header
+    // while the file is being flushed.
+    fn sync(&self) -> Result<()> {
+        let _st = try!(self.header.lock());
+        let fs = try!(self.OpenForReading());
+        try!(fs.sync_all());
+        Ok(())
+    }
+
+    // reads every page of every live segment, so that the OS has them
+    // cached before the first query needs them.  there is no page
+    // cache of our own to fill.  the blocks are read in file order,
+    // each one with a single read.  a block freed by a merge while
+    // this runs just gets read for nothing.
+    fn warm(&self) -> Result<()> {
+        let mut blocks = {
+            let st = try!(self.header.lock());
+            let mut blocks = Vec::new();
+            for g in st.header.currentState.iter() {
+                let info = st.header.segments.get(g).unwrap();
+                blocks.push_all(&info.blocks);
+            }
+            blocks
+        };
+        blocks.sort_by(|a, b| a.firstPage.cmp(&b.firstPage));
+        let mut fs = try!(self.OpenForReading());
+        // the pages are only read to get them cached, so one page of
+        // buffer does for all of them
+        let mut buf = vec![0; self.pgsz].into_boxed_slice();
+        for b in blocks {
+            try!(utils::SeekPage(&mut fs, self.pgsz, b.firstPage));
+            for _ in 0 .. b.count_pages() {
+                try!(misc::io::read_fully(&mut fs, &mut buf));
+            }
+        }
+        Ok(())
+    }
+
     // this code should not be called in a release build.  it helps
     // finds problems by zeroing out pages in blocks that
     // have been freed.
@@ -3696,6 +4736,7 @@ impl InnerPart {
     // a stored segmentinfo for a segment is a single blob of bytes.
     // root page
     // age
+    // commit
     // number of pairs
     // each pair is startBlock,countBlocks
     // all in varints
@@ -3714,6 +4755,7 @@ impl InnerPart {
             }
             a = a + varint::space_needed_for(info.root as u64);
             a = a + varint::space_needed_for(info.age as u64);
+            a = a + varint::space_needed_for(info.commit);
             a = a + varint::space_needed_for(info.blocks.len() as u64);
             a
         }
@@ -3731,7 +4773,6 @@ impl InnerPart {
         fn buildSegmentList(h: &HeaderData) -> PageBuilder {
             let space = spaceForHeader(h);
             let mut pb = PageBuilder::new(space);
-            // TODO format version number
             pb.PutVarint(h.currentState.len() as u64);
             for g in h.currentState.iter() {
                 pb.PutVarint(*g);
@@ -3739,6 +4780,7 @@ impl InnerPart {
                     Some(info) => {
                         pb.PutVarint(info.root as u64);
                         pb.PutVarint(info.age as u64);
+                        pb.PutVarint(info.commit);
                         pb.PutVarint(info.blocks.len() as u64);
                         // we store PageBlock as first/count instead of first/last, since the
                         // count will always compress better as a varint.
@@ -3755,7 +4797,7 @@ impl InnerPart {
         }
 
         let mut pb = PageBuilder::new(HEADER_SIZE_IN_BYTES);
-        pb.PutInt32(self.pgsz as u32);
+        pb.PutInt32((HEADER_FORMAT_VERSION << 24) | (self.pgsz as u32));
 
         pb.PutVarint(hdr.changeCounter);
         pb.PutVarint(hdr.mergeCounter);
@@ -3816,6 +4858,33 @@ impl InnerPart {
         }
     }
 
+    // another cursor on the same segment as csr.  the segment might
+    // already be a zombie, so this does not go through the header
+    // like getCursor does.  csr keeps it alive.
+    fn cloneSegmentCursor(&self, csr: &SegmentCursor) -> Result<SegmentCursor> {
+        let mut cursors = try!(self.cursors.lock());
+        let csrnum = cursors.nextCursorNum;
+        let c = try!(SegmentCursor::new(&self.path, self.pgsz, csr.rootPage, csr.blocks.clone(), &self, csr.segnum, csrnum));
+
+        cursors.nextCursorNum = cursors.nextCursorNum + 1;
+        let was = cursors.cursors.insert(csrnum, csr.segnum);
+        assert!(was.is_none());
+        Ok(c)
+    }
+
+    fn key_segment_depth(&self, k: &[u8]) -> Result<usize> {
+        let st = try!(self.header.lock());
+        let kref = KeyRef::for_slice(k);
+        let mut count = 0;
+        for g in st.header.currentState.iter() {
+            let mut csr = try!(self.getCursor(&st, *g));
+            if try!(csr.SeekRef(&kref, SeekOp::SEEK_EQ)).is_valid_and_equal() {
+                count = count + 1;
+            }
+        }
+        Ok(count)
+    }
+
     // TODO we also need a way to open a cursor on segments in waiting
     fn OpenCursor(&self) -> Result<LivingCursor> {
         // TODO this cursor needs to expose the changeCounter and segment list
@@ -3824,14 +4893,94 @@ impl InnerPart {
         // compare the two cursors to see if anything important changed.  if not,
         // commit their writes.  if so, nevermind the written segments and start over.
 
+        let mc = try!(self.openMultiCursor());
+        let lc = LivingCursor::Create(mc);
+        Ok(lc)
+    }
+
+    fn openMultiCursor(&self) -> Result<MultiCursor> {
         let st = try!(self.header.lock());
         let mut clist = Vec::with_capacity(st.header.currentState.len());
         for g in st.header.currentState.iter() {
             clist.push(try!(self.getCursor(&*st, *g)));
         }
         let mc = MultiCursor::Create(clist);
-        let lc = LivingCursor::Create(mc);
-        Ok(lc)
+        Ok(mc)
+    }
+
+    fn iter_tombstones(&self) -> Result<TombstoneIter> {
+        let mc = try!(self.openMultiCursor());
+        Ok(FilteredKeyIter::Create(mc, Option::is_none))
+    }
+
+    fn scan_keys(&self) -> Result<KeyIter> {
+        let mc = try!(self.openMultiCursor());
+        Ok(FilteredKeyIter::Create(mc, Option::is_some))
+    }
+
+    // the first and last keys in a committed segment.  this is not
+    // stored anywhere.  it comes from the segment's btree, which means
+    // reading one path down each side from the root.
+    fn segment_range(&self, g: SegmentNum) -> Result<(Box<[u8]>, Box<[u8]>)> {
+        let mut csr = {
+            let st = try!(self.header.lock());
+            try!(self.getCursor(&*st, g))
+        };
+        try!(csr.First());
+        if !csr.IsValid() {
+            return Err(Error::Misc("segment_range: segment is empty"));
+        }
+        let min = try!(csr.KeyRef()).into_boxed_slice();
+        try!(csr.Last());
+        let max = try!(csr.KeyRef()).into_boxed_slice();
+        Ok((min, max))
+    }
+
+    // reads every key and value in segment g, including overflowed
+    // values, so that any damage shows up as an error here instead
+    // of later.
+    fn validate_segment(&self, st: &SafeHeader, g: SegmentNum) -> Result<()> {
+        let mut csr = try!(self.getCursor(st, g));
+        try!(csr.First());
+        while csr.IsValid() {
+            try!(csr.KeyRef()).into_boxed_slice();
+            match try!(csr.ValueRef()) {
+                ValueRef::Overflowed(len, mut r) => {
+                    let mut buf = vec![0; len];
+                    let got = try!(misc::io::read_fully(&mut *r, &mut buf));
+                    if got != len {
+                        return Err(Error::CorruptFile("overflowed value too short"));
+                    }
+                },
+                _ => {
+                },
+            }
+            try!(csr.Next());
+        }
+        Ok(())
+    }
+
+    // only for a read-only salvage open.  the dropped segments stay in
+    // the file, they are just no longer part of the current state, and
+    // since nothing gets written, their pages are never reused.
+    fn drop_unreadable_segments(&self) -> Result<Vec<(SegmentNum, Error)>> {
+        assert!(self.read_only);
+        let mut st = try!(self.header.lock());
+        let mut bad = Vec::new();
+        for g in st.header.currentState.clone() {
+            match self.validate_segment(&*st, g) {
+                Ok(()) => {
+                },
+                Err(e) => {
+                    bad.push((g, e));
+                },
+            }
+        }
+        for &(g, _) in bad.iter() {
+            st.header.currentState.retain(|x| *x != g);
+            st.header.segments.remove(&g);
+        }
+        Ok(bad)
     }
 
     fn commitSegments(&self, 
@@ -3861,9 +5010,11 @@ impl InnerPart {
 
         let mut newHeader = st.header.clone();
         let mut newSegmentsInWaiting = waiting.segmentsInWaiting.clone();
+        let commit = newHeader.changeCounter + 1;
         for g in newSegs.iter() {
             match newSegmentsInWaiting.remove(&g) {
-                Some(info) => {
+                Some(mut info) => {
+                    info.commit = commit;
                     newHeader.segments.insert(*g,info);
                 },
                 None => {
@@ -3879,7 +5030,7 @@ impl InnerPart {
             newHeader.currentState.insert(i, g);
         }
 
-        newHeader.changeCounter = newHeader.changeCounter + 1;
+        newHeader.changeCounter = commit;
 
         let mut fs = try!(self.OpenForWriting());
         let oldHeaderOverflow = try!(self.writeHeader(&mut st, &mut space, &mut fs, newHeader));
@@ -3906,6 +5057,17 @@ impl InnerPart {
         Ok(g)
     }
 
+    fn segment_writer(&self) -> Result<SegmentWriter> {
+        let mut fs = try!(self.OpenForWriting());
+        let b = try!(SegmentBuilder::new(&mut fs, self));
+        let w = SegmentWriter {
+            inner: self,
+            fs: fs,
+            builder: Some(b),
+        };
+        Ok(w)
+    }
+
     // TODO bad fn name
     fn WriteSegment(&self, pairs: HashMap<Box<[u8]>,Box<[u8]>>) -> Result<SegmentNum> {
         let mut a : Vec<(Box<[u8]>,Box<[u8]>)> = pairs.into_iter().collect();
@@ -3943,6 +5105,10 @@ impl InnerPart {
     }
 
     fn merge(&self, level: u32, min: usize, max: Option<usize>) -> Result<Option<SegmentNum>> {
+        // check this now, before the segments get marked as being merged
+        if self.read_only {
+            return Err(Error::Misc("database is read-only"));
+        }
         let mrg = {
             let st = try!(self.header.lock());
 
@@ -3999,20 +5165,25 @@ impl InnerPart {
                 for g in segs.iter() {
                     mergeStuff.merging.insert(*g);
                 }
-                Some((segs,clist))
+
+                // tombstones exist to hide older values of the same key.
+                // if nothing is older than the segments being merged,
+                // the tombstones can go, once they have been around for
+                // long enough.
+                let includes_oldest = st.header.currentState.last() == segs.last();
+                let newest_commit = segs.iter().map(|g| st.header.segments.get(g).unwrap().commit).max().expect("this cannot be empty");
+                let drop_tombstones = 
+                    includes_oldest 
+                    && (st.header.changeCounter - newest_commit >= self.settings.TombstoneRetention);
+
+                Some((segs,clist,drop_tombstones))
             } else {
                 None
             }
         };
         match mrg {
-            Some((segs,clist)) => {
-                let mut mc = MultiCursor::Create(clist);
-                let mut fs = try!(self.OpenForWriting());
-                try!(mc.First());
-                let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, CursorIterator::new(mc)));
-                //printfn "merged %A to get %A" segs g
-                let mut mergeStuff = try!(self.mergeStuff.lock());
-                mergeStuff.pendingMerges.insert(g, segs);
+            Some((segs,clist,drop_tombstones)) => {
+                let (g, _) = try!(self.merge_segments(segs, clist, drop_tombstones));
                 Ok(Some(g))
             },
             None => {
@@ -4021,6 +5192,201 @@ impl InnerPart {
         }
     }
 
+    // merges every segment in currentState into one.  if any segment is
+    // already part of a merge, this fails rather than waiting for it.
+    fn compact_all(&self) -> Result<Option<(SegmentNum, CompactionStats)>> {
+        if self.read_only {
+            return Err(Error::Misc("database is read-only"));
+        }
+        let (segs,clist,drop_tombstones) = {
+            let st = try!(self.header.lock());
+
+            if st.header.currentState.len() < 2 {
+                return Ok(None)
+            }
+
+            let mut mergeStuff = try!(self.mergeStuff.lock());
+
+            if st.header.currentState.iter().any(|g| mergeStuff.merging.contains(g)) {
+                return Err(Error::Misc("compact_all: a merge is already in progress"));
+            }
+
+            let segs = st.header.currentState.clone();
+            let mut clist = Vec::with_capacity(segs.len());
+            for g in segs.iter() {
+                clist.push(try!(self.getCursor(&st, *g)));
+            }
+            for g in segs.iter() {
+                mergeStuff.merging.insert(*g);
+            }
+
+            // everything is being merged, so the oldest segment is
+            // included.  only the retention setting can keep tombstones.
+            let newest_commit = segs.iter().map(|g| st.header.segments.get(g).unwrap().commit).max().expect("this cannot be empty");
+            let drop_tombstones = st.header.changeCounter - newest_commit >= self.settings.TombstoneRetention;
+
+            (segs,clist,drop_tombstones)
+        };
+        let res = try!(self.merge_segments(segs, clist, drop_tombstones));
+        Ok(Some(res))
+    }
+
+    // merges each run of adjacent segments whose key ranges overlap,
+    // leaving segments that overlap nothing alone.  only adjacent
+    // segments can be merged together, because commitMerge needs the
+    // replaced segments to be contiguous in currentState.  a run which
+    // includes a segment already being merged is skipped.  returns the
+    // new segments, which still need to be committed with commitMerge.
+    fn merge_overlapping(&self) -> Result<Vec<SegmentNum>> {
+        if self.read_only {
+            return Err(Error::Misc("database is read-only"));
+        }
+        let groups = {
+            let st = try!(self.header.lock());
+
+            if st.header.currentState.len() < 2 {
+                return Ok(vec![])
+            }
+
+            let mut ranges = Vec::with_capacity(st.header.currentState.len());
+            for g in st.header.currentState.iter() {
+                let mut csr = try!(self.getCursor(&*st, *g));
+                try!(csr.First());
+                if csr.IsValid() {
+                    let min = try!(csr.KeyRef()).into_boxed_slice();
+                    try!(csr.Last());
+                    let max = try!(csr.KeyRef()).into_boxed_slice();
+                    ranges.push(Some((min, max)));
+                } else {
+                    ranges.push(None);
+                }
+            }
+
+            // walk currentState, newest first, growing a run as long as
+            // the next segment overlaps the combined range of the run.
+            let mut runs: Vec<Vec<SegmentNum>> = Vec::new();
+            let mut cur: Vec<SegmentNum> = Vec::new();
+            let mut lo: Option<Box<[u8]>> = None;
+            let mut hi: Option<Box<[u8]>> = None;
+            for (g, r) in st.header.currentState.iter().zip(ranges.into_iter()) {
+                match r {
+                    Some((min, max)) => {
+                        let overlaps =
+                            match (&lo, &hi) {
+                                (&Some(ref lo), &Some(ref hi)) => {
+                                    Ordering::Greater != bcmp::Compare(&min, hi)
+                                    && Ordering::Less != bcmp::Compare(&max, lo)
+                                },
+                                _ => false,
+                            };
+                        if overlaps {
+                            if Ordering::Less == bcmp::Compare(&min, lo.as_ref().expect("run has a range")) {
+                                lo = Some(min);
+                            }
+                            if Ordering::Greater == bcmp::Compare(&max, hi.as_ref().expect("run has a range")) {
+                                hi = Some(max);
+                            }
+                        } else {
+                            runs.push(cur);
+                            cur = Vec::new();
+                            lo = Some(min);
+                            hi = Some(max);
+                        }
+                        cur.push(*g);
+                    },
+                    None => {
+                        // an empty segment overlaps nothing
+                        runs.push(cur);
+                        cur = Vec::new();
+                        lo = None;
+                        hi = None;
+                    },
+                }
+            }
+            runs.push(cur);
+
+            let mut mergeStuff = try!(self.mergeStuff.lock());
+
+            let mut groups = Vec::new();
+            for segs in runs {
+                if segs.len() < 2 {
+                    continue;
+                }
+                if segs.iter().any(|g| mergeStuff.merging.contains(g)) {
+                    continue;
+                }
+                let mut clist = Vec::with_capacity(segs.len());
+                for g in segs.iter() {
+                    clist.push(try!(self.getCursor(&st, *g)));
+                }
+                for g in segs.iter() {
+                    mergeStuff.merging.insert(*g);
+                }
+
+                // same rule as merge: tombstones can only go if nothing
+                // older than this run exists.
+                let includes_oldest = st.header.currentState.last() == segs.last();
+                let newest_commit = segs.iter().map(|g| st.header.segments.get(g).unwrap().commit).max().expect("this cannot be empty");
+                let drop_tombstones = 
+                    includes_oldest 
+                    && (st.header.changeCounter - newest_commit >= self.settings.TombstoneRetention);
+
+                groups.push((segs,clist,drop_tombstones));
+            }
+            groups
+        };
+        let mut merged = Vec::with_capacity(groups.len());
+        for (segs,clist,drop_tombstones) in groups {
+            let (g, _) = try!(self.merge_segments(segs, clist, drop_tombstones));
+            merged.push(g);
+        }
+        Ok(merged)
+    }
+
+    // writes the merged segment and records it in pendingMerges.  the
+    // segments must already be marked as merging.  the caller still
+    // needs to commitMerge the result.
+    fn merge_segments(&self, segs: Vec<SegmentNum>, clist: Vec<SegmentCursor>, drop_tombstones: bool) -> Result<(SegmentNum, CompactionStats)> {
+        let pages_before = {
+            let st = try!(self.header.lock());
+            segs.iter().fold(0, |acc, g| acc + count_pages_in_blocks(&st.header.segments.get(g).unwrap().blocks))
+        };
+        let kept = std::cell::Cell::new(0);
+        let dropped = std::cell::Cell::new(0);
+        let mut mc = MultiCursor::Create(clist);
+        let mut fs = try!(self.OpenForWriting());
+        try!(mc.First());
+        let source = CursorIterator::new(mc).filter(
+            |r| match r {
+                &Ok(kvp {Value: Blob::Tombstone, ..}) if drop_tombstones => {
+                    dropped.set(dropped.get() + 1);
+                    false
+                },
+                &Ok(_) => {
+                    kept.set(kept.get() + 1);
+                    true
+                },
+                _ => true,
+            });
+        let (g,_) = try!(CreateFromSortedSequenceOfKeyValuePairs(&mut fs, self, source));
+        //printfn "merged %A to get %A" segs g
+        let pages_after = {
+            let waiting = try!(self.segmentsInWaiting.lock());
+            count_pages_in_blocks(&waiting.segmentsInWaiting.get(&g).unwrap().blocks)
+        };
+        let stats = CompactionStats {
+            segments_in: segs.len(),
+            segments_out: 1,
+            keys_kept: kept.get(),
+            tombstones_dropped: dropped.get(),
+            bytes_before: (pages_before as u64) * (self.pgsz as u64),
+            bytes_after: (pages_after as u64) * (self.pgsz as u64),
+        };
+        let mut mergeStuff = try!(self.mergeStuff.lock());
+        mergeStuff.pendingMerges.insert(g, segs);
+        Ok((g, stats))
+    }
+
     // TODO maybe commitSegments and commitMerge should be the same function.
     // just check to see if the segment being committed is a merge.  if so,
     // do the extra paperwork.
@@ -4092,6 +5458,7 @@ impl InnerPart {
             1 + ages.iter().max().expect("this cannot be empty")
         };
         newSegmentInfo.age = age_of_new_segment;
+        newSegmentInfo.commit = segmentsBeingReplaced.values().map(|info| info.commit).max().expect("this cannot be empty");
 
         newHeader.segments.insert(newSegNum, newSegmentInfo);
 
@@ -4146,6 +5513,14 @@ impl IPages for InnerPart {
         self.pgsz
     }
 
+    fn PrefixCompression(&self) -> bool {
+        self.settings.PrefixCompression
+    }
+
+    fn InlineValueMaxBytes(&self) -> Option<usize> {
+        self.settings.InlineValueMaxBytes
+    }
+
     fn Begin(&self) -> Result<PendingSegment> {
         let mut lck = try!(self.nextSeg.lock());
         let p = PendingSegment::new(lck.nextSeg);
@@ -4161,9 +5536,21 @@ impl IPages for InnerPart {
         Ok(blk)
     }
 
+    fn GetBlobBlock(&self, ps: &mut PendingSegment) -> Result<PageBlock> {
+        match self.settings.BlobBlockPages {
+            None => self.GetBlock(ps),
+            Some(n) => {
+                let mut space = try!(self.space.lock());
+                let blk = self.getBlock(&mut space, n);
+                ps.AddBlock(blk);
+                Ok(blk)
+            },
+        }
+    }
+
     fn End(&self, ps:PendingSegment, lastPage: PageNum) -> Result<SegmentNum> {
         let (g, blocks, leftovers) = ps.End(lastPage);
-        let info = SegmentInfo {age: 0,blocks:blocks,root:lastPage};
+        let info = SegmentInfo {age: 0,commit: 0,blocks:blocks,root:lastPage};
         let mut waiting = try!(self.segmentsInWaiting.lock());
         let mut space = try!(self.space.lock());
         waiting.segmentsInWaiting.insert(g,info);
@@ -4175,6 +5562,12 @@ impl IPages for InnerPart {
         Ok(g)
     }
 
+    fn Abandon(&self, ps: PendingSegment) -> Result<()> {
+        let mut space = try!(self.space.lock());
+        self.addFreeBlocks(&mut space, ps.blockList);
+        Ok(())
+    }
+
 }
 
 // ----------------------------------------------------------------
@@ -4330,6 +5723,67 @@ mod tests {
         assert!(f().is_ok());
     }
 
+    #[test]
+    fn tombstone_retention() {
+        use std::collections::HashMap;
+        use super::ICursor;
+
+        // returns whether the raw cursor (which does not skip
+        // tombstones) still has a tombstone for "a".
+        fn merge_after(extra_commits: usize) -> Result<bool> {
+            let settings = super::DbSettings {
+                TombstoneRetention : 2,
+                .. super::DEFAULT_SETTINGS
+            };
+            let db = try!(super::db::new(super::misc::tempfile("tombstone_retention"), settings));
+
+            let mut t1 = HashMap::new();
+            t1.insert(String::from("a").into_bytes().into_boxed_slice(), super::Blob::Array(String::from("1").into_bytes().into_boxed_slice()));
+            let g1 = try!(db.WriteSegment2(t1));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g1]));
+            }
+
+            let mut t2 = HashMap::new();
+            t2.insert(String::from("a").into_bytes().into_boxed_slice(), super::Blob::Tombstone);
+            let g2 = try!(db.WriteSegment2(t2));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g2]));
+            }
+
+            for i in 0 .. extra_commits {
+                let mut t = HashMap::new();
+                t.insert(format!("b{}", i).into_bytes().into_boxed_slice(), super::Blob::Array(String::from("2").into_bytes().into_boxed_slice()));
+                let g = try!(db.WriteSegment2(t));
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+
+            // merge the two oldest segments
+            let g3 = try!(db.merge(0, 2, Some(2))).unwrap();
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitMerge(g3));
+            }
+
+            let mut csr = try!(db.OpenCursor());
+            try!(csr.chain.SeekRef(&super::KeyRef::for_slice(b"a"), super::SeekOp::SEEK_EQ));
+            if csr.chain.IsValid() {
+                assert!(try!(csr.chain.ValueLength()).is_none());
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }
+
+        // merged right away, the tombstone is kept
+        assert!(merge_after(0).unwrap());
+        // two commits later, it is gone
+        assert!(!merge_after(2).unwrap());
+    }
+
 }
 
 pub struct GenerateNumbers {
@@ -4379,9 +5833,16 @@ impl Iterator for GenerateWeirdPairs {
                 f
             }
 
+            // the leading bytes are the counter, big endian, so the
+            // keys come out in ascending order.
             let mut k = Vec::new();
+            let c = endian::u64_to_bytes_be(self.cur as u64);
             for i in 0 .. self.klen {
-                k.push(get_weird(i + self.cur));
+                if i < c.len() {
+                    k.push(c[i]);
+                } else {
+                    k.push(get_weird(i + self.cur));
+                }
             }
             let k = k.into_boxed_slice();
 
@@ -383,6 +383,40 @@ fn empty_val() {
     assert!(f().is_ok());
 }
 
+#[test]
+fn empty_key() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("empty_key"), lsm::DEFAULT_SETTINGS));
+
+        let mut t1 = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t1, "", "empty");
+        insert_pair_string_string(&mut t1, "a", "1");
+        assert!(db.WriteSegment(t1).is_err());
+
+        let mut t2 = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t2, "a", "1");
+        insert_pair_string_string(&mut t2, "b", "2");
+        let g2 = try!(db.WriteSegment(t2));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g2]));
+        }
+        let mut csr = try!(db.OpenCursor());
+        try!(csr.SeekRef(&lsm::KeyRef::for_slice(&[]), lsm::SeekOp::SEEK_GE));
+        assert!(csr.IsValid());
+        assert_eq!(key_as_string(&csr), "a");
+        try!(csr.Prev());
+        assert!(!csr.IsValid());
+        try!(csr.SeekRef(&lsm::KeyRef::for_slice(&[]), lsm::SeekOp::SEEK_LE));
+        assert!(!csr.IsValid());
+        try!(csr.SeekRef(&lsm::KeyRef::for_slice(&[]), lsm::SeekOp::SEEK_EQ));
+        assert!(!csr.IsValid());
+
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
 #[test]
 fn delete_not_there() {
     fn f() -> lsm::Result<()> {
@@ -865,6 +899,53 @@ fn prefix_compression() {
     assert!(f().is_ok());
 }
 
+#[test]
+fn prefix_compression_off() {
+    fn f() -> lsm::Result<()> {
+        fn write(name: &str, on: bool) -> lsm::Result<(String, Vec<(String, String)>)> {
+            let settings = lsm::DbSettings {
+                    PrefixCompression : on,
+                    .. lsm::DEFAULT_SETTINGS
+                };
+            let path = tempfile(name);
+            let db = try!(lsm::db::new(path.clone(), settings));
+            let mut d = std::collections::HashMap::new();
+            for i in 1 .. 5000 {
+                let s = format!("{}", i);
+                insert_pair_string_string(&mut d, &("prefix_compression".to_string() + &s), &s);
+            }
+            let g = try!(db.WriteSegment(d));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+            let mut pairs = Vec::new();
+            let mut csr = try!(db.OpenCursor());
+            try!(csr.First());
+            while csr.IsValid() {
+                let k = key_as_string(&csr);
+                let v = from_utf8(try!(read_value(try!(csr.ValueRef()))));
+                pairs.push((k, v));
+                try!(csr.Next());
+            }
+            Ok((path, pairs))
+        }
+        let (path_on, on) = try!(write("prefix_compression_on", true));
+        let (path_off, off) = try!(write("prefix_compression_off", false));
+        assert_eq!(on.len(), 4999);
+        assert!(on == off);
+
+        // the keys share a long prefix, so the file is bigger without it
+        let len_on = try!(std::fs::metadata(path_on)).len();
+        let len_off = try!(std::fs::metadata(path_off)).len();
+        assert!(len_off > len_on);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
 #[test]
 fn threads() {
     fn f() -> lsm::Result<()> {
@@ -1094,3 +1175,1290 @@ fn simple_merge() {
     assert!(f().is_ok());
 }
 
+#[test]
+fn prefix_successor() {
+    assert_eq!(lsm::prefix_successor(&[0x01, 0xff]), Some(vec![0x02].into_boxed_slice()));
+    assert_eq!(lsm::prefix_successor(&[0x01, 0x02]), Some(vec![0x01, 0x03].into_boxed_slice()));
+    assert_eq!(lsm::prefix_successor(&[0xff, 0xff]), None);
+    assert_eq!(lsm::prefix_successor(&[]), None);
+}
+
+#[test]
+fn value_as_u64() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("value_as_u64"), lsm::DEFAULT_SETTINGS));
+        let mut t1 = std::collections::HashMap::new();
+        for i in 0 .. 100u64 {
+            let k = format!("{:03}", i);
+            let v = misc::endian::u64_to_bytes_be(i * 1000000007);
+            let mut a = Vec::new();
+            a.push_all(&v);
+            t1.insert(into_utf8(k), a.into_boxed_slice());
+        }
+        insert_pair_string_string(&mut t1, "short", "abc");
+        let g = try!(db.WriteSegment(t1));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        let mut csr = try!(db.OpenCursor());
+        for i in 0 .. 100u64 {
+            try!(csr.SeekRef(&lsm::KeyRef::for_slice(format!("{:03}", i).as_bytes()), lsm::SeekOp::SEEK_EQ));
+            assert!(csr.IsValid());
+            assert_eq!(try!(csr.ValueAsU64()), Some(i * 1000000007));
+        }
+        try!(csr.SeekRef(&lsm::KeyRef::for_slice(b"short"), lsm::SeekOp::SEEK_EQ));
+        assert!(csr.IsValid());
+        assert!(csr.ValueAsU64().is_err());
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn segment_range() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("segment_range"), lsm::DEFAULT_SETTINGS));
+        let g1 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 17, end: 1000, step: 3}));
+        let g2 = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 500, end: 600, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g1, g2]));
+        }
+        let (min, max) = try!(db.segment_range(g1));
+        assert_eq!(from_utf8(min), "00000017");
+        assert_eq!(from_utf8(max), "00000998");
+        let (min, max) = try!(db.segment_range(g2));
+        assert_eq!(from_utf8(min), "00000500");
+        assert_eq!(from_utf8(max), "00000600");
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn accessors_after_seek_eq_miss() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("accessors_after_seek_eq_miss"), lsm::DEFAULT_SETTINGS));
+        let mut t1 = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t1, "a", "1");
+        insert_pair_string_string(&mut t1, "c", "3");
+        let g = try!(db.WriteSegment(t1));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        let mut csr = try!(db.OpenCursor());
+        try!(csr.SeekStr("a", lsm::SeekOp::SEEK_EQ));
+        assert!(csr.IsValid());
+        assert_eq!(from_utf8(try!(csr.Key()).unwrap()), "a");
+        assert_eq!(Some(1), try!(csr.ValueLength()));
+
+        try!(csr.SeekStr("b", lsm::SeekOp::SEEK_EQ));
+        assert!(!csr.IsValid());
+        assert!(try!(csr.Key()).is_none());
+        assert!(try!(csr.Value()).is_none());
+        assert!(try!(csr.ValueLength()).is_none());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn sync() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("sync"), lsm::DEFAULT_SETTINGS));
+        try!(db.sync());
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        try!(db.sync());
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(100, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn warm() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                DefaultPageSize : 256,
+                PagesPerBlock : 4,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(tempfile("warm"), settings));
+        try!(db.warm());
+        for i in 0 .. 3 {
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 1000, end: i * 1000 + 999, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        try!(db.warm());
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(3000, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn merge_overlapping() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("merge_overlapping"), lsm::DEFAULT_SETTINGS));
+
+        assert!(try!(db.merge_overlapping()).is_empty());
+
+        let ranges = vec![(0, 99), (200, 299), (500, 599), (550, 650)];
+        let mut segs = Vec::new();
+        for &(cur, end) in ranges.iter() {
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: cur, end: end, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+            segs.push(g);
+        }
+        assert_eq!(try!(db.segment_count()), 4);
+
+        let merged = try!(db.merge_overlapping());
+        assert_eq!(merged.len(), 1);
+        assert_eq!(try!(db.segment_count()), 3);
+
+        // the disjoint segments were not touched
+        let (min, max) = try!(db.segment_range(segs[0]));
+        assert_eq!(from_utf8(min), "00000000");
+        assert_eq!(from_utf8(max), "00000099");
+        let (min, max) = try!(db.segment_range(segs[1]));
+        assert_eq!(from_utf8(min), "00000200");
+        assert_eq!(from_utf8(max), "00000299");
+
+        let (min, max) = try!(db.segment_range(merged[0]));
+        assert_eq!(from_utf8(min), "00000500");
+        assert_eq!(from_utf8(max), "00000650");
+
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(351, try!(count_keys_forward(&mut csr)));
+
+        // nothing overlaps anymore
+        assert!(try!(db.merge_overlapping()).is_empty());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn key_ref_no_copy() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("key_ref_no_copy"), lsm::DEFAULT_SETTINGS));
+        let mut t1 = std::collections::HashMap::new();
+        // some keys that share prefixes and some that do not
+        for i in 0 .. 200 {
+            insert_pair_string_string(&mut t1, &format!("prefix_{:05}", i), "v");
+            insert_pair_string_string(&mut t1, &format!("{}", i * 7), "v");
+        }
+        let g = try!(db.WriteSegment(t1));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        let mut csr = try!(db.OpenCursor());
+        try!(csr.First());
+        let mut count = 0;
+        while csr.IsValid() {
+            let owned = key_as_boxed_slice(&csr);
+            {
+                let k = try!(csr.KeyRef());
+                assert_eq!(k.len(), owned.len());
+                assert_eq!(lsm::KeyRef::cmp(&k, &lsm::KeyRef::for_slice(&owned)), std::cmp::Ordering::Equal);
+                // prefixed or not, the key comes back as one slice
+                match k.as_slice() {
+                    Some(a) => assert_eq!(a, &*owned),
+                    None => panic!("key was not borrowed whole: {:?}", k),
+                }
+            }
+            count = count + 1;
+            try!(csr.Next());
+        }
+        assert_eq!(count, 400);
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn settings_validate() {
+    let settings = lsm::DbSettings {
+            DefaultPageSize : 0,
+            .. lsm::DEFAULT_SETTINGS
+        };
+    assert!(settings.validate().is_err());
+    assert!(lsm::db::new(tempfile("settings_validate"), settings).is_err());
+
+    let settings = lsm::DbSettings {
+            PagesPerBlock : 0,
+            .. lsm::DEFAULT_SETTINGS
+        };
+    assert!(settings.validate().is_err());
+
+    let settings = lsm::DbSettings {
+            DefaultPageSize : 256,
+            PagesPerBlock : 4,
+            .. lsm::DEFAULT_SETTINGS
+        };
+    assert!(settings.validate().is_ok());
+    assert!(lsm::DEFAULT_SETTINGS.validate().is_ok());
+    assert!(lsm::db::new(tempfile("settings_validate"), settings).is_ok());
+}
+
+#[test]
+fn read_only() {
+    fn f() -> lsm::Result<()> {
+        let path = tempfile("read_only");
+        {
+            let db = try!(lsm::db::new(path.clone(), lsm::DEFAULT_SETTINGS));
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 100, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        let db = try!(lsm::db::open_read_only(path, lsm::DEFAULT_SETTINGS));
+        {
+            let mut csr = try!(db.OpenCursor());
+            assert_eq!(try!(count_keys_forward(&mut csr)), 101);
+        }
+
+        let mut t1 = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t1, "a", "1");
+        assert!(db.WriteSegment(t1).is_err());
+        assert!(db.GetWriteLock().is_err());
+        assert!(db.merge(0, 1, None).is_err());
+
+        assert!(lsm::db::open_read_only(tempfile("read_only_missing"), lsm::DEFAULT_SETTINGS).is_err());
+        Ok(())
+    }
+    assert!(f().is_ok());
+}
+
+#[test]
+fn blob_eq() {
+    let a = lsm::Blob::Array(str_to_utf8("hello"));
+    let b = lsm::Blob::Array(str_to_utf8("hello"));
+    let c = lsm::Blob::Array(str_to_utf8("world"));
+    assert_eq!(a, b);
+    assert!(a != c);
+    assert_eq!(lsm::Blob::Tombstone, lsm::Blob::Tombstone);
+    assert!(a != lsm::Blob::Tombstone);
+    let s = lsm::Blob::Stream(Box::new(std::io::Cursor::new(vec![1u8, 2, 3])));
+    assert!(s != lsm::Blob::Stream(Box::new(std::io::Cursor::new(vec![1u8, 2, 3]))));
+    assert_eq!(format!("{:?}", s), "<stream>");
+    assert_eq!(format!("{:?}", lsm::Blob::Tombstone), "<tombstone>");
+}
+
+#[test]
+fn blob_peek_bytes() {
+    fn f() -> lsm::Result<()> {
+        use std::io::Read;
+
+        let mut s = lsm::Blob::Stream(Box::new(std::io::Cursor::new(vec![1u8, 2, 3, 4, 5])));
+        assert_eq!(&*try!(s.peek_bytes(2)), &[1u8, 2]);
+        assert_eq!(&*try!(s.peek_bytes(3)), &[1u8, 2, 3]);
+        assert_eq!(&*try!(s.peek_bytes(10)), &[1u8, 2, 3, 4, 5]);
+        match s {
+            lsm::Blob::Stream(mut strm) => {
+                let mut all = Vec::new();
+                strm.read_to_end(&mut all).unwrap();
+                assert_eq!(all, vec![1u8, 2, 3, 4, 5]);
+            },
+            _ => panic!(),
+        }
+
+        let mut a = lsm::Blob::Array(str_to_utf8("hello"));
+        assert_eq!(&*try!(a.peek_bytes(2)), b"he");
+        assert_eq!(a, lsm::Blob::Array(str_to_utf8("hello")));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn blob_peek_bytes_error() {
+    // hands out two bytes, then fails once, then ends
+    struct Flaky {
+        calls: usize,
+    }
+
+    impl std::io::Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.calls = self.calls + 1;
+            match self.calls {
+                1 => {
+                    buf[0] = 7;
+                    buf[1] = 8;
+                    Ok(2)
+                },
+                2 => Err(std::io::Error::new(std::io::ErrorKind::Other, "flaky")),
+                _ => Ok(0),
+            }
+        }
+    }
+
+    use std::io::Read;
+
+    let mut s = lsm::Blob::Stream(Box::new(Flaky { calls: 0 }));
+    assert!(s.peek_bytes(4).is_err());
+    // the stream is still there, and has not lost what was read
+    match s {
+        lsm::Blob::Stream(mut strm) => {
+            let mut all = Vec::new();
+            strm.read_to_end(&mut all).unwrap();
+            assert_eq!(all, vec![7u8, 8]);
+        },
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn blob_as_slice() {
+    fn f() -> lsm::Result<()> {
+        let mut a = lsm::Blob::Array(str_to_utf8("hello"));
+        let owned = try!(a.peek_bytes(100));
+        assert_eq!(a.as_slice(), Some(&*owned));
+        assert_eq!(a.as_slice(), Some(&b"hello"[..]));
+
+        let s = lsm::Blob::Stream(Box::new(std::io::Cursor::new(vec![1u8, 2, 3])));
+        assert!(s.as_slice().is_none());
+        assert!(lsm::Blob::Tombstone.as_slice().is_none());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn index_key_order() {
+    fn f() -> lsm::Result<()> {
+        let tuples: Vec<Vec<&[u8]>> = vec![
+            vec![&b""[..], &b""[..]],
+            vec![&b""[..], &b"z"[..]],
+            vec![&b"\x00"[..], &b""[..]],
+            vec![&b"\x00\x00"[..], &b"a"[..]],
+            vec![&b"\x00\xff"[..], &b"a"[..]],
+            vec![&b"a"[..], &b"b"[..]],
+            vec![&b"a"[..], &b"b\x00"[..]],
+            vec![&b"a\x00"[..], &b""[..]],
+            vec![&b"ab"[..], &b""[..]],
+            vec![&b"b"[..], &b"a"[..]],
+            vec![&b"\xff"[..], &b"\x00"[..]],
+            ];
+        let keys = tuples.iter().map(|t| lsm::encode_index_key(t)).collect::<Vec<_>>();
+        for i in 0 .. tuples.len() {
+            for j in 0 .. tuples.len() {
+                assert_eq!(keys[i].cmp(&keys[j]), tuples[i].cmp(&tuples[j]));
+            }
+            let parts = try!(lsm::decode_index_key(&keys[i]));
+            assert_eq!(parts.len(), tuples[i].len());
+            for (p, t) in parts.iter().zip(tuples[i].iter()) {
+                assert_eq!(&**p, *t);
+            }
+        }
+        assert!(lsm::decode_index_key(b"a\x00").is_err());
+        assert!(lsm::decode_index_key(b"a\x00\x01").is_err());
+        assert!(lsm::decode_index_key(b"a").is_err());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn write_batch() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("write_batch"), lsm::DEFAULT_SETTINGS));
+
+        let mut batch = lsm::WriteBatch::new();
+        batch.put(str_to_utf8("gone"), lsm::Blob::Array(str_to_utf8("x")));
+        assert!(try!(db.write_batch(batch)).is_some());
+        assert_eq!(try!(db.segment_count()), 1);
+
+        assert!(try!(db.write_batch(lsm::WriteBatch::new())).is_none());
+        assert_eq!(try!(db.segment_count()), 1);
+
+        let mut batch = lsm::WriteBatch::new();
+        for i in 0 .. 500 {
+            let k = format!("{:08}", i);
+            batch.put(str_to_utf8(&k), lsm::Blob::Array(str_to_utf8(&k)));
+        }
+        batch.delete(str_to_utf8("gone"));
+        assert_eq!(batch.len(), 501);
+        assert!(try!(db.write_batch(batch)).is_some());
+        assert_eq!(try!(db.segment_count()), 2);
+
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(500, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn compact_all() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("compact_all"), lsm::DEFAULT_SETTINGS));
+
+        assert!(try!(db.compact_all()).is_none());
+
+        for s in 0 .. 10 {
+            let mut batch = lsm::WriteBatch::new();
+            for i in 0 .. 50 {
+                let k = format!("{:08}", s * 50 + i);
+                batch.put(str_to_utf8(&k), lsm::Blob::Array(str_to_utf8(&k)));
+            }
+            // overwrite a key from the previous segment
+            if s > 0 {
+                let k = format!("{:08}", s * 50 - 1);
+                batch.put(str_to_utf8(&k), lsm::Blob::Array(str_to_utf8("new")));
+            }
+            assert!(try!(db.write_batch(batch)).is_some());
+        }
+        assert_eq!(try!(db.segment_count()), 10);
+
+        {
+            let mut csr = try!(db.OpenCursor());
+            assert_eq!(500, try!(count_keys_forward(&mut csr)));
+        }
+
+        assert!(try!(db.compact_all()).is_some());
+        assert_eq!(try!(db.segment_count()), 1);
+
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(500, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn cursor_survives_compaction() {
+    fn f() -> lsm::Result<()> {
+        let settings = lsm::DbSettings {
+                DefaultPageSize : 256,
+                PagesPerBlock : 4,
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(tempfile("cursor_survives_compaction"), settings));
+
+        fn write<'a>(db: &'a lsm::db<'a>, v: &str) -> lsm::Result<()> {
+            for s in 0 .. 3 {
+                let mut batch = lsm::WriteBatch::new();
+                for i in 0 .. 100 {
+                    let k = format!("{:08}", s * 100 + i);
+                    batch.put(str_to_utf8(&k), lsm::Blob::Array(str_to_utf8(v)));
+                }
+                try!(db.write_batch(batch));
+            }
+            Ok(())
+        }
+
+        try!(write(&db, "old"));
+
+        // two cursors on the same segments, both open across the
+        // compaction.  dropping one must not free the segments out from
+        // under the other.
+        let mut csr = try!(db.OpenCursor());
+        try!(csr.First());
+        let mut other = try!(db.OpenCursor());
+        try!(other.First());
+
+        assert!(try!(db.compact_all()).is_some());
+
+        drop(other);
+
+        // new writes reuse whatever space the compaction freed
+        for _ in 0 .. 3 {
+            try!(write(&db, "new"));
+            try!(db.compact_all());
+        }
+
+        let mut count = 0;
+        while csr.IsValid() {
+            assert_eq!("old", from_utf8(try!(read_value(try!(csr.ValueRef())))));
+            count = count + 1;
+            try!(csr.Next());
+        }
+        assert_eq!(count, 300);
+
+        let mut csr = try!(db.OpenCursor());
+        try!(csr.First());
+        assert_eq!("new", from_utf8(try!(read_value(try!(csr.ValueRef())))));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn seek_str() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("seek_str"), lsm::DEFAULT_SETTINGS));
+        let mut batch = lsm::WriteBatch::new();
+        for i in 0 .. 100 {
+            let k = format!("{:08}", i * 2);
+            batch.put(str_to_utf8(&k), lsm::Blob::Array(str_to_utf8(&k)));
+        }
+        try!(db.write_batch(batch));
+
+        let mut csr = try!(db.OpenCursor());
+        let sr = try!(csr.SeekStr("00000042", lsm::SeekOp::SEEK_EQ));
+        assert!(match sr { lsm::SeekResult::Equal => true, _ => false });
+        assert_eq!(key_as_string(&csr), "00000042");
+
+        let sr = try!(csr.SeekStr("00000043", lsm::SeekOp::SEEK_EQ));
+        assert!(match sr { lsm::SeekResult::Invalid => true, _ => false });
+        assert!(!csr.IsValid());
+
+        let sr = try!(csr.SeekStr("00000043", lsm::SeekOp::SEEK_GE));
+        assert!(match sr { lsm::SeekResult::Unequal => true, _ => false });
+        assert_eq!(key_as_string(&csr), "00000044");
+
+        try!(csr.SeekBytes(b"00000043", lsm::SeekOp::SEEK_LE));
+        assert_eq!(key_as_string(&csr), "00000042");
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn blob_block_pages() {
+    fn f() -> lsm::Result<()> {
+        let bad = lsm::DbSettings {
+                BlobBlockPages : Some(0),
+                .. lsm::DEFAULT_SETTINGS
+            };
+        assert!(bad.validate().is_err());
+
+        for &n in &[1, 2, 7] {
+            let settings = lsm::DbSettings {
+                    DefaultPageSize : 256,
+                    PagesPerBlock : 16,
+                    BlobBlockPages : Some(n),
+                    .. lsm::DEFAULT_SETTINGS
+                };
+            let db = try!(lsm::db::new(tempfile("blob_block_pages"), settings));
+
+            const LEN : usize = 50000;
+            let mut v = Vec::new();
+            for i in 0 .. LEN {
+                v.push((i % 251) as u8);
+            }
+            let mut batch = lsm::WriteBatch::new();
+            batch.put(str_to_utf8("a"), lsm::Blob::Array(str_to_utf8("small")));
+            batch.put(str_to_utf8("b"), lsm::Blob::Array(v.clone().into_boxed_slice()));
+            batch.put(str_to_utf8("c"), lsm::Blob::Array(str_to_utf8("small")));
+            try!(db.write_batch(batch));
+
+            let mut csr = try!(db.OpenCursor());
+            assert_eq!(3, try!(count_keys_forward(&mut csr)));
+            try!(csr.SeekStr("b", lsm::SeekOp::SEEK_EQ));
+            assert!(csr.IsValid());
+            let a = try!(read_value(try!(csr.ValueRef())));
+            assert!(&*a == &v[..]);
+        }
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn key_segment_depth() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("key_segment_depth"), lsm::DEFAULT_SETTINGS));
+        for i in 0 .. 3 {
+            let mut batch = lsm::WriteBatch::new();
+            batch.put(str_to_utf8("k"), lsm::Blob::Array(into_utf8(format!("{}", i))));
+            batch.put(into_utf8(format!("only{}", i)), lsm::Blob::Array(str_to_utf8("x")));
+            try!(db.write_batch(batch));
+        }
+        assert_eq!(try!(db.key_segment_depth(b"k")), 3);
+        assert_eq!(try!(db.key_segment_depth(b"only1")), 1);
+        assert_eq!(try!(db.key_segment_depth(b"nope")), 0);
+
+        try!(db.compact_all());
+        assert_eq!(try!(db.key_segment_depth(b"k")), 1);
+        assert_eq!(try!(db.key_segment_depth(b"only1")), 1);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn stream_million_pairs() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("stream_million_pairs"), lsm::DEFAULT_SETTINGS));
+        const LEN : usize = 1000000;
+        let mut w = try!(db.segment_writer());
+        for i in 0 .. LEN {
+            let k = format!("{:08}", i).into_bytes().into_boxed_slice();
+            let v = format!("{}", i * 2).into_bytes().into_boxed_slice();
+            try!(w.add(k, lsm::Blob::Array(v)));
+        }
+        let g = try!(w.finish());
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        let mut csr = try!(db.OpenCursor());
+        let count = try!(count_keys_forward(&mut csr));
+        assert_eq!(LEN, count);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn unsorted_keys_rejected() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("unsorted_keys_rejected"), lsm::DEFAULT_SETTINGS));
+        // each run is sorted, but the second starts below the end of the first
+        let src = lsm::GenerateNumbers {cur: 50, end: 60, step: 1}.chain(lsm::GenerateNumbers {cur: 0, end: 10, step: 1});
+        let r = db.WriteSegmentFromSortedSequence(src);
+        assert!(r.is_err());
+
+        let mut w = try!(db.segment_writer());
+        try!(w.add(str_to_utf8("b"), lsm::Blob::Array(str_to_utf8("1"))));
+        assert!(w.add(str_to_utf8("a"), lsm::Blob::Array(str_to_utf8("2"))).is_err());
+
+        // and a segment needs at least one key
+        let w = try!(db.segment_writer());
+        assert!(w.finish().is_err());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn failed_segment_gives_back_pages() {
+    fn f() -> lsm::Result<()> {
+        fn write_good(db: &lsm::db) -> lsm::Result<()> {
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 9999, step: 1}));
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+            Ok(())
+        }
+
+        let name_a = tempfile("failed_segment_gives_back_pages_a");
+        let name_b = tempfile("failed_segment_gives_back_pages_b");
+        {
+            let db = try!(lsm::db::new(name_a.clone(), lsm::DEFAULT_SETTINGS));
+            // fails after the first run has been written
+            let src = lsm::GenerateNumbers {cur: 0, end: 9999, step: 1}.chain(lsm::GenerateNumbers {cur: 0, end: 10, step: 1});
+            assert!(db.WriteSegmentFromSortedSequence(src).is_err());
+            // and the same for a writer dropped before finish
+            {
+                let mut w = try!(db.segment_writer());
+                for i in 0 .. 10000 {
+                    try!(w.add(format!("{:08}", i).into_bytes().into_boxed_slice(), lsm::Blob::Array(str_to_utf8("x"))));
+                }
+            }
+            try!(write_good(&db));
+        }
+        {
+            let db = try!(lsm::db::new(name_b.clone(), lsm::DEFAULT_SETTINGS));
+            try!(write_good(&db));
+        }
+        // the good segment reused the pages the failed ones took
+        let len_a = try!(std::fs::metadata(&name_a)).len();
+        let len_b = try!(std::fs::metadata(&name_b)).len();
+        assert!(len_a <= len_b);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn duplicate_keys_last_wins() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("duplicate_keys_last_wins"), lsm::DEFAULT_SETTINGS));
+        let pairs = vec![
+            ("a", "1"),
+            ("b", "first"),
+            ("b", "second"),
+            ("b", "third"),
+            ("c", "3"),
+        ];
+        let src = pairs.into_iter().map(|(k, v)| Ok(lsm::kvp::new(str_to_utf8(k), lsm::Blob::Array(str_to_utf8(v)))));
+        let g = try!(db.WriteSegmentFromSortedSequence(src));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(3, try!(count_keys_forward(&mut csr)));
+        try!(csr.SeekStr("b", lsm::SeekOp::SEEK_EQ));
+        assert!(csr.IsValid());
+        assert_eq!("third", from_utf8(try!(read_value(try!(csr.ValueRef())))));
+
+        // runs of equal keys from a chained source collapse too
+        let src = lsm::GenerateNumbers {cur: 0, end: 10, step: 1}.chain(lsm::GenerateNumbers {cur: 10, end: 20, step: 1});
+        assert!(db.WriteSegmentFromSortedSequence(src).is_ok());
+
+        // and the same for a segment writer
+        let mut w = try!(db.segment_writer());
+        try!(w.add(str_to_utf8("d"), lsm::Blob::Array(str_to_utf8("first"))));
+        try!(w.add(str_to_utf8("d"), lsm::Blob::Array(str_to_utf8("second"))));
+        try!(w.add(str_to_utf8("e"), lsm::Blob::Array(str_to_utf8("5"))));
+        let g = try!(w.finish());
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(5, try!(count_keys_forward(&mut csr)));
+        try!(csr.SeekStr("d", lsm::SeekOp::SEEK_EQ));
+        assert!(csr.IsValid());
+        assert_eq!("second", from_utf8(try!(read_value(try!(csr.ValueRef())))));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn iter_tombstones() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("iter_tombstones"), lsm::DEFAULT_SETTINGS));
+        let mut t1 = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t1, "a", "1");
+        insert_pair_string_string(&mut t1, "b", "2");
+        insert_pair_string_string(&mut t1, "c", "3");
+        insert_pair_string_string(&mut t1, "d", "4");
+        let g1 = try!(db.WriteSegment(t1));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g1]));
+        }
+        let mut t2 = std::collections::HashMap::new();
+        insert_pair_string_blob(&mut t2, "b", lsm::Blob::Tombstone);
+        insert_pair_string_blob(&mut t2, "d", lsm::Blob::Tombstone);
+        insert_pair_string_blob(&mut t2, "x", lsm::Blob::Tombstone);
+        let g2 = try!(db.WriteSegment2(t2));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g2]));
+        }
+        // d comes back, so its tombstone is no longer the newest entry
+        let mut t3 = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t3, "d", "5");
+        let g3 = try!(db.WriteSegment(t3));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g3]));
+        }
+
+        let mut a = Vec::new();
+        for k in try!(db.iter_tombstones()) {
+            a.push(from_utf8(try!(k)));
+        }
+        assert_eq!(vec!["b", "x"], a);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn read_header_format_0() {
+    fn f() -> lsm::Result<()> {
+        use std::io::Write;
+        use std::io::Seek;
+        use misc::varint;
+
+        let name = tempfile("read_header_format_0");
+        {
+            let db = try!(lsm::db::new(name.clone(), lsm::DEFAULT_SETTINGS));
+            for i in 0 .. 2 {
+                let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 10, end: i * 10 + 9, step: 1}));
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+        }
+
+        // rewrite the header the way it was before segments had a
+        // commit, and before there was a format version
+        let mut f = try!(std::fs::OpenOptions::new().read(true).write(true).open(&name));
+        let mut hdr = vec![0u8; 4096];
+        assert_eq!(4096, try!(misc::io::read_fully(&mut f, &mut hdr)));
+        assert_eq!(hdr[0], 1);
+        let mut cur = 4;
+        let change_counter = varint::read(&hdr, &mut cur);
+        let merge_counter = varint::read(&hdr, &mut cur);
+        let _len = varint::read(&hdr, &mut cur);
+        assert_eq!(hdr[cur], 0);
+        cur = cur + 1;
+        let count = varint::read(&hdr, &mut cur);
+        let mut list = vec![];
+        for _ in 0 .. count {
+            let g = varint::read(&hdr, &mut cur);
+            let root = varint::read(&hdr, &mut cur);
+            let age = varint::read(&hdr, &mut cur);
+            let _commit = varint::read(&hdr, &mut cur);
+            let mut vals = vec![g, root, age];
+            let blocks = varint::read(&hdr, &mut cur);
+            vals.push(blocks);
+            for _ in 0 .. blocks * 2 {
+                vals.push(varint::read(&hdr, &mut cur));
+            }
+            list.push(vals);
+        }
+        let mut seglist = vec![0u8; 4096];
+        let mut len = 0;
+        varint::write(&mut seglist, &mut len, count);
+        for vals in list {
+            for v in vals {
+                varint::write(&mut seglist, &mut len, v);
+            }
+        }
+        let mut old = vec![0u8; 4096];
+        let pgsz = [0u8, hdr[1], hdr[2], hdr[3]];
+        misc::bytes::copy_into(&pgsz, &mut old[0 .. 4]);
+        let mut cur = 4;
+        varint::write(&mut old, &mut cur, change_counter);
+        varint::write(&mut old, &mut cur, merge_counter);
+        varint::write(&mut old, &mut cur, len as u64);
+        old[cur] = 0;
+        cur = cur + 1;
+        misc::bytes::copy_into(&seglist[0 .. len], &mut old[cur .. cur + len]);
+        try!(f.seek(std::io::SeekFrom::Start(0)));
+        try!(f.write_all(&old));
+        drop(f);
+
+        let db = try!(lsm::db::new(name, lsm::DEFAULT_SETTINGS));
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(20, try!(count_keys_forward(&mut csr)));
+        // everything in an old file counts as committed at the start
+        assert!(try!(db.latest_seq()) > 0);
+        assert!(try!(db.segments_since(0)).is_empty());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn segments_since() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("segments_since"), lsm::DEFAULT_SETTINGS));
+        let before = try!(db.latest_seq());
+        let mut seqs = Vec::new();
+        let mut segs = Vec::new();
+        for i in 0 .. 4 {
+            let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 10, end: i * 10 + 9, step: 1}));
+            {
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+            }
+            seqs.push(try!(db.latest_seq()));
+            segs.push(g);
+        }
+        for i in 1 .. seqs.len() {
+            assert!(seqs[i] > seqs[i - 1]);
+        }
+        assert!(seqs[0] > before);
+
+        assert_eq!(4, try!(db.segments_since(before)).len());
+        let since = try!(db.segments_since(seqs[1]));
+        assert_eq!(vec![segs[3], segs[2]], since.iter().map(|s| s.segnum).collect::<Vec<_>>());
+        assert_eq!(vec![seqs[3], seqs[2]], since.iter().map(|s| s.commit).collect::<Vec<_>>());
+        assert!(since.iter().all(|s| s.age == 0));
+        assert!(try!(db.segments_since(seqs[3])).is_empty());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn write_lock_timeout() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("write_lock_timeout"), lsm::DEFAULT_SETTINGS));
+        {
+            let _lck = try!(db.GetWriteLock());
+            // try_lock does not care which thread holds the lock, so
+            // this sees it held just as another thread would
+            let r = try!(db.GetWriteLockTimeout(std::time::Duration::from_millis(20)));
+            assert!(r.is_none());
+        }
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 9, step: 1}));
+        match try!(db.GetWriteLockTimeout(std::time::Duration::from_millis(20))) {
+            Some(lck) => try!(lck.commitSegments(vec![g])),
+            None => panic!("lock should be free"),
+        }
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(10, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn was_resurrected() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("was_resurrected"), lsm::DEFAULT_SETTINGS));
+        let mut t1 = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t1, "a", "1");
+        insert_pair_string_string(&mut t1, "b", "2");
+        insert_pair_string_string(&mut t1, "c", "3");
+        let g1 = try!(db.WriteSegment(t1));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g1]));
+        }
+        let mut t2 = std::collections::HashMap::new();
+        insert_pair_string_blob(&mut t2, "b", lsm::Blob::Tombstone);
+        let g2 = try!(db.WriteSegment2(t2));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g2]));
+        }
+        let mut t3 = std::collections::HashMap::new();
+        insert_pair_string_string(&mut t3, "b", "4");
+        insert_pair_string_string(&mut t3, "c", "5");
+        let g3 = try!(db.WriteSegment(t3));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g3]));
+        }
+
+        let mut csr = try!(db.OpenCursor());
+        let mut a = Vec::new();
+        try!(csr.First());
+        while csr.IsValid() {
+            a.push((key_as_string(&csr), try!(csr.was_resurrected())));
+            try!(csr.Next());
+        }
+        // c was overwritten, but never deleted
+        assert_eq!(vec![(String::from("a"), false), (String::from("b"), true), (String::from("c"), false)], a);
+
+        try!(csr.SeekStr("b", lsm::SeekOp::SEEK_EQ));
+        assert!(csr.IsValid());
+        assert!(try!(csr.was_resurrected()));
+        assert_eq!("4", from_utf8(read_value(csr.ValueRef().unwrap()).unwrap()));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn inline_value_max_bytes() {
+    fn f() -> lsm::Result<()> {
+        const MAX : usize = 100;
+        let settings = lsm::DbSettings {
+                InlineValueMaxBytes : Some(MAX),
+                .. lsm::DEFAULT_SETTINGS
+            };
+        let db = try!(lsm::db::new(tempfile("inline_value_max_bytes"), settings));
+
+        let lens = [0, 1, MAX - 1, MAX, MAX + 1, 1000];
+        let mut batch = lsm::WriteBatch::new();
+        for &n in lens.iter() {
+            let v = (0 .. n).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            batch.put(str_to_utf8(&format!("{:04}", n)), lsm::Blob::Array(v.into_boxed_slice()));
+        }
+        try!(db.write_batch(batch));
+
+        let mut csr = try!(db.OpenCursor());
+        for &n in lens.iter() {
+            try!(csr.SeekStr(&format!("{:04}", n), lsm::SeekOp::SEEK_EQ));
+            assert!(csr.IsValid());
+            assert_eq!(Some(n), try!(csr.ValueLength()));
+            let overflowed =
+                match try!(csr.ValueRef()) {
+                    lsm::ValueRef::Overflowed(_, _) => true,
+                    _ => false,
+                };
+            assert_eq!(n > MAX, overflowed);
+            let a = try!(read_value(try!(csr.ValueRef())));
+            let v = (0 .. n).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            assert!(&*a == &v[..]);
+        }
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn seek_ordinal() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("seek_ordinal"), lsm::DEFAULT_SETTINGS));
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        // deleted keys do not count
+        let mut batch = lsm::WriteBatch::new();
+        batch.delete(str_to_utf8("00000010"));
+        batch.delete(str_to_utf8("00000020"));
+        try!(db.write_batch(batch));
+
+        let mut csr = try!(db.OpenCursor());
+        try!(csr.First());
+        for _ in 0 .. 50 {
+            try!(csr.Next());
+        }
+        assert!(csr.IsValid());
+        let expected = key_as_string(&csr);
+        assert_eq!("00000052", expected);
+
+        try!(csr.SeekOrdinal(50));
+        assert!(csr.IsValid());
+        assert_eq!(expected, key_as_string(&csr));
+
+        try!(csr.SeekOrdinal(0));
+        assert_eq!("00000000", key_as_string(&csr));
+
+        try!(csr.SeekOrdinal(97));
+        assert_eq!("00000099", key_as_string(&csr));
+        try!(csr.SeekOrdinal(98));
+        assert!(!csr.IsValid());
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn open_salvage() {
+    fn f() -> lsm::Result<()> {
+        use std::io::Seek;
+        use std::io::Write;
+
+        fn settings() -> lsm::DbSettings {
+            lsm::DbSettings {
+                DefaultPageSize : 256,
+                PagesPerBlock : 4,
+                .. lsm::DEFAULT_SETTINGS
+            }
+        }
+        let path = tempfile("open_salvage");
+        let mut lens = Vec::new();
+        {
+            let db = try!(lsm::db::new(path.clone(), settings()));
+            for i in 0 .. 3 {
+                let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: i * 1000, end: i * 1000 + 999, step: 1}));
+                let lck = try!(db.GetWriteLock());
+                try!(lck.commitSegments(vec![g]));
+                lens.push(try!(std::fs::metadata(&path)).len());
+            }
+        }
+
+        // everything the middle segment wrote, its root page included
+        assert!(lens[1] > lens[0]);
+        {
+            let mut f = try!(std::fs::OpenOptions::new().write(true).open(&path));
+            try!(f.seek(std::io::SeekFrom::Start(lens[0])));
+            try!(f.write_all(&vec![0xff; (lens[1] - lens[0]) as usize]));
+        }
+
+        let (db, skipped) = try!(lsm::db::open_salvage(path, settings()));
+        assert_eq!(skipped.len(), 1);
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(2000, try!(count_keys_forward(&mut csr)));
+        for &(n, found) in [(0, true), (999, true), (1500, false), (2000, true), (2999, true)].iter() {
+            let k = into_utf8(format!("{:08}", n));
+            try!(csr.SeekRef(&lsm::KeyRef::from_boxed_slice(k), lsm::SeekOp::SEEK_EQ));
+            assert_eq!(found, csr.IsValid());
+        }
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn env_named_dbs() {
+    fn f() -> lsm::Result<()> {
+        let dir = tempfile("env_named_dbs");
+        let mut env = try!(lsm::Env::new(dir, lsm::DEFAULT_SETTINGS));
+        let a = try!(env.open("a"));
+        assert!(env.open("").is_err());
+        assert!(env.open("../c").is_err());
+
+        let g = try!(a.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+        {
+            let lck = try!(a.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        // a handle does not hold on to the Env, so another db can be
+        // opened while a is in use
+        let b = try!(env.open("b"));
+        let g = try!(b.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 1000, end: 1049, step: 1}));
+        {
+            let lck = try!(b.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+        assert_eq!(env.names(), vec![String::from("a"), String::from("b")]);
+        assert!(env.get("c").is_none());
+
+        {
+            let mut csr = try!(a.OpenCursor());
+            assert_eq!(100, try!(count_keys_forward(&mut csr)));
+            try!(csr.SeekRef(&lsm::KeyRef::from_boxed_slice(into_utf8(format!("{:08}", 1000))), lsm::SeekOp::SEEK_EQ));
+            assert!(!csr.IsValid());
+        }
+        let after_a = try!(env.cached_pages());
+        assert!(after_a > 0);
+
+        // the same cache holds b's pages too
+        {
+            let b = env.get("b").unwrap();
+            let mut csr = try!(b.OpenCursor());
+            assert_eq!(50, try!(count_keys_forward(&mut csr)));
+            try!(csr.SeekRef(&lsm::KeyRef::from_boxed_slice(into_utf8(format!("{:08}", 0))), lsm::SeekOp::SEEK_EQ));
+            assert!(!csr.IsValid());
+        }
+        let after_b = try!(env.cached_pages());
+        assert!(after_b > after_a);
+
+        // reading a again comes from the cache, and still sees only a
+        {
+            let mut csr = try!(a.OpenCursor());
+            assert_eq!(100, try!(count_keys_forward(&mut csr)));
+        }
+        assert_eq!(try!(env.cached_pages()), after_b);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn compaction_stats() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("compaction_stats"), lsm::DEFAULT_SETTINGS));
+
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 99, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        // overwrite ten keys, delete five others, and delete one key
+        // which was never there
+        let mut batch = lsm::WriteBatch::new();
+        for i in 0 .. 10 {
+            batch.put(into_utf8(format!("{:08}", i)), lsm::Blob::Array(str_to_utf8("new")));
+        }
+        for i in 50 .. 55 {
+            batch.delete(into_utf8(format!("{:08}", i)));
+        }
+        batch.delete(into_utf8(format!("{:08}", 1000)));
+        assert!(try!(db.write_batch(batch)).is_some());
+
+        let (_, stats) = try!(db.compact_all()).unwrap();
+        assert_eq!(stats.segments_in, 2);
+        assert_eq!(stats.segments_out, 1);
+        assert_eq!(stats.keys_kept, 95);
+        assert_eq!(stats.tombstones_dropped, 6);
+        assert!(stats.bytes_before > 0);
+        assert!(stats.bytes_after > 0);
+
+        let mut csr = try!(db.OpenCursor());
+        assert_eq!(95, try!(count_keys_forward(&mut csr)));
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn scan_keys() {
+    fn f() -> lsm::Result<()> {
+        let db = try!(lsm::db::new(tempfile("scan_keys"), lsm::DEFAULT_SETTINGS));
+        assert_eq!(0, try!(db.scan_keys()).count());
+
+        let g = try!(db.WriteSegmentFromSortedSequence(lsm::GenerateNumbers {cur: 0, end: 199, step: 1}));
+        {
+            let lck = try!(db.GetWriteLock());
+            try!(lck.commitSegments(vec![g]));
+        }
+
+        // some deletes, and some values big enough to overflow
+        let mut batch = lsm::WriteBatch::new();
+        for i in 0 .. 20 {
+            batch.delete(into_utf8(format!("{:08}", i * 7)));
+        }
+        for i in 0 .. 5 {
+            let v = vec![b'x'; 20000].into_boxed_slice();
+            batch.put(into_utf8(format!("{:08}", 1000 + i)), lsm::Blob::Array(v));
+        }
+        assert!(try!(db.write_batch(batch)).is_some());
+
+        let keys = try!(try!(db.scan_keys()).collect::<lsm::Result<Vec<_>>>());
+
+        let mut csr = try!(db.OpenCursor());
+        let mut expected = Vec::new();
+        try!(csr.First());
+        while csr.IsValid() {
+            expected.push(try!(csr.Key()).unwrap());
+            try!(csr.Next());
+        }
+        assert_eq!(expected.len(), 200 - 20 + 5);
+        assert_eq!(keys, expected);
+
+        let big = try!(db.scan_keys()).filter(|r| match r {
+            &Ok(ref k) => k[4] == b'1',
+            &Err(_) => true,
+        }).count();
+        assert_eq!(big, 5);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
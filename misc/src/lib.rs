@@ -294,6 +294,16 @@ pub mod bufndx {
 
     use std;
 
+    // look at the byte at i without consuming it.  None past the end.
+    #[inline]
+    pub fn peek_u8(ba: &[u8], i: usize) -> Option<u8> {
+        if i < ba.len() {
+            Some(ba[i])
+        } else {
+            None
+        }
+    }
+
     #[inline]
     pub fn slurp_8(ba: &[u8], i: &mut usize) -> [u8; 8] {
         let a = super::bytes::extract_8(&ba[*i .. *i + 8]);
@@ -511,6 +521,8 @@ pub mod io {
     use std::io::Read;
     use std::io::Write;
     use std::io::SeekFrom;
+    use std::net::TcpStream;
+    use std::time::Duration;
     use super::endian;
 
     pub fn write_fully(strm: &mut Write, buf: &[u8]) -> io::Result<usize> {
@@ -547,6 +559,32 @@ pub mod io {
         Ok(sofar)
     }
 
+    // like read_fully, but gives up if the stream goes quiet for longer
+    // than the timeout.  that comes back as an error for which
+    // is_timeout() is true.  anything read before the timeout is lost.
+    pub fn read_fully_timeout(strm: &mut TcpStream, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        try!(strm.set_read_timeout(Some(timeout)));
+        match read_fully(strm, buf) {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                if is_timeout(&e) {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+                } else {
+                    Err(e)
+                }
+            },
+        }
+    }
+
+    // a read timeout shows up as WouldBlock on some platforms and
+    // TimedOut on others.
+    pub fn is_timeout(e: &io::Error) -> bool {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+            _ => false,
+        }
+    }
+
     pub fn read_4(strm: &mut Read) -> io::Result<[u8; 4]> {
         let mut a = [0; 4];
         let got = try!(read_fully(strm, &mut a));
@@ -36,13 +36,96 @@ extern crate elmo;
 
 extern crate elmo_sqlite3;
 
+extern crate flate2;
+
 use std::io;
 use std::io::Read;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, ATOMIC_ISIZE_INIT, Ordering};
 
 use elmo::Error;
 use elmo::Result;
 
+// limits advertised to clients in the isMaster/hello reply.  drivers
+// use these to size their batches.
+const MAX_BSON_OBJECT_SIZE: i32 = 16793600;
+const MAX_MESSAGE_SIZE_BYTES: i32 = 48000000;
+const MAX_WRITE_BATCH_SIZE: i32 = 1000;
+
+// the commands listCommands reports.  keep these in sync with the
+// matches in reply_admin_cmd and reply_cmd.
+const ADMIN_COMMANDS: &'static [&'static str] = &[
+    "whatsmyuri",
+    "getLog",
+    "replSetGetStatus",
+    "hostInfo",
+    "bulkWrite",
+    "fsync",
+    "serverStatus",
+    "currentOp",
+    "dropConnections",
+    "isMaster",
+    "hello",
+    "renameCollection",
+    "getParameter",
+    "setParameter",
+];
+
+const DB_COMMANDS: &'static [&'static str] = &[
+    "explain",
+    "aggregate",
+    "find",
+    "insert",
+    "delete",
+    "update",
+    "count",
+    "collStats",
+    "dbStats",
+    "compact",
+    "validate",
+    "createIndexes",
+    "deleteIndexes",
+    "dropIndexes",
+    "collMod",
+    "drop",
+    "dropDatabase",
+    "listCollections",
+    "listIndexes",
+    "create",
+    "listCommands",
+];
+
+// a batch of documents stops growing once it adds up to this many
+// bytes.  the document which crosses the line still goes in, so this
+// leaves room for one more of the largest size, and for the reply
+// wrapped around the batch.
+const MAX_BATCH_BYTES: usize = (MAX_MESSAGE_SIZE_BYTES - MAX_BSON_OBJECT_SIZE - 1024 * 1024) as usize;
+
+// a client which sends nothing for this long gets disconnected
+const IDLE_TIMEOUT_SECS: u64 = 600;
+
+// once a message has started, the rest of it must arrive within this
+// long.  the worker waits for it, serving none of its other clients.
+const MESSAGE_TIMEOUT_SECS: u64 = 30;
+
+// how long a worker waits for a request from one of its clients
+// before going on to the next one
+const CLIENT_POLL_MILLIS: u64 = 10;
+
+// how many worker threads spawn_server starts.  each one serves
+// several clients, one request at a time, and takes on more from the
+// queue between requests.
+const DEFAULT_WORKERS: usize = 32;
+
+// what a bulkWrite did, added up over all of its ops
+struct BulkWriteCounts {
+    inserted : i32,
+    matched : i32,
+    modified : i32,
+    removed : i32,
+    upserted : i32,
+}
+
 #[derive(Debug)]
 struct Reply {
     req_id : i32,
@@ -53,6 +136,41 @@ struct Reply {
     docs : Vec<bson::Document>,
 }
 
+// the 16 bytes at the front of every message, in either direction
+#[derive(Debug, PartialEq)]
+struct MsgHeader {
+    // TODO do these really need to be signed?
+    message_length : i32,
+    request_id : i32,
+    response_to : i32,
+    op_code : i32,
+}
+
+impl MsgHeader {
+    const OP_REPLY: i32 = 1;
+    const OP_COMPRESSED: i32 = 2012;
+
+    fn parse(ba: &[u8], i: &mut usize) -> MsgHeader {
+        let message_length = bufndx::slurp_i32_le(ba, i);
+        let request_id = bufndx::slurp_i32_le(ba, i);
+        let response_to = bufndx::slurp_i32_le(ba, i);
+        let op_code = bufndx::slurp_i32_le(ba, i);
+        MsgHeader {
+            message_length: message_length,
+            request_id: request_id,
+            response_to: response_to,
+            op_code: op_code,
+        }
+    }
+
+    fn encode(&self, w: &mut Vec<u8>) {
+        w.push_all(&endian::i32_to_bytes_le(self.message_length));
+        w.push_all(&endian::i32_to_bytes_le(self.request_id));
+        w.push_all(&endian::i32_to_bytes_le(self.response_to));
+        w.push_all(&endian::i32_to_bytes_le(self.op_code));
+    }
+}
+
 #[derive(Debug)]
 // TODO consider calling this Msg2004
 struct MsgQuery {
@@ -84,25 +202,93 @@ enum Request {
     Query(MsgQuery),
     GetMore(MsgGetMore),
     KillCursors(MsgKillCursors),
+    // the compressor id, and the message it contained
+    Compressed(u8, Box<Request>),
+}
+
+// compressor ids used in OP_COMPRESSED
+const COMPRESSOR_NOOP: u8 = 0;
+const COMPRESSOR_ZLIB: u8 = 2;
+
+// the names a client can ask for in the compression field of isMaster
+const SUPPORTED_COMPRESSORS: &'static [&'static str] = &["zlib"];
+
+// the caller has already checked uncompressed_size against the
+// largest message we accept.  reading stops one byte past it, which is
+// enough to tell that the data does not match, without inflating all
+// of it.
+fn decompress(compressor_id: u8, ba: &[u8], uncompressed_size: usize) -> Result<Vec<u8>> {
+    let v = match compressor_id {
+        COMPRESSOR_NOOP => ba.to_vec(),
+        COMPRESSOR_ZLIB => {
+            let mut d = flate2::read::ZlibDecoder::new(ba).take((uncompressed_size as u64) + 1);
+            let mut v = Vec::with_capacity(uncompressed_size);
+            try!(d.read_to_end(&mut v));
+            v
+        },
+        _ => return Err(Error::Misc(format!("unsupported compressor: {}", compressor_id))),
+    };
+    if v.len() != uncompressed_size {
+        return Err(Error::CorruptFile("OP_COMPRESSED: wrong uncompressed size"));
+    }
+    Ok(v)
+}
+
+fn compress(compressor_id: u8, ba: &[u8]) -> Result<Vec<u8>> {
+    match compressor_id {
+        COMPRESSOR_NOOP => Ok(ba.to_vec()),
+        COMPRESSOR_ZLIB => {
+            let mut e = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+            try!(e.write_all(ba));
+            let v = try!(e.finish());
+            Ok(v)
+        },
+        _ => Err(Error::Misc(format!("unsupported compressor: {}", compressor_id))),
+    }
+}
+
+// wraps a whole message (header included) in an OP_COMPRESSED message
+fn compress_message(compressor_id: u8, ba: &[u8]) -> Result<Box<[u8]>> {
+    let mut i = 0;
+    let header = MsgHeader::parse(ba, &mut i);
+    let body = try!(compress(compressor_id, &ba[i ..]));
+    let mut w = Vec::new();
+    let h = MsgHeader {
+        // length placeholder, fixed below
+        message_length: 0,
+        request_id: header.request_id,
+        response_to: header.response_to,
+        op_code: MsgHeader::OP_COMPRESSED,
+    };
+    h.encode(&mut w);
+    w.push_all(&endian::i32_to_bytes_le(header.op_code));
+    w.push_all(&endian::i32_to_bytes_le((ba.len() - i) as i32));
+    w.push(compressor_id);
+    w.push_all(&body);
+    misc::bytes::copy_into(&endian::u32_to_bytes_le(w.len() as u32), &mut w[0 .. 4]);
+    Ok(w.into_boxed_slice())
 }
 
 impl Reply {
-    fn encode(&self) -> Box<[u8]> {
+    fn encode(&self) -> Result<Box<[u8]>> {
         let mut w = Vec::new();
-        // length placeholder
-        w.push_all(&[0u8; 4]);
-        w.push_all(&endian::i32_to_bytes_le(self.req_id));
-        w.push_all(&endian::i32_to_bytes_le(self.response_to));
-        w.push_all(&endian::u32_to_bytes_le(1u32)); 
+        let header = MsgHeader {
+            // length placeholder, fixed below
+            message_length: 0,
+            request_id: self.req_id,
+            response_to: self.response_to,
+            op_code: MsgHeader::OP_REPLY,
+        };
+        header.encode(&mut w);
         w.push_all(&endian::i32_to_bytes_le(self.flags));
         w.push_all(&endian::i64_to_bytes_le(self.cursor_id));
         w.push_all(&endian::i32_to_bytes_le(self.starting_from));
         w.push_all(&endian::u32_to_bytes_le(self.docs.len() as u32));
         for doc in &self.docs {
-            doc.to_bson(&mut w);
+            try!(doc.to_bson(&mut w));
         }
         misc::bytes::copy_into(&endian::u32_to_bytes_le(w.len() as u32), &mut w[0 .. 4]);
-        w.into_boxed_slice()
+        Ok(w.into_boxed_slice())
     }
 }
 
@@ -120,16 +306,24 @@ fn vec_docs_to_values(v: Vec<bson::Document>) -> Vec<bson::Value> {
 }
 
 fn parse_request(ba: &[u8]) -> Result<Request> {
+    if ba.len() < 16 {
+        return Err(Error::CorruptFile("message shorter than its header"));
+    }
     let mut i = 0;
-    let (message_len,req_id,response_to,op_code) = slurp_header(ba, &mut i);
-    match op_code {
+    let header = MsgHeader::parse(ba, &mut i);
+    let req_id = header.request_id;
+    match header.op_code {
         2004 => {
             let flags = bufndx::slurp_i32_le(ba, &mut i);
             let full_collection_name = try!(bufndx::slurp_cstring(ba, &mut i));
             let number_to_skip = bufndx::slurp_i32_le(ba, &mut i);
             let number_to_return = bufndx::slurp_i32_le(ba, &mut i);
             let query = try!(bson::slurp_document(ba, &mut i));
-            let return_fields_selector = if i < ba.len() { Some(try!(bson::slurp_document(ba, &mut i))) } else { None };
+            let return_fields_selector = 
+                match bufndx::peek_u8(ba, i) {
+                    Some(_) => Some(try!(bson::slurp_document(ba, &mut i))),
+                    None => None,
+                };
 
             let msg = MsgQuery {
                 req_id: req_id,
@@ -173,41 +367,190 @@ fn parse_request(ba: &[u8]) -> Result<Request> {
             Ok(Request::KillCursors(msg))
         },
 
-        _ => {
-            Err(Error::CorruptFile("unknown message opcode TODO"))
+        MsgHeader::OP_COMPRESSED => {
+            // original opcode, uncompressed size, compressor id
+            if ba.len() < i + 9 {
+                return Err(Error::CorruptFile("OP_COMPRESSED: truncated header"));
+            }
+            let original_op_code = bufndx::slurp_i32_le(ba, &mut i);
+            let uncompressed_size = bufndx::slurp_i32_le(ba, &mut i);
+            let compressor_id = ba[i];
+            i = i + 1;
+            if uncompressed_size < 0 || uncompressed_size > MAX_MESSAGE_SIZE_BYTES - 16 {
+                return Err(Error::CorruptFile("OP_COMPRESSED: bad uncompressed size"));
+            }
+            let body = try!(decompress(compressor_id, &ba[i ..], uncompressed_size as usize));
+            // put the original header back on, and parse that
+            let mut inner = Vec::with_capacity(16 + body.len());
+            let h = MsgHeader {
+                message_length: (16 + body.len()) as i32,
+                request_id: header.request_id,
+                response_to: header.response_to,
+                op_code: original_op_code,
+            };
+            h.encode(&mut inner);
+            inner.push_all(&body);
+            match try!(parse_request(&inner)) {
+                Request::Compressed(_, _) => Err(Error::CorruptFile("OP_COMPRESSED inside OP_COMPRESSED")),
+                msg => Ok(Request::Compressed(compressor_id, box msg)),
+            }
+        },
+
+        op => {
+            Err(Error::Misc(format!("unsupported opcode {}", op)))
         },
     }
 }
 
-// TODO do these really need to be signed?
-fn slurp_header(ba: &[u8], i: &mut usize) -> (i32,i32,i32,i32) {
-    let message_len = bufndx::slurp_i32_le(ba, i);
-    let req_id = bufndx::slurp_i32_le(ba, i);
-    let response_to = bufndx::slurp_i32_le(ba, i);
-    let op_code = bufndx::slurp_i32_le(ba, i);
-    let v = (message_len, req_id, response_to, op_code);
-    v
+// the legacy writes (update, insert, delete) and kill cursors get no
+// reply, not even when they cannot be parsed, since the client is not
+// waiting for one.  a compressed message goes by the opcode inside it.
+fn expects_reply(ba: &[u8]) -> bool {
+    let mut i = 0;
+    let header = MsgHeader::parse(ba, &mut i);
+    let op_code =
+        if header.op_code == MsgHeader::OP_COMPRESSED && ba.len() >= i + 4 {
+            bufndx::slurp_i32_le(ba, &mut i)
+        } else {
+            header.op_code
+        };
+    match op_code {
+        2001 | 2002 | 2006 | 2007 => false,
+        _ => true,
+    }
+}
+
+// running out of time partway through a message is not idleness,
+// since the stream is no longer in step, so it is reported as
+// something else.
+fn read_rest_of_message(stream: &mut std::net::TcpStream, buf: &mut [u8], timeout: std::time::Duration) -> Result<usize> {
+    match misc::io::read_fully_timeout(stream, buf, timeout) {
+        Ok(got) => Ok(got),
+        Err(ref e) if misc::io::is_timeout(e) => Err(Error::CorruptFile("timed out in the middle of a message")),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+// the timeout is for the first byte only
+fn read_message_bytes(stream: &mut std::net::TcpStream, timeout: std::time::Duration) -> Result<Option<Box<[u8]>>> {
+    read_message_bytes_within(stream, timeout, std::time::Duration::from_secs(MESSAGE_TIMEOUT_SECS))
 }
 
-fn read_message_bytes(stream: &mut Read) -> Result<Option<Box<[u8]>>> {
+// timeout is for the first byte, and rest_timeout for the rest
+fn read_message_bytes_within(stream: &mut std::net::TcpStream, timeout: std::time::Duration, rest_timeout: std::time::Duration) -> Result<Option<Box<[u8]>>> {
     let mut a = [0; 4];
-    let got = try!(misc::io::read_fully(stream, &mut a));
+    let got = try!(misc::io::read_fully_timeout(stream, &mut a[0 .. 1], timeout));
     if got == 0 {
         return Ok(None);
     }
+    let got = try!(read_rest_of_message(stream, &mut a[1 .. 4], rest_timeout));
+    if got != 3 {
+        return Err(Error::CorruptFile("end of file at the wrong time"));
+    }
     let message_len = endian::u32_from_bytes_le(a) as usize;
+    if message_len < 16 || message_len > MAX_MESSAGE_SIZE_BYTES as usize {
+        return Err(Error::CorruptFile("bad message length"));
+    }
     let mut msg = vec![0; message_len]; 
     misc::bytes::copy_into(&a, &mut msg[0 .. 4]);
-    let got = try!(misc::io::read_fully(stream, &mut msg[4 .. message_len]));
+    let got = try!(read_rest_of_message(stream, &mut msg[4 .. message_len], rest_timeout));
     if got != message_len - 4 {
         return Err(Error::CorruptFile("end of file at the wrong time"));
     }
     Ok(Some(msg.into_boxed_slice()))
 }
 
+// checks the pieces of a full collection name, as split on '.'.
+// the first is the db and the second is the collection (or the $cmd
+// sentinel).  none of them may be empty, and none may contain a
+// null byte or a '$' other than in $cmd.
+fn validate_name_parts(full_collection_name: &str, parts: &[String]) -> Result<()> {
+    if parts.len() < 2 {
+        return Err(Error::Misc(format!("bad collection name: {}", full_collection_name)));
+    }
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() {
+            return Err(Error::Misc(format!("bad collection name (empty part): {}", full_collection_name)));
+        }
+        if part.contains('\0') {
+            return Err(Error::Misc(format!("bad collection name (null byte): {:?}", full_collection_name)));
+        }
+        if part.contains('$') && !(i == 1 && part == "$cmd") {
+            return Err(Error::Misc(format!("bad collection name ($ not allowed): {}", full_collection_name)));
+        }
+    }
+    Ok(())
+}
+
+// request ids for the messages we send.  these only need to be unique,
+// and a Server is per-connection, so the counter is per-process.
+static NEXT_REQUEST_ID: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+fn new_request_id() -> i32 {
+    (NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst) + 1) as i32
+}
+
+// counters for serverStatus, shared by every connection.  the command
+// counts are per command, not per document.  nothing ever decides
+// anything based on these, so Relaxed is enough.
+struct Metrics {
+    commands: AtomicUsize,
+    inserts: AtomicUsize,
+    queries: AtomicUsize,
+    updates: AtomicUsize,
+    deletes: AtomicUsize,
+    errors: AtomicUsize,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            commands: AtomicUsize::new(0),
+            inserts: AtomicUsize::new(0),
+            queries: AtomicUsize::new(0),
+            updates: AtomicUsize::new(0),
+            deletes: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            bytes_in: AtomicUsize::new(0),
+            bytes_out: AtomicUsize::new(0),
+        }
+    }
+
+    fn bump(counter: &AtomicUsize, n: usize) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // counts a command by its lowercased name
+    fn command(&self, cmd: &str) {
+        Self::bump(&self.commands, 1);
+        match cmd {
+            "insert" => Self::bump(&self.inserts, 1),
+            "find" | "aggregate" | "count" => Self::bump(&self.queries, 1),
+            "update" => Self::bump(&self.updates, 1),
+            "delete" => Self::bump(&self.deletes, 1),
+            _ => (),
+        }
+    }
+
+    fn to_document(&self) -> bson::Document {
+        let mut doc = bson::Document::new_empty();
+        doc.set_i64("commands", self.commands.load(Ordering::Relaxed) as i64);
+        doc.set_i64("inserts", self.inserts.load(Ordering::Relaxed) as i64);
+        doc.set_i64("queries", self.queries.load(Ordering::Relaxed) as i64);
+        doc.set_i64("updates", self.updates.load(Ordering::Relaxed) as i64);
+        doc.set_i64("deletes", self.deletes.load(Ordering::Relaxed) as i64);
+        doc.set_i64("errors", self.errors.load(Ordering::Relaxed) as i64);
+        doc.set_i64("bytesIn", self.bytes_in.load(Ordering::Relaxed) as i64);
+        doc.set_i64("bytesOut", self.bytes_out.load(Ordering::Relaxed) as i64);
+        doc
+    }
+}
+
 fn create_reply(req_id: i32, docs: Vec<bson::Document>, cursor_id: i64) -> Reply {
     let msg = Reply {
-        req_id: 0,
+        req_id: new_request_id(),
         response_to: req_id,
         flags: 0,
         cursor_id: cursor_id,
@@ -244,15 +587,69 @@ fn reply_errmsg(req_id: i32, err: Error) -> Reply {
 
 // TODO mongo has a way of automatically killing a cursor after 10 minutes idle
 
-struct Server<'a> {
-    conn: elmo::Connection,
+// like std's Peekable, except that the first item has already been
+// taken, and gets handed back out before the rest.  this lets us look
+// at the first item of a sequence which is about to be stored as a
+// boxed cursor, where Peekable would be no help.
+struct PutBack<T> {
+    first: Option<elmo::Row>,
+    rest: T,
+}
+
+impl<T: Iterator<Item=Result<elmo::Row>>> Iterator for PutBack<T> {
+    type Item = Result<elmo::Row>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.first.take() {
+            Some(r) => Some(Ok(r)),
+            None => self.rest.next(),
+        }
+    }
+}
+
+fn default_parameters() -> bson::Document {
+    let mut fcv = bson::Document::new_empty();
+    fcv.set_str("version", "3.0");
+    let mut doc = bson::Document::new_empty();
+    doc.set_document("featureCompatibilityVersion", fcv);
+    doc.set_i32("logLevel", 0);
+    doc.set_bool("notablescan", false);
+    doc
+}
+
+// one client.  the clients on a worker thread share its connection.
+struct Server {
+    conn: std::rc::Rc<elmo::Connection>,
+    // reported as topologyVersion.processId.  it is shared by every
+    // connection, so it identifies this server process, not the connection.
+    process_id: [u8; 12],
+    metrics: std::sync::Arc<Metrics>,
+    // for getParameter and setParameter.
+    // TODO these belong to the connection, but should be server-wide
+    parameters: bson::Document,
     cursor_num: i64,
-    // TODO this is problematic when/if the Iterator has a reference to or the same lifetime
-    // as self.conn.
-    cursors: std::collections::HashMap<i64, (String, Box<Iterator<Item=Result<elmo::Row>> + 'a>)>,
+    // see MAX_BATCH_BYTES.  a field so tests can make it small.
+    max_batch_bytes: usize,
+    // see MESSAGE_TIMEOUT_SECS.  a field so tests can make it small.
+    message_timeout: std::time::Duration,
+    // each cursor reads from its own snapshot, so writes on conn can
+    // commit while cursors, this client's or another's, are open.
+    cursors: std::collections::HashMap<i64, (String, Box<Iterator<Item=Result<elmo::Row>>>)>,
 }
 
-impl<'b> Server<'b> {
+impl Server {
+
+    fn new(conn: std::rc::Rc<elmo::Connection>, process_id: [u8; 12], metrics: std::sync::Arc<Metrics>) -> Server {
+        Server {
+            conn: conn,
+            process_id: process_id,
+            metrics: metrics,
+            parameters: default_parameters(),
+            cursors: std::collections::HashMap::new(),
+            cursor_num: 0,
+            max_batch_bytes: MAX_BATCH_BYTES,
+            message_timeout: std::time::Duration::from_secs(MESSAGE_TIMEOUT_SECS),
+        }
+    }
 
     fn reply_whatsmyuri(&self, req: &MsgQuery) -> Result<Reply> {
         let mut doc = bson::Document::new_empty();
@@ -269,6 +666,60 @@ impl<'b> Server<'b> {
         Ok(create_reply(req.req_id, vec![doc], 0))
     }
 
+    // whatever the system will tell us cheaply.  the /proc files only
+    // exist on linux, so everything has a placeholder.
+    fn reply_host_info(&self, req: &MsgQuery) -> Result<Reply> {
+        fn read_proc(path: &str) -> Option<String> {
+            let mut s = String::new();
+            match std::fs::File::open(path) {
+                Ok(mut f) => {
+                    match f.read_to_string(&mut s) {
+                        Ok(_) => Some(s),
+                        Err(_) => None,
+                    }
+                },
+                Err(_) => None,
+            }
+        }
+
+        let hostname = read_proc("/proc/sys/kernel/hostname")
+            .map(|s| String::from(s.trim()))
+            .unwrap_or(String::from("localhost"));
+        let mem_mb = read_proc("/proc/meminfo")
+            .and_then(|s| {
+                s.lines()
+                    .find(|line| line.starts_with("MemTotal:"))
+                    .and_then(|line| line.split(' ').filter(|w| !w.is_empty()).nth(1))
+                    .and_then(|kb| kb.parse::<i64>().ok())
+            })
+            .map(|kb| kb / 1024)
+            .unwrap_or(0);
+        let cores = read_proc("/proc/cpuinfo")
+            .map(|s| s.lines().filter(|line| line.starts_with("processor")).count())
+            .unwrap_or(0);
+        let cores = if cores == 0 { 1 } else { cores };
+        let version = read_proc("/proc/sys/kernel/osrelease")
+            .map(|s| String::from(s.trim()))
+            .unwrap_or(String::from("unknown"));
+
+        let mut system = bson::Document::new_empty();
+        system.set_string("hostname", hostname);
+        system.set_i32("cpuAddrSize", (std::mem::size_of::<usize>() * 8) as i32);
+        system.set_i64("memSizeMB", mem_mb);
+        system.set_i32("numCores", cores as i32);
+
+        let mut os = bson::Document::new_empty();
+        os.set_str("type", std::env::consts::OS);
+        os.set_str("name", std::env::consts::OS);
+        os.set_string("version", version);
+
+        let mut doc = bson::Document::new_empty();
+        doc.set_document("system", system);
+        doc.set_document("os", os);
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
     fn reply_replsetgetstatus(&self, req: &MsgQuery) -> Result<Reply> {
         let mut mine = bson::Document::new_empty();
         mine.set_i32("_id", 0);
@@ -277,10 +728,10 @@ impl<'b> Server<'b> {
         mine.set_f64("health", 1.0);
         mine.set_str("stateStr", "PRIMARY");
         mine.set_i32("uptime", 0);
-        mine.set_timestamp("optime", 0);
+        mine.set("optime", bson::Value::timestamp(0, 0));
         mine.set_datetime("optimeDate", 0);
-        mine.set_timestamp("electionTime", 0);
-        mine.set_timestamp("electionDate", 0);
+        mine.set("electionTime", bson::Value::timestamp(0, 0));
+        mine.set("electionDate", bson::Value::timestamp(0, 0));
         mine.set_bool("self", true);
 
         let mut doc = bson::Document::new_empty();
@@ -294,9 +745,46 @@ impl<'b> Server<'b> {
     }
 
     fn reply_ismaster(&self, req: &MsgQuery) -> Result<Reply> {
+        let doc = self.get_ismaster_doc(false, &req.query);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    // hello is the newer name for isMaster.  the reply is the same,
+    // except that it uses the newer field names.
+    fn reply_hello(&self, req: &MsgQuery) -> Result<Reply> {
+        let doc = self.get_ismaster_doc(true, &req.query);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    fn get_ismaster_doc(&self, hello: bool, q: &bson::Document) -> bson::Document {
         let mut doc = bson::Document::new_empty();
-        doc.set_bool("ismaster", true);
+        // the client lists the compressors it can use, and we reply
+        // with the ones we can use too.
+        match q.get("compression") {
+            Some(&bson::Value::BArray(ref a)) => {
+                let names = a.items.iter().filter_map(
+                    |v| match v {
+                        &bson::Value::BString(ref s) if SUPPORTED_COMPRESSORS.contains(&s.as_str()) => Some(v.clone()),
+                        _ => None,
+                    }
+                    ).collect::<Vec<_>>();
+                doc.set_array("compression", bson::Array { items: names });
+            },
+            _ => (),
+        }
+        if hello {
+            doc.set_bool("isWritablePrimary", true);
+            let mut tv = bson::Document::new_empty();
+            tv.set_objectid("processId", self.process_id);
+            tv.set_i64("counter", 0);
+            doc.set_document("topologyVersion", tv);
+        } else {
+            doc.set_bool("ismaster", true);
+        }
         doc.set_bool("secondary", false);
+        doc.set_i32("maxBsonObjectSize", MAX_BSON_OBJECT_SIZE);
+        doc.set_i32("maxMessageSizeBytes", MAX_MESSAGE_SIZE_BYTES);
+        doc.set_i32("maxWriteBatchSize", MAX_WRITE_BATCH_SIZE);
         doc.set_i32("maxWireVersion", 3);
         doc.set_i32("minWireVersion", 2);
         // ver >= 2:  we don't support the older fire-and-forget write operations. 
@@ -306,31 +794,155 @@ impl<'b> Server<'b> {
         // explain, what happens is that we start getting the old fire-and-forget
         // write operations instead of the write commands that we want.
         doc.set_i32("ok", 1);
-        Ok(create_reply(req.req_id, vec![doc], 0))
+        doc
     }
 
-    fn reply_cmd_sys_inprog(&self, req: &MsgQuery, db: &str) -> Result<Reply> {
+    // currentOp, either as a query on <db>.$cmd.sys.inprog or as a
+    // command.  nothing here runs long enough to be worth listing, so
+    // the list is always empty.
+    fn reply_current_op(&self, req: &MsgQuery) -> Result<Reply> {
         let mut doc = bson::Document::new_empty();
         doc.set_array("inprog", bson::Array::new_empty());
         doc.set_i32("ok", 1);
         Ok(create_reply(req.req_id, vec![doc], 0))
     }
 
-    fn reply_admin_cmd(&self, req: &MsgQuery, db: &str) -> Result<Reply> {
+    // there are no connections to other servers, so there is nothing
+    // to drop.
+    fn reply_drop_connections(&self, req: &MsgQuery) -> Result<Reply> {
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    // {getParameter: 1, name: 1, ...} or {getParameter: "*"} for all of them
+    fn reply_get_parameter(&self, req: &MsgQuery) -> Result<Reply> {
+        let all =
+            match req.query.pairs[0].1 {
+                bson::Value::BString(ref s) => s == "*",
+                _ => false,
+            };
+        let mut doc = bson::Document::new_empty();
+        if all {
+            for &(ref k, ref v) in self.parameters.pairs.iter() {
+                doc.set(k, v.clone());
+            }
+        } else {
+            for &(ref k, _) in req.query.pairs.iter().skip(1) {
+                match self.parameters.get(k) {
+                    Some(v) => {
+                        doc.set(k, v.clone());
+                    },
+                    None => (),
+                }
+            }
+            if doc.pairs.is_empty() {
+                return Err(Error::Misc(String::from("no option found to get")));
+            }
+        }
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    // {setParameter: 1, name: value, ...}.  only the parameters we know
+    // about can be set, and nothing changes unless they all are known.
+    // with one parameter, was holds its old value, as in mongo.  with
+    // several, was is a document of the old values by name.
+    fn reply_set_parameter(&mut self, req: &MsgQuery) -> Result<Reply> {
+        let pairs = &req.query.pairs[1 ..];
+        if pairs.is_empty() {
+            return Err(Error::Misc(String::from("no option found to set")));
+        }
+        for &(ref k, _) in pairs {
+            if self.parameters.get(k).is_none() {
+                return Err(Error::Misc(format!("attempted to set unrecognized parameter: {}", k)));
+            }
+        }
+        let mut was = bson::Document::new_empty();
+        for &(ref k, ref v) in pairs {
+            let cur = self.parameters.get_mut(k).unwrap();
+            was.set(k, std::mem::replace(cur, v.clone()));
+        }
+        let mut doc = bson::Document::new_empty();
+        if was.pairs.len() == 1 {
+            let (_, v) = was.pairs.pop().unwrap();
+            doc.set("was", v);
+        } else {
+            doc.set_document("was", was);
+        }
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    fn reply_rename_collection(&mut self, req: &MsgQuery) -> Result<Reply> {
+        let old_name = try!(req.query.must_get_str("renameCollection"));
+        let new_name = try!(req.query.must_get_str("to"));
+        let drop_target =
+            match req.query.get("dropTarget") {
+                Some(v) => try!(v.as_bool()),
+                None => false,
+            };
+        self.remove_cursors_for_collection(old_name);
+        if drop_target {
+            self.remove_cursors_for_collection(new_name);
+        }
+        try!(self.conn.rename_collection(old_name, new_name, drop_target));
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    fn reply_list_commands(&self, req: &MsgQuery) -> Result<Reply> {
+        fn info(admin_only: bool) -> bson::Document {
+            let mut doc = bson::Document::new_empty();
+            doc.set_str("help", "");
+            doc.set_bool("slaveOk", true);
+            doc.set_bool("adminOnly", admin_only);
+            doc
+        }
+        let mut commands = bson::Document::new_empty();
+        for name in ADMIN_COMMANDS {
+            commands.set_document(name, info(true));
+        }
+        for name in DB_COMMANDS {
+            commands.set_document(name, info(false));
+        }
+        let mut doc = bson::Document::new_empty();
+        doc.set_document("commands", commands);
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    fn reply_admin_cmd(&mut self, req: &MsgQuery, db: &str) -> Result<Reply> {
         use std::ascii::AsciiExt;
         if req.query.pairs.is_empty() {
             Err(Error::Misc(String::from("empty query")))
         } else {
             // this code assumes that the first key is always the command
             let cmd = req.query.pairs[0].0.clone().to_ascii_lowercase();
+            self.metrics.command(&cmd);
             let res =
                 match cmd.as_str() {
                     "whatsmyuri" => self.reply_whatsmyuri(req),
                     "getlog" => self.reply_getlog(req),
                     "replsetgetstatus" => self.reply_replsetgetstatus(req),
+                    "hostinfo" => self.reply_host_info(req),
+                    "bulkwrite" => self.reply_bulk_write(req),
+                    "fsync" => self.reply_fsync(req),
+                    "serverstatus" => self.reply_server_status(req),
+                    "currentop" => self.reply_current_op(req),
+                    "dropconnections" => self.reply_drop_connections(req),
                     "ismaster" => self.reply_ismaster(req),
+                    "hello" => self.reply_hello(req),
+                    "renamecollection" => self.reply_rename_collection(req),
+                    "getparameter" => self.reply_get_parameter(req),
+                    "setparameter" => self.reply_set_parameter(req),
+                    "listcommands" => self.reply_list_commands(req),
                     _ => Err(Error::Misc(format!("unknown admin cmd: {}", cmd)))
                 };
+            if res.is_err() {
+                Metrics::bump(&self.metrics.errors, 1);
+            }
             res
         }
     }
@@ -343,7 +955,8 @@ impl<'b> Server<'b> {
         // TODO ordered
         let result = try!(self.conn.delete(db, coll, &deletes.items));
         let mut doc = bson::Document::new_empty();
-        doc.set_i32("ok", result as i32);
+        doc.set_i32("n", result as i32);
+        doc.set_i32("ok", 1);
         Ok(create_reply(req.req_id, vec![doc], 0))
     }
 
@@ -363,17 +976,29 @@ impl<'b> Server<'b> {
         let coll = try!(req.query.must_remove_string("insert"));
 
         let docs = try!(req.query.must_remove_array("documents"));
+        if docs.items.len() > MAX_WRITE_BATCH_SIZE as usize {
+            return Err(Error::Misc(format!("insert batch too large: {} documents, max is {}", docs.items.len(), MAX_WRITE_BATCH_SIZE)));
+        }
         let mut docs = try!(vec_values_to_docs(docs.items));
+        // conn.insert gives an ObjectId to every doc without an _id.
+        // remember which ones, so the reply can say what they got.
+        let had_id = docs.iter().map(|d| d.get("_id").is_some()).collect::<Vec<_>>();
 
         // TODO ordered
-        // TODO do we need to keep ownership of docs?
         let results = try!(self.conn.insert(db, &coll, &mut docs));
         let mut errors = Vec::new();
+        let mut generated = Vec::new();
         for i in 0 .. results.len() {
             if results[i].is_err() {
                 let msg = format!("{:?}", results[i]);
                 let err = bson::Value::BDocument(bson::Document {pairs: vec![(String::from("index"), bson::Value::BInt32(i as i32)), (String::from("errmsg"), bson::Value::BString(msg))]});
                 errors.push(err);
+            } else if !had_id[i] {
+                let id = try!(docs[i].must_get("_id")).clone();
+                let mut d = bson::Document::new_empty();
+                d.set_i32("index", i as i32);
+                d.set("_id", id);
+                generated.push(bson::Value::BDocument(d));
             }
         }
         let mut doc = bson::Document::new_empty();
@@ -381,11 +1006,124 @@ impl<'b> Server<'b> {
         if errors.len() > 0 {
             doc.set_array("writeErrors", bson::Array {items: errors});
         }
+        // not part of the mongo reply, which leaves ids to the driver,
+        // but laid out like the upserted array in an update reply.
+        if generated.len() > 0 {
+            doc.set_array("insertedIds", bson::Array {items: generated});
+        }
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    // one op from a bulkWrite.  the first key says what kind of op it
+    // is, and its value is an index into nsInfo.
+    fn bulk_write_op(&self, namespaces: &[(String, String)], mut op: bson::Document, counts: &mut BulkWriteCounts) -> Result<()> {
+        if op.pairs.len() == 0 {
+            return Err(Error::Misc(String::from("bulkWrite: empty op")));
+        }
+        let kind = op.pairs[0].0.clone();
+        let ndx = try!(op.pairs[0].1.numeric_to_i32());
+        if ndx < 0 || (ndx as usize) >= namespaces.len() {
+            return Err(Error::Misc(format!("bulkWrite: no nsInfo entry {}", ndx)));
+        }
+        let (ref db, ref coll) = namespaces[ndx as usize];
+        let multi = match op.get("multi") {
+            Some(v) => try!(v.as_bool()),
+            None => false,
+        };
+        match kind.as_str() {
+            "insert" => {
+                let doc = try!(op.must_remove_document("document"));
+                let mut docs = vec![doc];
+                let results = try!(self.conn.insert(db, coll, &mut docs));
+                for r in results {
+                    try!(r);
+                    counts.inserted = counts.inserted + 1;
+                }
+            },
+            "update" => {
+                let upsert = match op.get("upsert") {
+                    Some(v) => try!(v.as_bool()),
+                    None => false,
+                };
+                let mut upd = bson::Document::new_empty();
+                upd.set_document("q", try!(op.must_remove_document("filter")));
+                upd.set_document("u", try!(op.must_remove_document("updateMods")));
+                upd.set_bool("multi", multi);
+                upd.set_bool("upsert", upsert);
+                let mut updates = vec![upd];
+                let results = try!(self.conn.update(db, coll, &mut updates));
+                for r in results {
+                    let (matched, modified, upserted) = try!(r);
+                    counts.matched = counts.matched + matched as i32;
+                    counts.modified = counts.modified + modified as i32;
+                    if upserted.is_some() {
+                        counts.upserted = counts.upserted + 1;
+                    }
+                }
+            },
+            "delete" => {
+                let mut del = bson::Document::new_empty();
+                del.set_document("q", try!(op.must_remove_document("filter")));
+                del.set_i32("limit", if multi { 0 } else { 1 });
+                let n = try!(self.conn.delete(db, coll, &vec![bson::Value::BDocument(del)]));
+                counts.removed = counts.removed + n as i32;
+            },
+            _ => {
+                return Err(Error::Misc(format!("bulkWrite: unknown op: {}", kind)));
+            },
+        }
+        Ok(())
+    }
+
+    // each op goes through the same paths as the insert, update and
+    // delete commands, so a bulkWrite is no more atomic than sending
+    // those one at a time.
+    fn reply_bulk_write(&self, req: &MsgQuery) -> Result<Reply> {
+        let ops = try!(req.query.must_get_array("ops")).clone();
+        let ns_info = try!(req.query.must_get_array("nsInfo"));
+        let ordered = match req.query.get("ordered") {
+            Some(v) => try!(v.as_bool()),
+            None => true,
+        };
+        let mut namespaces = Vec::new();
+        for v in &ns_info.items {
+            let d = try!(v.as_document());
+            let ns = try!(d.must_get_str("ns"));
+            match ns.find('.') {
+                Some(dot) => namespaces.push((String::from(&ns[0 .. dot]), String::from(&ns[dot + 1 ..]))),
+                None => return Err(Error::Misc(format!("bulkWrite: bad namespace: {}", ns))),
+            }
+        }
+        let ops = try!(vec_values_to_docs(ops.items));
+        let mut counts = BulkWriteCounts { inserted: 0, matched: 0, modified: 0, removed: 0, upserted: 0 };
+        let mut errors = Vec::new();
+        for (i, op) in ops.into_iter().enumerate() {
+            match self.bulk_write_op(&namespaces, op, &mut counts) {
+                Ok(()) => (),
+                Err(e) => {
+                    let mut err = bson::Document::new_empty();
+                    err.set_i32("index", i as i32);
+                    err.set_string("errmsg", format!("{:?}", e));
+                    errors.push(bson::Value::BDocument(err));
+                    if ordered {
+                        break;
+                    }
+                },
+            }
+        }
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("nInserted", counts.inserted);
+        doc.set_i32("nMatched", counts.matched);
+        doc.set_i32("nModified", counts.modified);
+        doc.set_i32("nRemoved", counts.removed);
+        doc.set_i32("nUpserted", counts.upserted);
+        doc.set_array("writeErrors", bson::Array {items: errors});
         doc.set_i32("ok", 1);
         Ok(create_reply(req.req_id, vec![doc], 0))
     }
 
-    fn store_cursor<T: Iterator<Item=Result<elmo::Row>> + 'b>(&mut self, ns: &str, seq: T) -> i64 {
+    fn store_cursor<T: Iterator<Item=Result<elmo::Row>> + 'static>(&mut self, ns: &str, seq: T) -> i64 {
         self.cursor_num = self.cursor_num + 1;
         self.cursors.insert(self.cursor_num, (String::from(ns), box seq));
         self.cursor_num
@@ -398,25 +1136,37 @@ impl<'b> Server<'b> {
         }
     }
 
-    // grab is just a take() which doesn't take ownership of the iterator
+    // grab is just a take() which doesn't take ownership of the iterator,
+    // except that it also stops once the documents add up to max_bytes.
+    // the bool is true if it stopped for either limit, in which case we
+    // assume the sequence has more.  any projection has already been
+    // applied by the time rows get here, so it is the projected size
+    // that counts against max_bytes, not the stored size.
     // TODO investigate by_ref()
-    fn grab<T: Iterator<Item=Result<elmo::Row>>>(seq: &mut T, n: usize) -> Result<Vec<elmo::Row>> {
+    fn grab<T: Iterator<Item=Result<elmo::Row>>>(seq: &mut T, n: usize, max_bytes: usize) -> Result<(Vec<elmo::Row>, bool)> {
         let mut r = Vec::new();
-        for _ in 0 .. n {
+        let mut bytes = 0;
+        while r.len() < n {
             match seq.next() {
                 None => {
-                    break;
+                    return Ok((r, false));
                 },
                 Some(v) => {
-                    r.push(try!(v));
+                    let v = try!(v);
+                    // TODO this encodes every document twice
+                    bytes = bytes + try!(v.doc.to_bson_array()).len();
+                    r.push(v);
+                    if bytes >= max_bytes {
+                        return Ok((r, true));
+                    }
                 },
             }
         }
-        Ok(r)
+        Ok((r, true))
     }
 
     // this is the older way of returning a cursor.
-    fn do_limit<T: Iterator<Item=Result<elmo::Row>>>(ns: &str, seq: &mut T, number_to_return: i32) -> Result<(Vec<elmo::Row>, bool)> {
+    fn do_limit<T: Iterator<Item=Result<elmo::Row>>>(ns: &str, seq: &mut T, number_to_return: i32, max_bytes: usize) -> Result<(Vec<elmo::Row>, bool)> {
         if number_to_return < 0 || number_to_return == 1 {
             // hard limit.  do not return a cursor.
             let n = if number_to_return < 0 {
@@ -432,12 +1182,12 @@ impl<'b> Server<'b> {
             Ok((docs, false))
         } else if number_to_return == 0 {
             // return whatever the default size is
-            // TODO for now, just return them all and close the cursor
-            let docs = try!(seq.collect::<Result<Vec<_>>>());
-            Ok((docs, false))
+            // TODO for now, return as many as fit in one batch
+            let (docs, more) = try!(Self::grab(seq, std::usize::MAX, max_bytes));
+            Ok((docs, more))
         } else {
             // soft limit.  keep cursor open.
-            let docs = try!(Self::grab(seq, number_to_return as usize));
+            let (docs, _) = try!(Self::grab(seq, number_to_return as usize, max_bytes));
             if docs.len() > 0 {
                 Ok((docs, true))
             } else {
@@ -504,22 +1254,29 @@ impl<'b> Server<'b> {
                     // but we need to eval the first item in the seq,
                     // to make sure that an error gets found now.
                     // but we can't consume that first item and let it
-                    // get lost.  so we grab a batch but then put it back.
-
-                    // TODO peek, or something
-                    let cursor_id = self.store_cursor(ns, seq);
-                    (Vec::new(), Some(cursor_id))
+                    // get lost.  so we take it and then put it back.
+                    match seq.next() {
+                        None => (Vec::new(), None),
+                        Some(r) => {
+                            let seq = PutBack {
+                                first: Some(try!(r)),
+                                rest: seq,
+                            };
+                            let cursor_id = self.store_cursor(ns, seq);
+                            (Vec::new(), Some(cursor_id))
+                        },
+                    }
                 },
                 Some(n) => {
-                    let docs = try!(Self::grab(&mut seq, n));
-                    if docs.len() == n {
-                        // if we grabbed the same number we asked for, we assume the
-                        // sequence has more, so we store the cursor and return it.
+                    let (docs, more) = try!(Self::grab(&mut seq, n, self.max_batch_bytes));
+                    if more {
+                        // if we stopped at the number we asked for, or at the size
+                        // limit, we assume the sequence has more, so we store the
+                        // cursor and return it.
                         let cursor_id = self.store_cursor(ns, seq);
                         (docs, Some(cursor_id))
                     } else {
-                        // but if we got less than we asked for, we assume we have
-                        // consumed the whole sequence.
+                        // but if we ran out first, we have consumed the whole sequence.
                         (docs, None)
                     }
                 },
@@ -527,12 +1284,15 @@ impl<'b> Server<'b> {
 
 
         let mut doc = bson::Document::new_empty();
-        match cursor_id {
-            Some(cursor_id) => {
+        match number_to_return {
+            Some(_) => {
+                // a cursor was requested, so we reply with one, even if it
+                // has already been exhausted, in which case its id is 0.
                 let mut cursor = bson::Document::new_empty();
-                cursor.set_i64("id", cursor_id);
+                cursor.set_i64("id", cursor_id.unwrap_or(0));
                 cursor.set_str("ns", ns);
                 cursor.set_array("firstBatch", bson::Array { items: vec_rows_to_values(docs)});
+                doc.set_document("cursor", cursor);
             },
             None => {
                 doc.set_array("result", bson::Array { items: vec_rows_to_values(docs)});
@@ -613,8 +1373,23 @@ impl<'b> Server<'b> {
         Ok(create_reply(req.req_id, vec![doc], 0))
     }
 
-    fn reply_delete_indexes(&mut self, req: &MsgQuery, db: &str) -> Result<Reply> {
-        let coll = try!(req.query.must_get_str("deleteIndexes"));
+    fn reply_coll_mod(&mut self, mut req: MsgQuery, db: &str) -> Result<Reply> {
+        let coll = try!(req.query.must_remove_string("collMod"));
+        // everything else in the command is a modification
+        try!(self.conn.coll_mod(db, &coll, req.query));
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    // deleteIndexes is the older name for dropIndexes.  the collection
+    // name is the value of whichever one was sent.
+    fn reply_drop_indexes(&mut self, req: &MsgQuery, db: &str) -> Result<Reply> {
+        let coll = 
+            match req.query.get("dropIndexes") {
+                Some(_) => try!(req.query.must_get_str("dropIndexes")),
+                None => try!(req.query.must_get_str("deleteIndexes")),
+            };
         {
             // TODO is it safe/correct/necessary to remove the cursors BEFORE?
             let full_coll = format!("{}.{}", db, coll);
@@ -623,7 +1398,13 @@ impl<'b> Server<'b> {
         let index = try!(req.query.must_get("index"));
         let (count_indexes_before, num_indexes_deleted) = try!(self.conn.delete_indexes(db, coll, index));
         let mut doc = bson::Document::new_empty();
-        doc.set_i32("ok", 1);
+        doc.set_i32("nIndexesWas", count_indexes_before as i32);
+        if num_indexes_deleted == 0 && !(index.is_string() && try!(index.as_str()) == "*") {
+            doc.set_str("errmsg", "index not found");
+            doc.set_i32("ok", 0);
+        } else {
+            doc.set_i32("ok", 1);
+        }
         Ok(create_reply(req.req_id, vec![doc], 0))
     }
 
@@ -819,39 +1600,236 @@ impl<'b> Server<'b> {
         }
     }
 
-    fn reply_count(&mut self, req: MsgQuery, db: &str) -> Result<Reply> {
+    // the command form of a query, used by newer drivers instead of OP_QUERY
+    fn reply_find(&mut self, req: MsgQuery, db: &str) -> Result<Reply> {
         let MsgQuery {
             req_id,
-            flags,
-            full_collection_name,
-            number_to_skip,
-            number_to_return,
             mut query,
-            return_fields_selector,
+            ..
         } = req;
-        let coll = try!(query.must_remove_string("count"));
+        let coll = try!(query.must_remove_string("find"));
+        let filter =
+            match query.remove("filter") {
+                Some(v) => try!(v.into_document()),
+                None => bson::Document::new_empty(),
+            };
+        let sort = query.remove("sort");
+        let projection =
+            match query.remove("projection") {
+                Some(v) => Some(try!(v.into_document())),
+                None => None,
+            };
         let hint = query.remove("hint");
-        let q = try!(query.must_remove_document("query"));
+        let skip =
+            match query.remove("skip") {
+                Some(v) => try!(v.numeric_to_i64()),
+                None => 0,
+            };
+        let limit =
+            match query.remove("limit") {
+                Some(v) => try!(v.numeric_to_i64()),
+                None => 0,
+            };
+        if skip < 0 {
+            return Err(Error::Misc(format!("negative skip: {}", skip)));
+        }
+        if limit < 0 {
+            return Err(Error::Misc(format!("negative limit: {}", limit)));
+        }
+        let mut cursor_options = bson::Document::new_empty();
+        match query.remove("batchSize") {
+            Some(v) => {
+                cursor_options.set("batchSize", v);
+            },
+            None => cursor_options.set_i32("batchSize", 101),
+        }
+        let cursor_options = bson::Value::BDocument(cursor_options);
+
         let seq = try!(self.conn.find(
-                db, 
-                &coll, 
-                q,
-                None,
-                None,
+                db,
+                &coll,
+                filter,
+                sort,
+                projection,
                 None,
                 None,
                 hint,
                 None
                 ));
-        let count = seq.count();
-        // TODO skip/limit
-        let mut doc = bson::Document::new_empty();
-        doc.set_i32("n", count as i32);
-        doc.set_i32("ok", 1);
+        let seq = seq.skip(skip as usize);
+        let seq: Box<Iterator<Item=Result<elmo::Row>>> =
+            if limit > 0 {
+                box seq.take(limit as usize)
+            } else {
+                box seq
+            };
+        let ns = format!("{}.{}", db, coll);
+        let doc = try!(self.reply_with_cursor(&ns, seq, Some(&cursor_options), 101));
+        Ok(create_reply(req_id, vec![doc], 0))
+    }
+
+    // the newer form of explain, as a command wrapping another command.
+    // we only report the plan.  nothing gets executed.
+    fn reply_explain(&mut self, mut req: MsgQuery, db: &str) -> Result<Reply> {
+        use std::ascii::AsciiExt;
+        let mut inner = try!(req.query.must_remove_document("explain"));
+        if inner.pairs.is_empty() {
+            return Err(Error::Misc(String::from("explain: empty command")));
+        }
+        let (cmd, coll) = {
+            let (ref k, ref v) = inner.pairs[0];
+            (k.to_ascii_lowercase(), String::from(try!(v.as_str())))
+        };
+        let (q, hint) =
+            match cmd.as_str() {
+                "find" => (inner.remove("filter"), inner.remove("hint")),
+                "count" => (inner.remove("query"), inner.remove("hint")),
+                _ => return Err(Error::Misc(format!("explain not supported for: {}", cmd))),
+            };
+        let q =
+            match q {
+                Some(q) => try!(q.into_document()),
+                None => bson::Document::new_empty(),
+            };
+        let doc = try!(self.explain_query(db, &coll, q, None, None, hint));
         Ok(create_reply(req.req_id, vec![doc], 0))
     }
 
-    fn reply_query(&mut self, req: MsgQuery, db: &str) -> Result<Reply> {
+    fn explain_query(&self, db: &str, coll: &str, q: bson::Document, min: Option<bson::Value>, max: Option<bson::Value>, hint: Option<bson::Value>) -> Result<bson::Document> {
+        let ndx = try!(self.conn.explain_find(db, coll, q.clone(), min, max, hint));
+
+        let mut winning = bson::Document::new_empty();
+        match ndx {
+            Some(ndx) => {
+                winning.set_str("stage", "IXSCAN");
+                winning.set_string("indexName", ndx.name);
+                winning.set_document("keyPattern", ndx.spec);
+            },
+            None => {
+                winning.set_str("stage", "COLLSCAN");
+            },
+        }
+        let mut planner = bson::Document::new_empty();
+        planner.set_string("namespace", format!("{}.{}", db, coll));
+        planner.set_document("parsedQuery", q);
+        planner.set_document("winningPlan", winning);
+        let mut doc = bson::Document::new_empty();
+        doc.set_document("queryPlanner", planner);
+        doc.set_i32("ok", 1);
+        Ok(doc)
+    }
+
+    fn reply_count(&mut self, req: MsgQuery, db: &str) -> Result<Reply> {
+        let MsgQuery {
+            req_id,
+            flags,
+            full_collection_name,
+            number_to_skip,
+            number_to_return,
+            mut query,
+            return_fields_selector,
+        } = req;
+        let coll = try!(query.must_remove_string("count"));
+        let hint = query.remove("hint");
+        let q = try!(query.must_remove_document("query"));
+        let seq = try!(self.conn.find(
+                db, 
+                &coll, 
+                q,
+                None,
+                None,
+                None,
+                None,
+                hint,
+                None
+                ));
+        let count = seq.count();
+        // TODO skip/limit
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("n", count as i32);
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    fn reply_coll_stats(&mut self, req: &MsgQuery, db: &str) -> Result<Reply> {
+        let coll = try!(req.query.must_get_str("collStats"));
+        let stats = try!(self.conn.collection_stats(db, coll));
+        let mut doc = bson::Document::new_empty();
+        doc.set_string("ns", format!("{}.{}", db, coll));
+        doc.set_i64("count", stats.count as i64);
+        doc.set_i64("size", stats.size as i64);
+        if stats.count > 0 {
+            doc.set_i64("avgObjSize", (stats.size / stats.count) as i64);
+        }
+        // TODO sqlite doesn't tell us how much space a table uses
+        doc.set_i64("storageSize", stats.size as i64);
+        doc.set_i32("nindexes", stats.nindexes as i32);
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    fn reply_server_status(&self, req: &MsgQuery) -> Result<Reply> {
+        let mut doc = bson::Document::new_empty();
+        doc.set_document("metrics", self.metrics.to_document());
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    // the storage layer is sqlite in WAL mode, where a commit is durable
+    // once it is in the log.  fsync moves the log into the data files.
+    // there is no way to hold off other connections' writes, so lock
+    // is refused rather than quietly ignored.
+    fn reply_fsync(&mut self, req: &MsgQuery) -> Result<Reply> {
+        match req.query.get("lock") {
+            Some(&bson::Value::BBoolean(true)) => {
+                return Err(Error::Misc(String::from("fsync with lock is not supported")));
+            },
+            _ => (),
+        }
+        let num_files = try!(self.conn.checkpoint());
+        let mut doc = bson::Document::new_empty();
+        doc.set_i32("numFiles", num_files as i32);
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    // the storage layer compacts everything at once, so the collection
+    // named by the command is not used.
+    fn reply_compact(&mut self, req: &MsgQuery) -> Result<Reply> {
+        let freed = try!(self.conn.compact());
+        let mut doc = bson::Document::new_empty();
+        doc.set_i64("bytesFreed", freed as i64);
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    fn reply_db_stats(&mut self, req: &MsgQuery, db: &str) -> Result<Reply> {
+        let collections = try!(self.conn.list_collections()).into_iter().filter(|c| c.db == db).collect::<Vec<_>>();
+        let mut objects = 0;
+        let mut size = 0;
+        let mut indexes = 0;
+        for c in collections.iter() {
+            let stats = try!(self.conn.collection_stats(db, &c.coll));
+            objects = objects + stats.count;
+            size = size + stats.size;
+            indexes = indexes + stats.nindexes;
+        }
+        let mut doc = bson::Document::new_empty();
+        doc.set_str("db", db);
+        doc.set_i32("collections", collections.len() as i32);
+        doc.set_i64("objects", objects as i64);
+        if objects > 0 {
+            doc.set_i64("avgObjSize", (size / objects) as i64);
+        }
+        doc.set_i64("dataSize", size as i64);
+        // TODO see reply_coll_stats
+        doc.set_i64("storageSize", size as i64);
+        doc.set_i32("indexes", indexes as i32);
+        doc.set_i32("ok", 1);
+        Ok(create_reply(req.req_id, vec![doc], 0))
+    }
+
+    fn reply_query(&mut self, req: MsgQuery, db: &str) -> Result<Reply> {
         let MsgQuery {
             req_id,
             flags,
@@ -880,7 +1858,16 @@ impl<'b> Server<'b> {
                     let max = Self::try_remove_optional_prefix(&mut query, "$max");
                     let hint = Self::try_remove_optional_prefix(&mut query, "$hint");
                     let explain = Self::try_remove_optional_prefix(&mut query, "$explain");
+                    // $comment is only for the profiler/log, so it gets dropped
+                    let _ = Self::try_remove_optional_prefix(&mut query, "$comment");
                     let q = try!(q.into_document());
+                    match explain {
+                        None | Some(bson::Value::BBoolean(false)) => (),
+                        Some(_) => {
+                            let doc = try!(self.explain_query(db, coll, q, min, max, hint));
+                            return Ok(create_reply(req_id, vec![doc], 0));
+                        },
+                    }
                     let seq = try!(self.conn.find(
                             db, 
                             coll, 
@@ -900,7 +1887,7 @@ impl<'b> Server<'b> {
                             coll, 
                             query,
                             None,
-                            None,
+                            return_fields_selector,
                             None,
                             None,
                             None,
@@ -913,22 +1900,27 @@ impl<'b> Server<'b> {
         // TODO let s = crud.seqOnlyDoc s
 
         if number_to_skip < 0 {
-            panic!("TODO negative skip");
+            return Err(Error::Misc(format!("negative skip: {}", number_to_skip)));
         }
 
         let seq = seq.skip(number_to_skip as usize);
 
-        let mut seq = seq.map(
+        let seq = seq.map(
             |r| r.map_err(elmo::wrap_err)
         );
 
         //let docs = try!(Self::grab(&mut seq, number_to_return as usize));
         //Ok(create_reply(req_id, docs, 0))
 
-        let (docs, more) = try!(Self::do_limit(&full_collection_name, &mut seq, number_to_return));
+        self.reply_with_limit(req_id, &full_collection_name, seq, number_to_return)
+    }
+
+    // the older way of replying to a query, with the cursor id in the
+    // reply header.  pairs with do_limit().
+    fn reply_with_limit<T: Iterator<Item=Result<elmo::Row>> + 'static>(&mut self, req_id: i32, ns: &str, mut seq: T, number_to_return: i32) -> Result<Reply> {
+        let (docs, more) = try!(Self::do_limit(ns, &mut seq, number_to_return, self.max_batch_bytes));
         let cursor_id = if more {
-            self.store_cursor(&full_collection_name, seq)
-            //0
+            self.store_cursor(ns, seq)
         } else {
             0
         };
@@ -937,6 +1929,70 @@ impl<'b> Server<'b> {
         Ok(create_reply(req_id, docs, cursor_id))
     }
 
+    fn reply_system_namespaces(&mut self, req: MsgQuery, db: &str) -> Result<Reply> {
+        let results = try!(self.conn.list_collections());
+        let seq = {
+            let db = String::from(db);
+            results.into_iter().filter_map(
+                move |c| {
+                    if db.as_str() == c.db {
+                        let mut doc = bson::Document::new_empty();
+                        doc.set_string("name", format!("{}.{}", c.db, c.coll));
+                        doc.set_document("options", c.options);
+                        let r = elmo::Row {
+                            doc: bson::Value::BDocument(doc),
+                        };
+                        Some(Ok(r))
+                    } else {
+                        None
+                    }
+                }
+                )
+        };
+
+        // TODO filter in query?
+
+        if req.number_to_skip < 0 {
+            return Err(Error::Misc(format!("negative skip: {}", req.number_to_skip)));
+        }
+
+        let seq = seq.skip(req.number_to_skip as usize);
+        self.reply_with_limit(req.req_id, &req.full_collection_name, seq, req.number_to_return)
+    }
+
+    fn reply_system_indexes(&mut self, req: MsgQuery, db: &str) -> Result<Reply> {
+        let results = try!(self.conn.list_indexes());
+        let seq = {
+            let db = String::from(db);
+            results.into_iter().filter_map(
+                move |ndx| {
+                    if ndx.db.as_str() == db {
+                        let mut doc = bson::Document::new_empty();
+                        doc.set_i32("v", 1);
+                        doc.set_string("ns", ndx.full_collection_name());
+                        doc.set_string("name", ndx.name);
+                        doc.set_document("key", ndx.spec);
+                        let r = elmo::Row {
+                            doc: bson::Value::BDocument(doc),
+                        };
+                        Some(Ok(r))
+                    } else {
+                        None
+                    }
+                }
+                )
+        };
+
+        // TODO filter in query?
+
+        if req.number_to_skip < 0 {
+            return Err(Error::Misc(format!("negative skip: {}", req.number_to_skip)));
+        }
+
+        let seq = seq.skip(req.number_to_skip as usize);
+        self.reply_with_limit(req.req_id, &req.full_collection_name, seq, req.number_to_return)
+    }
+
     fn reply_cmd(&mut self, req: MsgQuery, db: &str) -> Result<Reply> {
         use std::ascii::AsciiExt;
         if req.query.pairs.is_empty() {
@@ -944,28 +2000,39 @@ impl<'b> Server<'b> {
         } else {
             // this code assumes that the first key is always the command
             let cmd = req.query.pairs[0].0.clone().to_ascii_lowercase();
+            self.metrics.command(&cmd);
             let res =
                 // TODO isMaster needs to be in here?
                 match cmd.as_str() {
-                    //"explain" => reply_explain req db
+                    "explain" => self.reply_explain(req, db),
                     "aggregate" => self.reply_aggregate(req, db),
+                    "find" => self.reply_find(req, db),
                     "insert" => self.reply_insert(req, db),
                     "delete" => self.reply_delete(&req, db),
                     //"distinct" => reply_distinct req db
                     "update" => self.reply_update(req, db),
                     //"findandmodify" => reply_FindAndModify req db
                     "count" => self.reply_count(req, db),
+                    "collstats" => self.reply_coll_stats(&req, db),
+                    "dbstats" => self.reply_db_stats(&req, db),
+                    "compact" => self.reply_compact(&req),
                     "validate" => self.reply_validate(req, db),
                     "createindexes" => self.reply_create_indexes(req, db),
-                    "deleteindexes" => self.reply_delete_indexes(&req, db),
+                    "deleteindexes" => self.reply_drop_indexes(&req, db),
+                    "dropindexes" => self.reply_drop_indexes(&req, db),
+                    "collmod" => self.reply_coll_mod(req, db),
                     "drop" => self.reply_drop_collection(&req, db),
                     "dropdatabase" => self.reply_drop_database(&req, db),
                     "listcollections" => self.reply_list_collections(&req, db),
                     "listindexes" => self.reply_list_indexes(&req, db),
                     "create" => self.reply_create_collection(&req, db),
+                    "listcommands" => self.reply_list_commands(&req),
                     //"features" => reply_features &req db
                     _ => Err(Error::Misc(format!("unknown cmd: {}", cmd)))
                 };
+            if res.is_err() {
+                Metrics::bump(&self.metrics.errors, 1);
+            }
             res
         }
     }
@@ -974,15 +2041,15 @@ impl<'b> Server<'b> {
         // reallocating the strings here so we can pass ownership of req down the line.
         // TODO we could deconstruct req now?
         let parts = req.full_collection_name.split('.').map(|s| String::from(s)).collect::<Vec<_>>();
-        // TODO check for bad collection name here
         let req_id = req.req_id;
         let r = 
-            if parts.len() < 2 {
-                // TODO failwith (sprintf "bad collection name: %s" (req.full_collection_name))
-                Err(Error::Misc(String::from("bad collection name")))
+            if let Err(e) = validate_name_parts(&req.full_collection_name, &parts) {
+                Err(e)
             } else {
                 let db = &parts[0];
-                if db == "admin" {
+                if parts.len() == 4 && parts[1] == "$cmd" && parts[2]=="sys" && parts[3]=="inprog" {
+                    self.reply_current_op(&req)
+                } else if db == "admin" {
                     if parts[1] == "$cmd" {
                         //reply_AdminCmd req
                         // TODO probably want to pass ownership of req down here
@@ -992,22 +2059,19 @@ impl<'b> Server<'b> {
                     }
                 } else {
                     if parts[1] == "$cmd" {
-                        if parts.len() == 4 && parts[2]=="sys" && parts[3]=="inprog" {
-                            self.reply_cmd_sys_inprog(&req, db)
-                            //Err(Error::Misc(format!("TODO: {:?}", req)))
-                        } else {
-                            self.reply_cmd(req, db)
-                        }
+                        self.reply_cmd(req, db)
                     } else if parts.len()==3 && parts[1]=="system" && parts[2]=="indexes" {
-                        //reply_system_indexes req db
-                        Err(Error::Misc(format!("TODO: {:?}", req)))
+                        self.reply_system_indexes(req, db)
                     } else if parts.len()==3 && parts[1]=="system" && parts[2]=="namespaces" {
-                        //reply_system_namespaces req db
-                        Err(Error::Misc(format!("TODO: {:?}", req)))
+                        self.reply_system_namespaces(req, db)
                     } else {
+                        Metrics::bump(&self.metrics.queries, 1);
                         match self.reply_query(req, db) {
                             Ok(r) => Ok(r),
-                            Err(e) => Ok(reply_err(req_id, e)),
+                            Err(e) => {
+                                Metrics::bump(&self.metrics.errors, 1);
+                                Ok(reply_err(req_id, e))
+                            },
                         }
                     }
                 }
@@ -1020,11 +2084,11 @@ impl<'b> Server<'b> {
         // TODO this function should be using reply_code
         match self.cursors.remove(&req.cursor_id) {
             Some((ns, mut seq)) => {
-                match Self::do_limit(&ns, &mut seq, req.number_to_return) {
+                match Self::do_limit(&ns, &mut seq, req.number_to_return, self.max_batch_bytes) {
                     Ok((docs, more)) => {
                         if more {
                             // put the cursor back for next time
-                            self.cursors.insert(req.cursor_id, (ns, box seq));
+                            self.cursors.insert(req.cursor_id, (ns, seq));
                         }
                         let docs = vec_rows_to_values(docs);
                         match vec_values_to_docs(docs) {
@@ -1047,12 +2111,19 @@ impl<'b> Server<'b> {
         }
     }
 
-    fn handle_one_message(&mut self, stream: &mut std::net::TcpStream) -> Result<bool> {
-        fn send_reply(stream: &mut std::net::TcpStream, resp: Reply) -> Result<bool> {
+    fn handle_one_message(&mut self, stream: &mut std::net::TcpStream, timeout: std::time::Duration) -> Result<bool> {
+        // a reply gets compressed the same way its request was
+        fn send_reply(stream: &mut std::net::TcpStream, metrics: &Metrics, resp: Reply, compressor_id: Option<u8>) -> Result<bool> {
             //println!("resp: {:?}", resp);
-            let ba = resp.encode();
+            let ba = try!(resp.encode());
+            let ba =
+                match compressor_id {
+                    Some(c) => try!(compress_message(c, &ba)),
+                    None => ba,
+                };
             //println!("ba: {:?}", ba);
             let wrote = try!(misc::io::write_fully(stream, &ba));
+            Metrics::bump(&metrics.bytes_out, wrote);
             if wrote != ba.len() {
                 return Err(Error::Misc(String::from("network write failed")));
             } else {
@@ -1060,7 +2131,7 @@ impl<'b> Server<'b> {
             }
         }
 
-        let ba = try!(read_message_bytes(stream));
+        let ba = try!(read_message_bytes_within(stream, timeout, self.message_timeout));
         match ba {
             None => {
                 println!("no request");
@@ -1068,8 +2139,30 @@ impl<'b> Server<'b> {
             },
             Some(ba) => {
                 //println!("{:?}", ba);
-                let msg = try!(parse_request(&ba));
+                Metrics::bump(&self.metrics.bytes_in, ba.len());
+                let msg =
+                    match parse_request(&ba) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            // the whole message has been read, so the
+                            // stream is still in step.  answer with the
+                            // error, such as a collection name which is
+                            // not UTF-8, instead of hanging up.
+                            if !expects_reply(&ba) {
+                                println!("bad request: {:?}", e);
+                                return Ok(true);
+                            }
+                            let mut i = 0;
+                            let header = MsgHeader::parse(&ba, &mut i);
+                            return send_reply(stream, &self.metrics, reply_err(header.request_id, e), None);
+                        },
+                    };
                 println!("request: {:?}", msg);
+                let (msg, compressor_id) =
+                    match msg {
+                        Request::Compressed(c, msg) => (*msg, Some(c)),
+                        msg => (msg, None),
+                    };
                 match msg {
                     Request::KillCursors(req) => {
                         for cursor_id in req.cursor_ids {
@@ -1085,65 +2178,2299 @@ impl<'b> Server<'b> {
                                 Ok(r) => r,
                                 Err(e) => reply_errmsg(req_id, e),
                             };
-                        send_reply(stream, resp)
+                        send_reply(stream, &self.metrics, resp, compressor_id)
                     },
                     Request::GetMore(req) => {
                         let resp = self.reply_2005(req);
-                        send_reply(stream, resp)
+                        send_reply(stream, &self.metrics, resp, compressor_id)
+                    },
+                    Request::Compressed(_, _) => {
+                        unreachable!();
                     },
                 }
             }
         }
     }
 
-    fn handle_client(&mut self, mut stream: std::net::TcpStream) -> Result<()> {
+}
+
+// the connections which are still being served, so that shutdown()
+// can hang up on them and then wait for them to finish.
+struct LiveConnections {
+    streams: std::sync::Mutex<std::collections::HashMap<usize, std::net::TcpStream>>,
+    done: std::sync::Condvar,
+}
+
+struct LiveGuard {
+    id: usize,
+    live: std::sync::Arc<LiveConnections>,
+}
+
+impl Drop for LiveGuard {
+    fn drop(&mut self) {
+        let mut streams = self.live.streams.lock().unwrap();
+        streams.remove(&self.id);
+        self.live.done.notify_all();
+    }
+}
+
+pub struct ServerHandle {
+    addr: std::net::SocketAddr,
+    stop: std::sync::Arc<AtomicBool>,
+    accept: std::thread::JoinHandle<()>,
+}
+
+impl ServerHandle {
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    // stops accepting, closes every client connection, and returns
+    // after all of them are finished.
+    pub fn shutdown(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // the accept loop is blocked, so connect to wake it up
+        let _ = std::net::TcpStream::connect(self.addr);
+        let _ = self.accept.join();
+    }
+
+    pub fn wait(self) {
+        let _ = self.accept.join();
+    }
+}
+
+pub fn spawn_server(addr: &str, datadir: &str) -> Result<ServerHandle> {
+    spawn_server_with_workers(addr, datadir, DEFAULT_WORKERS)
+}
+
+// a client which a worker is serving
+struct Client {
+    stream: std::net::TcpStream,
+    server: Server,
+    idle_millis: u64,
+    _guard: LiveGuard,
+}
+
+impl Client {
+    // gives the client one chance to send a request.  errors are
+    // reported here rather than unwound, so that a bad client does not
+    // cost the pool a worker.  returns false when the worker should
+    // stop serving this client.
+    fn poll(&mut self) -> bool {
+        match self.server.handle_one_message(&mut self.stream, std::time::Duration::from_millis(CLIENT_POLL_MILLIS)) {
+            Ok(false) => {
+                false
+            },
+            Ok(true) => {
+                self.idle_millis = 0;
+                true
+            },
+            Err(Error::Io(ref e)) if misc::io::is_timeout(e) => {
+                self.idle_millis = self.idle_millis + CLIENT_POLL_MILLIS;
+                if self.idle_millis >= IDLE_TIMEOUT_SECS * 1000 {
+                    println!("closing idle connection");
+                    false
+                } else {
+                    true
+                }
+            },
+            Err(e) => {
+                println!("client error: {:?}", e);
+                false
+            },
+        }
+    }
+}
+
+// everything a worker thread needs.  a panic while serving a client
+// would otherwise cost the pool that worker for good, so dropping a
+// Worker during a panic starts another thread in its place.  the
+// clients it was serving are hung up on.
+#[derive(Clone)]
+struct Worker {
+    incoming: std::sync::Arc<std::sync::Mutex<std::sync::mpsc::Receiver<(std::net::TcpStream, LiveGuard)>>>,
+    datadir: String,
+    process_id: [u8; 12],
+    metrics: std::sync::Arc<Metrics>,
+}
+
+impl Worker {
+    fn spawn(self) {
+        std::thread::spawn(move|| self.run());
+    }
+
+    // the clients take turns, one request at a time, so an idle one
+    // holds up nobody.  a worker with clients still takes on a queued
+    // one between requests, unless an idle worker is already waiting
+    // for it.
+    fn run(self) {
+        let mut conn = None;
+        let mut clients: Vec<Client> = Vec::new();
         loop {
-            match self.handle_one_message(&mut stream) {
-                Ok(false) => {
-                    return Ok(());
-                },
-                Ok(true) => {
-                    // keep going
-                },
-                Err(e) => {
-                    return Err(e);
-                },
+            let next =
+                if clients.is_empty() {
+                    // the lock is held only while waiting for the next
+                    // client, not while serving it
+                    match self.incoming.lock().unwrap().recv() {
+                        Ok(next) => Some(next),
+                        Err(_) => {
+                            // the queue is closed and empty
+                            break;
+                        },
+                    }
+                } else {
+                    match self.incoming.try_lock() {
+                        Ok(incoming) => incoming.try_recv().ok(),
+                        Err(_) => None,
+                    }
+                };
+            if let Some((stream, guard)) = next {
+                match self.connection(&mut conn) {
+                    Ok(conn) => {
+                        clients.push(Client {
+                            stream: stream,
+                            server: Server::new(conn, self.process_id, self.metrics.clone()),
+                            idle_millis: 0,
+                            _guard: guard,
+                        });
+                    },
+                    Err(e) => {
+                        println!("could not open a connection: {:?}", e);
+                    },
+                }
+            }
+            let mut i = 0;
+            while i < clients.len() {
+                if clients[i].poll() {
+                    i = i + 1;
+                } else {
+                    clients.swap_remove(i);
+                }
             }
         }
     }
 
+    // the storage connection is opened on this thread, the first time
+    // there is a client for it, and never leaves it
+    fn connection(&self, conn: &mut Option<std::rc::Rc<elmo::Connection>>) -> Result<std::rc::Rc<elmo::Connection>> {
+        if conn.is_none() {
+            let c = try!(elmo_sqlite3::connect_dir(&self.datadir));
+            *conn = Some(std::rc::Rc::new(elmo::Connection::new(c)));
+        }
+        Ok(conn.as_ref().unwrap().clone())
+    }
 }
 
-// TODO args:  filename, ipaddr, port
-pub fn serve() {
-    let listener = std::net::TcpListener::bind("127.0.0.1:27017").unwrap();
-
-    // accept connections and process them, spawning a new thread for each one
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                std::thread::spawn(move|| {
-                    // connection succeeded
-                    // TODO how to use filename arg.  lifetime problem.
-                    let conn = elmo_sqlite3::connect("elmodata.db").expect("TODO");
-                    let conn = elmo::Connection::new(conn);
-                    let mut s = Server {
-                        conn: conn,
-                        cursors: std::collections::HashMap::new(),
-                        cursor_num: 0,
-                    };
-                    s.handle_client(stream).expect("TODO");
-                });
-            }
-            Err(e) => { /* connection failed */ }
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            println!("worker panicked, starting another");
+            self.clone().spawn();
         }
     }
+}
+
+// accepted connections go into a queue, and a fixed number of worker
+// threads take them from there.  see Worker::run.
+pub fn spawn_server_with_workers(addr: &str, datadir: &str, workers: usize) -> Result<ServerHandle> {
+    if workers == 0 {
+        return Err(Error::Misc(String::from("the server needs at least one worker")));
+    }
+    let listener = try!(std::net::TcpListener::bind(addr));
+    let addr = try!(listener.local_addr());
+    let process_id = misc::new_bson_objectid_rand();
+    let metrics = std::sync::Arc::new(Metrics::new());
+    let stop = std::sync::Arc::new(AtomicBool::new(false));
+    let live = std::sync::Arc::new(LiveConnections {
+        streams: std::sync::Mutex::new(std::collections::HashMap::new()),
+        done: std::sync::Condvar::new(),
+    });
+
+    let (queue, incoming) = std::sync::mpsc::channel::<(std::net::TcpStream, LiveGuard)>();
+    let worker = Worker {
+        incoming: std::sync::Arc::new(std::sync::Mutex::new(incoming)),
+        datadir: String::from(datadir),
+        process_id: process_id,
+        metrics: metrics,
+    };
+    for _ in 0 .. workers {
+        worker.clone().spawn();
+    }
+    drop(worker);
+
+    let accept = {
+        let stop = stop.clone();
+        std::thread::spawn(move|| {
+            let mut next_id = 0;
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let hangup = match stream.try_clone() {
+                            Ok(s) => s,
+                            Err(_) => continue,
+                        };
+                        next_id = next_id + 1;
+                        live.streams.lock().unwrap().insert(next_id, hangup);
+                        let guard = LiveGuard {
+                            id: next_id,
+                            live: live.clone(),
+                        };
+                        if queue.send((stream, guard)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => { /* connection failed */ }
+                }
+            }
+
+            // close the socket server
+            drop(listener);
+
+            // the workers finish whatever is still queued, which
+            // will not take long once the streams are shut down, and
+            // then exit.
+            drop(queue);
+
+            let mut streams = live.streams.lock().unwrap();
+            for strm in streams.values() {
+                let _ = strm.shutdown(std::net::Shutdown::Both);
+            }
+            while !streams.is_empty() {
+                streams = live.done.wait(streams).unwrap();
+            }
+        })
+    };
+
+    let h = ServerHandle {
+        addr: addr,
+        stop: stop,
+        accept: accept,
+    };
+    Ok(h)
+}
 
-    // close the socket server
-    drop(listener);
+// TODO args:  ipaddr, port
+pub fn serve(datadir: &str, workers: usize) {
+    let h = spawn_server_with_workers("127.0.0.1:27017", datadir, workers).unwrap();
+    h.wait();
 }
 
+// usage: elmo [datadir [workers]]
 pub fn main() {
-    serve();
+    let datadir = std::env::args().nth(1).unwrap_or(String::from("elmodata"));
+    let workers =
+        match std::env::args().nth(2) {
+            Some(s) => {
+                match s.parse::<usize>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        println!("bad worker count: {}", s);
+                        return;
+                    },
+                }
+            },
+            None => DEFAULT_WORKERS,
+        };
+    serve(&datadir, workers);
 }
 
+#[cfg(test)]
+mod tests {
+    use super::Server;
+    use super::MsgQuery;
+
+    use std;
+    use misc;
+    use bson;
+    use elmo;
+    use elmo_sqlite3;
+
+    use elmo::Result;
+
+    fn open_server(base: &str) -> Result<Server> {
+        let conn = try!(elmo_sqlite3::connect(&misc::tempfile(base)));
+        let conn = std::rc::Rc::new(elmo::Connection::new(conn));
+        Ok(Server::new(conn, misc::new_bson_objectid_rand(), std::sync::Arc::new(super::Metrics::new())))
+    }
+
+    // an OP_QUERY message, as a client would send it
+    fn query_bytes(req_id: i32, ns: &str, q: &bson::Document) -> Result<Vec<u8>> {
+        let mut w = Vec::new();
+        let header = super::MsgHeader {
+            message_length: 0,
+            request_id: req_id,
+            response_to: 0,
+            op_code: 2004,
+        };
+        header.encode(&mut w);
+        w.push_all(&misc::endian::i32_to_bytes_le(0));
+        w.push_all(ns.as_bytes());
+        w.push(0);
+        w.push_all(&misc::endian::i32_to_bytes_le(0));
+        w.push_all(&misc::endian::i32_to_bytes_le(-1));
+        try!(q.to_bson(&mut w));
+        misc::bytes::copy_into(&misc::endian::u32_to_bytes_le(w.len() as u32), &mut w[0 .. 4]);
+        Ok(w)
+    }
+
+    // the documents in an OP_REPLY message
+    fn reply_docs(ba: &[u8]) -> Result<Vec<bson::Document>> {
+        let docs = try!(bson::documents_from_bytes(&ba[36 ..]).collect::<bson::Result<Vec<_>>>());
+        Ok(docs)
+    }
+
+    fn query(ns: &str, q: bson::Document) -> MsgQuery {
+        MsgQuery {
+            req_id: 1,
+            flags: 0,
+            full_collection_name: String::from(ns),
+            number_to_skip: 0,
+            number_to_return: -1,
+            query: q,
+            return_fields_selector: None,
+        }
+    }
+
+    #[test]
+    fn hello() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("hello"));
+            let mut q = bson::Document::new_empty();
+            q.set_i32("hello", 1);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            assert_eq!(r.docs.len(), 1);
+            let doc = &r.docs[0];
+            assert!(try!(try!(doc.must_get("isWritablePrimary")).as_bool()));
+            assert!(doc.get("ismaster").is_none());
+            assert!(doc.get("topologyVersion").is_some());
+            assert_eq!(try!(try!(doc.must_get("ok")).as_i32()), 1);
+            assert_eq!(try!(try!(doc.must_get("maxBsonObjectSize")).as_i32()), super::MAX_BSON_OBJECT_SIZE);
+            assert_eq!(try!(try!(doc.must_get("maxMessageSizeBytes")).as_i32()), super::MAX_MESSAGE_SIZE_BYTES);
+            assert_eq!(try!(try!(doc.must_get("maxWriteBatchSize")).as_i32()), super::MAX_WRITE_BATCH_SIZE);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn insert_batch_too_large() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("insert_batch_too_large"));
+            let mut docs = vec![];
+            for i in 0 .. super::MAX_WRITE_BATCH_SIZE + 1 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                docs.push(bson::Value::BDocument(d));
+            }
+            let mut q = bson::Document::new_empty();
+            q.set_str("insert", "foo");
+            q.set_array("documents", bson::Array { items: docs });
+            let r = s.reply_2004(query("test.$cmd", q));
+            assert!(r.is_err());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let h = super::MsgHeader {
+            message_length: 57,
+            request_id: 12,
+            response_to: 11,
+            op_code: 2004,
+        };
+        let mut w = Vec::new();
+        h.encode(&mut w);
+        assert_eq!(w.len(), 16);
+        let mut i = 0;
+        let h2 = super::MsgHeader::parse(&w, &mut i);
+        assert_eq!(i, 16);
+        assert_eq!(h, h2);
+    }
+
+    #[test]
+    fn reply_request_ids() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("reply_request_ids"));
+            let mut q = bson::Document::new_empty();
+            q.set_i32("ismaster", 1);
+            let r1 = try!(s.reply_2004(query("admin.$cmd", q.clone())));
+            let r2 = try!(s.reply_2004(query("admin.$cmd", q)));
+            assert!(r1.req_id > 0);
+            assert!(r2.req_id > r1.req_id);
+            assert_eq!(r1.response_to, 1);
+            assert_eq!(r2.response_to, 1);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    fn insert_docs(s: &mut Server, db: &str, coll: &str, docs: Vec<bson::Document>) -> Result<()> {
+        let count = docs.len();
+        let mut q = bson::Document::new_empty();
+        q.set_str("insert", coll);
+        let docs = docs.into_iter().map(|d| bson::Value::BDocument(d)).collect::<Vec<_>>();
+        q.set_array("documents", bson::Array { items: docs });
+        let r = try!(s.reply_2004(query(&format!("{}.$cmd", db), q)));
+        assert_eq!(try!(try!(r.docs[0].must_get("n")).as_i32()), count as i32);
+        Ok(())
+    }
+
+    // the sorted _ids of what a query on test.foo finds
+    fn ids(s: &mut Server, q: bson::Document) -> Result<Vec<i32>> {
+        let r = try!(s.reply_2004(query("test.foo", q)));
+        let mut ids = try!(r.docs.iter().map(|d| d.must_get("_id").and_then(|v| v.as_i32())).collect::<bson::Result<Vec<_>>>());
+        ids.sort();
+        Ok(ids)
+    }
+
+    #[test]
+    fn query_with_filter() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("query_with_filter"));
+            let mut docs = vec![];
+            for i in 1 .. 4 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("a", i * 10);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            let mut gt = bson::Document::new_empty();
+            gt.set_i32("$gt", 10);
+            let mut q = bson::Document::new_empty();
+            q.set_document("a", gt);
+            let mut req = query("test.foo", q);
+            req.number_to_return = 0;
+            let r = try!(s.reply_2004(req));
+            assert_eq!(r.cursor_id, 0);
+            assert_eq!(r.docs.len(), 2);
+            for d in &r.docs {
+                assert!(try!(try!(d.must_get("a")).as_i32()) > 10);
+            }
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("a", 30);
+            let r = try!(s.reply_2004(query("test.foo", q)));
+            assert_eq!(r.docs.len(), 1);
+            assert_eq!(try!(try!(r.docs[0].must_get("_id")).as_i32()), 3);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    fn create_collection(s: &mut Server, db: &str, coll: &str) -> Result<()> {
+        let mut q = bson::Document::new_empty();
+        q.set_str("create", coll);
+        let r = try!(s.reply_2004(query(&format!("{}.$cmd", db), q)));
+        assert_eq!(try!(try!(r.docs[0].must_get("ok")).as_i32()), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn system_namespaces() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("system_namespaces"));
+            try!(create_collection(&mut s, "test", "foo"));
+            try!(create_collection(&mut s, "test", "bar"));
+            try!(create_collection(&mut s, "other", "baz"));
+            let r = try!(s.reply_2004(query("test.system.namespaces", bson::Document::new_empty())));
+            let mut names = vec![];
+            for d in &r.docs {
+                names.push(String::from(try!(d.must_get_str("name"))));
+            }
+            names.sort();
+            assert_eq!(names, vec![String::from("test.bar"), String::from("test.foo")]);
+
+            let mut req = query("test.system.namespaces", bson::Document::new_empty());
+            req.number_to_skip = -1;
+            assert!(s.reply_2004(req).is_err());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn system_indexes() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("system_indexes"));
+            try!(create_collection(&mut s, "test", "foo"));
+            let r = try!(s.reply_2004(query("test.system.indexes", bson::Document::new_empty())));
+            assert_eq!(r.docs.len(), 1);
+            assert_eq!(try!(r.docs[0].must_get_str("name")), "_id_");
+            assert_eq!(try!(r.docs[0].must_get_str("ns")), "test.foo");
+
+            let mut req = query("test.system.indexes", bson::Document::new_empty());
+            req.number_to_skip = -1;
+            assert!(s.reply_2004(req).is_err());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn bad_collection_names() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("bad_collection_names"));
+            for ns in &["test.", "test..foo", ".foo", "te\0st.foo", "test.fo\0o", "test.$foo", "te$t.foo", "test"] {
+                let r = s.reply_2004(query(ns, bson::Document::new_empty()));
+                assert!(r.is_err(), "{:?} should be rejected", ns);
+            }
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn explain_find() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("explain_find"));
+            let mut d = bson::Document::new_empty();
+            d.set_i32("_id", 1);
+            try!(insert_docs(&mut s, "test", "foo", vec![d]));
+
+            let mut filter = bson::Document::new_empty();
+            filter.set_i32("a", 1);
+            let mut inner = bson::Document::new_empty();
+            inner.set_str("find", "foo");
+            inner.set_document("filter", filter);
+            let mut q = bson::Document::new_empty();
+            q.set_document("explain", inner);
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let doc = &r.docs[0];
+            assert_eq!(try!(try!(doc.must_get("ok")).as_i32()), 1);
+            let planner = try!(try!(doc.must_get("queryPlanner")).as_document());
+            assert_eq!(try!(planner.must_get_str("namespace")), "test.foo");
+            let winning = try!(try!(planner.must_get("winningPlan")).as_document());
+            assert_eq!(try!(winning.must_get_str("stage")), "COLLSCAN");
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn aggregate_match_count() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("aggregate_match_count"));
+            let mut docs = vec![];
+            for i in 0 .. 10 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("a", i % 3);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            let mut filter = bson::Document::new_empty();
+            filter.set_i32("a", 1);
+            let mut stage1 = bson::Document::new_empty();
+            stage1.set_document("$match", filter);
+            let mut stage2 = bson::Document::new_empty();
+            stage2.set_str("$count", "n");
+            let pipeline = vec![bson::Value::BDocument(stage1), bson::Value::BDocument(stage2)];
+            let mut q = bson::Document::new_empty();
+            q.set_str("aggregate", "foo");
+            q.set_array("pipeline", bson::Array { items: pipeline });
+            q.set_document("cursor", bson::Document::new_empty());
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+            let batch = try!(cursor.must_get_array("firstBatch"));
+            assert_eq!(batch.items.len(), 1);
+            let d = try!(batch.items[0].as_document());
+            assert_eq!(try!(try!(d.must_get("n")).as_i32()), 3);
+
+            // when nothing matches, there is no count at all
+            let mut filter = bson::Document::new_empty();
+            filter.set_i32("a", 7);
+            let mut stage1 = bson::Document::new_empty();
+            stage1.set_document("$match", filter);
+            let mut stage2 = bson::Document::new_empty();
+            stage2.set_str("$count", "n");
+            let pipeline = vec![bson::Value::BDocument(stage1), bson::Value::BDocument(stage2)];
+            let mut q = bson::Document::new_empty();
+            q.set_str("aggregate", "foo");
+            q.set_array("pipeline", bson::Array { items: pipeline });
+            q.set_document("cursor", bson::Document::new_empty());
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+            let batch = try!(cursor.must_get_array("firstBatch"));
+            assert!(batch.items.is_empty());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn read_timeout() {
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut s = open_server("read_timeout").unwrap();
+        s.message_timeout = std::time::Duration::from_millis(100);
+
+        // nothing at all is just an idle client
+        let r = s.handle_one_message(&mut stream, std::time::Duration::from_millis(100));
+        match r {
+            Err(elmo::Error::Io(ref e)) => assert!(misc::io::is_timeout(e)),
+            _ => panic!("expected a timeout: {:?}", r),
+        }
+
+        // the start of a frame, but never the rest of it
+        client.write(&[100, 0]).unwrap();
+        let r = s.handle_one_message(&mut stream, std::time::Duration::from_millis(100));
+        match r {
+            Err(elmo::Error::CorruptFile(_)) => (),
+            _ => panic!("expected a timeout in the middle of a message: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn clients_share_data() {
+        fn command(client: &mut std::net::TcpStream, req_id: i32, q: bson::Document) -> Result<bson::Document> {
+            use std::io::Write;
+
+            try!(client.write_all(&try!(query_bytes(req_id, "test.$cmd", &q))));
+            let ba = try!(super::read_message_bytes(client, std::time::Duration::from_secs(30)));
+            let ba = try!(ba.ok_or(elmo::Error::Misc(String::from("no reply"))));
+            let mut docs = try!(reply_docs(&ba));
+            Ok(docs.remove(0))
+        }
+
+        fn f() -> Result<()> {
+            // each worker has its own connection, so with three of them,
+            // the readers below are served by different connections
+            let h = try!(super::spawn_server_with_workers("127.0.0.1:0", &misc::tempfile("clients_share_data"), 3));
+            let addr = h.addr();
+            {
+                let mut writer = try!(std::net::TcpStream::connect(addr));
+                let mut docs = vec![];
+                for i in 0 .. 10 {
+                    let mut d = bson::Document::new_empty();
+                    d.set_i32("_id", i);
+                    docs.push(bson::Value::BDocument(d));
+                }
+                let mut q = bson::Document::new_empty();
+                q.set_str("insert", "foo");
+                q.set_array("documents", bson::Array { items: docs });
+                let r = try!(command(&mut writer, 1, q));
+                assert_eq!(try!(try!(r.must_get("n")).as_i32()), 10);
+            }
+            // both readers stay connected until both have their answer
+            let before = std::sync::Arc::new(std::sync::Barrier::new(2));
+            let after = std::sync::Arc::new(std::sync::Barrier::new(2));
+            let readers = (0 .. 2).map(|i| {
+                let before = before.clone();
+                let after = after.clone();
+                std::thread::spawn(move || -> Result<i32> {
+                    let mut client = try!(std::net::TcpStream::connect(addr));
+                    before.wait();
+                    let mut q = bson::Document::new_empty();
+                    q.set_str("count", "foo");
+                    q.set_document("query", bson::Document::new_empty());
+                    let r = try!(command(&mut client, 10 + i, q));
+                    after.wait();
+                    Ok(try!(try!(r.must_get("n")).as_i32()))
+                })
+            }).collect::<Vec<_>>();
+            for t in readers {
+                assert_eq!(try!(t.join().unwrap()), 10);
+            }
+            h.shutdown();
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn coll_stats() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("coll_stats"));
+            let mut docs = vec![];
+            for i in 0 .. 7 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_str("s", "hello");
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+            let mut q = bson::Document::new_empty();
+            q.set_str("collStats", "foo");
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let doc = &r.docs[0];
+            assert_eq!(try!(try!(doc.must_get("ns")).as_str()), "test.foo");
+            assert_eq!(try!(try!(doc.must_get("count")).numeric_to_i64()), 7);
+            assert!(try!(try!(doc.must_get("size")).numeric_to_i64()) > 0);
+            assert_eq!(try!(try!(doc.must_get("nindexes")).as_i32()), 1);
+            assert_eq!(try!(try!(doc.must_get("ok")).as_i32()), 1);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn rename_collection() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("rename_collection"));
+            let mut docs = vec![];
+            for i in 0 .. 3 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+            try!(create_collection(&mut s, "test", "other"));
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("renameCollection", "test.foo");
+            q.set_str("to", "test.other");
+            assert!(s.reply_2004(query("admin.$cmd", q.clone())).is_err());
+
+            q.set_bool("dropTarget", true);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("ok")).as_i32()), 1);
+
+            fn all(s: &mut Server, ns: &str) -> Result<usize> {
+                let mut req = query(ns, bson::Document::new_empty());
+                req.number_to_return = 0;
+                let r = try!(s.reply_2004(req));
+                Ok(r.docs.len())
+            }
+            assert_eq!(try!(all(&mut s, "test.foo")), 0);
+            assert_eq!(try!(all(&mut s, "test.other")), 3);
+
+            // to a name which is not taken, and with dots of its own
+            let mut q = bson::Document::new_empty();
+            q.set_str("renameCollection", "test.other");
+            q.set_str("to", "test.a.b");
+            try!(s.reply_2004(query("admin.$cmd", q)));
+            assert_eq!(try!(all(&mut s, "test.other")), 0);
+            assert_eq!(try!(all(&mut s, "test.a.b")), 3);
+
+            // names too short to have a dot
+            let mut q = bson::Document::new_empty();
+            q.set_str("renameCollection", "t");
+            q.set_str("to", "test.c");
+            assert!(s.reply_2004(query("admin.$cmd", q)).is_err());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn aggregate_batch_size_zero() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("aggregate_batch_size_zero"));
+            let mut docs = vec![];
+            for i in 0 .. 10 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("a", i % 3);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            let mut filter = bson::Document::new_empty();
+            filter.set_i32("a", 1);
+            let mut stage1 = bson::Document::new_empty();
+            stage1.set_document("$match", filter);
+            let mut cursor_options = bson::Document::new_empty();
+            cursor_options.set_i32("batchSize", 0);
+            let mut q = bson::Document::new_empty();
+            q.set_str("aggregate", "foo");
+            q.set_array("pipeline", bson::Array { items: vec![bson::Value::BDocument(stage1)] });
+            q.set_document("cursor", cursor_options);
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+            assert_eq!(try!(cursor.must_get_array("firstBatch")).items.len(), 0);
+            let cursor_id = try!(try!(cursor.must_get("id")).numeric_to_i64());
+            assert!(cursor_id != 0);
+
+            let r = s.reply_2005(super::MsgGetMore {
+                req_id: 2,
+                full_collection_name: String::from("test.foo"),
+                number_to_return: 100,
+                cursor_id: cursor_id,
+            });
+            let ids = try!(r.docs.iter().map(|d| d.must_get("_id").and_then(|v| v.as_i32())).collect::<bson::Result<Vec<_>>>());
+            assert_eq!(ids, vec![1, 4, 7]);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn db_stats() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("db_stats"));
+            for coll in ["foo", "bar"].iter() {
+                let mut docs = vec![];
+                for i in 0 .. 4 {
+                    let mut d = bson::Document::new_empty();
+                    d.set_i32("_id", i);
+                    docs.push(d);
+                }
+                try!(insert_docs(&mut s, "test", coll, docs));
+            }
+            try!(create_collection(&mut s, "other", "baz"));
+            let mut q = bson::Document::new_empty();
+            q.set_i32("dbStats", 1);
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let doc = &r.docs[0];
+            assert_eq!(try!(try!(doc.must_get("db")).as_str()), "test");
+            assert_eq!(try!(try!(doc.must_get("collections")).as_i32()), 2);
+            assert_eq!(try!(try!(doc.must_get("objects")).numeric_to_i64()), 8);
+            assert!(try!(try!(doc.must_get("dataSize")).numeric_to_i64()) > 0);
+            assert_eq!(try!(try!(doc.must_get("indexes")).as_i32()), 2);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn query_orderby() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("query_orderby"));
+            let mut docs = vec![];
+            for i in 0 .. 6 {
+                let mut inner = bson::Document::new_empty();
+                inner.set_i32("b", (i * 7) % 6);
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("g", i % 2);
+                d.set_document("a", inner);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            let mut orderby = bson::Document::new_empty();
+            orderby.set_i32("g", 1);
+            orderby.set_i32("a.b", -1);
+            let mut q = bson::Document::new_empty();
+            q.set_document("$query", bson::Document::new_empty());
+            q.set_document("$orderby", orderby);
+            let r = try!(s.reply_2004(query("test.foo", q)));
+            let ids = try!(r.docs.iter().map(|d| d.must_get("_id").and_then(|v| v.as_i32())).collect::<bson::Result<Vec<_>>>());
+            assert_eq!(ids, vec![4, 2, 0, 5, 3, 1]);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn find_command() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("find_command"));
+            let mut docs = vec![];
+            for i in 0 .. 6 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("x", i % 2);
+                d.set_i32("y", i * 10);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "c", docs));
+
+            let mut filter = bson::Document::new_empty();
+            filter.set_i32("x", 1);
+            let mut sort = bson::Document::new_empty();
+            sort.set_i32("_id", -1);
+            let mut projection = bson::Document::new_empty();
+            projection.set_i32("x", 0);
+            let mut q = bson::Document::new_empty();
+            q.set_str("find", "c");
+            q.set_document("filter", filter);
+            q.set_document("sort", sort);
+            q.set_document("projection", projection);
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+            assert_eq!(try!(try!(cursor.must_get("id")).numeric_to_i64()), 0);
+            let batch = try!(cursor.must_get_array("firstBatch"));
+            let mut ids = vec![];
+            for v in batch.items.iter() {
+                let d = try!(v.as_document());
+                assert!(d.get("x").is_none());
+                ids.push(try!(try!(d.must_get("_id")).as_i32()));
+            }
+            assert_eq!(ids, vec![5, 3, 1]);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn query_modifiers() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("query_modifiers"));
+            let mut docs = vec![];
+            for i in 0 .. 6 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("x", i % 2);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            let mut filter = bson::Document::new_empty();
+            filter.set_i32("x", 0);
+            let mut orderby = bson::Document::new_empty();
+            orderby.set_i32("_id", -1);
+            let mut q = bson::Document::new_empty();
+            q.set_document("$query", filter.clone());
+            q.set_document("$orderby", orderby);
+            q.set_str("$comment", "just a comment");
+            let r = try!(s.reply_2004(query("test.foo", q)));
+            let ids = try!(r.docs.iter().map(|d| d.must_get("_id").and_then(|v| v.as_i32())).collect::<bson::Result<Vec<_>>>());
+            assert_eq!(ids, vec![4, 2, 0]);
+
+            let mut q = bson::Document::new_empty();
+            q.set_document("$query", filter);
+            q.set_bool("$explain", true);
+            let r = try!(s.reply_2004(query("test.foo", q)));
+            assert_eq!(r.docs.len(), 1);
+            assert!(r.docs[0].get("queryPlanner").is_some());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn spawn_and_shutdown() {
+        fn f() -> Result<()> {
+            use std::io::Write;
+
+            let h = try!(super::spawn_server("127.0.0.1:0", &misc::tempfile("spawn_and_shutdown")));
+            let addr = h.addr();
+            let mut client = try!(std::net::TcpStream::connect(addr));
+            let mut q = bson::Document::new_empty();
+            q.set_i32("ismaster", 1);
+            try!(client.write_all(&try!(query_bytes(7, "admin.$cmd", &q))));
+            let ba = try!(super::read_message_bytes(&mut client, std::time::Duration::from_secs(10)));
+            let ba = try!(ba.ok_or(elmo::Error::Misc(String::from("no reply"))));
+            let docs = try!(reply_docs(&ba));
+            assert_eq!(docs.len(), 1);
+            assert!(try!(try!(docs[0].must_get("ismaster")).as_bool()));
+
+            // the client is still connected, but shutdown hangs up on it
+            h.shutdown();
+            let ba = try!(super::read_message_bytes(&mut client, std::time::Duration::from_secs(10)));
+            assert!(ba.is_none());
+            assert!(std::net::TcpStream::connect(addr).is_err());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn compressed_query() {
+        fn f() -> Result<()> {
+            use std::io::Write;
+
+            let h = try!(super::spawn_server("127.0.0.1:0", &misc::tempfile("compressed_query")));
+            let mut client = try!(std::net::TcpStream::connect(h.addr()));
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("isMaster", 1);
+            q.set_array("compression", bson::Array { items: vec![bson::Value::BString(String::from("snappy")), bson::Value::BString(String::from("zlib"))] });
+            let ba = try!(super::compress_message(super::COMPRESSOR_ZLIB, &try!(query_bytes(3, "admin.$cmd", &q))));
+            try!(client.write_all(&ba));
+
+            let ba = try!(super::read_message_bytes(&mut client, std::time::Duration::from_secs(10)));
+            let ba = try!(ba.ok_or(elmo::Error::Misc(String::from("no reply"))));
+            let mut i = 0;
+            let header = super::MsgHeader::parse(&ba, &mut i);
+            assert_eq!(header.op_code, super::MsgHeader::OP_COMPRESSED);
+            assert_eq!(header.response_to, 3);
+            assert_eq!(misc::bufndx::slurp_i32_le(&ba, &mut i), super::MsgHeader::OP_REPLY);
+            let uncompressed_size = misc::bufndx::slurp_i32_le(&ba, &mut i);
+            assert_eq!(ba[i], super::COMPRESSOR_ZLIB);
+            let reply = try!(super::decompress(ba[i], &ba[i + 1 ..], uncompressed_size as usize));
+            let docs = try!(bson::documents_from_bytes(&reply[20 ..]).collect::<bson::Result<Vec<_>>>());
+            assert_eq!(docs.len(), 1);
+            assert!(try!(try!(docs[0].must_get("ismaster")).as_bool()));
+            let compression = try!(docs[0].must_get_array("compression"));
+            assert_eq!(compression.items.len(), 1);
+            assert_eq!(try!(compression.items[0].as_str()), "zlib");
+
+            drop(client);
+            h.shutdown();
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn get_and_set_parameter() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("get_and_set_parameter"));
+            let mut q = bson::Document::new_empty();
+            q.set_i32("setParameter", 1);
+            q.set_i32("logLevel", 2);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("was")).as_i32()), 0);
+            assert_eq!(try!(try!(r.docs[0].must_get("ok")).as_i32()), 1);
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("getParameter", 1);
+            q.set_i32("logLevel", 1);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("logLevel")).as_i32()), 2);
+            assert!(r.docs[0].get("notablescan").is_none());
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("getParameter", "*");
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            assert!(r.docs[0].get("featureCompatibilityVersion").is_some());
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("setParameter", 1);
+            q.set_i32("noSuchThing", 2);
+            assert!(s.reply_2004(query("admin.$cmd", q)).is_err());
+
+            // an unknown name anywhere means nothing gets set
+            let mut q = bson::Document::new_empty();
+            q.set_i32("setParameter", 1);
+            q.set_i32("logLevel", 3);
+            q.set_i32("noSuchThing", 2);
+            assert!(s.reply_2004(query("admin.$cmd", q)).is_err());
+            let mut q = bson::Document::new_empty();
+            q.set_i32("getParameter", 1);
+            q.set_i32("logLevel", 1);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("logLevel")).as_i32()), 2);
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("setParameter", 1);
+            q.set_i32("logLevel", 3);
+            q.set_bool("notablescan", true);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            let was = try!(try!(r.docs[0].must_get("was")).as_document());
+            assert_eq!(try!(try!(was.must_get("logLevel")).as_i32()), 2);
+            assert_eq!(try!(try!(was.must_get("notablescan")).as_bool()), false);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn compact() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("compact"));
+            for coll in ["foo", "bar"].iter() {
+                let mut docs = vec![];
+                for i in 0 .. 200 {
+                    let mut d = bson::Document::new_empty();
+                    d.set_i32("_id", i);
+                    d.set_string("pad", std::iter::repeat("x").take(100).collect::<String>());
+                    docs.push(d);
+                }
+                try!(insert_docs(&mut s, "test", coll, docs));
+            }
+            let mut q = bson::Document::new_empty();
+            q.set_str("drop", "bar");
+            try!(s.reply_2004(query("test.$cmd", q)));
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("compact", "foo");
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let doc = &r.docs[0];
+            assert_eq!(try!(try!(doc.must_get("ok")).as_i32()), 1);
+            // the pages bar used are given back
+            assert!(try!(try!(doc.must_get("bytesFreed")).numeric_to_i64()) > 0);
+
+            // and foo has the same keys as before
+            assert_eq!(try!(ids(&mut s, bson::Document::new_empty())), (0 .. 200).collect::<Vec<_>>());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn elem_match() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("elem_match"));
+            fn sub(a: i32, b: i32) -> bson::Value {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("a", a);
+                d.set_i32("b", b);
+                bson::Value::BDocument(d)
+            }
+            let mut docs = vec![];
+            let arrays = vec![
+                (1, "arr", vec![sub(1, 0), sub(0, 2)]),
+                (2, "arr", vec![sub(1, 2)]),
+                (3, "arr", vec![sub(0, 0)]),
+                (4, "nums", vec![bson::Value::BInt32(1), bson::Value::BInt32(20)]),
+                (5, "nums", vec![bson::Value::BInt32(15)]),
+            ];
+            for (id, name, items) in arrays {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", id);
+                d.set_array(name, bson::Array { items: items });
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            // both conditions must hold for the same element
+            let mut em = bson::Document::new_empty();
+            em.set_i32("a", 1);
+            em.set_i32("b", 2);
+            let mut p = bson::Document::new_empty();
+            p.set_document("$elemMatch", em);
+            let mut q = bson::Document::new_empty();
+            q.set_document("arr", p);
+            assert_eq!(try!(ids(&mut s, q)), vec![2]);
+
+            // separately, each condition can match a different element
+            let mut q = bson::Document::new_empty();
+            q.set_i32("arr.a", 1);
+            q.set_i32("arr.b", 2);
+            assert_eq!(try!(ids(&mut s, q)), vec![1, 2]);
+
+            let mut range = bson::Document::new_empty();
+            range.set_i32("$gt", 10);
+            range.set_i32("$lt", 18);
+            let mut p = bson::Document::new_empty();
+            p.set_document("$elemMatch", range.clone());
+            let mut q = bson::Document::new_empty();
+            q.set_document("nums", p);
+            assert_eq!(try!(ids(&mut s, q)), vec![5]);
+
+            let mut q = bson::Document::new_empty();
+            q.set_document("nums", range);
+            assert_eq!(try!(ids(&mut s, q)), vec![4, 5]);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn logical_operators() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("logical_operators"));
+            let mut docs = vec![];
+            for i in 0 .. 6 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("x", i);
+                d.set_i32("y", i % 2);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            fn eq(k: &str, v: i32) -> bson::Value {
+                let mut d = bson::Document::new_empty();
+                d.set_i32(k, v);
+                bson::Value::BDocument(d)
+            }
+            fn op(k: &str, docs: Vec<bson::Value>) -> bson::Document {
+                let mut d = bson::Document::new_empty();
+                d.set_array(k, bson::Array { items: docs });
+                d
+            }
+
+            assert_eq!(try!(ids(&mut s, op("$or", vec![eq("x", 1), eq("x", 4)]))), vec![1, 4]);
+            assert_eq!(try!(ids(&mut s, op("$nor", vec![eq("y", 0), eq("x", 5)]))), vec![1, 3]);
+
+            let mut lt = bson::Document::new_empty();
+            lt.set_i32("$lt", 3);
+            let mut x_lt = bson::Document::new_empty();
+            x_lt.set_document("x", lt);
+            let q = op("$and", vec![
+                bson::Value::BDocument(op("$or", vec![eq("y", 0), eq("x", 1)])),
+                bson::Value::BDocument(op("$or", vec![bson::Value::BDocument(x_lt), eq("x", 5)])),
+            ]);
+            assert_eq!(try!(ids(&mut s, q)), vec![0, 1, 2]);
+
+            // several top-level keys are an implicit $and
+            let mut gt = bson::Document::new_empty();
+            gt.set_i32("$gt", 1);
+            let mut q = bson::Document::new_empty();
+            q.set_i32("y", 1);
+            q.set_document("x", gt);
+            assert_eq!(try!(ids(&mut s, q)), vec![3, 5]);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn regex_query() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("regex_query"));
+            let mut docs = vec![];
+            for (i, name) in ["apple", "Apricot", "banana", "grape"].iter().enumerate() {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i as i32);
+                d.set_str("name", name);
+                docs.push(d);
+            }
+            let mut d = bson::Document::new_empty();
+            d.set_i32("_id", 4);
+            d.set_i32("name", 7);
+            docs.push(d);
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            // anchored, and case matters
+            let mut q = bson::Document::new_empty();
+            q.set("name", bson::Value::BRegex(String::from("^ap"), String::new()));
+            assert_eq!(try!(ids(&mut s, q)), vec![0]);
+
+            let mut q = bson::Document::new_empty();
+            q.set("name", bson::Value::BRegex(String::from("ap"), String::new()));
+            assert_eq!(try!(ids(&mut s, q)), vec![0, 3]);
+
+            let mut re = bson::Document::new_empty();
+            re.set_str("$regex", "^ap");
+            re.set_str("$options", "i");
+            let mut q = bson::Document::new_empty();
+            q.set_document("name", re);
+            assert_eq!(try!(ids(&mut s, q)), vec![0, 1]);
+
+            let mut not = bson::Document::new_empty();
+            not.set("$not", bson::Value::BRegex(String::from("^AP"), String::from("i")));
+            let mut q = bson::Document::new_empty();
+            q.set_document("name", not);
+            assert_eq!(try!(ids(&mut s, q)), vec![2, 3, 4]);
+
+            let mut re = bson::Document::new_empty();
+            re.set_str("$regex", "^ap");
+            re.set_str("$options", "q");
+            let mut q = bson::Document::new_empty();
+            q.set_document("name", re);
+            let r = try!(s.reply_2004(query("test.foo", q)));
+            assert!(r.docs[0].get("$err").is_some());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn bson_error_converts() {
+        // try! turns a bson::Error into the elmo::Error the server uses
+        fn f() -> Result<bson::Document> {
+            // an empty document followed by a trailing byte
+            let doc = try!(bson::Document::from_bson(&[5, 0, 0, 0, 0, 0]));
+            Ok(doc)
+        }
+        match f() {
+            Err(elmo::Error::Bson(_)) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn coll_mod() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("coll_mod"));
+            let mut q = bson::Document::new_empty();
+            q.set_str("create", "foo");
+            try!(s.reply_2004(query("test.$cmd", q)));
+
+            let mut validator = bson::Document::new_empty();
+            validator.set_i32("a", 1);
+            let mut q = bson::Document::new_empty();
+            q.set_str("collMod", "foo");
+            q.set_document("validator", validator.clone());
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("ok")).as_i32()), 1);
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("listCollections", 1);
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+            let batch = try!(cursor.must_get_array("firstBatch"));
+            let mut found = false;
+            for c in batch.items.iter() {
+                let c = try!(c.as_document());
+                if try!(c.must_get_str("name")) == "foo" {
+                    let options = try!(try!(c.must_get("options")).as_document());
+                    assert_eq!(*try!(options.must_get("validator")), bson::Value::BDocument(validator.clone()));
+                    found = true;
+                }
+            }
+            assert!(found);
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("collMod", "foo");
+            q.set_bool("usePowerOf2Sizes", true);
+            assert!(s.reply_2004(query("test.$cmd", q)).is_err());
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("collMod", "nope");
+            q.set_document("validator", validator);
+            assert!(s.reply_2004(query("test.$cmd", q)).is_err());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn batch_byte_limit() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("batch_byte_limit"));
+            let mut docs = vec![];
+            for i in 0 .. 10 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_string("pad", std::iter::repeat("x").take(100000).collect::<String>());
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+            s.max_batch_bytes = 250000;
+
+            let mut cursor_options = bson::Document::new_empty();
+            cursor_options.set_i32("batchSize", 100);
+            let mut q = bson::Document::new_empty();
+            q.set_str("aggregate", "foo");
+            q.set_array("pipeline", bson::Array { items: vec![] });
+            q.set_document("cursor", cursor_options);
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+            let first = try!(cursor.must_get_array("firstBatch"));
+            // the limit is crossed by the third document, which still goes in
+            assert_eq!(first.items.len(), 3);
+            let cursor_id = try!(try!(cursor.must_get("id")).numeric_to_i64());
+            assert!(cursor_id != 0);
+
+            let mut got = first.items.len();
+            let mut batches = 1;
+            while got < 10 {
+                let r = s.reply_2005(super::MsgGetMore {
+                    req_id: 2,
+                    full_collection_name: String::from("test.foo"),
+                    number_to_return: 0,
+                    cursor_id: cursor_id,
+                });
+                assert!(r.docs.len() > 0 && r.docs.len() <= 3);
+                got = got + r.docs.len();
+                batches = batches + 1;
+            }
+            assert_eq!(got, 10);
+            assert_eq!(batches, 4);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn drop_indexes() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("drop_indexes"));
+            let mut d = bson::Document::new_empty();
+            d.set_i32("_id", 1);
+            d.set_i32("a", 2);
+            d.set_i32("b", 3);
+            try!(insert_docs(&mut s, "test", "foo", vec![d]));
+
+            let mut indexes = vec![];
+            for k in ["a", "b"].iter() {
+                let mut key = bson::Document::new_empty();
+                key.set_i32(k, 1);
+                let mut ndx = bson::Document::new_empty();
+                ndx.set_string("name", format!("{}_1", k));
+                ndx.set_document("key", key);
+                indexes.push(bson::Value::BDocument(ndx));
+            }
+            let mut q = bson::Document::new_empty();
+            q.set_str("createIndexes", "foo");
+            q.set_array("indexes", bson::Array { items: indexes });
+            try!(s.reply_2004(query("test.$cmd", q)));
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("dropIndexes", "foo");
+            q.set_str("index", "a_1");
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("ok")).as_i32()), 1);
+            assert_eq!(try!(try!(r.docs[0].must_get("nIndexesWas")).as_i32()), 3);
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("listIndexes", "foo");
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+            let mut names = vec![];
+            for ndx in try!(cursor.must_get_array("firstBatch")).items.iter() {
+                names.push(String::from(try!(try!(ndx.as_document()).must_get_str("name"))));
+            }
+            names.sort();
+            assert_eq!(names, vec!["_id_", "b_1"]);
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("dropIndexes", "foo");
+            q.set_str("index", "_id_");
+            assert!(s.reply_2004(query("test.$cmd", q)).is_err());
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("dropIndexes", "foo");
+            q.set_str("index", "nope");
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("ok")).as_i32()), 0);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn find_by_id_uses_index() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("find_by_id_uses_index"));
+            let mut docs = vec![];
+            for i in 0 .. 50 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("a", i * 2);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            let mut filter = bson::Document::new_empty();
+            filter.set_i32("_id", 17);
+            let mut q = bson::Document::new_empty();
+            q.set_str("find", "foo");
+            q.set_document("filter", filter.clone());
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+            let batch = try!(cursor.must_get_array("firstBatch"));
+            assert_eq!(batch.items.len(), 1);
+            assert_eq!(try!(try!(try!(batch.items[0].as_document()).must_get("a")).as_i32()), 34);
+
+            let mut inner = bson::Document::new_empty();
+            inner.set_str("find", "foo");
+            inner.set_document("filter", filter);
+            let mut q = bson::Document::new_empty();
+            q.set_document("explain", inner);
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let planner = try!(try!(r.docs[0].must_get("queryPlanner")).as_document());
+            let winning = try!(try!(planner.must_get("winningPlan")).as_document());
+            assert_eq!(try!(winning.must_get_str("stage")), "IXSCAN");
+            assert_eq!(try!(winning.must_get_str("indexName")), "_id_");
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn list_commands() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("list_commands"));
+            for ns in ["admin.$cmd", "test.$cmd"].iter() {
+                let mut q = bson::Document::new_empty();
+                q.set_i32("listCommands", 1);
+                let r = try!(s.reply_2004(query(ns, q)));
+                let doc = &r.docs[0];
+                assert_eq!(try!(try!(doc.must_get("ok")).as_i32()), 1);
+                let commands = try!(try!(doc.must_get("commands")).as_document());
+                for name in ["insert", "find", "isMaster", "listCommands"].iter() {
+                    let c = try!(try!(commands.must_get(name)).as_document());
+                    assert!(c.get("adminOnly").is_some());
+                }
+                assert!(commands.get("distinct").is_none());
+            }
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn insert_reports_generated_ids() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("insert_reports_generated_ids"));
+            let mut with_id = bson::Document::new_empty();
+            with_id.set_i32("_id", 7);
+            let mut a = bson::Document::new_empty();
+            a.set_i32("x", 1);
+            let mut b = bson::Document::new_empty();
+            b.set_i32("x", 2);
+            let docs = vec![a, with_id, b].into_iter().map(|d| bson::Value::BDocument(d)).collect::<Vec<_>>();
+            let mut q = bson::Document::new_empty();
+            q.set_str("insert", "foo");
+            q.set_array("documents", bson::Array { items: docs });
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let doc = &r.docs[0];
+            assert_eq!(try!(try!(doc.must_get("n")).as_i32()), 3);
+            let ids = try!(doc.must_get_array("insertedIds"));
+            assert_eq!(ids.items.len(), 2);
+            let first = try!(ids.items[0].as_document());
+            let second = try!(ids.items[1].as_document());
+            assert_eq!(try!(try!(first.must_get("index")).as_i32()), 0);
+            assert_eq!(try!(try!(second.must_get("index")).as_i32()), 2);
+            let id0 = try!(try!(first.must_get("_id")).as_objectid());
+            let id2 = try!(try!(second.must_get("_id")).as_objectid());
+            assert!(id0 != id2);
+
+            // and those are the ids that were stored
+            let mut filter = bson::Document::new_empty();
+            filter.set_objectid("_id", id2);
+            let mut q = bson::Document::new_empty();
+            q.set_str("count", "foo");
+            q.set_document("query", filter);
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("n")).numeric_to_i64()), 1);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn host_info() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("host_info"));
+            let mut q = bson::Document::new_empty();
+            q.set_i32("hostInfo", 1);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            let doc = &r.docs[0];
+            assert_eq!(try!(try!(doc.must_get("ok")).as_i32()), 1);
+            let system = try!(try!(doc.must_get("system")).as_document());
+            assert!(try!(try!(system.must_get("numCores")).as_i32()) >= 1);
+            assert!(system.get("hostname").is_some());
+            let os = try!(try!(doc.must_get("os")).as_document());
+            assert!(os.get("type").is_some());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn bulk_write() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("bulk_write"));
+            let mut docs = Vec::new();
+            for i in 0 .. 3 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("x", i);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            let mut ops = Vec::new();
+
+            let mut d = bson::Document::new_empty();
+            d.set_i32("_id", 10);
+            let mut op = bson::Document::new_empty();
+            op.set_i32("insert", 0);
+            op.set_document("document", d);
+            ops.push(bson::Value::BDocument(op));
+
+            let mut filter = bson::Document::new_empty();
+            filter.set_i32("_id", 1);
+            let mut set = bson::Document::new_empty();
+            set.set_i32("x", 100);
+            let mut mods = bson::Document::new_empty();
+            mods.set_document("$set", set);
+            let mut op = bson::Document::new_empty();
+            op.set_i32("update", 0);
+            op.set_document("filter", filter);
+            op.set_document("updateMods", mods);
+            ops.push(bson::Value::BDocument(op));
+
+            let mut filter = bson::Document::new_empty();
+            filter.set_i32("_id", 2);
+            let mut op = bson::Document::new_empty();
+            op.set_i32("delete", 0);
+            op.set_document("filter", filter);
+            ops.push(bson::Value::BDocument(op));
+
+            let mut ns = bson::Document::new_empty();
+            ns.set_str("ns", "test.foo");
+            let mut q = bson::Document::new_empty();
+            q.set_i32("bulkWrite", 1);
+            q.set_array("ops", bson::Array { items: ops });
+            q.set_array("nsInfo", bson::Array { items: vec![bson::Value::BDocument(ns)] });
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            let doc = &r.docs[0];
+            assert_eq!(try!(try!(doc.must_get("ok")).as_i32()), 1);
+            assert_eq!(try!(try!(doc.must_get("nInserted")).as_i32()), 1);
+            assert_eq!(try!(try!(doc.must_get("nMatched")).as_i32()), 1);
+            assert_eq!(try!(try!(doc.must_get("nModified")).as_i32()), 1);
+            assert_eq!(try!(try!(doc.must_get("nRemoved")).as_i32()), 1);
+            assert_eq!(try!(try!(doc.must_get("nUpserted")).as_i32()), 0);
+            assert_eq!(try!(doc.must_get_array("writeErrors")).len(), 0);
+
+            let mut q = bson::Document::new_empty();
+            q.set_str("count", "foo");
+            q.set_document("query", bson::Document::new_empty());
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("n")).as_i32()), 3);
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("_id", 1);
+            let r = try!(s.reply_2004(query("test.foo", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("x")).as_i32()), 100);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn capped_collection() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("capped_collection"));
+            let mut q = bson::Document::new_empty();
+            q.set_str("create", "foo");
+            q.set_bool("capped", true);
+            q.set_i32("size", 1024 * 1024);
+            q.set_i32("max", 3);
+            try!(s.reply_2004(query("test.$cmd", q)));
+
+            for i in 0 .. 5 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                try!(insert_docs(&mut s, "test", "foo", vec![d]));
+            }
+
+            let mut req = query("test.foo", bson::Document::new_empty());
+            req.number_to_return = 0;
+            let r = try!(s.reply_2004(req));
+            let ids = try!(r.docs.iter().map(|d| d.must_get("_id").and_then(|v| v.as_i32())).collect::<std::result::Result<Vec<_>, bson::Error>>());
+            assert_eq!(ids, vec![2, 3, 4]);
+
+            // the evicted documents are gone from the _id index too
+            let mut q = bson::Document::new_empty();
+            q.set_i32("_id", 0);
+            let r = try!(s.reply_2004(query("test.foo", q)));
+            assert_eq!(r.docs.len(), 0);
+
+            // a small size evicts by bytes instead
+            let mut q = bson::Document::new_empty();
+            q.set_str("create", "bar");
+            q.set_bool("capped", true);
+            q.set_i32("size", 100);
+            try!(s.reply_2004(query("test.$cmd", q)));
+            for i in 0 .. 10 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_str("pad", "0123456789");
+                try!(insert_docs(&mut s, "test", "bar", vec![d]));
+            }
+            let mut req = query("test.bar", bson::Document::new_empty());
+            req.number_to_return = 0;
+            let r = try!(s.reply_2004(req));
+            // each doc is 34 bytes, so a third would make 102
+            assert_eq!(r.docs.len(), 2);
+            let bytes = try!(r.docs.iter().map(|d| d.to_bson_array().map(|a| a.len())).collect::<std::result::Result<Vec<_>, bson::Error>>()).iter().fold(0, |acc, n| acc + n);
+            assert!(bytes <= 100);
+            assert_eq!(try!(try!(r.docs[r.docs.len() - 1].must_get("_id")).as_i32()), 9);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn read_your_writes() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("read_your_writes"));
+            let mut d = bson::Document::new_empty();
+            d.set_i32("_id", 1);
+            try!(insert_docs(&mut s, "test", "foo", vec![d]));
+            let mut q = bson::Document::new_empty();
+            q.set_i32("_id", 1);
+            let r = try!(s.reply_2004(query("test.foo", q)));
+            assert_eq!(r.docs.len(), 1);
+
+            let mut docs = vec![];
+            for i in 2 .. 10 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            // leave a cursor open
+            let mut req = query("test.foo", bson::Document::new_empty());
+            req.number_to_return = 2;
+            let r = try!(s.reply_2004(req));
+            assert_eq!(r.docs.len(), 2);
+            let cursor_id = r.cursor_id;
+            assert!(cursor_id != 0);
+
+            // a write on the same connection still commits, and is
+            // seen by the next query
+            let mut d = bson::Document::new_empty();
+            d.set_i32("_id", 100);
+            try!(insert_docs(&mut s, "test", "foo", vec![d]));
+            let mut q = bson::Document::new_empty();
+            q.set_i32("_id", 100);
+            let r = try!(s.reply_2004(query("test.foo", q)));
+            assert_eq!(r.docs.len(), 1);
+
+            // and the open cursor still works, returning what it saw
+            // when it was opened
+            let r = s.reply_2005(super::MsgGetMore {
+                req_id: 2,
+                full_collection_name: String::from("test.foo"),
+                number_to_return: 100,
+                cursor_id: cursor_id,
+            });
+            assert_eq!(r.docs.len(), 7);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn more_clients_than_workers() {
+        fn f() -> Result<()> {
+            let h = try!(super::spawn_server_with_workers("127.0.0.1:0", &misc::tempfile("more_clients_than_workers"), 2));
+            let addr = h.addr();
+            // every client stays connected until all of them have been
+            // served, so the workers must take turns between them
+            let served = std::sync::Arc::new(std::sync::Barrier::new(8));
+            let clients = (0 .. 8).map(|i| {
+                let served = served.clone();
+                std::thread::spawn(move || -> Result<bool> {
+                    use std::io::Write;
+
+                    let mut client = try!(std::net::TcpStream::connect(addr));
+                    let mut q = bson::Document::new_empty();
+                    q.set_i32("ismaster", 1);
+                    try!(client.write_all(&try!(query_bytes(i, "admin.$cmd", &q))));
+                    let ba = try!(super::read_message_bytes(&mut client, std::time::Duration::from_secs(30)));
+                    let ba = try!(ba.ok_or(elmo::Error::Misc(String::from("no reply"))));
+                    let docs = try!(reply_docs(&ba));
+                    served.wait();
+                    drop(client);
+                    Ok(try!(try!(docs[0].must_get("ismaster")).as_bool()))
+                })
+            }).collect::<Vec<_>>();
+            for t in clients {
+                let r = t.join().unwrap();
+                assert!(try!(r));
+            }
+            h.shutdown();
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn non_utf8_collection_name() {
+        fn f() -> Result<()> {
+            use std::io::Write;
+
+            let h = try!(super::spawn_server("127.0.0.1:0", &misc::tempfile("non_utf8_collection_name")));
+            let mut client = try!(std::net::TcpStream::connect(h.addr()));
+
+            let mut ba = try!(query_bytes(5, "test.foo", &bson::Document::new_empty()));
+            // the o in foo, right after the header, flags and "test.f"
+            let pos = 16 + 4 + 6;
+            assert_eq!(ba[pos], b'o');
+            ba[pos] = 0xff;
+            try!(client.write_all(&ba));
+            let reply = try!(super::read_message_bytes(&mut client, std::time::Duration::from_secs(10)));
+            let reply = try!(reply.ok_or(elmo::Error::Misc(String::from("no reply"))));
+            let docs = try!(reply_docs(&reply));
+            assert_eq!(try!(try!(docs[0].must_get("ok")).as_i32()), 0);
+            assert!(try!(try!(docs[0].must_get("$err")).as_str()).contains("Utf8"));
+
+            // a kill cursors gets no reply, not even when it is bad.
+            // this one is compressed, with a negative uncompressed size.
+            let mut ba = Vec::new();
+            let h = super::MsgHeader {
+                message_length: 0,
+                request_id: 7,
+                response_to: 0,
+                op_code: super::MsgHeader::OP_COMPRESSED,
+            };
+            h.encode(&mut ba);
+            ba.push_all(&misc::endian::i32_to_bytes_le(2007));
+            ba.push_all(&misc::endian::i32_to_bytes_le(-1));
+            ba.push(super::COMPRESSOR_NOOP);
+            ba.push_all(&[0; 8]);
+            let len = ba.len() as i32;
+            misc::bytes::copy_into(&misc::endian::i32_to_bytes_le(len), &mut ba[0 .. 4]);
+            try!(client.write_all(&ba));
+
+            // the connection is still good, and the next reply is to
+            // the next request
+            let mut q = bson::Document::new_empty();
+            q.set_i32("ismaster", 1);
+            try!(client.write_all(&try!(query_bytes(6, "admin.$cmd", &q))));
+            let reply = try!(super::read_message_bytes(&mut client, std::time::Duration::from_secs(10)));
+            let reply = try!(reply.ok_or(elmo::Error::Misc(String::from("no reply"))));
+            let mut i = 0;
+            assert_eq!(super::MsgHeader::parse(&reply, &mut i).response_to, 6);
+            let docs = try!(reply_docs(&reply));
+            assert!(try!(try!(docs[0].must_get("ismaster")).as_bool()));
+
+            drop(client);
+            h.shutdown();
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn fsync() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("fsync"));
+            let mut docs = vec![];
+            for i in 0 .. 10 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("fsync", 1);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            let doc = &r.docs[0];
+            assert_eq!(try!(try!(doc.must_get("ok")).as_i32()), 1);
+            assert!(try!(try!(doc.must_get("numFiles")).as_i32()) >= 1);
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("fsync", 1);
+            q.set_bool("lock", true);
+            assert!(s.reply_2004(query("admin.$cmd", q)).is_err());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn slice_projection() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("slice_projection"));
+            let mut d = bson::Document::new_empty();
+            d.set_i32("_id", 1);
+            d.set_i32("x", 5);
+            d.set_array("arr", bson::Array { items: (0 .. 10).map(|i| bson::Value::BInt32(i)).collect() });
+            try!(insert_docs(&mut s, "test", "foo", vec![d]));
+
+            fn project(s: &mut Server, projection: bson::Document) -> Result<bson::Document> {
+                let mut q = bson::Document::new_empty();
+                q.set_str("find", "foo");
+                q.set_document("projection", projection);
+                let r = try!(s.reply_2004(query("test.$cmd", q)));
+                let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+                let batch = try!(cursor.must_get_array("firstBatch"));
+                let d = try!(batch.items[0].as_document()).clone();
+                Ok(d)
+            }
+
+            fn slice(s: &mut Server, v: bson::Value) -> Result<Vec<i32>> {
+                let mut p = bson::Document::new_empty();
+                p.set("$slice", v);
+                let mut projection = bson::Document::new_empty();
+                projection.set_document("arr", p);
+                let d = try!(project(s, projection));
+                // a $slice by itself leaves the other fields alone
+                assert!(d.get("x").is_some());
+                let arr = try!(d.must_get_array("arr"));
+                let a = try!(arr.items.iter().map(|v| v.as_i32()).collect::<bson::Result<Vec<_>>>());
+                Ok(a)
+            }
+
+            fn pair(skip: i32, limit: i32) -> bson::Value {
+                bson::Value::BArray(bson::Array { items: vec![bson::Value::BInt32(skip), bson::Value::BInt32(limit)] })
+            }
+
+            assert_eq!(try!(slice(&mut s, bson::Value::BInt32(3))), vec![0, 1, 2]);
+            assert_eq!(try!(slice(&mut s, bson::Value::BInt32(-2))), vec![8, 9]);
+            assert_eq!(try!(slice(&mut s, bson::Value::BInt32(20))), (0 .. 10).collect::<Vec<_>>());
+            assert_eq!(try!(slice(&mut s, bson::Value::BInt32(-20))), (0 .. 10).collect::<Vec<_>>());
+            assert_eq!(try!(slice(&mut s, pair(2, 3))), vec![2, 3, 4]);
+            assert_eq!(try!(slice(&mut s, pair(-3, 10))), vec![7, 8, 9]);
+            assert_eq!(try!(slice(&mut s, pair(12, 3))), Vec::<i32>::new());
+
+            // alongside an inclusion, the sliced array is included too
+            let mut p = bson::Document::new_empty();
+            p.set_i32("$slice", 1);
+            let mut projection = bson::Document::new_empty();
+            projection.set_i32("_id", 0);
+            projection.set_document("arr", p);
+            projection.set_i32("x", 1);
+            let d = try!(project(&mut s, projection));
+            assert!(d.get("_id").is_none());
+            assert_eq!(try!(try!(d.must_get("x")).as_i32()), 5);
+            assert_eq!(try!(d.must_get_array("arr")).len(), 1);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn batch_byte_limit_after_projection() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("batch_byte_limit_after_projection"));
+            let mut docs = vec![];
+            for i in 0 .. 10 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                d.set_i32("x", i);
+                d.set_string("pad", std::iter::repeat("x").take(100000).collect::<String>());
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+            s.max_batch_bytes = 250000;
+
+            let mut projection = bson::Document::new_empty();
+            projection.set_i32("x", 1);
+            let mut q = bson::Document::new_empty();
+            q.set_str("find", "foo");
+            q.set_document("filter", bson::Document::new_empty());
+            q.set_document("projection", projection);
+            q.set_i32("batchSize", 100);
+            let r = try!(s.reply_2004(query("test.$cmd", q)));
+            let cursor = try!(try!(r.docs[0].must_get("cursor")).as_document());
+            let first = try!(cursor.must_get_array("firstBatch"));
+            // stored, these would go three to a batch
+            assert_eq!(first.items.len(), 10);
+            assert_eq!(try!(try!(cursor.must_get("id")).numeric_to_i64()), 0);
+            for v in first.items.iter() {
+                assert!(try!(v.as_document()).get("pad").is_none());
+            }
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn server_status_metrics() {
+        fn metrics(s: &mut Server) -> Result<bson::Document> {
+            let mut q = bson::Document::new_empty();
+            q.set_i32("serverStatus", 1);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            let m = try!(try!(r.docs[0].must_get("metrics")).as_document());
+            Ok(m.clone())
+        }
+
+        fn counter(m: &bson::Document, name: &str) -> Result<i64> {
+            let n = try!(try!(m.must_get(name)).numeric_to_i64());
+            Ok(n)
+        }
+
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("server_status_metrics"));
+            let before = try!(metrics(&mut s));
+            assert_eq!(try!(counter(&before, "inserts")), 0);
+
+            let mut docs = vec![];
+            for i in 0 .. 3 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                docs.push(d);
+            }
+            try!(insert_docs(&mut s, "test", "foo", docs));
+            let mut q = bson::Document::new_empty();
+            q.set_str("find", "foo");
+            try!(s.reply_2004(query("test.$cmd", q)));
+            let mut q = bson::Document::new_empty();
+            q.set_i32("noSuchCommand", 1);
+            assert!(s.reply_2004(query("test.$cmd", q)).is_err());
+
+            let after = try!(metrics(&mut s));
+            // the first serverStatus counts too
+            assert_eq!(try!(counter(&after, "commands")), try!(counter(&before, "commands")) + 4);
+            assert_eq!(try!(counter(&after, "inserts")), 1);
+            assert_eq!(try!(counter(&after, "queries")), 1);
+            assert_eq!(try!(counter(&after, "errors")), 1);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn server_status_bytes() {
+        fn f() -> Result<()> {
+            use std::io::Write;
+
+            let h = try!(super::spawn_server("127.0.0.1:0", &misc::tempfile("server_status_bytes")));
+            let mut client = try!(std::net::TcpStream::connect(h.addr()));
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("serverStatus", 1);
+            let ba = try!(query_bytes(1, "admin.$cmd", &q));
+            try!(client.write_all(&ba));
+            let reply = try!(super::read_message_bytes(&mut client, std::time::Duration::from_secs(10)));
+            let reply = try!(reply.ok_or(elmo::Error::Misc(String::from("no reply"))));
+
+            try!(client.write_all(&ba));
+            let reply2 = try!(super::read_message_bytes(&mut client, std::time::Duration::from_secs(10)));
+            let reply2 = try!(reply2.ok_or(elmo::Error::Misc(String::from("no reply"))));
+            let docs = try!(reply_docs(&reply2));
+            let m = try!(try!(docs[0].must_get("metrics")).as_document());
+            // the second request is counted before it is answered, the
+            // second reply after
+            assert_eq!(try!(try!(m.must_get("bytesIn")).numeric_to_i64()), 2 * ba.len() as i64);
+            assert_eq!(try!(try!(m.must_get("bytesOut")).numeric_to_i64()), reply.len() as i64);
+
+            drop(client);
+            h.shutdown();
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn current_op() {
+        fn f() -> Result<()> {
+            let mut s = try!(open_server("current_op"));
+            for ns in ["test.$cmd.sys.inprog", "admin.$cmd.sys.inprog"].iter() {
+                let r = try!(s.reply_2004(query(ns, bson::Document::new_empty())));
+                assert_eq!(try!(try!(r.docs[0].must_get("ok")).as_i32()), 1);
+                assert!(try!(r.docs[0].must_get_array("inprog")).items.is_empty());
+            }
+
+            let mut q = bson::Document::new_empty();
+            q.set_i32("currentOp", 1);
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            assert!(try!(r.docs[0].must_get_array("inprog")).items.is_empty());
+
+            let mut q = bson::Document::new_empty();
+            q.set_array("dropConnections", bson::Array::new_empty());
+            let r = try!(s.reply_2004(query("admin.$cmd", q)));
+            assert_eq!(try!(try!(r.docs[0].must_get("ok")).as_i32()), 1);
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn unsupported_opcode() {
+        let mut ba = Vec::new();
+        let header = super::MsgHeader {
+            message_length: 0,
+            request_id: 3,
+            response_to: 0,
+            op_code: 9999,
+        };
+        header.encode(&mut ba);
+        ba.push_all(&[0xff; 20]);
+        let len = ba.len() as i32;
+        misc::bytes::copy_into(&misc::endian::i32_to_bytes_le(len), &mut ba[0 .. 4]);
+        match super::parse_request(&ba) {
+            Err(elmo::Error::Misc(ref s)) => assert_eq!(s, "unsupported opcode 9999"),
+            Err(e) => panic!("wrong error: {:?}", e),
+            Ok(_) => panic!("an unknown opcode was accepted"),
+        }
+    }
+
+    #[test]
+    fn unsafe_db_names() {
+        fn f() -> Result<()> {
+            // with a data directory, each db name becomes a file name,
+            // so it must not be able to point anywhere else
+            let conn = try!(elmo_sqlite3::connect_dir(&misc::tempfile("unsafe_db_names")));
+            let conn = std::rc::Rc::new(elmo::Connection::new(conn));
+            let mut s = Server::new(conn, misc::new_bson_objectid_rand(), std::sync::Arc::new(super::Metrics::new()));
+            for db in vec!["a/b", "a\\b", "c:d"] {
+                let mut q = bson::Document::new_empty();
+                q.set_str("create", "foo");
+                assert!(s.reply_2004(query(&format!("{}.$cmd", db), q)).is_err());
+            }
+            try!(create_collection(&mut s, "new_db-2", "foo"));
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn compressed_bad_sizes() {
+        fn f() -> Result<()> {
+            // header, then original opcode, uncompressed size, compressor
+            fn compressed(size: i32, body: &[u8], truncate: usize) -> Vec<u8> {
+                let mut w = Vec::new();
+                let h = super::MsgHeader {
+                    message_length: 0,
+                    request_id: 1,
+                    response_to: 0,
+                    op_code: super::MsgHeader::OP_COMPRESSED,
+                };
+                h.encode(&mut w);
+                w.push_all(&misc::endian::i32_to_bytes_le(2004));
+                w.push_all(&misc::endian::i32_to_bytes_le(size));
+                w.push(super::COMPRESSOR_NOOP);
+                w.push_all(body);
+                let len = w.len() - truncate;
+                w.truncate(len);
+                w
+            }
+            let body = [0u8; 20];
+            assert!(super::parse_request(&compressed(-1, &body, 0)).is_err());
+            assert!(super::parse_request(&compressed(std::i32::MAX, &body, 0)).is_err());
+            assert!(super::parse_request(&compressed(19, &body, 0)).is_err());
+            assert!(super::parse_request(&compressed(21, &body, 0)).is_err());
+            // cut off in the middle of the compression header
+            assert!(super::parse_request(&compressed(20, &[], 5)).is_err());
+            assert!(super::parse_request(&compressed(20, &[], 1)).is_err());
+            // and in the middle of the message header
+            assert!(super::parse_request(&compressed(20, &[], 15)).is_err());
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+
+    #[test]
+    fn idle_client_shares_worker() {
+        fn f() -> Result<()> {
+            use std::io::Write;
+
+            fn command(client: &mut std::net::TcpStream, req_id: i32, q: bson::Document) -> Result<Box<[u8]>> {
+                try!(client.write_all(&try!(query_bytes(req_id, "test.$cmd", &q))));
+                let ba = try!(super::read_message_bytes(client, std::time::Duration::from_secs(30)));
+                ba.ok_or(elmo::Error::Misc(String::from("no reply")))
+            }
+
+            fn get_more_bytes(req_id: i32, ns: &str, cursor_id: i64) -> Vec<u8> {
+                let mut w = Vec::new();
+                let header = super::MsgHeader {
+                    message_length: 0,
+                    request_id: req_id,
+                    response_to: 0,
+                    op_code: 2005,
+                };
+                header.encode(&mut w);
+                w.push_all(&misc::endian::i32_to_bytes_le(0));
+                w.push_all(ns.as_bytes());
+                w.push(0);
+                w.push_all(&misc::endian::i32_to_bytes_le(0));
+                w.push_all(&misc::endian::i64_to_bytes_le(cursor_id));
+                misc::bytes::copy_into(&misc::endian::u32_to_bytes_le(w.len() as u32), &mut w[0 .. 4]);
+                w
+            }
+
+            let h = try!(super::spawn_server_with_workers("127.0.0.1:0", &misc::tempfile("idle_client_shares_worker"), 1));
+            // the only worker, which this client leaves with an open
+            // cursor, and then goes idle
+            let mut first = try!(std::net::TcpStream::connect(h.addr()));
+            let mut docs = vec![];
+            for i in 0 .. 5 {
+                let mut d = bson::Document::new_empty();
+                d.set_i32("_id", i);
+                docs.push(bson::Value::BDocument(d));
+            }
+            let mut q = bson::Document::new_empty();
+            q.set_str("insert", "foo");
+            q.set_array("documents", bson::Array { items: docs });
+            try!(command(&mut first, 1, q));
+            let mut q = bson::Document::new_empty();
+            q.set_str("find", "foo");
+            q.set_i32("batchSize", 2);
+            let r = try!(reply_docs(&try!(command(&mut first, 2, q))));
+            let cursor = try!(try!(r[0].must_get("cursor")).as_document());
+            assert_eq!(try!(cursor.must_get_array("firstBatch")).items.len(), 2);
+            let cursor_id = try!(try!(cursor.must_get("id")).numeric_to_i64());
+            assert!(cursor_id != 0);
+
+            // still gets served, well before IDLE_TIMEOUT_SECS
+            let mut second = try!(std::net::TcpStream::connect(h.addr()));
+            let mut q = bson::Document::new_empty();
+            q.set_str("count", "foo");
+            q.set_document("query", bson::Document::new_empty());
+            let r = try!(reply_docs(&try!(command(&mut second, 3, q))));
+            assert_eq!(try!(try!(r[0].must_get("n")).as_i32()), 5);
+
+            // and the first client was not hung up on.  its cursor is
+            // still there.
+            try!(first.write_all(&get_more_bytes(4, "test.foo", cursor_id)));
+            let ba = try!(super::read_message_bytes(&mut first, std::time::Duration::from_secs(30)));
+            let ba = try!(ba.ok_or(elmo::Error::Misc(String::from("no reply"))));
+            assert_eq!(try!(reply_docs(&ba)).len(), 3);
+
+            drop(first);
+            drop(second);
+            h.shutdown();
+            Ok(())
+        }
+        let r = f();
+        println!("{:?}", r);
+        assert!(r.is_ok());
+    }
+}
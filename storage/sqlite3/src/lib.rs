@@ -38,9 +38,20 @@ struct MyCollectionWriter {
     update: sqlite3::PreparedStatement,
     stmt_find_rowid: Option<sqlite3::PreparedStatement>,
     indexes: Vec<IndexPrep>,
+    cap: Option<Cap>,
     myconn: std::rc::Rc<MyConn>,
 }
 
+// a capped collection keeps only its newest documents.  did is the
+// rowid, which grows with every insert, so the oldest document is
+// the one with the smallest did.
+struct Cap {
+    size: Option<i64>,
+    max: Option<i64>,
+    stmt_totals: sqlite3::PreparedStatement,
+    stmt_oldest: sqlite3::PreparedStatement,
+}
+
 struct StatementBsonValueIterator {
     stmt: sqlite3::PreparedStatement,
 }
@@ -146,15 +157,16 @@ impl Iterator for MyEmptyIterator {
 }
 
 struct MyCollectionReader {
-    commit_on_drop: bool,
     seq: Box<Iterator<Item=Result<elmo::Row>>>,
-    myconn: std::rc::Rc<MyConn>,
+    // the read transaction this reader ends when it is dropped.  None
+    // for a reader inside a write, which the write ends instead.
+    read: Option<std::rc::Rc<MyReadConn>>,
 
     // TODO need counts here
 }
 
 struct MyReader {
-    myconn: std::rc::Rc<MyConn>,
+    read: std::rc::Rc<MyReadConn>,
     in_tx: bool,
 }
 
@@ -165,6 +177,33 @@ struct MyWriter {
 
 struct MyConn {
     conn: sqlite3::DatabaseConnection,
+    // when this is set, the catalog lives in the main file and the
+    // tables for each database live in <datadir>/<db>.db, attached
+    // under the schema name returned by get_schema_name_for_db().
+    datadir: Option<String>,
+    attached: std::cell::RefCell<std::collections::HashSet<String>>,
+    // the main file, for opening more connections to it
+    file: String,
+    // whether a write transaction is open on conn.  reads do not use
+    // conn, so a commit here is always a real commit, even while a
+    // cursor is open.
+    tx_open: std::cell::Cell<bool>,
+    // how many writes on conn have committed.  see begin_read_tx().
+    writes: std::cell::Cell<u64>,
+    // connections for reading, each with its own snapshot.  an idle
+    // one is reused, so there are only as many as there have been
+    // snapshots open at once.
+    reads: std::cell::RefCell<Vec<std::rc::Rc<MyReadConn>>>,
+}
+
+struct MyReadConn {
+    conn: sqlite3::DatabaseConnection,
+    attached: std::cell::RefCell<std::collections::HashSet<String>>,
+    // the readers using this connection's transaction.  the last one
+    // out commits it.
+    users: std::cell::Cell<usize>,
+    // MyConn.writes when the transaction began
+    writes: std::cell::Cell<u64>,
 }
 
 struct MyPublicConn {
@@ -211,6 +250,12 @@ fn get_table_name_for_index(db: &str, coll: &str, name: &str) -> String {
     format!("ndx.{}.{}.{}", db, coll, name) 
 }
 
+fn get_schema_name_for_db(db: &str) -> String { 
+    // prefixed so that a db named main or temp does not collide
+    // with the schemas sqlite reserves.
+    format!("db.{}", db) 
+}
+
 fn get_index_entries(new_doc: &bson::Document, normspec: &Vec<(String, elmo::IndexType)>, weights: &Option<std::collections::HashMap<String,i32>>, options: &bson::Document, entries: &mut Vec<Vec<(bson::Value,bool)>>) -> Result<()> {
     fn find_index_entry_vals(normspec: &Vec<(String, elmo::IndexType)>, new_doc: &bson::Document, sparse: bool) -> Vec<(bson::Value,bool)> {
         let mut r = Vec::new();
@@ -373,6 +418,63 @@ fn get_collection_info_from_row(r: &sqlite3::ResultRow) -> Result<elmo::Collecti
     Ok(info)
 }
 
+fn get_collection_options(conn: &sqlite3::DatabaseConnection, db: &str, coll: &str) -> Result<Option<bson::Document>> {
+    let mut stmt = try!(conn.prepare("SELECT options FROM \"collections\" WHERE dbName=? AND collName=?").map_err(elmo::wrap_err));
+    try!(stmt.bind_text(1, db).map_err(elmo::wrap_err));
+    try!(stmt.bind_text(2, coll).map_err(elmo::wrap_err));
+    // TODO step_row() ?
+    match try!(stmt.step().map_err(elmo::wrap_err)) {
+        None => Ok(None),
+        Some(r) => {
+            let v = try!(bson::Document::from_bson(&r.column_slice(0).expect("NOT NULL")));
+            Ok(Some(v))
+        },
+    }
+}
+
+fn list_indexes(conn: &sqlite3::DatabaseConnection) -> Result<Vec<elmo::IndexInfo>> {
+    // TODO DRY this string, in MyConn::get_index_info
+    let mut stmt = try!(conn.prepare("SELECT ndxName, spec, options, dbName, collName FROM \"indexes\"").map_err(elmo::wrap_err));
+    let mut v = Vec::new();
+    loop {
+        match try!(stmt.step().map_err(elmo::wrap_err)) {
+            None => break,
+            Some(row) => {
+                let info = try!(get_index_info_from_row(&row));
+                v.push(info);
+            },
+        }
+    }
+    Ok(v)
+}
+
+fn list_collections(conn: &sqlite3::DatabaseConnection) -> Result<Vec<elmo::CollectionInfo>> {
+    let mut stmt = try!(conn.prepare("SELECT dbName, collName, options FROM \"collections\" ORDER BY collName ASC").map_err(elmo::wrap_err));
+    let mut v = Vec::new();
+    loop {
+        match try!(stmt.step().map_err(elmo::wrap_err)) {
+            None => break,
+            Some(row) => {
+                let info = try!(get_collection_info_from_row(&row));
+                v.push(info);
+            },
+        }
+    }
+    Ok(v)
+}
+
+// attaches <dir>/<db>.db under the schema name for db
+fn attach_file(conn: &sqlite3::DatabaseConnection, dir: &str, db: &str) -> Result<()> {
+    let file = std::path::Path::new(dir).join(format!("{}.db", db));
+    let file = try!(file.to_str().ok_or(elmo::Error::Misc(format!("bad database name: {}", db))));
+    let schema = get_schema_name_for_db(db);
+    let mut stmt = try!(conn.prepare(&format!("ATTACH DATABASE ? AS \"{}\"", schema)).map_err(elmo::wrap_err));
+    try!(stmt.bind_text(1, file).map_err(elmo::wrap_err));
+    try!(step_done(&mut stmt));
+    try!(conn.exec(&format!("PRAGMA \"{}\".journal_mode=WAL", schema)).map_err(elmo::wrap_err));
+    Ok(())
+}
+
 fn index_insert_step(stmt: &mut sqlite3::PreparedStatement, k: Vec<u8>, doc_rowid: i64) -> Result<()> {
     stmt.clear_bindings();
     try!(stmt.bind_blob(1, &k).map_err(elmo::wrap_err));
@@ -382,22 +484,204 @@ fn index_insert_step(stmt: &mut sqlite3::PreparedStatement, k: Vec<u8>, doc_rowi
     Ok(())
 }
 
+impl MyReadConn {
+    // the last reader out commits
+    fn end_read_tx(&self) -> Result<()> {
+        let users = self.users.get();
+        if users > 0 {
+            self.users.set(users - 1);
+            if users == 1 {
+                try!(self.conn.exec("COMMIT TRANSACTION").map_err(elmo::wrap_err));
+            }
+        }
+        Ok(())
+    }
+}
+
 impl MyConn {
-    fn get_collection_options(&self, db: &str, coll: &str) -> Result<Option<bson::Document>> {
-        let mut stmt = try!(self.conn.prepare("SELECT options FROM \"collections\" WHERE dbName=? AND collName=?").map_err(elmo::wrap_err));
-        try!(stmt.bind_text(1, db).map_err(elmo::wrap_err));
-        try!(stmt.bind_text(2, coll).map_err(elmo::wrap_err));
-        // TODO step_row() ?
+    // a read shares a transaction which is already open only if no
+    // write on this connection has committed since it began.  so every
+    // read sees the writes before it, and a cursor which is already
+    // open keeps the snapshot it started with.
+    fn begin_read_tx(&self) -> Result<std::rc::Rc<MyReadConn>> {
+        let writes = self.writes.get();
+        let mut reads = self.reads.borrow_mut();
+        for r in reads.iter() {
+            if r.users.get() > 0 && r.writes.get() == writes {
+                r.users.set(r.users.get() + 1);
+                return Ok(r.clone());
+            }
+        }
+        let idle = reads.iter().position(|r| r.users.get() == 0);
+        let r =
+            match idle {
+                Some(i) => reads[i].clone(),
+                None => {
+                    let conn = try!(base_connect(&self.file).map_err(elmo::wrap_err));
+                    let r = std::rc::Rc::new(MyReadConn {
+                        conn: conn,
+                        attached: std::cell::RefCell::new(std::collections::HashSet::new()),
+                        users: std::cell::Cell::new(0),
+                        writes: std::cell::Cell::new(0),
+                    });
+                    reads.push(r.clone());
+                    r
+                },
+            };
+        // nothing is open on it, so this is the time to attach anything
+        // which conn has attached since it was last used
+        if let Some(ref dir) = self.datadir {
+            for db in self.attached.borrow().iter() {
+                if !r.attached.borrow().contains(db) {
+                    try!(attach_file(&r.conn, dir, db));
+                    r.attached.borrow_mut().insert(db.clone());
+                }
+            }
+        }
+        try!(r.conn.exec("BEGIN TRANSACTION").map_err(elmo::wrap_err));
+        r.writes.set(writes);
+        r.users.set(1);
+        Ok(r)
+    }
+
+    fn begin_write_tx(&self) -> Result<()> {
+        if self.tx_open.get() {
+            return Err(elmo::Error::Misc(String::from("a write is already open on this connection")));
+        }
+        try!(self.conn.exec("BEGIN IMMEDIATE TRANSACTION").map_err(elmo::wrap_err));
+        self.tx_open.set(true);
+        Ok(())
+    }
+
+    fn end_write_tx(&self, commit: bool) -> Result<()> {
+        self.tx_open.set(false);
+        let sql = if commit { "COMMIT TRANSACTION" } else { "ROLLBACK TRANSACTION" };
+        try!(self.conn.exec(sql).map_err(elmo::wrap_err));
+        if commit {
+            self.writes.set(self.writes.get() + 1);
+        }
+        Ok(())
+    }
+
+    fn reads_open(&self) -> bool {
+        self.reads.borrow().iter().any(|r| r.users.get() > 0)
+    }
+
+    fn qualify(&self, db: &str, tbl: &str) -> String {
+        match self.datadir {
+            Some(_) => format!("\"{}\".\"{}\"", get_schema_name_for_db(db), tbl),
+            None => format!("\"{}\"", tbl),
+        }
+    }
+
+    fn is_attached(&self, db: &str) -> bool {
+        match self.datadir {
+            Some(_) => self.attached.borrow().contains(db),
+            None => true,
+        }
+    }
+
+    // sqlite does not allow ATTACH inside a transaction
+    fn attach(&self, db: &str) -> Result<()> {
+        if self.is_attached(db) {
+            return Ok(());
+        }
+        // the name becomes a file name in datadir, so nothing which
+        // could reach outside it, like '/' or "..", gets through.
+        if !is_safe_db_name(db) {
+            return Err(elmo::Error::Misc(format!("bad database name: {}", db)));
+        }
+        if self.tx_open.get() {
+            return Err(elmo::Error::Misc(format!("cannot create database {} inside a transaction", db)));
+        }
+        match self.datadir {
+            Some(ref dir) => try!(attach_file(&self.conn, dir, db)),
+            None => unreachable!(),
+        }
+        self.attached.borrow_mut().insert(String::from(db));
+        Ok(())
+    }
+
+    // another connection may have created a database since we last
+    // looked, so this happens at the start of every transaction.
+    fn attach_all(&self) -> Result<()> {
+        if self.datadir.is_none() {
+            return Ok(());
+        }
+        let mut dbs = Vec::new();
+        {
+            let mut stmt = try!(self.conn.prepare("SELECT DISTINCT dbName FROM \"collections\"").map_err(elmo::wrap_err));
+            loop {
+                match try!(stmt.step().map_err(elmo::wrap_err)) {
+                    None => break,
+                    Some(r) => {
+                        let db = r.column_text(0).expect("NOT NULL");
+                        if !self.is_attached(&db) {
+                            dbs.push(db);
+                        }
+                    },
+                }
+            }
+        }
+        for db in dbs {
+            try!(self.attach(&db));
+        }
+        Ok(())
+    }
+
+    // copies the WAL of each file into the file itself and empties it
+    fn checkpoint(&self) -> Result<usize> {
+        if self.tx_open.get() || self.reads_open() {
+            return Err(elmo::Error::Misc(String::from("cannot checkpoint while a transaction is open on this connection")));
+        }
+        try!(self.attach_all());
+        let schemas = self.schemas();
+        for schema in schemas.iter() {
+            let busy = try!(self.pragma_int(schema, "wal_checkpoint(TRUNCATE)"));
+            if busy != 0 {
+                return Err(elmo::Error::Misc(format!("checkpoint of {} was blocked by another connection", schema)));
+            }
+        }
+        Ok(schemas.len())
+    }
+
+    fn schemas(&self) -> Vec<String> {
+        let mut a = vec![String::from("main")];
+        for db in self.attached.borrow().iter() {
+            a.push(get_schema_name_for_db(db));
+        }
+        a
+    }
+
+    fn pragma_int(&self, schema: &str, pragma: &str) -> Result<i64> {
+        let mut stmt = try!(self.conn.prepare(&format!("PRAGMA \"{}\".{}", schema, pragma)).map_err(elmo::wrap_err));
         match try!(stmt.step().map_err(elmo::wrap_err)) {
-            None => Ok(None),
-            Some(r) => {
-                let v = try!(bson::Document::from_bson(&r.column_slice(0).expect("NOT NULL")));
-                Ok(Some(v))
-            },
+            None => Err(elmo::Error::Misc(format!("PRAGMA {} returned no row", pragma))),
+            Some(r) => Ok(r.column_int64(0)),
         }
     }
 
-    fn get_stmt_for_index_scan(myconn: &MyConn, plan: elmo::QueryPlan) -> Result<sqlite3::PreparedStatement> {
+    fn schema_size(&self, schema: &str) -> Result<u64> {
+        let pages = try!(self.pragma_int(schema, "page_count"));
+        let page_size = try!(self.pragma_int(schema, "page_size"));
+        Ok((pages * page_size) as u64)
+    }
+
+    fn compact(&self) -> Result<u64> {
+        try!(self.attach_all());
+        let mut freed = 0;
+        for schema in self.schemas() {
+            let before = try!(self.schema_size(&schema));
+            try!(self.conn.exec(&format!("VACUUM \"{}\"", schema)).map_err(elmo::wrap_err));
+            let after = try!(self.schema_size(&schema));
+            if before > after {
+                freed = freed + (before - after);
+            }
+        }
+        Ok(freed)
+    }
+
+    fn get_stmt_for_index_scan(conn: &sqlite3::DatabaseConnection, plan: elmo::QueryPlan) -> Result<sqlite3::PreparedStatement> {
         let tbl_coll = get_table_name_for_collection(&plan.ndx.db, &plan.ndx.coll);
         let tbl_ndx = get_table_name_for_index(&plan.ndx.db, &plan.ndx.coll, &plan.ndx.name);
 
@@ -433,7 +717,7 @@ impl MyConn {
 
         let f_twok = |kmin: Vec<u8>, kmax: Vec<u8>, op1: &str, op2: &str| -> Result<sqlite3::PreparedStatement> {
             let sql = format!("SELECT DISTINCT d.bson FROM \"{}\" d INNER JOIN \"{}\" i ON (d.did = i.doc_rowid) WHERE k {} ? AND k {} ?", tbl_coll, tbl_ndx, op1, op2);
-            let mut stmt = try!(myconn.conn.prepare(&sql).map_err(elmo::wrap_err));
+            let mut stmt = try!(conn.prepare(&sql).map_err(elmo::wrap_err));
             try!(stmt.bind_blob(1, &kmin).map_err(elmo::wrap_err));
             try!(stmt.bind_blob(2, &kmax).map_err(elmo::wrap_err));
             Ok(stmt)
@@ -448,7 +732,7 @@ impl MyConn {
         let f_one = |vals: elmo::QueryKey, op: &str| -> Result<sqlite3::PreparedStatement> {
             let k = bson::Value::encode_multi_for_index(copy_dirs_from_normspec_to_vals(&normspec, vals));
             let sql = format!("SELECT DISTINCT d.bson FROM \"{}\" d INNER JOIN \"{}\" i ON (d.did = i.doc_rowid) WHERE k {} ?", tbl_coll, tbl_ndx, op);
-            let mut stmt = try!(myconn.conn.prepare(&sql).map_err(elmo::wrap_err));
+            let mut stmt = try!(conn.prepare(&sql).map_err(elmo::wrap_err));
             try!(stmt.bind_blob(1, &k).map_err(elmo::wrap_err));
             Ok(stmt)
         };
@@ -471,9 +755,9 @@ impl MyConn {
         }
     }
 
-    fn get_table_scan_reader(myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, db: &str, coll: &str) -> Result<MyCollectionReader> {
+    fn get_table_scan_reader(conn: &sqlite3::DatabaseConnection, read: Option<std::rc::Rc<MyReadConn>>, db: &str, coll: &str) -> Result<MyCollectionReader> {
         let tbl = get_table_name_for_collection(db, coll);
-        let stmt = try!(myconn.conn.prepare(&format!("SELECT bson FROM \"{}\"", tbl)).map_err(elmo::wrap_err));
+        let stmt = try!(conn.prepare(&format!("SELECT bson FROM \"{}\"", tbl)).map_err(elmo::wrap_err));
         // TODO keep track of total keys examined, etc.
         let seq = 
             StatementBsonValueIterator {
@@ -481,15 +765,14 @@ impl MyConn {
             };
         let rdr = 
             MyCollectionReader {
-                commit_on_drop: commit_on_drop,
                 seq: box seq,
-                myconn: myconn,
+                read: read,
             };
         Ok(rdr)
     }
 
-    fn get_nontext_index_scan_reader(myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, plan: elmo::QueryPlan) -> Result<MyCollectionReader> {
-        let stmt = try!(Self::get_stmt_for_index_scan(&myconn, plan));
+    fn get_nontext_index_scan_reader(conn: &sqlite3::DatabaseConnection, read: Option<std::rc::Rc<MyReadConn>>, plan: elmo::QueryPlan) -> Result<MyCollectionReader> {
+        let stmt = try!(Self::get_stmt_for_index_scan(conn, plan));
 
         // TODO keep track of total keys examined, etc.
         let seq = 
@@ -498,14 +781,13 @@ impl MyConn {
             };
         let rdr = 
             MyCollectionReader {
-                commit_on_drop: commit_on_drop,
                 seq: box seq,
-                myconn: myconn,
+                read: read,
             };
         Ok(rdr)
     }
 
-    fn get_text_index_scan_reader(myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, ndx: &elmo::IndexInfo,  eq: elmo::QueryKey, terms: Vec<elmo::TextQueryTerm>) -> Result<MyCollectionReader> {
+    fn get_text_index_scan_reader(conn: &sqlite3::DatabaseConnection, read: Option<std::rc::Rc<MyReadConn>>, ndx: &elmo::IndexInfo,  eq: elmo::QueryKey, terms: Vec<elmo::TextQueryTerm>) -> Result<MyCollectionReader> {
         let tbl_coll = get_table_name_for_collection(&ndx.db, &ndx.coll);
         let tbl_ndx = get_table_name_for_index(&ndx.db, &ndx.coll, &ndx.name);
         let (normspec, weights) = try!(elmo::get_normalized_spec(&ndx));
@@ -551,7 +833,7 @@ impl MyConn {
         let vals = copy_dirs_from_normspec_to_vals(&normspec, eq);
 
         let sql = format!("SELECT k, doc_rowid FROM \"{}\" i WHERE k > ? AND k < ?", tbl_ndx);
-        let mut stmt = try!(myconn.conn.prepare(&sql).map_err(elmo::wrap_err));
+        let mut stmt = try!(conn.prepare(&sql).map_err(elmo::wrap_err));
 
         let mut found = Vec::new();
         for term in &terms {
@@ -657,7 +939,7 @@ impl MyConn {
         }
 
         let sql = format!("SELECT bson FROM \"{}\" WHERE did=?", tbl_coll);
-        let mut stmt = try!(myconn.conn.prepare(&sql).map_err(elmo::wrap_err));
+        let mut stmt = try!(conn.prepare(&sql).map_err(elmo::wrap_err));
 
         let mut res = Vec::new();
         for (did, cur_weights) in doc_weights {
@@ -679,21 +961,20 @@ impl MyConn {
 
         let rdr = 
             MyCollectionReader {
-                commit_on_drop: commit_on_drop,
                 seq: box res.into_iter(),
-                myconn: myconn,
+                read: read,
             };
         Ok(rdr)
     }
 
-    fn get_collection_reader(&self, myconn: std::rc::Rc<MyConn>, commit_on_drop: bool, db: &str, coll: &str, plan: Option<elmo::QueryPlan>) -> Result<MyCollectionReader> {
-        match try!(self.get_collection_options(db, coll)) {
+    // read, if any, is the read transaction conn belongs to
+    fn get_collection_reader(conn: &sqlite3::DatabaseConnection, read: Option<std::rc::Rc<MyReadConn>>, db: &str, coll: &str, plan: Option<elmo::QueryPlan>) -> Result<MyCollectionReader> {
+        match try!(get_collection_options(conn, db, coll)) {
             None => {
                 let rdr = 
                     MyCollectionReader {
-                        commit_on_drop: commit_on_drop,
                         seq: box MyEmptyIterator,
-                        myconn: myconn,
+                        read: read,
                     };
                 Ok(rdr)
             },
@@ -702,17 +983,17 @@ impl MyConn {
                     Some(plan) => {
                         match plan.bounds {
                             elmo::QueryBounds::Text(eq,terms) => {
-                                let rdr = try!(Self::get_text_index_scan_reader(myconn, commit_on_drop, &plan.ndx, eq, terms));
+                                let rdr = try!(Self::get_text_index_scan_reader(conn, read, &plan.ndx, eq, terms));
                                 return Ok(rdr);
                             },
                             _ => {
-                                let rdr = try!(Self::get_nontext_index_scan_reader(myconn, commit_on_drop, plan));
+                                let rdr = try!(Self::get_nontext_index_scan_reader(conn, read, plan));
                                 return Ok(rdr);
                             },
                         }
                     },
                     None => {
-                        let rdr = try!(Self::get_table_scan_reader(myconn, commit_on_drop, db, coll));
+                        let rdr = try!(Self::get_table_scan_reader(conn, read, db, coll));
                         return Ok(rdr);
                     },
                 };
@@ -721,7 +1002,7 @@ impl MyConn {
     }
 
     fn get_index_info(&self, db: &str, coll: &str, name: &str) -> Result<Option<elmo::IndexInfo>> {
-        // TODO DRY this string, below
+        // TODO DRY this string, in list_indexes
         let mut stmt = try!(self.conn.prepare("SELECT ndxName, spec, options, dbName, collName FROM \"indexes\" WHERE dbName=? AND collName=? AND ndxName=?").map_err(elmo::wrap_err));
         try!(stmt.bind_text(1, db).map_err(elmo::wrap_err));
         try!(stmt.bind_text(2, coll).map_err(elmo::wrap_err));
@@ -735,37 +1016,6 @@ impl MyConn {
         }
     }
 
-    fn base_list_indexes(&self) -> Result<Vec<elmo::IndexInfo>> {
-        // TODO DRY this string, above
-        let mut stmt = try!(self.conn.prepare("SELECT ndxName, spec, options, dbName, collName FROM \"indexes\"").map_err(elmo::wrap_err));
-        let mut v = Vec::new();
-        loop {
-            match try!(stmt.step().map_err(elmo::wrap_err)) {
-                None => break,
-                Some(row) => {
-                    let info = try!(get_index_info_from_row(&row));
-                    v.push(info);
-                },
-            }
-        }
-        Ok(v)
-    }
-
-    fn base_list_collections(&self) -> Result<Vec<elmo::CollectionInfo>> {
-        let mut stmt = try!(self.conn.prepare("SELECT dbName, collName, options FROM \"collections\" ORDER BY collName ASC").map_err(elmo::wrap_err));
-        let mut v = Vec::new();
-        loop {
-            match try!(stmt.step().map_err(elmo::wrap_err)) {
-                None => break,
-                Some(row) => {
-                    let info = try!(get_collection_info_from_row(&row));
-                    v.push(info);
-                },
-            }
-        }
-        Ok(v)
-    }
-
 }
 
 impl MyCollectionWriter {
@@ -787,6 +1037,45 @@ impl MyCollectionWriter {
                 }
     }
 
+    // deletes the oldest documents until the collection fits within
+    // its cap again.  the document just inserted always stays, even
+    // if it is bigger than the cap all by itself.
+    fn enforce_cap(&mut self) -> Result<()> {
+        loop {
+            let oldest = 
+                match self.cap {
+                    None => return Ok(()),
+                    Some(ref mut cap) => {
+                        // both of these stop at their row, not at done, so
+                        // they have to be reset before they can run again
+                        cap.stmt_totals.reset();
+                        cap.stmt_totals.clear_bindings();
+                        let (count, bytes) =
+                            match try!(cap.stmt_totals.step().map_err(elmo::wrap_err)) {
+                                None => return Err(elmo::Error::Misc(String::from("capped collection totals returned no row"))),
+                                Some(r) => (r.column_int64(0), r.column_int64(1)),
+                            };
+                        let over_max = cap.max.map_or(false, |max| count > max);
+                        let over_size = cap.size.map_or(false, |size| bytes > size);
+                        if count <= 1 || !(over_max || over_size) {
+                            return Ok(());
+                        }
+                        cap.stmt_oldest.reset();
+                        cap.stmt_oldest.clear_bindings();
+                        match try!(cap.stmt_oldest.step().map_err(elmo::wrap_err)) {
+                            None => return Ok(()),
+                            Some(r) => r.column_int64(0),
+                        }
+                    },
+                };
+            self.delete.clear_bindings();
+            try!(self.delete.bind_int64(1, oldest).map_err(elmo::wrap_err));
+            try!(step_done(&mut self.delete));
+            try!(verify_changes(&self.delete, 1));
+            try!(Self::update_indexes_delete(&mut self.indexes, oldest));
+        }
+    }
+
     fn update_indexes_delete(indexes: &mut Vec<IndexPrep>, rowid: i64) -> Result<()> {
         for t in indexes {
             t.stmt_delete.clear_bindings();
@@ -820,7 +1109,7 @@ impl elmo::StorageCollectionWriter for MyCollectionWriter {
                 match try!(self.find_rowid(&id).map_err(elmo::wrap_err)) {
                     None => Err(elmo::Error::Misc(String::from("update but does not exist"))),
                     Some(rowid) => {
-                                let ba = v.to_bson_array();
+                                let ba = try!(v.to_bson_array());
                                 self.update.clear_bindings();
                                 try!(self.update.bind_blob(1,&ba).map_err(elmo::wrap_err));
                                 try!(self.update.bind_int64(2, rowid).map_err(elmo::wrap_err));
@@ -858,7 +1147,7 @@ impl elmo::StorageCollectionWriter for MyCollectionWriter {
     }
 
     fn insert(&mut self, v: &bson::Document) -> Result<()> {
-                let ba = v.to_bson_array();
+                let ba = try!(v.to_bson_array());
                 self.insert.clear_bindings();
                 try!(self.insert.bind_blob(1,&ba).map_err(elmo::wrap_err));
                 try!(step_done(&mut self.insert));
@@ -866,6 +1155,7 @@ impl elmo::StorageCollectionWriter for MyCollectionWriter {
                 let rowid = self.myconn.conn.last_insert_rowid();
                 try!(Self::update_indexes_delete(&mut self.indexes, rowid));
                 try!(Self::update_indexes_insert(&mut self.indexes, rowid, &v));
+                try!(self.enforce_cap());
                 Ok(())
     }
 
@@ -893,8 +1183,8 @@ impl MyWriter {
                 // TODO if we already have a text index (where any of its spec keys are text)
                 // then fail.
 
-                let ba_spec = info.spec.to_bson_array();
-                let ba_options = info.options.to_bson_array();
+                let ba_spec = try!(info.spec.to_bson_array());
+                let ba_options = try!(info.options.to_bson_array());
                 let mut stmt = try!(self.myconn.conn.prepare("INSERT INTO \"indexes\" (dbName,collName,ndxName,spec,options) VALUES (?,?,?,?,?)").map_err(elmo::wrap_err));
                 try!(stmt.bind_text(1, &info.db).map_err(elmo::wrap_err));
                 try!(stmt.bind_text(2, &info.coll).map_err(elmo::wrap_err));
@@ -908,14 +1198,14 @@ impl MyWriter {
                         let s =
                         match info.options.get("unique") {
                             Some(&bson::Value::BBoolean(true)) => {
-                                format!("CREATE TABLE \"{}\" (k BLOB NOT NULL, doc_rowid int NOT NULL REFERENCES \"{}\"(did) ON DELETE CASCADE, PRIMARY KEY (k))", tbl_ndx, tbl_coll)
+                                format!("CREATE TABLE {} (k BLOB NOT NULL, doc_rowid int NOT NULL REFERENCES \"{}\"(did) ON DELETE CASCADE, PRIMARY KEY (k))", self.myconn.qualify(&info.db, &tbl_ndx), tbl_coll)
                             },
                             _ => {
-                                format!("CREATE TABLE \"{}\" (k BLOB NOT NULL, doc_rowid int NOT NULL REFERENCES \"{}\"(did) ON DELETE CASCADE, PRIMARY KEY (k,doc_rowid))", tbl_ndx, tbl_coll)
+                                format!("CREATE TABLE {} (k BLOB NOT NULL, doc_rowid int NOT NULL REFERENCES \"{}\"(did) ON DELETE CASCADE, PRIMARY KEY (k,doc_rowid))", self.myconn.qualify(&info.db, &tbl_ndx), tbl_coll)
                             },
                         };
                         try!(self.myconn.conn.exec(&s).map_err(elmo::wrap_err));
-                        try!(self.myconn.conn.exec(&format!("CREATE INDEX \"childndx_{}\" ON \"{}\" (doc_rowid)", tbl_ndx, tbl_ndx)).map_err(elmo::wrap_err));
+                        try!(self.myconn.conn.exec(&format!("CREATE INDEX {} ON \"{}\" (doc_rowid)", self.myconn.qualify(&info.db, &format!("childndx_{}", tbl_ndx)), tbl_ndx)).map_err(elmo::wrap_err));
                         // now insert index entries for every doc that already exists
                         let (normspec, weights) = try!(elmo::get_normalized_spec(&info));
                         let mut stmt2 = try!(self.myconn.conn.prepare(&format!("SELECT did,bson FROM \"{}\"", tbl_coll)).map_err(elmo::wrap_err));
@@ -947,7 +1237,7 @@ impl MyWriter {
     }
 
     fn base_clear_collection(&self, db: &str, coll: &str) -> Result<bool> {
-        match try!(self.myconn.get_collection_options(db, coll)) {
+        match try!(get_collection_options(&self.myconn.conn, db, coll)) {
             None => {
                 let created = try!(self.base_create_collection(db, coll, bson::Document::new_empty()));
                 Ok(created)
@@ -961,8 +1251,8 @@ impl MyWriter {
     }
 
     fn base_rename_collection(&self, old_name: &str, new_name: &str, drop_target: bool) -> Result<bool> {
-        let (old_db, old_coll) = bson::split_name(old_name);
-        let (new_db, new_coll) = bson::split_name(new_name);
+        let (old_db, old_coll) = try!(bson::split_name(old_name));
+        let (new_db, new_coll) = try!(bson::split_name(new_name));
 
         // jstests/core/rename8.js seems to think that renaming to/from a system collection is illegal unless
         // that collection is system.users, which is "whitelisted".  for now, we emulate this behavior, even
@@ -978,12 +1268,16 @@ impl MyWriter {
             let _deleted = try!(self.base_drop_collection(new_db, new_coll));
         }
 
-        match try!(self.myconn.get_collection_options(old_db, old_coll)) {
+        match try!(get_collection_options(&self.myconn.conn, old_db, old_coll)) {
             None => {
                 let created = try!(self.base_create_collection(new_db, new_coll, bson::Document::new_empty()));
                 Ok(created)
             },
             Some(_) => {
+                if old_db != new_db && self.myconn.datadir.is_some() {
+                    // ALTER TABLE cannot move a table to another file
+                    return Err(elmo::Error::Misc(String::from("renameCollection across databases is not supported with a data directory")));
+                }
                 let old_tbl = get_table_name_for_collection(old_db, old_coll);
                 let new_tbl = get_table_name_for_collection(new_db, new_coll);
 
@@ -996,7 +1290,7 @@ impl MyWriter {
 
                 try!(self.myconn.conn.exec(&format!("ALTER TABLE \"{}\" RENAME TO \"{}\"", old_tbl, new_tbl)).map_err(elmo::wrap_err));
 
-                let indexes = try!(self.myconn.base_list_indexes());
+                let indexes = try!(list_indexes(&self.myconn.conn));
                 for info in indexes {
                     if info.db == old_db && info.coll == old_coll {
                         let old_ndx_tbl = get_table_name_for_index(old_db, old_coll, &info.name);
@@ -1010,10 +1304,16 @@ impl MyWriter {
     }
 
     fn base_create_collection(&self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
-        match try!(self.myconn.get_collection_options(db, coll)) {
+        match try!(get_collection_options(&self.myconn.conn, db, coll)) {
             Some(_) => Ok(false),
             None => {
-                let v_options = options.to_bson_array();
+                if !self.myconn.is_attached(db) {
+                    // the first collection in a new database.  its file
+                    // has to be attached by prepare_database() before the
+                    // write began.
+                    return Err(elmo::Error::Misc(format!("database {} was not prepared before the write", db)));
+                }
+                let v_options = try!(options.to_bson_array());
                 let mut stmt = try!(self.myconn.conn.prepare("INSERT INTO \"collections\" (dbName,collName,options) VALUES (?,?,?)").map_err(elmo::wrap_err));
                 try!(stmt.bind_text(1, db).map_err(elmo::wrap_err));
                 try!(stmt.bind_text(2, coll).map_err(elmo::wrap_err));
@@ -1021,7 +1321,7 @@ impl MyWriter {
                 match try!(stmt.step().map_err(elmo::wrap_err)) {
                     None => {
                         let tbl = get_table_name_for_collection(db, coll);
-                        try!(self.myconn.conn.exec(&format!("CREATE TABLE \"{}\" (did INTEGER PRIMARY KEY, bson BLOB NOT NULL)", tbl)).map_err(elmo::wrap_err));
+                        try!(self.myconn.conn.exec(&format!("CREATE TABLE {} (did INTEGER PRIMARY KEY, bson BLOB NOT NULL)", self.myconn.qualify(db, &tbl))).map_err(elmo::wrap_err));
                         // now create mongo index for _id
                         match options.get("autoIndexId") {
                             Some(&bson::Value::BBoolean(false)) => (),
@@ -1072,8 +1372,29 @@ impl MyWriter {
         }
     }
 
+    fn base_set_collection_options(&self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
+        let v_options = try!(options.to_bson_array());
+        let mut stmt = try!(self.myconn.conn.prepare("UPDATE \"collections\" SET options=? WHERE dbName=? AND collName=?").map_err(elmo::wrap_err));
+        try!(stmt.bind_blob(1, &v_options).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(2, db).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(3, coll).map_err(elmo::wrap_err));
+        try!(step_done(&mut stmt));
+        Ok(stmt.changes() == 1)
+    }
+
+    fn base_set_index_options(&self, db: &str, coll: &str, name: &str, options: bson::Document) -> Result<bool> {
+        let v_options = try!(options.to_bson_array());
+        let mut stmt = try!(self.myconn.conn.prepare("UPDATE \"indexes\" SET options=? WHERE dbName=? AND collName=? AND ndxName=?").map_err(elmo::wrap_err));
+        try!(stmt.bind_blob(1, &v_options).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(2, db).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(3, coll).map_err(elmo::wrap_err));
+        try!(stmt.bind_text(4, name).map_err(elmo::wrap_err));
+        try!(step_done(&mut stmt));
+        Ok(stmt.changes() == 1)
+    }
+
     fn base_drop_database(&self, db: &str) -> Result<bool> {
-        let collections = try!(self.myconn.base_list_collections());
+        let collections = try!(list_collections(&self.myconn.conn));
         let mut b = false;
         for t in collections {
             if t.db == db {
@@ -1086,10 +1407,10 @@ impl MyWriter {
     }
 
     fn base_drop_collection(&self, db: &str, coll: &str) -> Result<bool> {
-        match try!(self.myconn.get_collection_options(db, coll)) {
+        match try!(get_collection_options(&self.myconn.conn, db, coll)) {
             None => Ok(false),
             Some(_) => {
-                let indexes = try!(self.myconn.base_list_indexes());
+                let indexes = try!(list_indexes(&self.myconn.conn));
                 for info in indexes {
                     if info.db == db && info.coll == coll {
                         try!(self.base_drop_index(&info.db, &info.coll, &info.name));
@@ -1116,7 +1437,7 @@ impl elmo::StorageWriter for MyWriter {
         let stmt_insert = try!(self.myconn.conn.prepare(&format!("INSERT INTO \"{}\" (bson) VALUES (?)", tbl)).map_err(elmo::wrap_err));
         let stmt_delete = try!(self.myconn.conn.prepare(&format!("DELETE FROM \"{}\" WHERE rowid=?", tbl)).map_err(elmo::wrap_err));
         let stmt_update = try!(self.myconn.conn.prepare(&format!("UPDATE \"{}\" SET bson=? WHERE rowid=?", tbl)).map_err(elmo::wrap_err));
-        let indexes = try!(self.myconn.base_list_indexes());
+        let indexes = try!(list_indexes(&self.myconn.conn));
         let indexes = indexes.into_iter().filter(
             |ndx| ndx.db == db && ndx.coll == coll
             ).collect::<Vec<_>>();
@@ -1140,26 +1461,46 @@ impl elmo::StorageWriter for MyWriter {
             };
             index_stmts.push(t);
         }
+        let options = try!(get_collection_options(&self.myconn.conn, db, coll)).unwrap_or(bson::Document::new_empty());
+        let cap =
+            match options.get("capped") {
+                Some(&bson::Value::BBoolean(true)) => {
+                    let get_limit = |k: &str| match options.get(k) {
+                        Some(&bson::Value::BInt64(n)) if n > 0 => Some(n),
+                        _ => None,
+                    };
+                    let stmt_totals = try!(self.myconn.conn.prepare(&format!("SELECT count(*), coalesce(sum(length(bson)), 0) FROM \"{}\"", tbl)).map_err(elmo::wrap_err));
+                    let stmt_oldest = try!(self.myconn.conn.prepare(&format!("SELECT did FROM \"{}\" ORDER BY did LIMIT 1", tbl)).map_err(elmo::wrap_err));
+                    Some(Cap {
+                        size: get_limit("size"),
+                        max: get_limit("max"),
+                        stmt_totals: stmt_totals,
+                        stmt_oldest: stmt_oldest,
+                    })
+                },
+                _ => None,
+            };
         let c = MyCollectionWriter {
             insert: stmt_insert,
             delete: stmt_delete,
             update: stmt_update,
             stmt_find_rowid: find_rowid,
             indexes: index_stmts,
+            cap: cap,
             myconn: self.myconn.clone(),
         };
         Ok(box c)
     }
 
     fn commit(mut self: Box<Self>) -> Result<()> {
-        try!(self.myconn.conn.exec("COMMIT TRANSACTION").map_err(elmo::wrap_err));
         self.in_tx = false;
+        try!(self.myconn.end_write_tx(true));
         Ok(())
     }
 
     fn rollback(mut self: Box<Self>) -> Result<()> {
-        try!(self.myconn.conn.exec("ROLLBACK TRANSACTION").map_err(elmo::wrap_err));
         self.in_tx = false;
+        try!(self.myconn.end_write_tx(false));
         Ok(())
     }
 
@@ -1185,6 +1526,14 @@ impl elmo::StorageWriter for MyWriter {
         self.base_drop_index(db, coll, name)
     }
 
+    fn set_collection_options(&self, db: &str, coll: &str, options: bson::Document) -> Result<bool> {
+        self.base_set_collection_options(db, coll, options)
+    }
+
+    fn set_index_options(&self, db: &str, coll: &str, name: &str, options: bson::Document) -> Result<bool> {
+        self.base_set_index_options(db, coll, name, options)
+    }
+
     fn drop_database(&self, db: &str) -> Result<bool> {
         self.base_drop_database(db)
     }
@@ -1202,7 +1551,7 @@ impl Drop for MyWriter {
         // explicitly commit or rollback.
         if self.in_tx {
             // TODO should rollback be the default here?  or commit?
-            let _ignored = self.myconn.conn.exec("ROLLBACK TRANSACTION");
+            let _ignored = self.myconn.end_write_tx(false);
         }
     }
 }
@@ -1218,7 +1567,7 @@ impl Drop for MyReader {
         // if temp tables were created, commit is MUCH faster than rollback.
         // but this is sqlite.  anyway...
         if self.in_tx {
-            let _ignored = self.myconn.conn.exec("COMMIT TRANSACTION");
+            let _ignored = self.read.end_read_tx();
         }
     }
 }
@@ -1229,8 +1578,8 @@ impl Drop for MyCollectionReader {
         // matter in principle whether we commit or rollback.  in SQL Server,
         // if temp tables were created, commit is MUCH faster than rollback.
         // but this is sqlite.  anyway...
-        if self.commit_on_drop {
-            let _ignored = self.myconn.conn.exec("COMMIT TRANSACTION");
+        if let Some(ref read) = self.read {
+            let _ignored = read.end_read_tx();
         }
     }
 }
@@ -1244,24 +1593,25 @@ impl Iterator for MyCollectionReader {
 
 impl elmo::StorageBase for MyReader {
     fn get_collection_reader(&self, db: &str, coll: &str, plan: Option<elmo::QueryPlan>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
-        let rdr = try!(self.myconn.get_collection_reader(self.myconn.clone(), false, db, coll, plan));
+        let rdr = try!(MyConn::get_collection_reader(&self.read.conn, None, db, coll, plan));
         Ok(box rdr)
     }
 
     fn list_collections(&self) -> Result<Vec<elmo::CollectionInfo>> {
-        self.myconn.base_list_collections()
+        list_collections(&self.read.conn)
     }
 
     fn list_indexes(&self) -> Result<Vec<elmo::IndexInfo>> {
-        self.myconn.base_list_indexes()
+        list_indexes(&self.read.conn)
     }
 
 }
 
 impl elmo::StorageReader for MyReader {
     fn into_collection_reader(mut self: Box<Self>, db: &str, coll: &str, plan: Option<elmo::QueryPlan>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
+        // the reader takes over this reader's share of the transaction
         self.in_tx = false;
-        let rdr = try!(self.myconn.get_collection_reader(self.myconn.clone(), true, db, coll, plan));
+        let rdr = try!(MyConn::get_collection_reader(&self.read.conn, Some(self.read.clone()), db, coll, plan));
         Ok(box rdr)
     }
 
@@ -1269,23 +1619,24 @@ impl elmo::StorageReader for MyReader {
 
 impl elmo::StorageBase for MyWriter {
     fn get_collection_reader(&self, db: &str, coll: &str, plan: Option<elmo::QueryPlan>) -> Result<Box<Iterator<Item=Result<elmo::Row>> + 'static>> {
-        let rdr = try!(self.myconn.get_collection_reader(self.myconn.clone(), false, db, coll, plan));
+        let rdr = try!(MyConn::get_collection_reader(&self.myconn.conn, None, db, coll, plan));
         Ok(box rdr)
     }
 
     fn list_collections(&self) -> Result<Vec<elmo::CollectionInfo>> {
-        self.myconn.base_list_collections()
+        list_collections(&self.myconn.conn)
     }
 
     fn list_indexes(&self) -> Result<Vec<elmo::IndexInfo>> {
-        self.myconn.base_list_indexes()
+        list_indexes(&self.myconn.conn)
     }
 
 }
 
 impl elmo::StorageConnection for MyPublicConn {
     fn begin_write(&self) -> Result<Box<elmo::StorageWriter + 'static>> {
-        try!(self.myconn.conn.exec("BEGIN IMMEDIATE TRANSACTION").map_err(elmo::wrap_err));
+        try!(self.myconn.attach_all());
+        try!(self.myconn.begin_write_tx());
         let w = MyWriter {
             myconn: self.myconn.clone(),
             in_tx: true,
@@ -1294,13 +1645,30 @@ impl elmo::StorageConnection for MyPublicConn {
     }
 
     fn begin_read(&self) -> Result<Box<elmo::StorageReader + 'static>> {
-        try!(self.myconn.conn.exec("BEGIN TRANSACTION").map_err(elmo::wrap_err));
+        try!(self.myconn.attach_all());
+        let read = try!(self.myconn.begin_read_tx());
         let r = MyReader {
-            myconn: self.myconn.clone(),
+            read: read,
             in_tx: true,
         };
         Ok(box r)
     }
+
+    fn prepare_database(&self, db: &str) -> Result<()> {
+        self.myconn.attach(db)
+    }
+
+    fn compact(&self) -> Result<u64> {
+        self.myconn.compact()
+    }
+
+    fn checkpoint(&self) -> Result<usize> {
+        self.myconn.checkpoint()
+    }
+}
+
+fn is_safe_db_name(db: &str) -> bool {
+    !db.is_empty() && db.chars().all(|c| (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || c == '_' || c == '-')
 }
 
 fn base_connect(name: &str) -> sqlite3::SqliteResult<sqlite3::DatabaseConnection> {
@@ -1318,7 +1686,38 @@ pub fn connect(name: &str) -> Result<Box<elmo::StorageConnection>> {
     let conn = try!(base_connect(name).map_err(elmo::wrap_err));
     let c = MyConn {
         conn: conn,
+        datadir: None,
+        attached: std::cell::RefCell::new(std::collections::HashSet::new()),
+        file: String::from(name),
+        tx_open: std::cell::Cell::new(false),
+        writes: std::cell::Cell::new(0),
+        reads: std::cell::RefCell::new(Vec::new()),
+    };
+    let c = MyPublicConn {
+        myconn: std::rc::Rc::new(c)
+    };
+    Ok(box c)
+}
+
+/// Like connect(), but each database gets its own file, <dir>/<db>.db,
+/// created when its first collection is.  The list of collections and
+/// indexes is kept in <dir>/catalog.  Note that sqlite limits how many
+/// files can be attached to one connection (10, by default).
+pub fn connect_dir(dir: &str) -> Result<Box<elmo::StorageConnection>> {
+    try!(std::fs::create_dir_all(dir));
+    let catalog = std::path::Path::new(dir).join("catalog");
+    let catalog = try!(catalog.to_str().ok_or(elmo::Error::Misc(format!("bad data directory: {}", dir))));
+    let conn = try!(base_connect(catalog).map_err(elmo::wrap_err));
+    let c = MyConn {
+        conn: conn,
+        datadir: Some(String::from(dir)),
+        attached: std::cell::RefCell::new(std::collections::HashSet::new()),
+        file: String::from(catalog),
+        tx_open: std::cell::Cell::new(false),
+        writes: std::cell::Cell::new(0),
+        reads: std::cell::RefCell::new(Vec::new()),
     };
+    try!(c.attach_all());
     let c = MyPublicConn {
         myconn: std::rc::Rc::new(c)
     };
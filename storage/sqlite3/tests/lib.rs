@@ -58,3 +58,100 @@ fn insert() {
     assert!(r.is_ok());
 }
 
+#[test]
+fn data_directory() {
+    fn f() -> elmo::Result<()> {
+        let dir = misc::tempfile("data_directory");
+        let db = try!(elmo_sqlite3::connect_dir(&dir));
+        // a name which could point outside the directory is refused
+        assert!(db.prepare_database("../x").is_err());
+        assert!(!std::path::Path::new(&dir).join("../x.db").exists());
+
+        // each database file is attached before the write begins
+        try!(db.prepare_database("first"));
+        try!(db.prepare_database("second"));
+        {
+            let tx = try!(db.begin_write());
+            {
+                let mut p = try!(tx.get_collection_writer("first", "stuff"));
+                let mut doc = bson::Document::new_empty();
+                doc.set_i32("_id", 1);
+                try!(p.insert(&doc));
+            }
+            {
+                let mut p = try!(tx.get_collection_writer("second", "stuff"));
+                let mut doc = bson::Document::new_empty();
+                doc.set_i32("_id", 2);
+                try!(p.insert(&doc));
+                let mut doc = bson::Document::new_empty();
+                doc.set_i32("_id", 3);
+                try!(p.insert(&doc));
+            }
+            try!(tx.commit());
+        }
+        drop(db);
+
+        assert!(std::path::Path::new(&dir).join("first.db").exists());
+        assert!(std::path::Path::new(&dir).join("second.db").exists());
+
+        // reopen, to make sure the files get attached again
+        let db = try!(elmo_sqlite3::connect_dir(&dir));
+        {
+            let tx = try!(db.begin_read());
+            let first = try!(tx.get_collection_reader("first", "stuff", None)).collect::<Vec<_>>();
+            assert_eq!(first.len(), 1);
+            let second = try!(tx.get_collection_reader("second", "stuff", None)).collect::<Vec<_>>();
+            assert_eq!(second.len(), 2);
+        }
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}
+
+#[test]
+fn write_while_cursor_open() {
+    fn f() -> elmo::Result<()> {
+        fn insert(db: &Box<elmo::StorageConnection>, id: i32) -> elmo::Result<()> {
+            let tx = try!(db.begin_write());
+            {
+                let mut p = try!(tx.get_collection_writer("foo", "bar"));
+                let mut doc = bson::Document::new_empty();
+                doc.set_i32("_id", id);
+                try!(p.insert(&doc));
+            }
+            try!(tx.commit());
+            Ok(())
+        }
+
+        let name = misc::tempfile("write_while_cursor_open");
+        let db = try!(elmo_sqlite3::connect(&name));
+        for i in 0 .. 5 {
+            try!(insert(&db, i));
+        }
+
+        // a cursor, part way through
+        let mut cursor = try!(try!(db.begin_read()).into_collection_reader("foo", "bar", None));
+        assert!(cursor.next().is_some());
+
+        // a write on the same connection commits for real
+        try!(insert(&db, 5));
+        let other = try!(elmo_sqlite3::connect(&name));
+        {
+            let tx = try!(other.begin_read());
+            assert_eq!(try!(tx.get_collection_reader("foo", "bar", None)).count(), 6);
+        }
+
+        // a read on the same connection sees it too
+        let n = try!(try!(db.begin_read()).into_collection_reader("foo", "bar", None)).count();
+        assert_eq!(n, 6);
+
+        // while the cursor still has the snapshot it started with
+        assert_eq!(cursor.count(), 4);
+        Ok(())
+    }
+    let r = f();
+    println!("{:?}", r);
+    assert!(r.is_ok());
+}